@@ -9,6 +9,7 @@ async fn main() {
         max_requests: 5,
         window: std::time::Duration::from_secs(30),
         retry_after_format: RetryAfterFormat::HttpDate,
+        ..Default::default()
     };
 
     // We'll have a single route, /hello, that will be rate limited:
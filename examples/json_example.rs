@@ -24,6 +24,7 @@ async fn main() {
         max_requests: 3,
         window: std::time::Duration::from_secs(30),
         retry_after_format: RetryAfterFormat::Seconds,
+        ..Default::default()
     };
 
     // Create routes
@@ -71,12 +72,12 @@ async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible
     if let Some(rate_limit_rejection) = rejection.find::<RateLimitRejection>() {
         
         // Grab the rate limit info:
-        let info = get_rate_limit_info(&rate_limit_rejection);
+        let info = get_rate_limit_info(rate_limit_rejection);
 
         // Create a json response based on that info:
         let mut json_response = warp::reply::with_status(
             warp::reply::json(&MyCustomError {
-                error: format!("Rate limit exceeded. Try again after {:?}", rate_limit_rejection.retry_after),
+                error: format!("Rate limit exceeded. Try again after {:?}", rate_limit_rejection.retry_after()),
                 code: StatusCode::TOO_MANY_REQUESTS.as_u16()
             }),
             StatusCode::TOO_MANY_REQUESTS
@@ -91,7 +92,7 @@ async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible
         // Handle other rejections with JSON
         Ok(warp::reply::with_status(
             warp::reply::json(&MyCustomError {
-                error: format!("Something went wrong."),
+                error: "Something went wrong.".to_string(),
                 code: 500
             }),
             StatusCode::TOO_MANY_REQUESTS
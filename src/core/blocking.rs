@@ -0,0 +1,132 @@
+//! A blocking front for the limiter, so CLI tools, cron jobs, and other
+//! synchronous code can share limit logic (and counters) with the async
+//! side of an application without spinning up a runtime
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use super::{BoxFuture, RateLimitConfig, RateLimitInfo, RateLimitRejection, RateLimiter, Runtime};
+
+/// Checks requests from synchronous code. Cloning a `BlockingRateLimiter` is
+/// cheap and the clones share their counters.
+///
+/// Build one from an async [`RateLimiter`] to share its counters:
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100));
+/// let blocking = BlockingRateLimiter::from(limiter.clone());
+/// std::thread::spawn(move || {
+///     for job in jobs {
+///         if blocking.check(&job.owner).is_ok() {
+///             job.run();
+///         }
+///     }
+/// });
+/// ```
+///
+/// A tarpitted rejection blocks the calling thread for the tarpit's delay.
+#[derive(Clone, Debug)]
+pub struct BlockingRateLimiter {
+    limiter: RateLimiter,
+}
+
+impl BlockingRateLimiter {
+    /// Build a limiter that enforces `config`
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self::from(RateLimiter::new(config))
+    }
+
+    /// Counts a request against `key`, returning the updated status or a
+    /// rejection if the key has exhausted its window
+    pub fn check(&self, key: &str) -> Result<RateLimitInfo, RateLimitRejection> {
+        self.consume(key, 1)
+    }
+
+    /// Counts a request costing `units` against `key`. The request is
+    /// rejected if it would take the key past its limit.
+    pub fn consume(&self, key: &str, units: u32) -> Result<RateLimitInfo, RateLimitRejection> {
+        block_on(self.limiter.check_rate_limit_with_cost(key, units))
+    }
+
+    /// The async limiter behind this one, sharing its counters
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+}
+
+/// Shares `limiter`'s counters and config
+impl From<RateLimiter> for BlockingRateLimiter {
+    fn from(limiter: RateLimiter) -> Self {
+        Self {
+            limiter: limiter.with_runtime(CurrentThread),
+        }
+    }
+}
+
+/// Sleeps by blocking the calling thread, and runs spawned tasks on threads
+/// of their own
+#[derive(Debug)]
+struct CurrentThread;
+
+impl Runtime for CurrentThread {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        thread::spawn(move || block_on(task));
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async move { thread::sleep(duration) })
+    }
+}
+
+/// Wakes the blocked thread
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread, parking it while the future is
+/// pending
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_and_consume_without_a_runtime() {
+        let limiter = BlockingRateLimiter::new(RateLimitConfig::max_per_minute(5));
+        assert_eq!(limiter.check("a").unwrap().remaining, 4);
+        assert_eq!(limiter.consume("a", 3).unwrap().remaining, 1);
+        assert!(limiter.consume("a", 2).is_err());
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_counters_are_shared_with_the_async_limiter() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(2));
+        let blocking = BlockingRateLimiter::from(limiter.clone());
+        limiter.check_rate_limit("a").await.unwrap();
+
+        let blocking = thread::spawn(move || blocking.check("a").is_ok() && blocking.check("a").is_err());
+        assert!(blocking.join().unwrap());
+        assert!(limiter.check_rate_limit("a").await.is_err());
+    }
+}
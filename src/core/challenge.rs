@@ -0,0 +1,169 @@
+//! Challenges (a captcha redirect, a proof-of-work token) issued to keys over
+//! their limit in place of a plain 429, which restore the key's budget once
+//! answered
+
+use http::{header, Response, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::{add_rate_limit_headers, get_rate_limit_info, RateLimitRejection};
+
+/// The request header carrying a client's answer to its challenge
+pub const CHALLENGE_RESPONSE_HEADER: &str = "x-ratelimit-challenge-response";
+
+/// What a suspicious client must do to get its budget back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Challenge {
+    /// Send the client to a page (e.g. a captcha) that hands it an answer
+    Redirect(String),
+    /// A token the client must work on (e.g. a proof-of-work puzzle), sent
+    /// in the `x-ratelimit-challenge` header
+    Token(String),
+}
+
+/// Rejection for a key over its limit that was issued a challenge
+#[derive(Clone, Debug)]
+pub struct ChallengeRejection {
+    /// The challenge to present
+    pub challenge: Challenge,
+    /// The underlying rate limit rejection
+    pub rejection: RateLimitRejection,
+}
+
+type Issue = Arc<dyn Fn(&str) -> Challenge + Send + Sync>;
+type Verify = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Issues challenges to keys over their limit and verifies the answers.
+/// Cloning a `Challenges` is cheap and the clones share their state.
+///
+/// ```rust,no_run,ignore
+/// let challenges = Challenges::new(
+///     |key| Challenge::Redirect(format!("/captcha?for={key}")),
+///     |key, answer| captcha::verify(key, answer),
+/// );
+/// let api = with_challenge_rate_limit(limiter, challenges, key::remote_ip()).and(routes);
+/// ```
+///
+/// A key is suspicious from the moment it is issued a challenge until it
+/// answers it or `expiry` passes; only suspicious keys can answer.
+#[derive(Clone)]
+pub struct Challenges {
+    issue: Issue,
+    verify: Verify,
+    expiry: Duration,
+    suspicious: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Challenges {
+    /// Build challenges with `issue` and check answers with `verify`, which
+    /// receives the key and the client's answer. Challenges expire after
+    /// ten minutes by default.
+    pub fn new<I, V>(issue: I, verify: V) -> Self
+    where
+        I: Fn(&str) -> Challenge + Send + Sync + 'static,
+        V: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            issue: Arc::new(issue),
+            verify: Arc::new(verify),
+            expiry: Duration::from_secs(10 * 60),
+            suspicious: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How long an issued challenge can be answered
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Whether `key` has an unanswered challenge
+    pub fn is_suspicious(&self, key: &str) -> bool {
+        let suspicious = self.suspicious.lock().unwrap_or_else(|e| e.into_inner());
+        suspicious.get(key).is_some_and(|issued| issued.elapsed() < self.expiry)
+    }
+
+    /// Marks `key` suspicious and issues it a challenge in place of `rejection`
+    pub fn challenge(&self, key: &str, rejection: RateLimitRejection) -> ChallengeRejection {
+        let now = Instant::now();
+        let mut suspicious = self.suspicious.lock().unwrap_or_else(|e| e.into_inner());
+        suspicious.retain(|_, issued| now.duration_since(*issued) < self.expiry);
+        suspicious.insert(key.to_string(), now);
+        drop(suspicious);
+
+        ChallengeRejection {
+            challenge: (self.issue)(key),
+            rejection,
+        }
+    }
+
+    /// Checks `answer` for a suspicious `key`. A correct answer clears the
+    /// key's suspicion; the caller then restores its budget.
+    pub fn verify(&self, key: &str, answer: &str) -> bool {
+        if !self.is_suspicious(key) || !(self.verify)(key, answer) {
+            return false;
+        }
+        let mut suspicious = self.suspicious.lock().unwrap_or_else(|e| e.into_inner());
+        suspicious.remove(key);
+        true
+    }
+}
+
+impl fmt::Debug for Challenges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Challenges").field("expiry", &self.expiry).finish_non_exhaustive()
+    }
+}
+
+/// Builds the challenge response: a `303 See Other` for redirects, or a
+/// `429 Too Many Requests` carrying the token in `x-ratelimit-challenge`.
+/// Both include the rate limit headers.
+impl<B: From<String>> From<&ChallengeRejection> for Response<B> {
+    fn from(rejection: &ChallengeRejection) -> Self {
+        let mut response = Response::new(B::from(String::new()));
+        // Challenge values that aren't valid header values are left out
+        match &rejection.challenge {
+            Challenge::Redirect(location) => {
+                *response.status_mut() = StatusCode::SEE_OTHER;
+                if let Ok(value) = location.parse() {
+                    response.headers_mut().insert(header::LOCATION, value);
+                }
+            }
+            Challenge::Token(token) => {
+                if let Ok(value) = token.parse() {
+                    response.headers_mut().insert("x-ratelimit-challenge", value);
+                }
+                *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                *response.body_mut() = B::from("Rate limit exceeded. Solve the challenge to continue.".to_string());
+            }
+        }
+
+        let _ = add_rate_limit_headers(response.headers_mut(), &get_rate_limit_info(&rejection.rejection));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_challenged_keys_can_answer() {
+        let challenges = Challenges::new(|key| Challenge::Token(format!("puzzle-{key}")), |_, answer| answer == "42");
+        assert!(!challenges.verify("client", "42"));
+
+        let rejection = challenges.challenge("client", RateLimitRejection::new(Duration::from_secs(30), 10));
+        assert_eq!(rejection.challenge, Challenge::Token("puzzle-client".to_string()));
+        let response = Response::<String>::from(&rejection);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-challenge"], "puzzle-client");
+
+        assert!(challenges.is_suspicious("client"));
+        assert!(!challenges.verify("client", "41"));
+        assert!(challenges.verify("client", "42"));
+        assert!(!challenges.is_suspicious("client"));
+    }
+}
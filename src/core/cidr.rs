@@ -0,0 +1,122 @@
+//! CIDR ranges of IP addresses, for matching clients and proxies by network
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::ParseConfigError;
+
+/// A range of IP addresses such as `10.0.0.0/8` or `2001:db8::/32`. A bare
+/// address is a range of one. IPv4-mapped IPv6 addresses match IPv4 ranges.
+///
+/// Serialized as its string form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    /// The range of addresses sharing `addr`'s first `prefix` bits. The
+    /// prefix is capped at the address length.
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let addr = addr.to_canonical();
+        let prefix = prefix.min(Self::bits(addr));
+        Self {
+            addr: Self::masked(addr, prefix),
+            prefix,
+        }
+    }
+
+    /// Whether `ip` falls in the range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        Self::bits(ip) == Self::bits(self.addr) && Self::masked(ip, self.prefix) == self.addr
+    }
+
+    fn bits(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn masked(addr: IpAddr, prefix: u8) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                IpAddr::from((u32::from(v4) & mask).to_be_bytes())
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                IpAddr::from((u128::from(v6) & mask).to_be_bytes())
+            }
+        }
+    }
+}
+
+/// The range of just `addr`
+impl From<IpAddr> for IpCidr {
+    fn from(addr: IpAddr) -> Self {
+        Self::new(addr, u8::MAX)
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = ParseConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseConfigError::new("CIDR range", s, &["<ip>/<prefix>, e.g. 10.0.0.0/8"]);
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| error())?)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| error())?;
+        let bits = Self::bits(addr);
+        match prefix {
+            Some(prefix) if prefix > bits => Err(error()),
+            prefix => Ok(Self::new(addr, prefix.unwrap_or(bits))),
+        }
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpCidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpCidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges_contain_their_addresses() {
+        let private: IpCidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(private.to_string(), "10.0.0.0/8");
+        assert!(private.contains("10.200.0.1".parse().unwrap()));
+        assert!(private.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!private.contains("11.0.0.1".parse().unwrap()));
+
+        let single: IpCidr = "2001:db8::1".parse().unwrap();
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpCidr>().unwrap().contains("203.0.113.7".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("proxy".parse::<IpCidr>().is_err());
+    }
+}
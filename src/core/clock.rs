@@ -0,0 +1,171 @@
+//! The wall clock behind every timestamp the crate emits. Windows are
+//! enforced on the monotonic clock; this decides how their ends are turned
+//! into the dates in `X-RateLimit-Reset`, `Retry-After`, and JSON bodies.
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex as StdMutex, RwLock as StdRwLock};
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::RateLimitStore;
+
+/// How long an offset read from a store's clock is trusted before it is
+/// read again
+const STORE_CLOCK_REFRESH: Duration = Duration::from_secs(60);
+
+/// Where emitted timestamps get the current time from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeSource {
+    /// Read the system clock each time. A clock jump shifts emitted reset
+    /// times while the windows themselves stay put.
+    #[default]
+    System,
+    /// Read the system clock once, then advance it with the monotonic clock
+    /// the windows run on, so emitted times always agree with enforcement.
+    /// Call [`reconcile_clock`] to pick up a deliberate clock correction.
+    Monotonic,
+}
+
+/// A wall clock time paired with the monotonic instant it was read at
+#[derive(Clone, Copy, Debug)]
+struct Anchor {
+    instant: Instant,
+    utc: DateTime<Utc>,
+}
+
+impl Anchor {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            utc: Utc::now(),
+        }
+    }
+
+    /// The wall clock time at `instant`, measured from the anchor
+    fn at(&self, instant: Instant) -> DateTime<Utc> {
+        let offset = if instant >= self.instant {
+            ChronoDuration::from_std(instant - self.instant)
+        } else {
+            ChronoDuration::from_std(self.instant - instant).map(|d| -d)
+        };
+        self.utc + offset.unwrap_or_else(|_| ChronoDuration::zero())
+    }
+}
+
+static MONOTONIC: AtomicBool = AtomicBool::new(false);
+static ANCHOR: StdRwLock<Option<Anchor>> = StdRwLock::new(None);
+
+/// Sets the time source for every timestamp emitted by this process. The
+/// default is [`TimeSource::System`].
+pub fn set_time_source(source: TimeSource) {
+    if source == TimeSource::Monotonic {
+        reconcile_clock();
+    }
+    MONOTONIC.store(source == TimeSource::Monotonic, Ordering::Relaxed);
+}
+
+/// The time source set with [`set_time_source`]
+pub fn time_source() -> TimeSource {
+    if MONOTONIC.load(Ordering::Relaxed) {
+        TimeSource::Monotonic
+    } else {
+        TimeSource::System
+    }
+}
+
+/// Re-reads the system clock under [`TimeSource::Monotonic`], e.g. after NTP
+/// steps it. Emitted times jump once, here, rather than drifting silently.
+pub fn reconcile_clock() {
+    *ANCHOR.write().unwrap_or_else(|e| e.into_inner()) = Some(Anchor::now());
+}
+
+/// The current wall clock time under the configured time source
+pub(crate) fn wall_clock_now() -> DateTime<Utc> {
+    wall_clock_at(Instant::now())
+}
+
+/// The wall clock time at `instant` under the configured time source
+pub(crate) fn wall_clock_at(instant: Instant) -> DateTime<Utc> {
+    if time_source() == TimeSource::System {
+        return Anchor::now().at(instant);
+    }
+    let anchor = *ANCHOR.read().unwrap_or_else(|e| e.into_inner());
+    match anchor {
+        Some(anchor) => anchor.at(instant),
+        None => Anchor::now().at(instant),
+    }
+}
+
+/// A store's clock, as this process's wall clock plus an offset read from
+/// the store every minute. Offsets within the tolerance are taken for
+/// measurement noise and ignored.
+#[derive(Debug)]
+pub(crate) struct StoreClock {
+    tolerance: Duration,
+    /// When the offset was last read, and what it was
+    offset: StdMutex<Option<(Instant, ChronoDuration)>>,
+}
+
+impl StoreClock {
+    pub(crate) fn new(tolerance: Duration) -> Self {
+        Self {
+            tolerance,
+            offset: StdMutex::new(None),
+        }
+    }
+
+    /// The current time by `store`'s clock. While one caller reads the
+    /// store's clock, the others go by the offset read before.
+    pub(crate) async fn now(&self, store: &dyn RateLimitStore) -> DateTime<Utc> {
+        let sent = Instant::now();
+        let offset = {
+            let mut offset = self.offset.lock().unwrap_or_else(|e| e.into_inner());
+            match *offset {
+                Some((read, offset)) if sent.duration_since(read) < STORE_CLOCK_REFRESH => {
+                    return wall_clock_at(sent) + offset;
+                }
+                // Claim the read, so concurrent callers don't read it too
+                previous => {
+                    let previous = previous.map_or_else(ChronoDuration::zero, |(_, offset)| offset);
+                    *offset = Some((sent, previous));
+                    previous
+                }
+            }
+        };
+
+        let offset = match store.now().await {
+            Ok(remote) => {
+                // The store read its clock about halfway through the round trip
+                let midway = wall_clock_at(sent + sent.elapsed() / 2);
+                let measured = remote - midway;
+                match measured.abs().to_std() {
+                    Ok(skew) if skew <= self.tolerance => ChronoDuration::zero(),
+                    _ => {
+                        tracing::debug!("rate limit store's clock is {} ms off this one", measured.num_milliseconds());
+                        measured
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!("failed to read the rate limit store's clock: {}", e);
+                offset
+            }
+        };
+        *self.offset.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), offset));
+        wall_clock_now() + offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anchor_follows_the_monotonic_clock() {
+        let anchor = Anchor::now();
+        let later = anchor.instant + Duration::from_secs(30);
+        assert_eq!(anchor.at(later), anchor.utc + ChronoDuration::seconds(30));
+        assert_eq!(anchor.at(anchor.instant), anchor.utc);
+    }
+}
@@ -0,0 +1,249 @@
+//! Concurrent connection limits for streaming routes (SSE, long polling),
+//! separate from the request-rate limiter, and caps on identical and slow
+//! requests in flight
+
+use http::Method;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::sync::Mutex;
+
+/// Caps how many connections each key may hold open at once. Cloning a
+/// `ConcurrencyLimiter` is cheap and the clones share their slots.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimiter {
+    max_per_key: u32,
+    state: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl ConcurrencyLimiter {
+    /// Build a limiter allowing `max_per_key` open connections per key
+    pub fn new(max_per_key: u32) -> Self {
+        Self {
+            max_per_key,
+            state: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a slot for `key`. The slot is held until the returned permit is
+    /// dropped, so move the permit into whatever lives as long as the
+    /// connection (e.g. the SSE stream).
+    pub fn try_acquire(&self, key: &str) -> Result<ConnectionPermit, ConcurrencyLimitRejection> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let open = state.entry(key.to_string()).or_insert(0);
+        if *open >= self.max_per_key {
+            return Err(ConcurrencyLimitRejection {
+                limit: self.max_per_key,
+            });
+        }
+        *open += 1;
+
+        Ok(ConnectionPermit {
+            key: key.to_string(),
+            state: self.state.clone(),
+        })
+    }
+
+    /// Number of connections currently held open by `key`
+    pub fn open_connections(&self, key: &str) -> u32 {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.get(key).copied().unwrap_or(0)
+    }
+}
+
+/// A held connection slot, released on drop
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    key: String,
+    state: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(open) = state.get_mut(&self.key) {
+            *open = open.saturating_sub(1);
+            if *open == 0 {
+                state.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Rejection produced when a key already holds its maximum number of
+/// connections
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimitRejection {
+    /// Maximum concurrent connections allowed per key
+    pub limit: u32,
+}
+
+/// Start times of each key's requests in flight, by request ID
+type InFlight = HashMap<String, HashMap<u64, Instant>>;
+
+/// Caps how many slow requests each key may have running at once. A request
+/// counts as slow once it has run longer than the threshold; a key already
+/// at its cap can't start new requests until one finishes. This stops
+/// slow-loris style clients that stay well under the request-rate limit
+/// from tying up every worker. Cloning a `SlowRequestGuard` is cheap and the
+/// clones share their state.
+#[derive(Clone, Debug)]
+pub struct SlowRequestGuard {
+    threshold: Duration,
+    max_slow_per_key: u32,
+    state: Arc<Mutex<(u64, InFlight)>>,
+}
+
+impl SlowRequestGuard {
+    /// Allow each key `max_slow_per_key` requests running longer than
+    /// `threshold` at once
+    pub fn new(threshold: Duration, max_slow_per_key: u32) -> Self {
+        Self {
+            threshold,
+            max_slow_per_key,
+            state: Arc::new(Mutex::new((0, HashMap::new()))),
+        }
+    }
+
+    /// Starts tracking a request for `key`, or rejects it if the key already
+    /// has its maximum of slow requests running. Hold the permit until the
+    /// request completes.
+    pub fn try_start(&self, key: &str) -> Result<SlowRequestPermit, ConcurrencyLimitRejection> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (next_id, in_flight) = &mut *state;
+        let requests = in_flight.entry(key.to_string()).or_default();
+        let slow = requests.values().filter(|start| start.elapsed() > self.threshold).count();
+        if slow >= self.max_slow_per_key as usize {
+            return Err(ConcurrencyLimitRejection {
+                limit: self.max_slow_per_key,
+            });
+        }
+
+        let id = *next_id;
+        *next_id += 1;
+        requests.insert(id, Instant::now());
+        Ok(SlowRequestPermit {
+            key: key.to_string(),
+            id,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Number of `key`'s requests in flight that have run past the threshold
+    pub fn slow_requests(&self, key: &str) -> u32 {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.1.get(key).map_or(0, |requests| {
+            requests.values().filter(|start| start.elapsed() > self.threshold).count() as u32
+        })
+    }
+}
+
+/// A request tracked by a [`SlowRequestGuard`], released on drop
+#[derive(Debug)]
+pub struct SlowRequestPermit {
+    key: String,
+    id: u64,
+    state: Arc<Mutex<(u64, InFlight)>>,
+}
+
+impl Drop for SlowRequestPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(requests) = state.1.get_mut(&self.key) {
+            requests.remove(&self.id);
+            if requests.is_empty() {
+                state.1.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A concurrency key identifying identical requests from one client, so a
+/// [`ConcurrencyLimiter`] keyed on it caps duplicates in flight, e.g. a
+/// client stuck in a retry loop. The body, when given, is hashed in.
+pub fn request_fingerprint(key: &str, method: &Method, path_and_query: &str, body: Option<&[u8]>) -> String {
+    match body {
+        Some(body) => {
+            let mut hasher = DefaultHasher::new();
+            body.hash(&mut hasher);
+            format!("{} {} {} {:016x}", key, method, path_and_query, hasher.finish())
+        }
+        None => format!("{} {} {}", key, method, path_and_query),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_release_on_drop() {
+        let limiter = ConcurrencyLimiter::new(2);
+
+        let first = limiter.try_acquire("a").unwrap();
+        let _second = limiter.try_acquire("a").unwrap();
+        assert_eq!(limiter.try_acquire("a").unwrap_err().limit, 2);
+        assert!(limiter.try_acquire("b").is_ok());
+
+        drop(first);
+        assert_eq!(limiter.open_connections("a"), 1);
+        assert!(limiter.try_acquire("a").is_ok());
+    }
+
+    #[test]
+    fn test_request_fingerprints() {
+        let get = request_fingerprint("a", &Method::GET, "/orders?page=2", None);
+        assert_eq!(get, "a GET /orders?page=2");
+        assert_ne!(get, request_fingerprint("b", &Method::GET, "/orders?page=2", None));
+
+        let first = request_fingerprint("a", &Method::POST, "/orders", Some(b"{\"id\":1}"));
+        assert_eq!(first, request_fingerprint("a", &Method::POST, "/orders", Some(b"{\"id\":1}")));
+        assert_ne!(first, request_fingerprint("a", &Method::POST, "/orders", Some(b"{\"id\":2}")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_requests_block_new_ones() {
+        let guard = SlowRequestGuard::new(Duration::from_secs(5), 1);
+
+        let slow = guard.try_start("a").unwrap();
+        // Fast requests in flight don't count
+        let fast = guard.try_start("a").unwrap();
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_eq!(guard.slow_requests("a"), 2);
+        assert_eq!(guard.try_start("a").unwrap_err().limit, 1);
+        assert!(guard.try_start("b").is_ok());
+
+        drop(slow);
+        drop(fast);
+        assert!(guard.try_start("a").is_ok());
+    }
+}
+
+#[cfg(all(test, rate_limit_loom))]
+mod loom_tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_never_exceed_max() {
+        loom::model(|| {
+            let limiter = ConcurrencyLimiter::new(1);
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let limiter = limiter.clone();
+                    loom::thread::spawn(move || {
+                        let permit = limiter.try_acquire("a");
+                        assert!(limiter.open_connections("a") <= 1);
+                        permit.is_ok()
+                    })
+                })
+                .collect();
+            let acquired = threads.into_iter().map(|t| t.join().unwrap()).filter(|&ok| ok).count();
+            assert!(acquired >= 1);
+            assert_eq!(limiter.open_connections("a"), 0);
+        });
+    }
+}
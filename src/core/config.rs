@@ -0,0 +1,1146 @@
+//! Rate limit configuration and the option enums it is built from
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveTime, TimeZone, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use super::{IpCidr, RateLimitError};
+
+/// Rejected requests held in the tarpit at once unless configured otherwise
+const DEFAULT_MAX_TARPITTED: usize = 1024;
+
+/// Configuration for the rate limiter
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// Maximum number of requests allowed within the window
+    pub max_requests: u32,
+    /// Time window for rate limiting
+    pub window: Duration,
+    /// Format for Retry-After header (RFC 7231 Date or Seconds)
+    pub retry_after_format: RetryAfterFormat,
+    /// Scale each request's cost by its `Content-Length`. When unset, every
+    /// request costs one unit.
+    pub content_length_cost: Option<ContentLengthCost>,
+    /// Which gateway's rate limit headers to emit
+    pub header_style: HeaderStyle,
+    /// Prefix for every key this config limits, so tenants sharing one store
+    /// never collide. Keys are stored as `namespace:key`.
+    pub namespace: Option<String>,
+    /// Roll part of each window's unused budget into the next one. When
+    /// unset, every window starts with exactly `max_requests`.
+    pub carry_over: Option<CarryOver>,
+    /// Percentage of the limit (e.g. 80) past which responses carry an
+    /// `X-RateLimit-Warning` header and a `SoftLimitReached` event fires
+    pub soft_limit: Option<u8>,
+    /// How long to hold a rejected request before answering it, raising
+    /// the cost of scraping past the limit. When unset, rejections are
+    /// immediate. Applied by the HTTP adapters, not by
+    /// `RateLimiter::check_rate_limit` itself.
+    pub tarpit: Option<Duration>,
+    /// Most rejected requests held in the tarpit at once, across the
+    /// limiter and its views. Rejections past it are answered at once.
+    pub max_tarpitted: usize,
+    /// How CORS preflight requests are counted. By default they count like
+    /// any other request; exempting them lets anyone send unlimited
+    /// `OPTIONS` requests, so it is opt-in.
+    pub preflight: PreflightPolicy,
+    /// Most burst credits a key can bank. Idle keys earn credits at the
+    /// steady rate and spend them to go past `max_requests` in a burst.
+    /// When unset, keys never earn credits.
+    pub burst_credits: Option<u32>,
+    /// Percentage of the limit (e.g. 90) past which a growing share of
+    /// requests is rejected at random, so clients slow down before they hit
+    /// the limit outright
+    pub early_rejection: Option<u8>,
+    /// When each key's window starts and ends
+    pub reset_mode: ResetMode,
+    /// Units per window a key may go past its limit, each reported as a
+    /// `RateLimitEvent::Overage` for billing instead of being rejected.
+    /// When unset, requests past the limit are rejected.
+    pub overage: Option<u32>,
+    /// How long a key may go without a request before its state is dropped,
+    /// whatever the window length. When unset, keys are kept indefinitely.
+    pub idle_ttl: Option<Duration>,
+    /// Let a key that has spent its window borrow from the next one, paying
+    /// it back with interest. When unset, keys can't borrow.
+    pub borrowing: Option<Borrowing>,
+    /// How requests are counted against the limit
+    pub algorithm: RateLimitAlgorithm,
+    /// Most keys to keep state for at once, forgetting the least recently
+    /// seen past it. When unset, the number of keys is unbounded.
+    pub max_tracked_keys: Option<usize>,
+    /// Client IPs that are never counted. Matched against the client's
+    /// address, whatever the key (see `RateLimiter::screen`).
+    pub allowlist: Vec<IpCidr>,
+    /// Client IPs turned away outright, whatever their usage. Matched
+    /// against the client's address, whatever the key.
+    pub denylist: Vec<IpCidr>,
+    /// The status denylisted clients are answered with
+    pub deny_status: StatusCode,
+}
+
+/// The fields a child config overrides on top of a parent, leaving the rest
+/// to follow the parent. Apply with [`ConfigOverrides::apply`], or register
+/// with `RateLimiter::with_endpoint_overrides` so endpoints track the
+/// limiter's config as it changes:
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(600).with_header_style(HeaderStyle::Draft))
+///     // 10/min, with the org-wide window, headers, and format
+///     .with_endpoint_overrides("search", ConfigOverrides::new().with_max_requests(10));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigOverrides {
+    /// Replaces `max_requests`
+    pub max_requests: Option<u32>,
+    /// Replaces `window`
+    pub window: Option<Duration>,
+    /// Replaces `retry_after_format`
+    pub retry_after_format: Option<RetryAfterFormat>,
+    /// Replaces `header_style`
+    pub header_style: Option<HeaderStyle>,
+    /// Replaces `reset_mode`
+    pub reset_mode: Option<ResetMode>,
+    /// Replaces `preflight`
+    pub preflight: Option<PreflightPolicy>,
+    /// Replaces `soft_limit`
+    pub soft_limit: Option<u8>,
+    /// Replaces `tarpit`
+    pub tarpit: Option<Duration>,
+    /// Replaces `burst_credits`
+    pub burst_credits: Option<u32>,
+}
+
+impl ConfigOverrides {
+    /// Overrides nothing: the child is the parent
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override the number of requests per window
+    pub fn with_max_requests(mut self, max_requests: u32) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Override the window length
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Override the Retry-After format
+    pub fn with_retry_after_format(mut self, retry_after_format: RetryAfterFormat) -> Self {
+        self.retry_after_format = Some(retry_after_format);
+        self
+    }
+
+    /// Override which gateway's headers to emit
+    pub fn with_header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.header_style = Some(header_style);
+        self
+    }
+
+    /// Override when windows start and end
+    pub fn with_reset_mode(mut self, reset_mode: ResetMode) -> Self {
+        self.reset_mode = Some(reset_mode);
+        self
+    }
+
+    /// Override how CORS preflights are counted
+    pub fn with_preflight(mut self, preflight: PreflightPolicy) -> Self {
+        self.preflight = Some(preflight);
+        self
+    }
+
+    /// Override the soft limit percentage
+    pub fn with_soft_limit(mut self, percent: u8) -> Self {
+        self.soft_limit = Some(percent);
+        self
+    }
+
+    /// Override the tarpit delay
+    pub fn with_tarpit(mut self, delay: Duration) -> Self {
+        self.tarpit = Some(delay);
+        self
+    }
+
+    /// Override the burst credit cap
+    pub fn with_burst_credits(mut self, cap: u32) -> Self {
+        self.burst_credits = Some(cap);
+        self
+    }
+
+    /// `parent` with these overrides applied
+    pub fn apply(&self, parent: &RateLimitConfig) -> RateLimitConfig {
+        let mut config = parent.clone();
+        if let Some(max_requests) = self.max_requests {
+            config.max_requests = max_requests;
+        }
+        if let Some(window) = self.window {
+            config.window = window;
+        }
+        if let Some(retry_after_format) = &self.retry_after_format {
+            config.retry_after_format = retry_after_format.clone();
+        }
+        if let Some(header_style) = self.header_style {
+            config.header_style = header_style;
+        }
+        if let Some(reset_mode) = self.reset_mode {
+            config.reset_mode = reset_mode;
+        }
+        if let Some(preflight) = self.preflight {
+            config.preflight = preflight;
+        }
+        config.soft_limit = self.soft_limit.or(config.soft_limit);
+        config.tarpit = self.tarpit.or(config.tarpit);
+        config.burst_credits = self.burst_credits.or(config.burst_credits);
+        config
+    }
+}
+
+/// How much unused budget rolls into the next window, for long-lived quotas
+/// such as monthly API allowances
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CarryOver {
+    /// Percentage of the unused budget carried over, 0 to 100
+    pub percent: u8,
+    /// The most that can be carried into a window, however long the key
+    /// has been idle
+    pub cap: u32,
+}
+
+impl CarryOver {
+    /// The budget carried into the next window when `unused` was left over
+    pub fn carry(&self, unused: u32) -> u32 {
+        let carried = u64::from(unused) * u64::from(self.percent.min(100)) / 100;
+        u32::try_from(carried).unwrap_or(u32::MAX).min(self.cap)
+    }
+}
+
+/// How much a key may borrow from its next window once the current one is
+/// spent, smoothing hard cutoffs for bursty but honest clients
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Borrowing {
+    /// The most a key can borrow per window
+    pub cap: u32,
+    /// Interest on what was borrowed, as a percentage: at 100, borrowing 5
+    /// takes 10 from the next window
+    pub interest_percent: u32,
+}
+
+impl Borrowing {
+    /// What the next window owes when `borrowed` units were borrowed,
+    /// rounding the interest up to whole units
+    pub fn repayment(&self, borrowed: u32) -> u32 {
+        let owed = (u64::from(borrowed) * (100 + u64::from(self.interest_percent))).div_ceil(100);
+        u32::try_from(owed).unwrap_or(u32::MAX)
+    }
+}
+
+/// Scales the cost of a request by its `Content-Length`, so large uploads
+/// draw down the budget faster than tiny pings
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentLengthCost {
+    /// Bytes per unit of cost, e.g. `100 * 1024` for one unit per 100KB
+    pub bytes_per_unit: u64,
+    /// The least a request can cost, including requests without a body
+    pub min_cost: u32,
+}
+
+impl ContentLengthCost {
+    /// The cost of a request with the given `Content-Length`, rounding up to
+    /// whole units
+    pub fn cost(&self, content_length: Option<u64>) -> u32 {
+        let units = content_length
+            .unwrap_or(0)
+            .div_ceil(self.bytes_per_unit.max(1));
+        u32::try_from(units).unwrap_or(u32::MAX).max(self.min_cost)
+    }
+}
+
+/// Format options for the Retry-After header
+///
+/// Serialized and parsed as `"http-date"` or `"seconds"`, so it can be
+/// expressed naturally in file- and env-based configuration.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryAfterFormat {
+    /// HTTP-date format (RFC 7231)
+    #[default]
+    #[serde(alias = "HttpDate")]
+    HttpDate,
+    /// Number of seconds
+    #[serde(alias = "Seconds")]
+    Seconds,
+}
+
+impl RetryAfterFormat {
+    const VARIANTS: &'static [&'static str] = &["http-date", "seconds"];
+}
+
+impl std::fmt::Display for RetryAfterFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetryAfterFormat::HttpDate => f.write_str("http-date"),
+            RetryAfterFormat::Seconds => f.write_str("seconds"),
+        }
+    }
+}
+
+impl std::str::FromStr for RetryAfterFormat {
+    type Err = ParseConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "http-date" | "httpdate" => Ok(RetryAfterFormat::HttpDate),
+            "seconds" => Ok(RetryAfterFormat::Seconds),
+            _ => Err(ParseConfigError::new("retry-after format", s, Self::VARIANTS)),
+        }
+    }
+}
+
+/// The set of rate limit headers to emit, so services migrating off a
+/// gateway keep the exact header shapes their clients already parse. Every
+/// style also sends `Retry-After`.
+///
+/// Serialized and parsed as `"legacy"`, `"github"`, `"draft"`, or `"both"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeaderStyle {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining`, and `X-RateLimit-Reset`
+    /// as a Unix timestamp
+    #[default]
+    Legacy,
+    /// GitHub's shape: the legacy headers plus `X-RateLimit-Used`
+    #[serde(rename = "github")]
+    GitHub,
+    /// The IETF `RateLimit` header fields draft: `RateLimit-Limit`,
+    /// `RateLimit-Remaining`, `RateLimit-Reset` in seconds from now, and
+    /// `RateLimit-Policy` as `limit;w=window`
+    Draft,
+    /// The legacy and draft headers together, for moving clients over to the
+    /// draft names without breaking those still reading the old ones
+    Both,
+}
+
+/// How CORS preflight requests (`OPTIONS` with `Access-Control-Request-Method`)
+/// are counted
+///
+/// Serialized as `"exempt"`, `"count"`, or `{"budget": n}`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreflightPolicy {
+    /// Preflights are never counted or rejected. Anyone can then send as
+    /// many `OPTIONS` requests with `Access-Control-Request-Method` as they
+    /// like, so prefer `Budget` where preflights need room of their own.
+    Exempt,
+    /// Preflights count like any other request
+    #[default]
+    Count,
+    /// Preflights get their own budget of this many per window, apart from
+    /// the key's main budget
+    Budget(u32),
+}
+
+/// When a key's window starts, and so when its budget resets
+///
+/// Serialized as `"rolling"`, `"fixed-interval"`, or `"calendar"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResetMode {
+    /// Each key's window opens with its first request and lasts `window`,
+    /// so keys reset at different times
+    #[default]
+    Rolling,
+    /// Windows are aligned to multiples of `window` since the Unix epoch, so
+    /// every key resets together, e.g. every minute on the minute
+    FixedInterval,
+    /// Windows are aligned to the UTC calendar: windows of 28 days or more
+    /// reset on the 1st of each month, 7 day windows on Mondays, and shorter
+    /// windows as with `FixedInterval` (daily windows at midnight)
+    Calendar,
+}
+
+impl ResetMode {
+    /// The start and end of the window containing `at`, or `None` for
+    /// rolling windows, which start whenever the key's first request arrives
+    pub fn bounds(&self, window: Duration, at: DateTime<Utc>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let window = ChronoDuration::from_std(window).ok().filter(|w| *w > ChronoDuration::zero())?;
+        let midnight = |date: chrono::NaiveDate| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN));
+        match self {
+            ResetMode::Rolling => None,
+            ResetMode::Calendar if window >= ChronoDuration::days(28) => {
+                let first = at.date_naive().with_day(1)?;
+                let next = first.checked_add_months(chrono::Months::new(1))?;
+                Some((midnight(first), midnight(next)))
+            }
+            ResetMode::Calendar if window == ChronoDuration::days(7) => {
+                let monday = at.date_naive().week(chrono::Weekday::Mon).first_day();
+                Some((midnight(monday), midnight(monday) + window))
+            }
+            ResetMode::FixedInterval | ResetMode::Calendar => {
+                let millis = window.num_milliseconds().max(1);
+                let start = at.timestamp_millis().div_euclid(millis) * millis;
+                let start = Utc.timestamp_millis_opt(start).single()?;
+                Some((start, start + window))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ResetMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetMode::Rolling => f.write_str("rolling"),
+            ResetMode::FixedInterval => f.write_str("fixed-interval"),
+            ResetMode::Calendar => f.write_str("calendar"),
+        }
+    }
+}
+
+/// How a key's requests are weighed against its limit
+///
+/// Serialized as `"fixed-window"`, `"sliding-window"`, or `"token-bucket"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitAlgorithm {
+    /// Count requests in the current window only. A window's budget comes
+    /// back all at once when it ends, so a client can fit up to twice the
+    /// limit into a short burst straddling the boundary.
+    #[default]
+    FixedWindow,
+    /// Also count the previous window's requests, weighted by how much of
+    /// it still falls within one window length of now. Usage decays
+    /// smoothly instead of resetting, which removes the boundary burst.
+    /// Rolling windows follow on from each other rather than opening with
+    /// each key's next request.
+    SlidingWindow,
+    /// Give each key a bucket of `max_requests` tokens that refills one
+    /// token every `window / max_requests`, and spend a token per unit of
+    /// cost. Idle keys can spend a full bucket at once, then settle to the
+    /// refill rate. Carry-over, burst credits, and borrowing don't apply.
+    TokenBucket,
+}
+
+impl std::fmt::Display for RateLimitAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateLimitAlgorithm::FixedWindow => f.write_str("fixed-window"),
+            RateLimitAlgorithm::SlidingWindow => f.write_str("sliding-window"),
+            RateLimitAlgorithm::TokenBucket => f.write_str("token-bucket"),
+        }
+    }
+}
+
+/// Whether a request is a CORS preflight: an `OPTIONS` request carrying
+/// `Access-Control-Request-Method`
+pub fn is_preflight(method: &http::Method, headers: &http::HeaderMap) -> bool {
+    method == http::Method::OPTIONS && headers.contains_key(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+impl HeaderStyle {
+    const VARIANTS: &'static [&'static str] = &["legacy", "github", "draft", "both"];
+
+    /// The rate limit headers sent in this style, plus `Retry-After`, which
+    /// rejections carry in every style
+    pub fn header_names(&self) -> &'static [&'static str] {
+        match self {
+            HeaderStyle::Legacy => &["X-RateLimit-Limit", "X-RateLimit-Remaining", "X-RateLimit-Reset", "Retry-After"],
+            HeaderStyle::GitHub => &[
+                "X-RateLimit-Limit",
+                "X-RateLimit-Remaining",
+                "X-RateLimit-Used",
+                "X-RateLimit-Reset",
+                "Retry-After",
+            ],
+            HeaderStyle::Draft => &["RateLimit-Limit", "RateLimit-Remaining", "RateLimit-Reset", "RateLimit-Policy", "Retry-After"],
+            HeaderStyle::Both => &[
+                "X-RateLimit-Limit",
+                "X-RateLimit-Remaining",
+                "X-RateLimit-Reset",
+                "RateLimit-Limit",
+                "RateLimit-Remaining",
+                "RateLimit-Reset",
+                "RateLimit-Policy",
+                "Retry-After",
+            ],
+        }
+    }
+
+    /// Whether this style sends the `X-RateLimit-*` headers
+    pub fn sends_legacy(&self) -> bool {
+        matches!(self, HeaderStyle::Legacy | HeaderStyle::GitHub | HeaderStyle::Both)
+    }
+
+    /// Whether this style sends the draft `RateLimit-*` headers
+    pub fn sends_draft(&self) -> bool {
+        matches!(self, HeaderStyle::Draft | HeaderStyle::Both)
+    }
+}
+
+impl std::fmt::Display for HeaderStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderStyle::Legacy => f.write_str("legacy"),
+            HeaderStyle::GitHub => f.write_str("github"),
+            HeaderStyle::Draft => f.write_str("draft"),
+            HeaderStyle::Both => f.write_str("both"),
+        }
+    }
+}
+
+impl std::str::FromStr for HeaderStyle {
+    type Err = ParseConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "legacy" => Ok(HeaderStyle::Legacy),
+            "github" | "git-hub" => Ok(HeaderStyle::GitHub),
+            "draft" | "ietf" => Ok(HeaderStyle::Draft),
+            "both" => Ok(HeaderStyle::Both),
+            _ => Err(ParseConfigError::new("header style", s, Self::VARIANTS)),
+        }
+    }
+}
+
+/// Error returned when a configuration string does not name a known option
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseConfigError {
+    /// What was being parsed, e.g. "retry-after format"
+    pub kind: &'static str,
+    /// The value that failed to parse
+    pub value: String,
+    /// The accepted spellings
+    pub expected: &'static [&'static str],
+}
+
+impl ParseConfigError {
+    pub(crate) fn new(kind: &'static str, value: &str, expected: &'static [&'static str]) -> Self {
+        Self {
+            kind,
+            value: value.to_string(),
+            expected,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid {} {:?}; expected one of: {}",
+            self.kind,
+            self.value,
+            self.expected.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ParseConfigError {}
+
+/// Sensible (opinionated) defaults
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 60, // 60 req/min baseline
+            window: Duration::from_secs(60),
+            retry_after_format: RetryAfterFormat::HttpDate,
+            content_length_cost: None,
+            header_style: HeaderStyle::Legacy,
+            namespace: None,
+            carry_over: None,
+            soft_limit: None,
+            tarpit: None,
+            max_tarpitted: DEFAULT_MAX_TARPITTED,
+            preflight: PreflightPolicy::Count,
+            burst_credits: None,
+            early_rejection: None,
+            reset_mode: ResetMode::Rolling,
+            overage: None,
+            borrowing: None,
+            idle_ttl: None,
+            algorithm: RateLimitAlgorithm::FixedWindow,
+            max_tracked_keys: None,
+            allowlist: Vec::new(),
+            denylist: Vec::new(),
+            deny_status: StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// Factory methods for quickly building a rate limiter
+impl RateLimitConfig {
+    /// Build a `RateLimitConfig` with sensible defaults for requests per minute
+    pub fn max_per_minute(max: u32) -> Self {
+        Self {
+            max_requests: max,
+            window: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    /// Build a `RateLimitConfig` with custom window size in seconds
+    pub fn max_per_window(max_requests: u32, window_seconds: u64) -> Self {
+        Self {
+            max_requests,
+            window: Duration::from_secs(window_seconds),
+            ..Default::default()
+        }
+    }
+
+    /// Build a token bucket `RateLimitConfig`: each key may burst up to
+    /// `burst` requests, refilled at `rate_per_sec`
+    pub fn token_bucket(rate_per_sec: f64, burst: u32) -> Self {
+        Self {
+            max_requests: burst,
+            window: Duration::try_from_secs_f64(f64::from(burst) / rate_per_sec).unwrap_or(Duration::MAX),
+            algorithm: RateLimitAlgorithm::TokenBucket,
+            ..Default::default()
+        }
+    }
+
+    /// Charge one unit per `bytes_per_unit` of `Content-Length`, and at least
+    /// `min_cost` per request
+    pub fn with_content_length_cost(mut self, bytes_per_unit: u64, min_cost: u32) -> Self {
+        self.content_length_cost = Some(ContentLengthCost {
+            bytes_per_unit,
+            min_cost,
+        });
+        self
+    }
+
+    /// Emit rate limit headers in the given gateway's shape
+    pub fn with_header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.header_style = header_style;
+        self
+    }
+
+    /// Prefix every key with `namespace`, e.g. a tenant ID
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Carry `percent` of each window's unused budget into the next, up to
+    /// `cap`. Combine with a long window for quotas, e.g. a 30 day window for
+    /// a monthly allowance.
+    pub fn with_carry_over(mut self, percent: u8, cap: u32) -> Self {
+        self.carry_over = Some(CarryOver { percent, cap });
+        self
+    }
+
+    /// Admit up to `cap` units per window past the limit (after any burst
+    /// credits), firing a `RateLimitEvent::Overage` for each request so the
+    /// overage can be billed rather than rejected
+    pub fn with_overage(mut self, cap: u32) -> Self {
+        self.overage = Some(cap);
+        self
+    }
+
+    /// Once a key has spent its window, let it borrow up to `cap` units from
+    /// the next, which then starts with the amount borrowed plus
+    /// `interest_percent` fewer. Borrowing comes after burst credits and
+    /// before any overage allowance, and a window still paying back can't
+    /// borrow.
+    pub fn with_borrowing(mut self, cap: u32, interest_percent: u32) -> Self {
+        self.borrowing = Some(Borrowing { cap, interest_percent });
+        self
+    }
+
+    /// Forget keys that make no requests for `ttl`, keeping memory bounded
+    /// for long windows: a key idle for 10 minutes can be dropped even if
+    /// its quota period is a month. A forgotten key starts over with a full
+    /// budget (and no banked credits) when it returns.
+    pub fn with_idle_ttl(mut self, ttl: Duration) -> Self {
+        self.idle_ttl = Some(ttl);
+        self
+    }
+
+    /// Keep state for at most `max` keys, so a scan from millions of
+    /// addresses can't exhaust memory. Past the cap, the least recently
+    /// seen keys are forgotten (a tenth of the cap at a time) and start
+    /// over with a full budget; [`RateLimiter::evicted_keys`](crate::RateLimiter::evicted_keys)
+    /// counts them.
+    pub fn with_max_tracked_keys(mut self, max: usize) -> Self {
+        self.max_tracked_keys = Some(max);
+        self
+    }
+
+    /// Never count clients in `cidr`, e.g. internal monitoring. Their
+    /// requests pass with the key's current status.
+    pub fn allow(mut self, cidr: impl Into<IpCidr>) -> Self {
+        self.allowlist.push(cidr.into());
+        self
+    }
+
+    /// Reject every request from clients in `cidr` with the deny status
+    /// (`403 Forbidden` unless set with [`with_deny_status`](Self::with_deny_status)).
+    /// The allowlist wins where the two overlap.
+    pub fn deny(mut self, cidr: impl Into<IpCidr>) -> Self {
+        self.denylist.push(cidr.into());
+        self
+    }
+
+    /// Set the status denylisted clients are answered with
+    pub fn with_deny_status(mut self, status: StatusCode) -> Self {
+        self.deny_status = status;
+        self
+    }
+
+    /// Whether `ip` is on the allowlist
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowlist.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Whether `ip` is on the denylist and not the allowlist
+    pub fn is_denied(&self, ip: IpAddr) -> bool {
+        self.denylist.iter().any(|cidr| cidr.contains(ip)) && !self.is_allowed(ip)
+    }
+
+    /// Warn once a key has used `percent` of its limit, so well-behaved
+    /// clients can back off before they are rejected
+    pub fn with_soft_limit(mut self, percent: u8) -> Self {
+        self.soft_limit = Some(percent.min(100));
+        self
+    }
+
+    /// Hold each rejected request for `delay` before answering it. Clients
+    /// within their limit are never delayed; scrapers pushing past it pay
+    /// for every extra attempt with an open connection.
+    pub fn with_tarpit(mut self, delay: Duration) -> Self {
+        self.tarpit = Some(delay);
+        self
+    }
+
+    /// Hold at most `max` rejected requests in the tarpit at once (1024 by
+    /// default), answering the rest straight away, so a flood of
+    /// rejections can't tie up the server's own connections
+    pub fn with_max_tarpitted(mut self, max: usize) -> Self {
+        self.max_tarpitted = max;
+        self
+    }
+
+    /// Let keys bank up to `cap` burst credits. A key earns credits at the
+    /// steady rate (`max_requests` per window) for every moment it is idle
+    /// beyond a full window, and spends them once it is past its limit, so
+    /// a client quiet for an hour can briefly burst above the steady rate.
+    /// Banked credits are reported in `X-RateLimit-Credits`.
+    pub fn with_burst_credits(mut self, cap: u32) -> Self {
+        self.burst_credits = Some(cap);
+        self
+    }
+
+    /// Past `percent` of the limit, reject a share of requests at random that
+    /// grows linearly toward the limit, smoothing the cliff between all
+    /// requests succeeding and all being rejected
+    pub fn with_early_rejection(mut self, percent: u8) -> Self {
+        self.early_rejection = Some(percent.min(100));
+        self
+    }
+
+    /// Count requests with `algorithm`, e.g. `RateLimitAlgorithm::SlidingWindow`
+    /// to smooth out bursts at window boundaries
+    pub fn with_algorithm(mut self, algorithm: RateLimitAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Reset windows per `reset_mode`, e.g. `ResetMode::Calendar` with a 30
+    /// day window for quotas that renew on the 1st of the month
+    pub fn with_reset_mode(mut self, reset_mode: ResetMode) -> Self {
+        self.reset_mode = reset_mode;
+        self
+    }
+
+    /// Count CORS preflights per `preflight` rather than like other requests
+    pub fn with_preflight(mut self, preflight: PreflightPolicy) -> Self {
+        self.preflight = preflight;
+        self
+    }
+
+    /// `key` as stored, prefixed with the namespace if one is set
+    pub fn scoped_key<'a>(&self, key: &'a str) -> std::borrow::Cow<'a, str> {
+        match &self.namespace {
+            Some(namespace) => format!("{}:{}", namespace, key).into(),
+            None => key.into(),
+        }
+    }
+
+    /// This config, made from `from`, moved onto `onto`: every field it left
+    /// as `from` had it follows `onto`, and the fields it changed are kept
+    pub(crate) fn rebased(&self, from: &RateLimitConfig, onto: &RateLimitConfig) -> RateLimitConfig {
+        if from == onto {
+            return self.clone();
+        }
+        let mut config = self.clone();
+        macro_rules! follow {
+            ($($field:ident),* $(,)?) => {
+                // Listing every field, so one added later can't be left out
+                let RateLimitConfig { $($field: _),* } = self;
+                $(
+                    if self.$field == from.$field {
+                        config.$field = onto.$field.clone();
+                    }
+                )*
+            };
+        }
+        follow!(
+            max_requests,
+            window,
+            retry_after_format,
+            content_length_cost,
+            header_style,
+            namespace,
+            carry_over,
+            soft_limit,
+            tarpit,
+            max_tarpitted,
+            preflight,
+            burst_credits,
+            early_rejection,
+            reset_mode,
+            overage,
+            idle_ttl,
+            borrowing,
+            algorithm,
+            max_tracked_keys,
+            allowlist,
+            denylist,
+            deny_status,
+        );
+        config
+    }
+
+    /// Parse a policy from JSON, e.g.
+    /// `{"max_requests": 100, "window_secs": 60, "retry_after_format": "seconds"}`.
+    /// Fields other than `max_requests` and `window_secs` are optional;
+    /// `tarpit_ms` sets the tarpit delay in milliseconds and `idle_ttl_secs`
+    /// the idle TTL in seconds.
+    pub fn from_json(json: &str) -> Result<Self, RateLimitError> {
+        let file: ConfigFile = serde_json::from_str(json).map_err(|e| RateLimitError::Other(Box::new(e)))?;
+        Ok(Self {
+            max_requests: file.max_requests,
+            window: Duration::from_secs(file.window_secs),
+            retry_after_format: file.retry_after_format,
+            content_length_cost: file.content_length_cost,
+            header_style: file.header_style,
+            namespace: file.namespace,
+            carry_over: file.carry_over,
+            soft_limit: file.soft_limit,
+            tarpit: file.tarpit_ms.map(Duration::from_millis),
+            max_tarpitted: file.max_tarpitted.unwrap_or(DEFAULT_MAX_TARPITTED),
+            preflight: file.preflight,
+            burst_credits: file.burst_credits,
+            early_rejection: file.early_rejection,
+            reset_mode: file.reset_mode,
+            overage: file.overage,
+            idle_ttl: file.idle_ttl_secs.map(Duration::from_secs),
+            borrowing: file.borrowing,
+            algorithm: file.algorithm,
+            max_tracked_keys: file.max_tracked_keys,
+            allowlist: file.allowlist,
+            denylist: file.denylist,
+            deny_status: match file.deny_status {
+                Some(status) => StatusCode::from_u16(status).map_err(|e| RateLimitError::Other(Box::new(e)))?,
+                None => StatusCode::FORBIDDEN,
+            },
+        })
+    }
+
+    /// Read and parse a JSON policy file, see [`RateLimitConfig::from_json`]
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, RateLimitError> {
+        let json = std::fs::read_to_string(path).map_err(|e| RateLimitError::Other(Box::new(e)))?;
+        Self::from_json(&json)
+    }
+
+    /// A machine-readable description of this policy, for clients to
+    /// configure their backoff from:
+    ///
+    /// ```json
+    /// {
+    ///   "limit": 100,
+    ///   "window_seconds": 60,
+    ///   "reset_mode": "rolling",
+    ///   "algorithm": "fixed-window",
+    ///   "header_style": "legacy",
+    ///   "headers": ["X-RateLimit-Limit", "X-RateLimit-Remaining", "X-RateLimit-Reset", "Retry-After"],
+    ///   "retry_after_format": "http-date"
+    /// }
+    /// ```
+    pub fn to_policy_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "limit": self.max_requests,
+            "window_seconds": self.window.as_secs(),
+            "reset_mode": self.reset_mode,
+            "algorithm": self.algorithm,
+            "header_style": self.header_style,
+            "headers": self.header_style.header_names(),
+            "retry_after_format": self.retry_after_format,
+        })
+    }
+}
+
+/// Parses a policy string such as `"100/1m"` into a request count and a
+/// window in seconds. The window is an optional count followed by a unit:
+/// `s`, `m`, `h`, or `d`, so `"10/s"` and `"5000/30d"` are both valid.
+///
+/// This is a `const fn` so the [`rate_limit!`](crate::rate_limit) macro can
+/// reject malformed policies at compile time.
+pub const fn parse_policy(policy: &str) -> Result<(u32, u64), &'static str> {
+    let bytes = policy.as_bytes();
+    let mut i = 0;
+    let mut max: u64 = 0;
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        max = max * 10 + (bytes[i] - b'0') as u64;
+        if max > u32::MAX as u64 {
+            return Err("request count is too large");
+        }
+        i += 1;
+    }
+    if i == 0 {
+        return Err("policy must start with a request count, e.g. 100/1m");
+    }
+    if i == bytes.len() || bytes[i] != b'/' {
+        return Err("expected '/' after the request count, e.g. 100/1m");
+    }
+    i += 1;
+
+    let digits = i;
+    let mut count: u64 = 0;
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        count = count.saturating_mul(10).saturating_add((bytes[i] - b'0') as u64);
+        i += 1;
+    }
+    if i == digits {
+        count = 1;
+    }
+    if i + 1 != bytes.len() {
+        return Err("window must be a count and one unit (s, m, h, or d), e.g. 1m");
+    }
+    let unit = match bytes[i] {
+        b's' => 1,
+        b'm' => 60,
+        b'h' => 60 * 60,
+        b'd' => 24 * 60 * 60,
+        _ => return Err("unknown window unit; expected s, m, h, or d"),
+    };
+    if count == 0 {
+        return Err("window must be longer than zero");
+    }
+    Ok((max as u32, count.saturating_mul(unit)))
+}
+
+/// Builds a [`RateLimitConfig`] from a policy string checked at compile
+/// time, see [`parse_policy`](crate::parse_policy)
+///
+/// ```rust,no_run,ignore
+/// let search = warp::path("search").map(handler).rate_limited(rate_limit!("100/1m"));
+/// ```
+#[macro_export]
+macro_rules! rate_limit {
+    ($policy:expr) => {{
+        const POLICY: (u32, u64) = match $crate::parse_policy($policy) {
+            Ok(policy) => policy,
+            Err(message) => panic!("{}", message),
+        };
+        $crate::RateLimitConfig::max_per_window(POLICY.0, POLICY.1)
+    }};
+}
+
+/// Parses a policy string such as `"100/1m"`, see [`parse_policy`]
+impl std::str::FromStr for RateLimitConfig {
+    type Err = ParseConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_policy(s.trim()) {
+            Ok((max, window)) => Ok(Self::max_per_window(max, window)),
+            Err(_) => Err(ParseConfigError::new("rate limit policy", s, &["<count>/<window>, e.g. 100/1m"])),
+        }
+    }
+}
+
+/// The on-disk shape of a policy file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    max_requests: u32,
+    window_secs: u64,
+    #[serde(default)]
+    retry_after_format: RetryAfterFormat,
+    #[serde(default)]
+    content_length_cost: Option<ContentLengthCost>,
+    #[serde(default)]
+    header_style: HeaderStyle,
+    #[serde(default)]
+    namespace: Option<String>,
+    #[serde(default)]
+    carry_over: Option<CarryOver>,
+    #[serde(default)]
+    soft_limit: Option<u8>,
+    #[serde(default)]
+    tarpit_ms: Option<u64>,
+    #[serde(default)]
+    max_tarpitted: Option<usize>,
+    #[serde(default)]
+    preflight: PreflightPolicy,
+    #[serde(default)]
+    burst_credits: Option<u32>,
+    #[serde(default)]
+    early_rejection: Option<u8>,
+    #[serde(default)]
+    reset_mode: ResetMode,
+    #[serde(default)]
+    overage: Option<u32>,
+    #[serde(default)]
+    idle_ttl_secs: Option<u64>,
+    #[serde(default)]
+    borrowing: Option<Borrowing>,
+    #[serde(default)]
+    algorithm: RateLimitAlgorithm,
+    #[serde(default)]
+    max_tracked_keys: Option<usize>,
+    #[serde(default)]
+    allowlist: Vec<IpCidr>,
+    #[serde(default)]
+    denylist: Vec<IpCidr>,
+    #[serde(default)]
+    deny_status: Option<u16>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_builders() {
+        // Test max_per_minute builder
+        let per_minute = RateLimitConfig::max_per_minute(60);
+        assert_eq!(per_minute.window, Duration::from_secs(60));
+        assert_eq!(per_minute.max_requests, 60);
+        assert_eq!(per_minute.retry_after_format, RetryAfterFormat::HttpDate);
+
+        // Test max_per_window builder
+        let custom = RateLimitConfig::max_per_window(30, 120);
+        assert_eq!(custom.window, Duration::from_secs(120));
+        assert_eq!(custom.max_requests, 30);
+        assert_eq!(custom.retry_after_format, RetryAfterFormat::HttpDate);
+
+        // Test default config
+        let default = RateLimitConfig::default();
+        assert_eq!(default.window, Duration::from_secs(60));
+        assert_eq!(default.max_requests, 60);
+        assert_eq!(default.retry_after_format, RetryAfterFormat::HttpDate);
+    }
+
+    #[test]
+    fn test_overrides_follow_the_parent() {
+        let overrides = ConfigOverrides::new().with_max_requests(10).with_tarpit(Duration::from_secs(1));
+        let parent = RateLimitConfig::max_per_window(600, 120).with_header_style(HeaderStyle::Draft);
+        let child = overrides.apply(&parent);
+        assert_eq!(child.max_requests, 10);
+        assert_eq!(child.tarpit, Some(Duration::from_secs(1)));
+        assert_eq!(child.window, Duration::from_secs(120));
+        assert_eq!(child.header_style, HeaderStyle::Draft);
+        assert_eq!(ConfigOverrides::new().apply(&parent), parent);
+    }
+
+    #[test]
+    fn test_content_length_cost() {
+        let config = RateLimitConfig::max_per_minute(100).with_content_length_cost(100 * 1024, 1);
+        let cost = config.content_length_cost.unwrap();
+
+        assert_eq!(cost.cost(None), 1);
+        assert_eq!(cost.cost(Some(10)), 1);
+        assert_eq!(cost.cost(Some(100 * 1024)), 1);
+        assert_eq!(cost.cost(Some(100 * 1024 + 1)), 2);
+        assert_eq!(cost.cost(Some(1024 * 1024)), 11);
+    }
+
+    #[test]
+    fn test_config_from_json() {
+        let config = RateLimitConfig::from_json(
+            r#"{"max_requests": 100, "window_secs": 30, "retry_after_format": "seconds"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.max_requests, 100);
+        assert_eq!(config.window, Duration::from_secs(30));
+        assert_eq!(config.retry_after_format, RetryAfterFormat::Seconds);
+        assert_eq!(config.content_length_cost, None);
+
+        assert!(RateLimitConfig::from_json(r#"{"max_requests": 100}"#).is_err());
+        assert!(RateLimitConfig::from_json(r#"{"max_requests": 1, "window_secs": 1, "typo": 1}"#).is_err());
+
+        let config = RateLimitConfig::from_json(
+            r#"{"max_requests": 1, "window_secs": 1, "allowlist": ["10.0.0.0/8"], "denylist": ["::1"], "deny_status": 404}"#,
+        )
+        .unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(config.is_allowed(ip("10.0.0.1")) && !config.is_allowed(ip("11.0.0.1")));
+        assert!(config.is_denied(ip("::1")) && !config.is_denied(ip("::2")));
+        assert_eq!(config.deny_status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_retry_after_format_parsing() {
+        assert_eq!("http-date".parse(), Ok(RetryAfterFormat::HttpDate));
+        assert_eq!("HTTP_DATE".parse(), Ok(RetryAfterFormat::HttpDate));
+        assert_eq!(" seconds ".parse(), Ok(RetryAfterFormat::Seconds));
+        assert_eq!(RetryAfterFormat::Seconds.to_string(), "seconds");
+
+        let err = "minutes".parse::<RetryAfterFormat>().unwrap_err();
+        assert_eq!(err.to_string(), "invalid retry-after format \"minutes\"; expected one of: http-date, seconds");
+
+        // Serde uses the same string forms, and still accepts the old variant names
+        assert_eq!(serde_json::to_string(&RetryAfterFormat::HttpDate).unwrap(), "\"http-date\"");
+        assert_eq!(serde_json::from_str::<RetryAfterFormat>("\"seconds\"").unwrap(), RetryAfterFormat::Seconds);
+        assert_eq!(serde_json::from_str::<RetryAfterFormat>("\"HttpDate\"").unwrap(), RetryAfterFormat::HttpDate);
+
+        assert_eq!("GitHub".parse(), Ok(HeaderStyle::GitHub));
+        assert_eq!(serde_json::from_str::<HeaderStyle>("\"github\"").unwrap(), HeaderStyle::GitHub);
+        assert_eq!(HeaderStyle::Draft.to_string(), "draft");
+        assert_eq!("both".parse(), Ok(HeaderStyle::Both));
+    }
+
+    #[test]
+    fn test_reset_mode_bounds() {
+        let at = Utc.with_ymd_and_hms(2024, 2, 15, 10, 30, 45).unwrap();
+        let minute = Duration::from_secs(60);
+        assert_eq!(ResetMode::Rolling.bounds(minute, at), None);
+
+        let (start, end) = ResetMode::FixedInterval.bounds(minute, at).unwrap();
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 15, 10, 30, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 2, 15, 10, 31, 0).unwrap());
+
+        // A Thursday; weeks start on Monday
+        let (start, end) = ResetMode::Calendar.bounds(Duration::from_secs(7 * 86400), at).unwrap();
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 12, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 2, 19, 0, 0, 0).unwrap());
+
+        let (start, end) = ResetMode::Calendar.bounds(Duration::from_secs(30 * 86400), at).unwrap();
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap());
+
+        let config = RateLimitConfig::from_json(r#"{"max_requests": 5, "window_secs": 60, "reset_mode": "fixed-interval"}"#);
+        assert_eq!(config.unwrap().reset_mode, ResetMode::FixedInterval);
+    }
+
+    #[test]
+    fn test_policy_strings() {
+        assert_eq!(parse_policy("100/1m"), Ok((100, 60)));
+        assert_eq!(parse_policy("10/s"), Ok((10, 1)));
+        assert_eq!(parse_policy("5000/30d"), Ok((5000, 30 * 24 * 60 * 60)));
+        assert!(parse_policy("100").is_err());
+        assert!(parse_policy("100/0m").is_err());
+        assert!(parse_policy("100/1w").is_err());
+        assert!(parse_policy("/1m").is_err());
+
+        let config = rate_limit!("30/2h");
+        assert_eq!(config.max_requests, 30);
+        assert_eq!(config.window, Duration::from_secs(2 * 60 * 60));
+        assert_eq!("30/2h".parse::<RateLimitConfig>().unwrap().window, config.window);
+        assert!("thirty".parse::<RateLimitConfig>().is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Limits on new connections per source address, applied in the accept loop
+//! before any bytes are read, for basic connection-flood protection
+
+use futures_core::Stream;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{RateLimitConfig, RateLimiter};
+
+/// Connections [`LimitedIncoming`] waits on verdicts for at once before it
+/// stops accepting more
+const MAX_PENDING: usize = 64;
+
+/// Limits how often each source IP may open a connection, separately from
+/// any HTTP request limits. Cloning a `ConnectionLimiter` is cheap and the
+/// clones share their counters.
+///
+/// Serve through [`ConnectionLimiter::incoming`] to drop excess connections
+/// as they are accepted (TLS handshakes included, since they happen later):
+///
+/// ```rust,no_run,ignore
+/// let limiter = ConnectionLimiter::new(RateLimitConfig::max_per_window(20, 1));
+/// let listener = TcpListener::bind("0.0.0.0:8080").await?;
+/// warp::serve(routes).run_incoming(limiter.incoming(listener)).await;
+/// ```
+///
+/// or call [`ConnectionLimiter::allow`] from your own accept loop.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimiter {
+    limiter: RateLimiter,
+}
+
+impl ConnectionLimiter {
+    /// Allow each source IP `config.max_requests` new connections per window
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            limiter: RateLimiter::new(config),
+        }
+    }
+
+    /// Counts a new connection from `ip`, returning whether to keep it
+    pub async fn allow(&self, ip: IpAddr) -> bool {
+        let key = ip.to_string();
+        match self.limiter.screen(&key, Some(ip), &http::HeaderMap::new()).await {
+            Some(screened) => screened.is_ok(),
+            None => self.limiter.check_rate_limit(&key).await.is_ok(),
+        }
+    }
+
+    /// Accepted connections from `listener`, with those over their source's
+    /// limit closed right away
+    pub fn incoming(&self, listener: TcpListener) -> LimitedIncoming {
+        LimitedIncoming {
+            listener,
+            limiter: self.clone(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+type Pending = Pin<Box<dyn Future<Output = Option<TcpStream>> + Send>>;
+
+/// Stream of connections returned by [`ConnectionLimiter::incoming`]
+pub struct LimitedIncoming {
+    listener: TcpListener,
+    limiter: ConnectionLimiter,
+    /// Connections waiting on the limiter's verdict. A slow verdict (e.g.
+    /// from a store) holds up only its own connection, not the accept loop.
+    pending: Vec<Pending>,
+}
+
+impl Stream for LimitedIncoming {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let mut i = 0;
+            while i < self.pending.len() {
+                match self.pending[i].as_mut().poll(cx) {
+                    Poll::Ready(verdict) => {
+                        drop(self.pending.swap_remove(i));
+                        if let Some(stream) = verdict {
+                            return Poll::Ready(Some(Ok(stream)));
+                        }
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+            // Every pending verdict has registered to wake us
+            if self.pending.len() >= MAX_PENDING {
+                return Poll::Pending;
+            }
+
+            let (stream, addr): (TcpStream, SocketAddr) = std::task::ready!(self.listener.poll_accept(cx))?;
+            let limiter = self.limiter.clone();
+            self.pending.push(Box::pin(async move {
+                if limiter.allow(addr.ip()).await {
+                    Some(stream)
+                } else {
+                    tracing::debug!("dropping connection from {}: over its connection limit", addr.ip());
+                    None
+                }
+            }));
+        }
+    }
+}
+
+impl std::fmt::Debug for LimitedIncoming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LimitedIncoming")
+            .field("listener", &self.listener)
+            .field("limiter", &self.limiter)
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Admission, BoxFuture, RateLimitError, RateLimitStore, StoredCount};
+    use std::future::poll_fn;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_excess_connections_are_dropped() {
+        let limiter = ConnectionLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = limiter.incoming(listener);
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let accepted = poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx)).await;
+        assert!(accepted.unwrap().is_ok());
+
+        // The second connection is closed without being yielded
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let next = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx)),
+        )
+        .await;
+        assert!(next.is_err());
+        assert_eq!(second.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    /// A store whose first verdict never comes
+    #[derive(Debug, Default)]
+    struct StuckOnce(std::sync::atomic::AtomicBool);
+
+    impl RateLimitStore for StuckOnce {
+        fn get<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+            Box::pin(std::future::ready(Ok(None)))
+        }
+
+        fn increment<'a>(&'a self, _: &'a str, amount: u32, ttl: Duration) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+            Box::pin(std::future::ready(Ok(StoredCount { count: amount, ttl })))
+        }
+
+        fn increment_within<'a>(
+            &'a self,
+            _: &'a str,
+            amount: u32,
+            _: u32,
+            ttl: Duration,
+        ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+            if !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return Box::pin(std::future::pending());
+            }
+            Box::pin(std::future::ready(Ok(Admission::Admitted(StoredCount { count: amount, ttl }))))
+        }
+
+        fn expire<'a>(&'a self, _: &'a str, _: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_a_slow_verdict_does_not_block_accepting() {
+        let limiter = ConnectionLimiter {
+            limiter: RateLimiter::new(RateLimitConfig::max_per_window(10, 60)).with_store(StuckOnce::default()).unwrap(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = limiter.incoming(listener);
+
+        let _stuck = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+        let next = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx)),
+        )
+        .await;
+        assert!(next.unwrap().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+//! Events the limiter reports to a user-supplied callback, for logging,
+//! alerting, or billing
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::Arc;
+
+use super::{KeyMetadata, RateLimitErrorCode};
+
+/// Something noteworthy that happened while limiting a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RateLimitEvent {
+    /// `key` crossed the config's soft limit. Fired once per window.
+    SoftLimitReached {
+        /// The key, including its namespace
+        key: String,
+        /// Units used in the window
+        used: u32,
+        /// The key's limit for the window
+        limit: u32,
+        /// The metadata attached to the key
+        metadata: KeyMetadata,
+    },
+    /// `key` was let past its limit under the config's overage allowance.
+    /// Fired for every request admitted this way, with the units it went
+    /// over by, so the overage can be billed.
+    Overage {
+        /// The key, including its namespace
+        key: String,
+        /// Units admitted past the limit by this request
+        units: u32,
+        /// When the billing period (the key's window) started
+        period_start: DateTime<Utc>,
+        /// When the billing period ends
+        period_end: DateTime<Utc>,
+        /// The metadata attached to the key
+        metadata: KeyMetadata,
+    },
+    /// A request from `key` was turned away
+    Rejected {
+        /// The key, including its namespace
+        key: String,
+        /// Why the request was turned away
+        code: RateLimitErrorCode,
+        /// The metadata attached to the key
+        metadata: KeyMetadata,
+    },
+}
+
+/// A callback receiving [`RateLimitEvent`]s. It runs inline on the request
+/// path, so hand slow work off to a channel or task.
+#[derive(Clone)]
+pub struct EventHook(Arc<dyn Fn(&RateLimitEvent) + Send + Sync>);
+
+impl EventHook {
+    /// Wrap `callback` as a hook
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&RateLimitEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+
+    /// Deliver `event` to the callback
+    pub fn emit(&self, event: &RateLimitEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventHook(..)")
+    }
+}
@@ -0,0 +1,353 @@
+//! Pluggable, framework-agnostic key extraction: a [`KeyExtractor`] derives
+//! the rate limit key from the request head, so apps can limit per user or
+//! per API key rather than per socket IP
+
+use http::request::Parts;
+use http::HeaderMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+
+use super::rollout::fnv1a;
+use super::{BoxFuture, IpCidr, ParseConfigError};
+
+/// Derives the rate limit key from a request's head. Adapters hand it the
+/// request parts, with the peer's `SocketAddr` in the extensions when they
+/// know it. `None` means the request carries no key; adapters then fall
+/// back to `"unknown"`, so keyless requests share one budget.
+///
+/// Closures taking `&Parts` and returning a future implement it, so custom
+/// extraction (a session lookup, say) needs no new type. The future can't
+/// borrow the parts, so copy out what it needs first:
+///
+/// ```rust,no_run,ignore
+/// let by_user = |parts: &Parts| {
+///     let cookie = parts.headers.get("cookie").cloned();
+///     let sessions = sessions.clone();
+///     async move { sessions.user_for(cookie?).await }
+/// };
+/// let key = by_user.or_else(ForwardedFor);
+/// ```
+pub trait KeyExtractor: Send + Sync + 'static {
+    /// The key for the request `parts` belong to
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>>;
+
+    /// Falls back to `fallback` for requests this extractor finds no key in,
+    /// e.g. to limit signed-in users by API key and everyone else by IP
+    fn or_else<E: KeyExtractor>(self, fallback: E) -> OrElse<Self, E>
+    where
+        Self: Sized,
+    {
+        OrElse {
+            first: self,
+            fallback,
+        }
+    }
+}
+
+impl<F, Fut> KeyExtractor for F
+where
+    F: Fn(&Parts) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<String>> + Send + 'static,
+{
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>> {
+        Box::pin(self(parts))
+    }
+}
+
+/// The extractor returned by [`KeyExtractor::or_else`]
+#[derive(Clone, Copy, Debug)]
+pub struct OrElse<A, B> {
+    first: A,
+    fallback: B,
+}
+
+impl<A: KeyExtractor, B: KeyExtractor> KeyExtractor for OrElse<A, B> {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>> {
+        Box::pin(async move {
+            match self.first.extract(parts).await {
+                Some(key) => Some(key),
+                None => self.fallback.extract(parts).await,
+            }
+        })
+    }
+}
+
+/// The client IP from the rightmost `X-Forwarded-For` entry, the one added
+/// by the single proxy in front of the app, falling back to the peer
+/// address. Entries to its left come from the client and are never read.
+/// Only safe when every request arrives through that one proxy; with more
+/// proxies, or with clients that can connect directly, use
+/// [`TrustedProxies`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ForwardedFor;
+
+impl KeyExtractor for ForwardedFor {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>> {
+        let forwarded = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|client| client.trim().parse::<IpAddr>().ok());
+        let ip = forwarded.or_else(|| peer_ip(parts));
+        Box::pin(std::future::ready(ip.map(|ip| ip.to_string())))
+    }
+}
+
+/// The client IP as seen by the first of a chain of trusted reverse proxies
+/// (nginx, an ALB). When the peer is a trusted proxy, the client is the
+/// rightmost address in the configured forwarding header that isn't a
+/// trusted proxy itself. Any other peer is the client, so clients
+/// connecting directly can't pick their key by sending the headers.
+///
+/// Only the header named in the config is read, never the other: if the
+/// proxies set `X-Forwarded-For`, a `Forwarded` header passed through from
+/// the client can't stand in for it.
+///
+/// ```rust,no_run,ignore
+/// let proxies = TrustedProxies::parse(ForwardedHeader::XForwardedFor, ["10.0.0.0/8", "172.16.0.0/12"])?;
+/// let limited = with_rate_limit_by(config, key::client_ip(proxies));
+/// ```
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    header: ForwardedHeader,
+    ranges: Vec<IpCidr>,
+}
+
+/// The header a chain of proxies records the addresses it forwarded for in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// The standard `Forwarded` header's `for=` parameters (RFC 7239)
+    Forwarded,
+    /// `X-Forwarded-For`
+    XForwardedFor,
+}
+
+impl TrustedProxies {
+    /// Trust peers in `ranges`, which record clients in `header`
+    pub fn new(header: ForwardedHeader, ranges: impl IntoIterator<Item = IpCidr>) -> Self {
+        Self {
+            header,
+            ranges: ranges.into_iter().collect(),
+        }
+    }
+
+    /// Trust peers in `ranges`, written like `10.0.0.0/8` or `::1`, which
+    /// record clients in `header`
+    pub fn parse<I>(header: ForwardedHeader, ranges: I) -> Result<Self, ParseConfigError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let ranges = ranges.into_iter().map(|range| range.as_ref().parse()).collect::<Result<_, _>>()?;
+        Ok(Self { header, ranges })
+    }
+
+    /// Whether `ip` is a trusted proxy
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+
+    /// The client behind `peer`, per the forwarding header in `headers` if
+    /// `peer` is trusted. The walk stops at an entry that isn't an address
+    /// (an obfuscated `Forwarded` node, say), leaving the proxy that added it.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.is_trusted(peer) {
+            return client;
+        }
+        for hop in forwarded_hops(self.header, headers).into_iter().rev() {
+            match hop {
+                Some(hop) => client = hop,
+                None => break,
+            }
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+impl KeyExtractor for TrustedProxies {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>> {
+        let ip = peer_ip(parts).map(|peer| self.client_ip(peer, &parts.headers));
+        Box::pin(std::future::ready(ip.map(|ip| ip.to_string())))
+    }
+}
+
+/// The addresses proxies recorded in `header`, leftmost first. Entries that
+/// aren't addresses are `None`.
+fn forwarded_hops(header: ForwardedHeader, headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| headers.get_all(name).iter().filter_map(|value| value.to_str().ok());
+    if header == ForwardedHeader::Forwarded {
+        values(http::header::FORWARDED.as_str())
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                let node = element.split(';').find_map(|pair| {
+                    let (name, node) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then_some(node)
+                });
+                node.and_then(node_ip)
+            })
+            .collect()
+    } else {
+        values("x-forwarded-for").flat_map(|value| value.split(',')).map(node_ip).collect()
+    }
+}
+
+/// The IP of a forwarded node such as `192.0.2.60`, `"[2001:db8::1]:4711"`,
+/// or `192.0.2.60:8080`
+fn node_ip(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':').and_then(|(ip, _)| ip.parse().ok()))
+}
+
+/// The value of an API key header, `X-API-Key` unless built with
+/// [`ApiKey::header`]
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    header: String,
+}
+
+impl ApiKey {
+    /// Read the API key from the header `name`
+    pub fn header(name: impl Into<String>) -> Self {
+        Self { header: name.into() }
+    }
+}
+
+impl Default for ApiKey {
+    fn default() -> Self {
+        Self::header("x-api-key")
+    }
+}
+
+impl KeyExtractor for ApiKey {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>> {
+        let key = parts
+            .headers
+            .get(self.header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string);
+        Box::pin(std::future::ready(key))
+    }
+}
+
+/// The bearer token from the `Authorization` header, hashed to
+/// `bearer:<hex>` so tokens aren't kept in the limiter or its events. The
+/// hash (FNV-1a) is the same across builds, so replicas sharing a store
+/// agree on each token's key; it isn't a secure hash, so wrap the store in
+/// `HashedKeys` (with the `hashed-keys` feature) to keep keys unreadable
+/// there.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BearerToken;
+
+impl KeyExtractor for BearerToken {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<String>> {
+        let key = parts
+            .headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, token)| scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty())
+            .map(|(_, token)| format!("bearer:{:016x}", fnv1a(token.trim())));
+        Box::pin(std::future::ready(key))
+    }
+}
+
+/// The IP of the peer address an adapter stored in the extensions
+fn peer_ip(parts: &Parts) -> Option<IpAddr> {
+    parts.extensions.get::<SocketAddr>().map(SocketAddr::ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Request;
+
+    fn parts(headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(()).unwrap();
+        request.extensions_mut().insert("10.0.0.1:443".parse::<SocketAddr>().unwrap());
+        request.into_parts().0
+    }
+
+    #[tokio::test]
+    async fn test_built_in_extractors() {
+        // The entries a client sends itself sit left of the one its proxy adds
+        let forwarded = parts(&[("x-forwarded-for", "1.1.1.1, 203.0.113.7")]);
+        assert_eq!(ForwardedFor.extract(&forwarded).await.as_deref(), Some("203.0.113.7"));
+        assert_eq!(ForwardedFor.extract(&parts(&[])).await.as_deref(), Some("10.0.0.1"));
+
+        let api_key = parts(&[("x-api-key", "alpha")]);
+        assert_eq!(ApiKey::default().extract(&api_key).await.as_deref(), Some("alpha"));
+        assert_eq!(ApiKey::header("x-token").extract(&api_key).await, None);
+
+        let bearer = BearerToken.extract(&parts(&[("authorization", "Bearer s3cret")])).await.unwrap();
+        // Pinned, so a token's key stays the same across builds
+        assert_eq!(bearer, "bearer:36fc55b03b7a4e67");
+        assert_eq!(BearerToken.extract(&parts(&[("authorization", "Basic abc")])).await, None);
+    }
+
+    #[test]
+    fn test_trusted_proxies_walk_the_chain() {
+        let proxies = TrustedProxies::parse(ForwardedHeader::XForwardedFor, ["10.0.0.0/8"]).unwrap();
+        let client = |peer: &str, headers: &[(&str, &str)]| {
+            proxies.client_ip(peer.parse().unwrap(), &parts(headers).headers).to_string()
+        };
+
+        // Spoofed entries left of the real client are ignored
+        let chain = [("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.9")];
+        assert_eq!(client("10.0.0.1", &chain), "203.0.113.7");
+        // Untrusted peers are the client, whatever they send
+        assert_eq!(client("198.51.100.4", &chain), "198.51.100.4");
+
+        assert_eq!(client("10.0.0.1", &[]), "10.0.0.1");
+
+        let proxies = TrustedProxies::parse(ForwardedHeader::Forwarded, ["10.0.0.0/8"]).unwrap();
+        let client = |peer: &str, headers: &[(&str, &str)]| {
+            proxies.client_ip(peer.parse().unwrap(), &parts(headers).headers).to_string()
+        };
+        let forwarded = [("forwarded", "for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.9")];
+        assert_eq!(client("10.0.0.1", &forwarded), "2001:db8::7");
+        assert_eq!(client("10.0.0.1", &[("forwarded", "for=_hidden, for=10.0.0.9")]), "10.0.0.9");
+    }
+
+    #[test]
+    fn test_trusted_proxies_ignore_the_other_header() {
+        // The proxy appends to X-Forwarded-For and passes Forwarded through
+        // untouched, so a client sending Forwarded can't choose its key
+        let proxies = TrustedProxies::parse(ForwardedHeader::XForwardedFor, ["10.0.0.0/8"]).unwrap();
+        let spoofed = parts(&[("forwarded", "for=1.1.1.1"), ("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(proxies.client_ip("10.0.0.1".parse().unwrap(), &spoofed.headers).to_string(), "203.0.113.7");
+
+        // And the other way around
+        let proxies = TrustedProxies::parse(ForwardedHeader::Forwarded, ["10.0.0.0/8"]).unwrap();
+        let spoofed = parts(&[("forwarded", "for=203.0.113.7"), ("x-forwarded-for", "1.1.1.1")]);
+        assert_eq!(proxies.client_ip("10.0.0.1".parse().unwrap(), &spoofed.headers).to_string(), "203.0.113.7");
+    }
+
+    #[tokio::test]
+    async fn test_closures_and_fallbacks() {
+        let by_user = |parts: &Parts| {
+            let user = parts.headers.get("x-user").and_then(|v| v.to_str().ok()).map(|user| format!("user:{}", user));
+            async move { user }
+        };
+        let key = by_user.or_else(ForwardedFor);
+        assert_eq!(key.extract(&parts(&[("x-user", "ada")])).await.as_deref(), Some("user:ada"));
+        assert_eq!(key.extract(&parts(&[])).await.as_deref(), Some("10.0.0.1"));
+    }
+}
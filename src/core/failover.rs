@@ -0,0 +1,392 @@
+//! Failing over from one store to another, e.g. from Redis to memory, so a
+//! primary outage degrades sharing rather than the limiter
+//!
+//! ```rust,no_run,ignore
+//! let store = FailoverStore::new(RedisStore::open("redis://redis:6379")?, MemoryStore::new())
+//!     .with_retry_interval(Duration::from_secs(10));
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_store(store)?;
+//! ```
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::sync::{Mutex, MutexGuard};
+use super::{Admission, BoxFuture, RateLimitError, RateLimitStore, StoredCount};
+
+/// Counts in a primary store while it works and in a fallback while it
+/// doesn't. After a failure the primary is left alone for the retry
+/// interval; the next call after that tries it again, first adding what the
+/// fallback counted in the meantime to the primary's counts, so the outage
+/// isn't a free window for the keys it touched.
+#[derive(Debug)]
+pub struct FailoverStore<P, F> {
+    primary: P,
+    fallback: F,
+    retry_interval: Duration,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    /// When to try the primary again, while it's down
+    retry_at: Option<Instant>,
+    /// Keys counted in the fallback since the primary went down, each with
+    /// how many times it was, so recovery can tell a key diverted again
+    /// while it was being moved
+    diverted: HashMap<String, u64>,
+}
+
+/// Which store a call goes to
+enum Route {
+    Primary,
+    /// The primary is due another try, once the fallback's counts are back
+    Recover,
+    Fallback,
+}
+
+impl<P: RateLimitStore, F: RateLimitStore> FailoverStore<P, F> {
+    /// Count in `primary`, and in `fallback` while `primary` fails. The
+    /// primary is retried every five seconds while it's down.
+    pub fn new(primary: P, fallback: F) -> Self {
+        Self {
+            primary,
+            fallback,
+            retry_interval: Duration::from_secs(5),
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// Wait `interval` rather than five seconds before trying a failed
+    /// primary again
+    pub fn with_retry_interval(mut self, interval: Duration) -> Self {
+        self.retry_interval = interval;
+        self
+    }
+
+    /// Whether calls are going to the fallback
+    pub fn is_failed_over(&self) -> bool {
+        self.health().retry_at.is_some()
+    }
+
+    fn health(&self) -> MutexGuard<'_, Health> {
+        self.health.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Picks the store for the next call, letting one caller at a time try
+    /// a primary that's due another go
+    fn route(&self) -> Route {
+        let mut health = self.health();
+        match health.retry_at {
+            None => Route::Primary,
+            Some(retry_at) if Instant::now() >= retry_at => {
+                health.retry_at = Some(Instant::now() + self.retry_interval);
+                Route::Recover
+            }
+            Some(_) => Route::Fallback,
+        }
+    }
+
+    /// Whether to call the primary, moving the fallback's counts back to it
+    /// first if it has been down
+    async fn use_primary(&self) -> bool {
+        match self.route() {
+            Route::Primary => true,
+            Route::Fallback => false,
+            Route::Recover => self.recover().await,
+        }
+    }
+
+    /// Adds the counts the fallback took while the primary was down to the
+    /// primary, taking them off the fallback. Fails over again if the
+    /// primary still fails.
+    async fn recover(&self) -> bool {
+        loop {
+            let diverted: Vec<(String, u64)> = {
+                let mut health = self.health();
+                if health.diverted.is_empty() {
+                    health.retry_at = None;
+                    tracing::info!("primary rate limit store is back");
+                    return true;
+                }
+                health.diverted.iter().map(|(key, times)| (key.clone(), *times)).collect()
+            };
+            for (key, times) in diverted {
+                match self.fallback.get(&key).await {
+                    Ok(Some(stored)) if stored.count > 0 => {
+                        if let Err(e) = self.primary.increment(&key, stored.count, stored.ttl).await {
+                            self.primary_failed(e);
+                            return false;
+                        }
+                        // Only what was moved, so counts landing meanwhile stay for the next pass
+                        if let Err(e) = self.fallback.decrement(&key, stored.count).await {
+                            tracing::warn!("failed to take {} off the fallback rate limit store: {}", key, e);
+                            if let Err(e) = self.fallback.expire(&key, Duration::ZERO).await {
+                                tracing::warn!("failed to forget {} in the fallback rate limit store: {}", key, e);
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("failed to read {} from the fallback rate limit store: {}", key, e),
+                }
+                // A key diverted again meanwhile is moved on the next pass
+                let mut health = self.health();
+                if health.diverted.get(&key) == Some(&times) {
+                    health.diverted.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn primary_failed(&self, e: RateLimitError) {
+        let mut health = self.health();
+        if health.retry_at.is_none() {
+            tracing::warn!("primary rate limit store failed, failing over: {}", e);
+        }
+        health.retry_at = Some(Instant::now() + self.retry_interval);
+    }
+
+    fn divert(&self, key: &str) {
+        let mut health = self.health();
+        match health.diverted.get_mut(key) {
+            Some(times) => *times += 1,
+            None => {
+                health.diverted.insert(key.to_string(), 1);
+            }
+        }
+    }
+}
+
+impl<P: RateLimitStore, F: RateLimitStore> RateLimitStore for FailoverStore<P, F> {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            if self.use_primary().await {
+                match self.primary.get(key).await {
+                    Ok(stored) => return Ok(stored),
+                    Err(e) => self.primary_failed(e),
+                }
+            }
+            self.fallback.get(key).await
+        })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+        Box::pin(async move {
+            if self.use_primary().await {
+                match self.primary.increment(key, amount, window).await {
+                    Ok(stored) => return Ok(stored),
+                    Err(e) => self.primary_failed(e),
+                }
+            }
+            self.divert(key);
+            self.fallback.increment(key, amount, window).await
+        })
+    }
+
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+        Box::pin(async move {
+            if self.use_primary().await {
+                match self.primary.increment_within(key, amount, limit, window).await {
+                    Ok(admission) => return Ok(admission),
+                    Err(e) => self.primary_failed(e),
+                }
+            }
+            self.divert(key);
+            self.fallback.increment_within(key, amount, limit, window).await
+        })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+        Box::pin(async move {
+            // The fallback may hold a count from an earlier outage
+            let fallback = self.fallback.expire(key, ttl).await;
+            if self.use_primary().await {
+                match self.primary.expire(key, ttl).await {
+                    Ok(()) => return Ok(()),
+                    Err(e) => self.primary_failed(e),
+                }
+            }
+            fallback
+        })
+    }
+
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            if self.use_primary().await {
+                match self.primary.decrement(key, amount).await {
+                    Ok(stored) => return Ok(stored),
+                    Err(e) => self.primary_failed(e),
+                }
+            }
+            self.fallback.decrement(key, amount).await
+        })
+    }
+
+    fn now(&self) -> BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+        Box::pin(async move {
+            if self.use_primary().await {
+                match self.primary.now().await {
+                    Ok(now) => return Ok(now),
+                    Err(e) => self.primary_failed(e),
+                }
+            }
+            self.fallback.now().await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{MemoryStore, RateLimitConfig, RateLimiter};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// A memory store that can be taken down, or slowed down
+    #[derive(Clone, Debug, Default)]
+    struct Flaky {
+        store: MemoryStore,
+        down: Arc<AtomicBool>,
+        slow: Arc<AtomicBool>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<(), RateLimitError> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err(RateLimitError::Other("down".into())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl RateLimitStore for Flaky {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+            Box::pin(async move {
+                self.check()?;
+                self.store.get(key).await
+            })
+        }
+
+        fn increment<'a>(
+            &'a self,
+            key: &'a str,
+            amount: u32,
+            window: Duration,
+        ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+            Box::pin(async move {
+                self.check()?;
+                if self.slow.load(Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                self.store.increment(key, amount, window).await
+            })
+        }
+
+        fn increment_within<'a>(
+            &'a self,
+            key: &'a str,
+            amount: u32,
+            limit: u32,
+            window: Duration,
+        ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+            Box::pin(async move {
+                self.check()?;
+                self.store.increment_within(key, amount, limit, window).await
+            })
+        }
+
+        fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+            Box::pin(async move {
+                self.check()?;
+                self.store.expire(key, ttl).await
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_counts_fail_over_and_return_to_the_primary() {
+        let primary = Flaky::default();
+        let fallback = MemoryStore::new();
+        let store = FailoverStore::new(primary.clone(), fallback.clone()).with_retry_interval(Duration::from_secs(5));
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60)).with_store(store).unwrap();
+
+        limiter.check_rate_limit("a").await.unwrap();
+        primary.down.store(true, Ordering::SeqCst);
+        limiter.check_rate_limit("a").await.unwrap();
+        limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!(fallback.get("a").await.unwrap().unwrap().count, 2);
+
+        // Back, but not retried until the interval is up
+        primary.down.store(false, Ordering::SeqCst);
+        limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!(primary.store.get("a").await.unwrap().unwrap().count, 1);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let info = limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!(info.remaining, 0);
+        assert_eq!(primary.store.get("a").await.unwrap().unwrap().count, 5);
+        assert_eq!(fallback.get("a").await.unwrap().map_or(0, |stored| stored.count), 0);
+        assert!(limiter.check_rate_limit("a").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_a_primary_still_down_keeps_the_fallback() {
+        let primary = Flaky::default();
+        primary.down.store(true, Ordering::SeqCst);
+        let store = FailoverStore::new(primary.clone(), MemoryStore::new());
+        let window = Duration::from_secs(60);
+
+        store.increment("a", 1, window).await.unwrap();
+        assert!(store.is_failed_over());
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(store.increment("a", 1, window).await.unwrap().count, 2);
+        assert!(store.is_failed_over());
+
+        primary.down.store(false, Ordering::SeqCst);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(store.get("a").await.unwrap().unwrap().count, 2);
+        assert!(!store.is_failed_over());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_counts_diverted_while_recovering_are_moved_too() {
+        let primary = Flaky::default();
+        let fallback = MemoryStore::new();
+        let store = Arc::new(FailoverStore::new(primary.clone(), fallback.clone()));
+        let window = Duration::from_secs(60);
+
+        primary.down.store(true, Ordering::SeqCst);
+        store.increment("a", 2, window).await.unwrap();
+        primary.down.store(false, Ordering::SeqCst);
+        primary.slow.store(true, Ordering::SeqCst);
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        // One call moves the fallback's count while another lands in the fallback
+        let recovering = tokio::spawn({
+            let store = store.clone();
+            async move { store.increment("a", 1, window).await.unwrap() }
+        });
+        tokio::task::yield_now().await;
+        store.increment("a", 1, window).await.unwrap();
+        recovering.await.unwrap();
+
+        assert_eq!(primary.store.get("a").await.unwrap().unwrap().count, 4);
+        assert!(!store.is_failed_over());
+    }
+}
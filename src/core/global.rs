@@ -0,0 +1,312 @@
+//! A global ceiling shared by every key, layered over the per-key limiter
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::{ParseConfigError, RateLimitConfig, RateLimitErrorCode, RateLimitInfo, RateLimitRejection, RateLimiter};
+
+/// How a request may draw on the global pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Draws from the shared pool, and is shed first once the pool passes
+    /// the limiter's shedding threshold
+    Low,
+    /// Draws from the shared pool only
+    #[default]
+    Normal,
+    /// May also draw from the reserve once the shared pool is exhausted,
+    /// e.g. for paying customers during an abuse wave
+    High,
+}
+
+impl Priority {
+    const VARIANTS: &'static [&'static str] = &["low", "normal", "high"];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Parses `"low"`, `"normal"`, or `"high"`, ignoring case
+impl std::str::FromStr for Priority {
+    type Err = ParseConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(ParseConfigError::new("priority", s, Self::VARIANTS)),
+        }
+    }
+}
+
+/// Requests of one priority the global limiter has seen since it was built
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PriorityStats {
+    /// Requests admitted by the global pool
+    pub admitted: u64,
+    /// Requests turned away by the global pool, whether for the ceiling or
+    /// for shedding
+    pub shed: u64,
+}
+
+/// Per-priority admitted and shed counters
+#[derive(Debug, Default)]
+struct Counters {
+    admitted: [AtomicU64; 3],
+    shed: [AtomicU64; 3],
+}
+
+/// Usage of the global pool in its current window
+#[derive(Debug)]
+struct GlobalWindow {
+    start: Instant,
+    shared_used: u32,
+    reserve_used: u32,
+    /// Units each key drew from the pool in this window
+    per_key: HashMap<String, u32>,
+}
+
+impl GlobalWindow {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            shared_used: 0,
+            reserve_used: 0,
+            per_key: HashMap::new(),
+        }
+    }
+}
+
+/// Which part of the global pool a request was charged to, so it can be
+/// given back if the per-key check rejects it
+#[derive(Clone, Copy)]
+enum Draw {
+    Reservation,
+    Shared,
+    Reserve,
+}
+
+/// Enforces each key's own limit and a ceiling on all keys combined. Both
+/// share the per-key config's window. Cloning a `GlobalLimiter` is cheap and
+/// the clones share their counters.
+#[derive(Clone, Debug)]
+pub struct GlobalLimiter {
+    keys: RateLimiter,
+    ceiling: u32,
+    reserve: u32,
+    reservations: Arc<HashMap<String, u32>>,
+    max_share: Option<u32>,
+    shed_low_at: Option<u32>,
+    window: Arc<Mutex<GlobalWindow>>,
+    counters: Arc<Counters>,
+}
+
+impl GlobalLimiter {
+    /// Limit each key per `per_key`, and all keys together to `ceiling`
+    /// units per window
+    pub fn new(per_key: RateLimitConfig, ceiling: u32) -> Self {
+        Self {
+            keys: RateLimiter::new(per_key),
+            ceiling,
+            reserve: 0,
+            reservations: Arc::new(HashMap::new()),
+            max_share: None,
+            shed_low_at: None,
+            window: Arc::new(Mutex::new(GlobalWindow::new(Instant::now()))),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Hold back `fraction` (0.0 to 1.0) of the ceiling for
+    /// [`Priority::High`] requests, which draw on it only once the rest of
+    /// the ceiling is used up
+    pub fn with_reserve(mut self, fraction: f64) -> Self {
+        self.reserve = (f64::from(self.ceiling) * fraction.clamp(0.0, 1.0)).round() as u32;
+        self
+    }
+
+    /// Guarantee `key` at least `units` per window, carved out of the
+    /// ceiling: no other key can use them, so `key` keeps working even when
+    /// the rest of the pool is saturated. Beyond its reservation, `key` draws
+    /// from the shared pool like any other. `key` is still subject to its
+    /// own per-key limit.
+    pub fn with_reservation(mut self, key: impl Into<String>, units: u32) -> Self {
+        Arc::make_mut(&mut self.reservations).insert(key.into(), units);
+        self
+    }
+
+    /// Cap any single key at `fraction` (0.0 to 1.0) of the ceiling per
+    /// window, so one aggressive client can't starve everyone else before
+    /// its own per-key limit kicks in. Reserved units don't count toward the
+    /// cap.
+    pub fn with_max_share(mut self, fraction: f64) -> Self {
+        self.max_share = Some((f64::from(self.ceiling) * fraction.clamp(0.0, 1.0)).ceil() as u32);
+        self
+    }
+
+    /// Shed [`Priority::Low`] requests once the pool has used `fraction`
+    /// (0.0 to 1.0) of the ceiling in the current window, keeping the rest
+    /// for normal and high priority traffic
+    pub fn with_shedding(mut self, fraction: f64) -> Self {
+        self.shed_low_at = Some((f64::from(self.ceiling) * fraction.clamp(0.0, 1.0)).round() as u32);
+        self
+    }
+
+    /// How many requests of `priority` the global pool has admitted and shed
+    pub fn stats(&self, priority: Priority) -> PriorityStats {
+        PriorityStats {
+            admitted: self.counters.admitted[priority.index()].load(Ordering::Relaxed),
+            shed: self.counters.shed[priority.index()].load(Ordering::Relaxed),
+        }
+    }
+
+    /// The per-key limiter
+    pub fn keys(&self) -> &RateLimiter {
+        &self.keys
+    }
+
+    /// Counts a request against both `key` and the global ceiling. The
+    /// request is rejected if either is exhausted.
+    pub async fn check_rate_limit(&self, key: &str, priority: Priority) -> Result<RateLimitInfo, RateLimitRejection> {
+        let draw = match self.take(key, priority) {
+            Ok(draw) => {
+                self.counters.admitted[priority.index()].fetch_add(1, Ordering::Relaxed);
+                draw
+            }
+            Err(rejection) => {
+                self.counters.shed[priority.index()].fetch_add(1, Ordering::Relaxed);
+                if let Some(delay) = self.keys.config().tarpit {
+                    self.keys.sleep(delay).await;
+                }
+                return Err(rejection);
+            }
+        };
+        match self.keys.check_rate_limit(key).await {
+            Ok(info) => Ok(info),
+            Err(rejection) => {
+                self.give_back(key, draw);
+                Err(rejection)
+            }
+        }
+    }
+
+    fn take(&self, key: &str, priority: Priority) -> Result<Draw, RateLimitRejection> {
+        let config = self.keys.config();
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if now.duration_since(window.start) > config.window {
+            *window = GlobalWindow::new(now);
+        }
+
+        let used = window.per_key.get(key).copied().unwrap_or(0);
+        let reservation = self.reservations.get(key).copied().unwrap_or(0);
+        let reserved: u32 = self.reservations.values().sum();
+        let pool_used = window.shared_used + window.reserve_used;
+        let shed = priority == Priority::Low && self.shed_low_at.is_some_and(|at| pool_used >= at);
+        let draw = if used < reservation {
+            Some(Draw::Reservation)
+        } else if shed || self.max_share.is_some_and(|max| used - reservation >= max) {
+            None
+        } else if window.shared_used < self.ceiling.saturating_sub(self.reserve).saturating_sub(reserved) {
+            window.shared_used += 1;
+            Some(Draw::Shared)
+        } else if priority == Priority::High && window.reserve_used < self.reserve {
+            window.reserve_used += 1;
+            Some(Draw::Reserve)
+        } else {
+            None
+        };
+        if let Some(draw) = draw {
+            *window.per_key.entry(key.to_string()).or_insert(0) += 1;
+            return Ok(draw);
+        }
+
+        let retry_after = config.window.saturating_sub(now.duration_since(window.start));
+        Err(self.rejection(&config, retry_after).with_code(RateLimitErrorCode::GlobalOverload))
+    }
+
+    fn give_back(&self, key: &str, draw: Draw) {
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(used) = window.per_key.get_mut(key) {
+            *used = used.saturating_sub(1);
+        }
+        match draw {
+            Draw::Reservation => {}
+            Draw::Shared => window.shared_used = window.shared_used.saturating_sub(1),
+            Draw::Reserve => window.reserve_used = window.reserve_used.saturating_sub(1),
+        }
+    }
+
+    fn rejection(&self, config: &RateLimitConfig, retry_after: Duration) -> RateLimitRejection {
+        RateLimitRejection::new(retry_after, self.ceiling)
+            .with_window(config.window)
+            .with_retry_after_format(config.retry_after_format.clone())
+            .with_header_style(config.header_style)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reserve_is_kept_for_priority_keys() {
+        let limiter = GlobalLimiter::new(RateLimitConfig::max_per_window(10, 60), 4).with_reserve(0.5);
+
+        assert!(limiter.check_rate_limit("a", Priority::Normal).await.is_ok());
+        assert!(limiter.check_rate_limit("b", Priority::Normal).await.is_ok());
+        // The shared pool is exhausted, so only priority keys get through
+        let rejection = limiter.check_rate_limit("c", Priority::Normal).await.unwrap_err();
+        assert_eq!(rejection.limit, 4);
+        assert_eq!(rejection.code, RateLimitErrorCode::GlobalOverload);
+        assert_eq!(rejection.to_json_body()["code"], "global_overload");
+        assert!(limiter.check_rate_limit("paid", Priority::High).await.is_ok());
+        assert!(limiter.check_rate_limit("paid", Priority::High).await.is_ok());
+        assert!(limiter.check_rate_limit("paid", Priority::High).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reservations_survive_saturation() {
+        let limiter = GlobalLimiter::new(RateLimitConfig::max_per_window(10, 60), 4).with_reservation("premium", 2);
+
+        // Only 2 units are shared; the abuser can't touch the reservation
+        assert!(limiter.check_rate_limit("abuser", Priority::Normal).await.is_ok());
+        assert!(limiter.check_rate_limit("abuser", Priority::Normal).await.is_ok());
+        assert!(limiter.check_rate_limit("abuser", Priority::Normal).await.is_err());
+
+        assert!(limiter.check_rate_limit("premium", Priority::Normal).await.is_ok());
+        assert!(limiter.check_rate_limit("premium", Priority::Normal).await.is_ok());
+        assert!(limiter.check_rate_limit("premium", Priority::Normal).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_low_priority_is_shed_first() {
+        let limiter = GlobalLimiter::new(RateLimitConfig::max_per_window(10, 60), 4).with_shedding(0.5);
+
+        assert!(limiter.check_rate_limit("a", Priority::Low).await.is_ok());
+        assert!(limiter.check_rate_limit("b", Priority::Normal).await.is_ok());
+        // Half the pool is used, so only low priority traffic is turned away
+        assert!(limiter.check_rate_limit("c", Priority::Low).await.is_err());
+        assert!(limiter.check_rate_limit("d", Priority::Normal).await.is_ok());
+
+        assert_eq!(limiter.stats(Priority::Low), PriorityStats { admitted: 1, shed: 1 });
+        assert_eq!(limiter.stats(Priority::Normal), PriorityStats { admitted: 2, shed: 0 });
+        assert_eq!("HIGH".parse(), Ok(Priority::High));
+    }
+
+    #[tokio::test]
+    async fn test_max_share_caps_one_key() {
+        let limiter = GlobalLimiter::new(RateLimitConfig::max_per_window(10, 60), 10).with_max_share(0.2);
+
+        assert!(limiter.check_rate_limit("greedy", Priority::Normal).await.is_ok());
+        assert!(limiter.check_rate_limit("greedy", Priority::Normal).await.is_ok());
+        // Well under its per-key limit, but at 20% of the ceiling
+        assert!(limiter.check_rate_limit("greedy", Priority::Normal).await.is_err());
+        assert!(limiter.check_rate_limit("polite", Priority::Normal).await.is_ok());
+    }
+}
@@ -0,0 +1,114 @@
+//! Keeping client identifiers out of a shared store, so the IPs and API keys
+//! a limiter counts by aren't left readable in Redis key names, dumps, or
+//! backups
+//!
+//! ```rust,no_run,ignore
+//! let store = HashedKeys::new(RedisStore::open("redis://redis:6379")?, std::env::var("STORE_KEY_SECRET")?);
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_store(store)?;
+//! ```
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Admission, BoxFuture, RateLimitError, RateLimitStore, StoredCount};
+
+/// Counts each key in `store` under its HMAC-SHA256 with a secret rather
+/// than under the key itself. Every replica sharing the store needs the same
+/// secret; changing it starts every key afresh. A [`ShardedStore`](super::ShardedStore)
+/// places and matches hot keys by the plain key, so wrap its shards rather
+/// than the sharded store.
+pub struct HashedKeys<S> {
+    store: S,
+    secret: Arc<[u8]>,
+}
+
+impl<S: RateLimitStore> HashedKeys<S> {
+    /// Count in `store` under keys hashed with `secret`
+    pub fn new(store: S, secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            store,
+            secret: secret.as_ref().into(),
+        }
+    }
+
+    /// The name `key` is stored under
+    fn hashed(&self, key: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(key.as_bytes());
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for HashedKeys<S> {
+    /// Leaves the secret out
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashedKeys").field("store", &self.store).finish_non_exhaustive()
+    }
+}
+
+impl<S: RateLimitStore> RateLimitStore for HashedKeys<S> {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move { self.store.get(&self.hashed(key)).await })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+        Box::pin(async move { self.store.increment(&self.hashed(key), amount, window).await })
+    }
+
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+        Box::pin(async move { self.store.increment_within(&self.hashed(key), amount, limit, window).await })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+        Box::pin(async move { self.store.expire(&self.hashed(key), ttl).await })
+    }
+
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move { self.store.decrement(&self.hashed(key), amount).await })
+    }
+
+    fn now(&self) -> BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+        self.store.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{MemoryStore, RateLimitConfig, RateLimiter};
+
+    #[tokio::test]
+    async fn test_keys_are_stored_hashed() {
+        let store = MemoryStore::new();
+        let hashed = HashedKeys::new(store.clone(), "secret");
+        let name = hashed.hashed("10.0.0.1");
+        assert_eq!(name.len(), 64);
+        assert_ne!(name, HashedKeys::new(MemoryStore::new(), "other").hashed("10.0.0.1"));
+
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(2, 60)).with_store(hashed).unwrap();
+        limiter.check_rate_limit("10.0.0.1").await.unwrap();
+        assert_eq!(limiter.check_rate_limit("10.0.0.1").await.unwrap().remaining, 0);
+        assert_eq!(store.get("10.0.0.1").await.unwrap(), None);
+        assert_eq!(store.get(&name).await.unwrap().unwrap().count, 2);
+        assert!(!format!("{:?}", limiter).contains("secret"));
+    }
+}
@@ -0,0 +1,78 @@
+//! Client identities established by the transport, e.g. the subject or SPIFFE
+//! ID of an mTLS client certificate, and fingerprints derived from requests
+
+use http::HeaderMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// The identity of the client certificate presented on a connection.
+///
+/// warp does not expose peer certificates, so the TLS acceptor in front of it
+/// is responsible for extracting the identity (the certificate subject, or the
+/// SPIFFE ID from its URI SAN) and inserting a `ClientIdentity` into the
+/// request extensions. The rate limiting filters can then key on it, limiting
+/// machine clients by who they are rather than by the IP they share behind NAT.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClientIdentity(String);
+
+impl ClientIdentity {
+    /// Wrap an identity string, e.g. `spiffe://example.org/billing`
+    pub fn new(identity: impl Into<String>) -> Self {
+        Self(identity.into())
+    }
+
+    /// The identity string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A hash of a request's normalized header set: the sorted names of every
+/// header present, plus the trimmed, lowercased values of the headers in
+/// `values` (e.g. `user-agent`, `accept-language`). Scrapers rotating
+/// through IPs with the same client stack share a fingerprint.
+///
+/// The hash is stable across processes built with the same Rust version,
+/// so instances sharing counts agree on it.
+pub fn header_fingerprint(headers: &HeaderMap, values: &[&str]) -> String {
+    let mut names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let mut hasher = DefaultHasher::new();
+    names.hash(&mut hasher);
+    for name in values {
+        let value = headers.get(*name).and_then(|v| v.to_str().ok()).unwrap_or("");
+        value.trim().to_ascii_lowercase().hash(&mut hasher);
+    }
+    format!("fp:{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_header_fingerprint_ignores_order_and_case() {
+        let mut a = HeaderMap::new();
+        a.insert("user-agent", HeaderValue::from_static("Scraper/1.0"));
+        a.insert("accept", HeaderValue::from_static("*/*"));
+        let mut b = HeaderMap::new();
+        b.insert("accept", HeaderValue::from_static("text/html"));
+        b.insert("user-agent", HeaderValue::from_static(" scraper/1.0"));
+
+        assert_eq!(header_fingerprint(&a, &["user-agent"]), header_fingerprint(&b, &["user-agent"]));
+        assert_ne!(header_fingerprint(&a, &["user-agent", "accept"]), header_fingerprint(&b, &["user-agent", "accept"]));
+
+        b.insert("cookie", HeaderValue::from_static("session=1"));
+        assert_ne!(header_fingerprint(&a, &["user-agent"]), header_fingerprint(&b, &["user-agent"]));
+    }
+}
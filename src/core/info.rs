@@ -0,0 +1,326 @@
+//! Rate limit status reported to handlers, and the headers derived from it
+
+use chrono::{DateTime, Utc};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::{wall_clock_now, HeaderStyle, RateLimitError, ResetMode, RetryAfterFormat};
+
+/// Small facts about a key attached by the application, e.g. `plan`,
+/// `account_id`, or `region`, carried through the limiter's output so
+/// consumers don't need a second lookup to make sense of it
+pub type KeyMetadata = BTreeMap<String, String>;
+
+/// Information about the current rate limit status
+///
+/// Fields may be added in minor releases; build one outside the limiter with
+/// [`RateLimitInfo::new`] and set the fields you need on the result.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RateLimitInfo {
+    /// Time until the rate limit resets
+    pub retry_after: String,
+    /// Maximum requests allowed in the window
+    pub limit: u32,
+    /// Remaining requests in the current window
+    pub remaining: u32,
+    /// Requests consumed in the current window
+    pub used: u32,
+    /// Length of the rate limiting window
+    pub window: Duration,
+    /// When the current window started
+    pub window_start: DateTime<Utc>,
+    /// When the current window ends
+    pub window_end: DateTime<Utc>,
+    /// Unix timestamp when the rate limit resets
+    pub reset_timestamp: i64,
+    /// Format used for retry-after header
+    pub retry_after_format: RetryAfterFormat,
+    /// Which gateway's rate limit headers to emit
+    pub header_style: HeaderStyle,
+    /// Whether the key is past the config's soft limit
+    #[serde(default)]
+    pub soft_limit_reached: bool,
+    /// Burst credits the key has banked, when the config grants them
+    #[serde(default)]
+    pub credits: Option<u32>,
+    /// How the limit's windows reset
+    #[serde(default)]
+    pub reset_mode: ResetMode,
+    /// The metadata attached to the key, see
+    /// [`RateLimiter::set_metadata`](crate::RateLimiter::set_metadata)
+    #[serde(default, skip_serializing_if = "KeyMetadata::is_empty")]
+    pub metadata: KeyMetadata,
+}
+
+impl RateLimitInfo {
+    /// Status for a client with `remaining` of `limit` left in a window of
+    /// length `window` that ends at `window_end`, e.g. for handlers that track
+    /// the limit themselves. Everything else takes its default.
+    pub fn new(limit: u32, remaining: u32, window: Duration, window_end: DateTime<Utc>) -> Self {
+        Self {
+            retry_after: window_end.to_rfc2822(),
+            limit,
+            remaining,
+            used: limit.saturating_sub(remaining),
+            window,
+            window_start: window_end - chrono::Duration::from_std(window).unwrap_or_else(|_| chrono::Duration::zero()),
+            window_end,
+            reset_timestamp: window_end.timestamp(),
+            retry_after_format: RetryAfterFormat::HttpDate,
+            header_style: HeaderStyle::default(),
+            soft_limit_reached: false,
+            credits: None,
+            reset_mode: ResetMode::default(),
+            metadata: KeyMetadata::new(),
+        }
+    }
+
+    /// Returns the rate limit headers as `(HeaderName, HeaderValue)` pairs in
+    /// this info's `HeaderStyle`, rendering Retry-After in the given format. This lets callers on other
+    /// frameworks or custom response types emit the same headers.
+    pub fn header_pairs(
+        &self,
+        format: &RetryAfterFormat,
+    ) -> Result<impl Iterator<Item = (HeaderName, HeaderValue)>, RateLimitError> {
+        let retry_after = if *format == self.retry_after_format {
+            self.retry_after.clone()
+        } else {
+            match format {
+                RetryAfterFormat::HttpDate => self.window_end.to_rfc2822(),
+                RetryAfterFormat::Seconds => seconds_until(self.window_end).to_string(),
+            }
+        };
+
+        let mut pairs = vec![(header::RETRY_AFTER, retry_after)];
+        if self.header_style.sends_legacy() {
+            pairs.push((HeaderName::from_static("x-ratelimit-limit"), self.limit.to_string()));
+            pairs.push((HeaderName::from_static("x-ratelimit-remaining"), self.remaining.to_string()));
+            if self.header_style == HeaderStyle::GitHub {
+                pairs.push((HeaderName::from_static("x-ratelimit-used"), self.used.to_string()));
+            }
+            pairs.push((HeaderName::from_static("x-ratelimit-reset"), self.reset_timestamp.to_string()));
+        }
+        if self.header_style.sends_draft() {
+            let reset = seconds_until(self.window_end);
+            pairs.push((HeaderName::from_static("ratelimit-limit"), self.limit.to_string()));
+            pairs.push((HeaderName::from_static("ratelimit-remaining"), self.remaining.to_string()));
+            pairs.push((HeaderName::from_static("ratelimit-reset"), reset.to_string()));
+            pairs.push((
+                HeaderName::from_static("ratelimit-policy"),
+                format!("{};w={}", self.limit, self.window.as_secs()),
+            ));
+        }
+        if self.soft_limit_reached {
+            pairs.push((
+                HeaderName::from_static("x-ratelimit-warning"),
+                format!("{} of {} requests used", self.used, self.limit),
+            ));
+        }
+        if self.reset_mode != ResetMode::Rolling {
+            pairs.push((HeaderName::from_static("x-ratelimit-reset-mode"), self.reset_mode.to_string()));
+        }
+        if let Some(credits) = self.credits {
+            pairs.push((HeaderName::from_static("x-ratelimit-credits"), credits.to_string()));
+        }
+
+        let mut headers = Vec::with_capacity(pairs.len());
+        for (name, value) in pairs {
+            headers.push((name, HeaderValue::from_str(&value).map_err(RateLimitError::HeaderError)?));
+        }
+        Ok(headers.into_iter())
+    }
+
+    /// Builds the standard JSON body for a rate limited response. The schema
+    /// is stable across releases:
+    ///
+    /// ```json
+    /// {
+    ///   "error": "Rate limit exceeded",
+    ///   "code": "rate_limited",
+    ///   "limit": 60,
+    ///   "remaining": 0,
+    ///   "retry_after_seconds": 42,
+    ///   "reset": 1704067260,
+    ///   "reset_mode": "rolling"
+    /// }
+    /// ```
+    ///
+    /// `code` is a [`RateLimitErrorCode`](super::RateLimitErrorCode); use
+    /// `RateLimitRejection::to_json_body` to report codes other than
+    /// `rate_limited`. `reset` is the Unix timestamp at which the window
+    /// resets, and `reset_mode` is the config's [`ResetMode`].
+    pub fn to_json_body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": "Rate limit exceeded",
+            "code": super::RateLimitErrorCode::RateLimited,
+            "limit": self.limit,
+            "remaining": self.remaining,
+            "retry_after_seconds": seconds_until(self.window_end),
+            "reset": self.reset_timestamp,
+            "reset_mode": self.reset_mode,
+        })
+    }
+
+    /// Builds the JSON body for a client asking after its own standing:
+    ///
+    /// ```json
+    /// {
+    ///   "limit": 60,
+    ///   "remaining": 18,
+    ///   "used": 42,
+    ///   "reset": 1704067260,
+    ///   "reset_in_seconds": 37
+    /// }
+    /// ```
+    pub fn to_status_body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "limit": self.limit,
+            "remaining": self.remaining,
+            "used": self.used,
+            "reset": self.reset_timestamp,
+            "reset_in_seconds": seconds_until(self.window_end),
+        })
+    }
+
+    /// Builds a `HeaderMap` containing the rate limit headers for this info,
+    /// ready to extend an existing map or attach to a reply
+    pub fn to_headers(&self) -> Result<HeaderMap, RateLimitError> {
+        Ok(self.header_pairs(&self.retry_after_format)?.collect())
+    }
+}
+
+/// Whole seconds from now until `reset`, rounded up so clients never retry
+/// early. Every seconds-based value the crate emits goes through this.
+pub(crate) fn seconds_until(reset: DateTime<Utc>) -> i64 {
+    let millis = (reset - wall_clock_now()).num_milliseconds().max(0);
+    (millis + 999) / 1000
+}
+
+/// Adds rate limit headers to a response
+pub fn add_rate_limit_headers(
+    headers: &mut HeaderMap,
+    info: &RateLimitInfo,
+) -> Result<(), RateLimitError> {
+    headers.extend(info.to_headers()?);
+    Ok(())
+}
+
+/// The `RateLimitInfo` a middleware (`RateLimitService`, `RateLimitLayer`,
+/// or the axum `rate_limit` function) stashed in the request extensions, for
+/// handlers behind it that want their caller's status
+pub fn rate_limit_info(extensions: &http::Extensions) -> Option<&RateLimitInfo> {
+    extensions.get::<RateLimitInfo>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{get_rate_limit_info, RateLimitRejection};
+
+    #[test]
+    fn test_info_to_headers() {
+        let info = get_rate_limit_info(&RateLimitRejection::new(Duration::from_secs(5), 7)
+            .with_retry_after_format(RetryAfterFormat::Seconds));
+
+        let headers = info.to_headers().unwrap();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "5");
+        assert_eq!(headers.get("X-RateLimit-Limit").unwrap(), "7");
+
+        // Extending an existing map keeps unrelated headers intact
+        let mut existing = HeaderMap::new();
+        existing.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        existing.extend(headers);
+        assert_eq!(existing.len(), 5);
+    }
+
+    #[test]
+    fn test_header_pairs_formats() {
+        let info = get_rate_limit_info(&RateLimitRejection::new(Duration::from_secs(5), 7)
+            .with_retry_after_format(RetryAfterFormat::Seconds));
+
+        let pairs: Vec<_> = info.header_pairs(&RetryAfterFormat::Seconds).unwrap().collect();
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(pairs[0], (header::RETRY_AFTER, HeaderValue::from_static("5")));
+
+        // Asking for a different format re-renders Retry-After from the window end
+        let pairs: Vec<_> = info.header_pairs(&RetryAfterFormat::HttpDate).unwrap().collect();
+        assert_eq!(pairs[0].1.to_str().unwrap(), info.window_end.to_rfc2822());
+    }
+
+    #[test]
+    fn test_header_styles() {
+        let mut info = get_rate_limit_info(&RateLimitRejection::new(Duration::from_secs(60), 10));
+
+        info.header_style = HeaderStyle::GitHub;
+        let headers = info.to_headers().unwrap();
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers.get("x-ratelimit-used").unwrap(), "10");
+
+        info.header_style = HeaderStyle::Draft;
+        let headers = info.to_headers().unwrap();
+        assert!(!headers.contains_key("x-ratelimit-limit"));
+        assert_eq!(headers.get("ratelimit-limit").unwrap(), "10");
+        assert_eq!(headers.get("ratelimit-policy").unwrap(), "10;w=60");
+        let reset: i64 = headers.get("ratelimit-reset").unwrap().to_str().unwrap().parse().unwrap();
+        assert!((59..=60).contains(&reset));
+
+        info.header_style = HeaderStyle::Both;
+        let headers = info.to_headers().unwrap();
+        assert_eq!(headers.get("x-ratelimit-limit").unwrap(), "10");
+        assert_eq!(headers.get("ratelimit-limit").unwrap(), "10");
+        assert_eq!(headers.get("x-ratelimit-remaining"), headers.get("ratelimit-remaining"));
+        assert!(!headers.contains_key("x-ratelimit-used"));
+    }
+
+    #[test]
+    fn test_json_body() {
+        let info = get_rate_limit_info(&RateLimitRejection::new(Duration::from_secs(30), 5));
+        let body = info.to_json_body();
+
+        assert_eq!(body["error"], "Rate limit exceeded");
+        assert_eq!(body["limit"], 5);
+        assert_eq!(body["remaining"], 0);
+        assert_eq!(body["reset"], info.reset_timestamp);
+        let retry_after = body["retry_after_seconds"].as_i64().unwrap();
+        assert!((29..=30).contains(&retry_after));
+    }
+
+    #[test]
+    fn test_info_built_outside_the_limiter() {
+        let end = Utc::now() + chrono::Duration::seconds(30);
+        let mut info = RateLimitInfo::new(10, 4, Duration::from_secs(60), end);
+        info.header_style = HeaderStyle::Draft;
+        let headers = info.to_headers().unwrap();
+        assert_eq!(headers["ratelimit-remaining"], "4");
+        assert_eq!(headers["ratelimit-policy"], "10;w=60");
+        assert_eq!((info.used, info.to_status_body()["reset"].as_i64()), (6, Some(end.timestamp())));
+    }
+
+    #[test]
+    fn test_invalid_header_value_handling() {
+        let mut headers = HeaderMap::new();
+        let invalid_info = RateLimitInfo {
+            retry_after: "invalid\u{0000}characters".to_string(),
+            limit: 100,
+            remaining: 50,
+            used: 50,
+            window: Duration::from_secs(60),
+            window_start: Utc::now(),
+            window_end: Utc::now(),
+            reset_timestamp: 1234567890,
+            retry_after_format: RetryAfterFormat::Seconds,
+            header_style: HeaderStyle::Legacy,
+            soft_limit_reached: false,
+            credits: None,
+            reset_mode: ResetMode::Rolling,
+            metadata: KeyMetadata::new(),
+        };
+        
+        let result = add_rate_limit_headers(&mut headers, &invalid_info);
+        assert!(matches!(result, Err(RateLimitError::HeaderError(_))));
+    }
+}
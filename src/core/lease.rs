@@ -0,0 +1,301 @@
+//! Leasing budget from a shared store in batches, so a busy key costs one
+//! store round trip per batch rather than one per request
+//!
+//! ```rust,no_run,ignore
+//! let store = LeasingStore::new(RedisStore::open("redis://redis:6379")?, 20);
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(10_000)).with_store(store)?;
+//! ```
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::sync::{Mutex, MutexGuard};
+use super::{Admission, BoxFuture, RateLimitError, RateLimitStore, StoredCount};
+
+/// Takes a key's budget from a shared store a batch at a time and spends it
+/// locally, going back to the store once the batch runs out. Near the limit
+/// it leases whatever is left. Leased units count against the key in the
+/// store as soon as they're leased, so replicas together never admit more
+/// than the limit; units a replica leased but didn't spend before the
+/// window ended are lost, so the limit may be reached early by up to a
+/// batch per replica.
+#[derive(Debug)]
+pub struct LeasingStore<S> {
+    store: S,
+    batch: u32,
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Lease {
+    /// Units leased but not yet spent
+    left: u32,
+    /// The key's count in the store when the lease was taken
+    count: u32,
+    /// When the key's window, and so the lease, ends
+    expires: Instant,
+}
+
+impl Lease {
+    /// The key's count as far as this replica knows: the store's, less what
+    /// it has leased but not spent
+    fn stored(&self, now: Instant) -> StoredCount {
+        StoredCount {
+            count: self.count.saturating_sub(self.left),
+            ttl: self.expires.saturating_duration_since(now),
+        }
+    }
+}
+
+impl<S: RateLimitStore> LeasingStore<S> {
+    /// Lease budget from `store` `batch` units at a time
+    pub fn new(store: S, batch: u32) -> Self {
+        Self {
+            store,
+            batch: batch.max(1),
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn leases(&self) -> MutexGuard<'_, HashMap<String, Lease>> {
+        self.leases.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Spends `amount` from `key`'s lease, if it holds that much and the
+    /// key's count stays within `limit`, which may have shrunk since the
+    /// lease was taken
+    fn spend(&self, key: &str, amount: u32, limit: u32, now: Instant) -> Option<StoredCount> {
+        let mut leases = self.leases();
+        let lease = leases.get_mut(key).filter(|lease| {
+            lease.expires > now && lease.left >= amount && lease.stored(now).count.saturating_add(amount) <= limit
+        })?;
+        lease.left -= amount;
+        Some(lease.stored(now))
+    }
+
+    /// Leases `units` of `key`'s budget from the store, spending `amount` of
+    /// them right away
+    async fn lease(
+        &self,
+        key: &str,
+        units: u32,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> Result<Admission, RateLimitError> {
+        let stored = match self.store.increment_within(key, units, limit, window).await? {
+            Admission::Admitted(stored) => stored,
+            refused => return Ok(refused),
+        };
+        let now = Instant::now();
+        let mut leases = self.leases();
+        leases.retain(|_, lease| lease.expires > now);
+        // A concurrent miss may have leased too; keep what it left unspent
+        let lease = leases
+            .entry(key.to_string())
+            .and_modify(|lease| {
+                lease.left += units - amount;
+                lease.count = lease.count.max(stored.count);
+                lease.expires = now + stored.ttl;
+            })
+            .or_insert(Lease {
+                left: units - amount,
+                count: stored.count,
+                expires: now + stored.ttl,
+            });
+        Ok(Admission::Admitted(lease.stored(now)))
+    }
+}
+
+impl<S: RateLimitStore> RateLimitStore for LeasingStore<S> {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            let stored = self.store.get(key).await?;
+            let now = Instant::now();
+            let left = self.leases().get(key).filter(|lease| lease.expires > now).map_or(0, |lease| lease.left);
+            Ok(stored.map(|stored| StoredCount {
+                count: stored.count.saturating_sub(left),
+                ..stored
+            }))
+        })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+        self.store.increment(key, amount, window)
+    }
+
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+        Box::pin(async move {
+            if let Some(stored) = self.spend(key, amount, limit, Instant::now()) {
+                return Ok(Admission::Admitted(stored));
+            }
+            match self.lease(key, self.batch.max(amount), amount, limit, window).await? {
+                Admission::Refused(stored) if limit.saturating_sub(stored.count) >= amount => {
+                    // Less than a batch is left; lease all of it
+                    self.lease(key, limit - stored.count, amount, limit, window).await
+                }
+                admission => Ok(admission),
+            }
+        })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+        if ttl.is_zero() {
+            self.leases().remove(key);
+        }
+        self.store.expire(key, ttl)
+    }
+
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            let stored = self.store.decrement(key, amount).await?;
+            let now = Instant::now();
+            let mut leases = self.leases();
+            let left = match leases.get_mut(key).filter(|lease| lease.expires > now) {
+                Some(lease) => {
+                    lease.count = lease.count.saturating_sub(amount);
+                    lease.left
+                }
+                None => 0,
+            };
+            Ok(stored.map(|stored| StoredCount {
+                count: stored.count.saturating_sub(left),
+                ..stored
+            }))
+        })
+    }
+
+    fn now(&self) -> BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+        self.store.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{MemoryStore, RateLimitConfig, RateLimiter};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A memory store that counts the calls made to it
+    #[derive(Clone, Debug, Default)]
+    struct Counting {
+        store: MemoryStore,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl RateLimitStore for Counting {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.store.get(key)
+        }
+
+        fn increment<'a>(
+            &'a self,
+            key: &'a str,
+            amount: u32,
+            window: Duration,
+        ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.store.increment(key, amount, window)
+        }
+
+        fn increment_within<'a>(
+            &'a self,
+            key: &'a str,
+            amount: u32,
+            limit: u32,
+            window: Duration,
+        ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.store.increment_within(key, amount, limit, window)
+        }
+
+        fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.store.expire(key, ttl)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_budget_is_leased_in_batches() {
+        let store = Counting::default();
+        let config = RateLimitConfig::max_per_window(25, 60);
+        let limiter = RateLimiter::new(config).with_store(LeasingStore::new(store.clone(), 10)).unwrap();
+
+        for used in 1..=25 {
+            let info = limiter.check_rate_limit("a").await.unwrap();
+            assert_eq!(info.remaining, 25 - used);
+        }
+        // Two batches of ten, a refused third, and the last five
+        assert_eq!(store.calls.load(Ordering::SeqCst), 4);
+        assert!(limiter.check_rate_limit("a").await.is_err());
+        assert_eq!(store.store.get("a").await.unwrap().unwrap().count, 25);
+    }
+
+    #[tokio::test]
+    async fn test_replicas_leasing_together_stay_within_the_limit() {
+        let store = MemoryStore::new();
+        let config = RateLimitConfig::max_per_window(25, 60);
+        let a = RateLimiter::new(config.clone()).with_store(LeasingStore::new(store.clone(), 10)).unwrap();
+        let b = RateLimiter::new(config).with_store(LeasingStore::new(store.clone(), 10)).unwrap();
+
+        let mut admitted = 0;
+        for _ in 0..30 {
+            admitted += usize::from(a.check_rate_limit("k").await.is_ok());
+            admitted += usize::from(b.check_rate_limit("k").await.is_ok());
+        }
+        assert_eq!(admitted, 25);
+    }
+
+    #[tokio::test]
+    async fn test_racing_leases_are_merged_and_spent_within_the_limit() {
+        let store = Counting::default();
+        let leasing = LeasingStore::new(store.clone(), 10);
+        let window = Duration::from_secs(60);
+        let admitted = |admission: Admission| matches!(admission, Admission::Admitted(_));
+
+        // Two misses for the same key, each leasing a batch
+        leasing.lease("a", 10, 1, 100, window).await.unwrap();
+        leasing.lease("a", 10, 1, 100, window).await.unwrap();
+        for _ in 0..18 {
+            assert!(admitted(leasing.increment_within("a", 1, 100, window).await.unwrap()));
+        }
+        assert_eq!(store.calls.load(Ordering::SeqCst), 2);
+
+        // A lowered limit holds for units already leased
+        assert!(admitted(leasing.increment_within("b", 1, 100, window).await.unwrap()));
+        assert!(admitted(leasing.increment_within("b", 1, 2, window).await.unwrap()));
+        assert!(!admitted(leasing.increment_within("b", 1, 2, window).await.unwrap()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_leases_end_with_the_window() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60))
+            .with_store(LeasingStore::new(MemoryStore::new(), 10))
+            .unwrap();
+        for _ in 0..5 {
+            limiter.check_rate_limit("a").await.unwrap();
+        }
+        assert!(limiter.check_rate_limit("a").await.is_err());
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 4);
+    }
+}
@@ -0,0 +1,2795 @@
+//! The in-memory fixed and sliding window limiter
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use http::{HeaderMap, StatusCode};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::Duration;
+
+use super::runtime::{self, default_runtime, Instant};
+use super::sync::{Mutex, MutexGuard};
+use super::{
+    seconds_until, wall_clock_at, wall_clock_now, Admission, ConfigOverrides, EventHook, KeyCount, KeyMetadata, PeerCounts,
+    PreflightPolicy, PressureTracker, QuotaResolver, Quotas, RateLimitAlgorithm, RateLimitConfig, RateLimitError,
+    RateLimitErrorCode, RateLimitEvent, RateLimitInfo, RateLimitRejection, RateLimitStore, Reputation, ResetMode,
+    RetryAfterFormat, Rollout, RolloutStats, Runtime, StoreClock, StoreFailurePolicy, StoredCount, TrustedProxies,
+    UsageLedger, UsageRollups,
+};
+
+/// Keys [`UsageIter`] reads per lock
+const USAGE_BATCH: usize = 256;
+
+/// How long clients turned away for an unreachable store are told to wait
+const STORE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// How far a store's clock may be from this one's before aligned windows
+/// in the store are corrected for it
+const CLOCK_SKEW_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// How many keys may have metadata attached at once, by default
+const MAX_METADATA_KEYS: usize = 100_000;
+
+/// A key's current window
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    start: Instant,
+    /// Length of the window, from the config that opened it
+    length: Duration,
+    count: u32,
+    /// Requests counted in the window before this one, weighed in under
+    /// [`RateLimitAlgorithm::SlidingWindow`]
+    previous: u32,
+    /// Budget carried over from earlier windows, on top of `max_requests`
+    carried: u32,
+    /// Whether the soft limit event already fired this window
+    warned: bool,
+    /// Burst credits earned while idle and not yet spent. Unlike `carried`,
+    /// they outlive the window.
+    credits: u32,
+    /// When the key's last request was let through
+    last_seen: Instant,
+    /// Units admitted past the limit this window under the overage allowance
+    overage: u32,
+    /// Units borrowed from the next window
+    borrowed: u32,
+    /// Units this window owes for borrowing in the one before, taken off
+    /// its limit
+    debt: u32,
+    /// When the key last made a request, admitted or not
+    touched: Instant,
+    /// How long the key may go without a request before it is forgotten,
+    /// from the config that last checked it
+    idle_ttl: Option<Duration>,
+}
+
+impl Window {
+    fn new(start: Instant, length: Duration) -> Self {
+        Self {
+            start,
+            length,
+            count: 0,
+            previous: 0,
+            carried: 0,
+            warned: false,
+            credits: 0,
+            last_seen: start,
+            overage: 0,
+            borrowed: 0,
+            debt: 0,
+            touched: start,
+            idle_ttl: None,
+        }
+    }
+
+    /// A fresh window containing `now`: starting `now` for rolling windows,
+    /// or at the boundary before `now` for aligned ones
+    fn open(config: &RateLimitConfig, now: Instant) -> Self {
+        if config.algorithm == RateLimitAlgorithm::TokenBucket {
+            return Self {
+                idle_ttl: config.idle_ttl,
+                ..Self::new(now, config.window)
+            };
+        }
+        let wall_now = wall_clock_at(now);
+        let Some((start, end)) = config.reset_mode.bounds(config.window, wall_now) else {
+            return Self {
+                idle_ttl: config.idle_ttl,
+                ..Self::new(now, config.window)
+            };
+        };
+        let into = (wall_now - start).to_std().unwrap_or_default();
+        let length = (end - start).to_std().unwrap_or(config.window);
+        Self {
+            last_seen: now,
+            touched: now,
+            idle_ttl: config.idle_ttl,
+            ..Self::new(now.checked_sub(into).unwrap_or(now), length)
+        }
+    }
+
+    /// The window in force at `now`: this one if it hasn't ended, otherwise a
+    /// fresh one with any carry-over and burst credits applied
+    fn current(self, config: &RateLimitConfig, now: Instant) -> Self {
+        if config.algorithm == RateLimitAlgorithm::TokenBucket {
+            return self.refilled(config, now);
+        }
+        let elapsed = now.duration_since(self.start);
+        // Rolling windows follow config reloads; aligned ones keep their bounds
+        let length = match config.reset_mode {
+            ResetMode::Rolling => config.window,
+            _ => self.length,
+        };
+        if elapsed < length || (config.reset_mode == ResetMode::Rolling && elapsed == length) {
+            return Self {
+                length,
+                idle_ttl: config.idle_ttl,
+                ..self
+            };
+        }
+
+        let carried = config.carry_over.map_or(0, |carry_over| {
+            // Carry from the window that ended, then from any skipped idle windows
+            let skipped = (elapsed.as_nanos() / config.window.as_nanos().max(1)).min(32) as u32;
+            let mut carried = carry_over.carry(config.max_requests.saturating_add(self.carried).saturating_sub(self.count));
+            for _ in 1..skipped {
+                carried = carry_over.carry(config.max_requests.saturating_add(carried));
+            }
+            carried
+        });
+        let credits = config.burst_credits.map_or(0, |cap| {
+            // Idle time past the first window accrues at the steady rate;
+            // the first window is already covered by the reset itself
+            let idle = now.duration_since(self.last_seen).saturating_sub(config.window);
+            let earned = idle.as_nanos() * u128::from(config.max_requests) / config.window.as_nanos().max(1);
+            u128::from(self.credits).saturating_add(earned).min(u128::from(cap)) as u32
+        });
+        // Only the window right after the borrowing pays it back
+        let debt = match config.borrowing {
+            Some(borrowing) if elapsed < length * 2 => borrowing.repayment(self.borrowed),
+            _ => 0,
+        };
+        let mut next = Self {
+            carried,
+            credits,
+            debt,
+            last_seen: self.last_seen,
+            touched: self.touched,
+            ..Self::open(config, now)
+        };
+        if config.algorithm == RateLimitAlgorithm::SlidingWindow {
+            // Sliding windows follow on from each other, so the previous
+            // window's share decays over exactly the next one
+            if config.reset_mode == ResetMode::Rolling {
+                let into = elapsed.as_nanos() % length.as_nanos().max(1);
+                next.start = now.checked_sub(Duration::from_nanos(into as u64)).unwrap_or(now);
+            }
+            next.previous = if elapsed < length * 2 { self.count } else { 0 };
+        }
+        next
+    }
+
+    /// The bucket at `now`, with the tokens refilled since it was last
+    /// checked. `count` holds the tokens spent, and `start` when the next
+    /// one began refilling.
+    fn refilled(self, config: &RateLimitConfig, now: Instant) -> Self {
+        let interval = Self::refill_interval(config);
+        let refilled = now.duration_since(self.start).as_nanos() / interval.as_nanos().max(1);
+        if refilled >= u128::from(self.count) {
+            return Self {
+                start: now,
+                length: config.window,
+                count: 0,
+                warned: false,
+                idle_ttl: config.idle_ttl,
+                ..self
+            };
+        }
+        Self {
+            start: self.start + interval * refilled as u32,
+            length: config.window,
+            count: self.count - refilled as u32,
+            idle_ttl: config.idle_ttl,
+            ..self
+        }
+    }
+
+    /// How often a token bucket gains a token
+    fn refill_interval(config: &RateLimitConfig) -> Duration {
+        config.window / config.max_requests.max(1)
+    }
+
+    /// The fixed window of `length` a store's count for a key stands for
+    /// at `now`
+    fn stored(length: Duration, stored: StoredCount, now: Instant) -> Self {
+        let into = length.saturating_sub(stored.ttl);
+        Self {
+            count: stored.count,
+            ..Self::new(now.checked_sub(into).unwrap_or(now), length)
+        }
+    }
+
+    /// Requests counted against the limit at `now`: this window's, plus
+    /// under a sliding window the previous window's share still in reach
+    fn used(&self, config: &RateLimitConfig, now: Instant) -> u32 {
+        self.count.saturating_add(self.previous_share(config, now))
+    }
+
+    /// The previous window's requests, weighted by how much of the window
+    /// before `now` they overlap
+    fn previous_share(&self, config: &RateLimitConfig, now: Instant) -> u32 {
+        if config.algorithm != RateLimitAlgorithm::SlidingWindow {
+            return 0;
+        }
+        let left = self.length.saturating_sub(now.duration_since(self.start));
+        (u128::from(self.previous) * left.as_nanos() / self.length.as_nanos().max(1)) as u32
+    }
+
+    /// How long a rejected request costing `cost` should wait: until the
+    /// window ends, or under a sliding window until the previous window's
+    /// share has decayed enough to make room, whichever comes first
+    fn retry_after(&self, config: &RateLimitConfig, limit: u32, current: u32, cost: u32, now: Instant) -> Duration {
+        if config.algorithm == RateLimitAlgorithm::TokenBucket {
+            // Until enough tokens have refilled to cover the request
+            let short = current.saturating_add(cost).saturating_sub(limit).max(1);
+            let wait = Self::refill_interval(config).checked_mul(short).unwrap_or(Duration::MAX);
+            return wait.saturating_sub(now.duration_since(self.start));
+        }
+        let left = self.length.saturating_sub(now.duration_since(self.start));
+        let room = limit.saturating_sub(current.saturating_add(cost));
+        if self.previous_share(config, now) == 0 || room == 0 {
+            return left;
+        }
+        let fits = u128::from(room) * self.length.as_nanos() / u128::from(self.previous);
+        left.saturating_sub(Duration::from_nanos(fits.min(u128::from(u64::MAX)) as u64))
+    }
+
+    fn limit(&self, config: &RateLimitConfig) -> u32 {
+        config.max_requests.saturating_add(self.carried).saturating_sub(self.debt)
+    }
+
+    /// Whether both this window and the one after it have ended, so nothing
+    /// of it is left to count against the key
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.start) >= self.length.saturating_mul(2)
+    }
+
+    /// Whether the key has gone unseen for longer than its idle TTL
+    fn is_idle(&self, now: Instant) -> bool {
+        self.idle_ttl.is_some_and(|ttl| now.duration_since(self.touched) >= ttl)
+    }
+}
+
+/// Tracks request counts per key. Cloning a `RateLimiter` is cheap and the
+/// clones share their counters.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    state: Arc<Mutex<HashMap<String, Window>>>,
+    /// The config shared with every endpoint and scoped view
+    config: Arc<StdRwLock<RateLimitConfig>>,
+    /// How a scoped view's config differs from the shared one
+    scope: Option<Arc<Scope>>,
+    peers: Option<PeerCounts>,
+    events: Option<EventHook>,
+    usage: Option<UsageLedger>,
+    rollups: Option<UsageRollups>,
+    reputation: Option<Reputation>,
+    endpoints: Arc<HashMap<String, EndpointConfig>>,
+    /// When idle keys were last swept from `state`
+    swept: Arc<Mutex<Instant>>,
+    emergency: Arc<StdRwLock<Emergency>>,
+    pressure: Arc<PressureTracker>,
+    /// A candidate config being rolled out to some keys. Not shared with
+    /// scoped views, which enforce configs of their own.
+    rollout: Arc<StdRwLock<Option<Arc<Rollout>>>>,
+    /// What tarpitted rejections sleep on
+    runtime: Option<Arc<dyn Runtime>>,
+    /// Metadata attached to keys, so every view of the limiter reports the
+    /// same metadata for a client
+    metadata: Arc<StdRwLock<AttachedMetadata>>,
+    /// Where counts are kept when not in `state`
+    store: Option<Arc<dyn RateLimitStore>>,
+    /// What to do with requests while `store` fails
+    store_failure: StoreFailurePolicy,
+    /// The store's clock, which aligned windows in the store go by
+    store_clock: Arc<StoreClock>,
+    /// Keys forgotten to stay under `max_tracked_keys`
+    evicted: Arc<AtomicU64>,
+    /// Per-key configs. Not shared with scoped views, which enforce configs
+    /// of their own.
+    quotas: Option<Quotas>,
+    /// The namespace of every scoped view made of this limiter, so a key's
+    /// windows can be found under each
+    namespaces: Arc<StdRwLock<HashSet<String>>>,
+    /// Where the IP lists find the client behind a proxy
+    proxies: Option<Arc<TrustedProxies>>,
+    /// The view counting preflights under `PreflightPolicy::Budget`. Not
+    /// shared with scoped views, which make their own.
+    preflights: Arc<StdRwLock<Option<Preflights>>>,
+}
+
+/// A request admitted against its window
+#[derive(Debug)]
+struct Counted {
+    /// The window as the request left it
+    window: Window,
+    limit: u32,
+    used: u32,
+    /// Units admitted past the limit under the overage allowance
+    overage: u32,
+    soft_limit_reached: bool,
+    /// Whether this request crossed the soft limit
+    newly_warned: bool,
+}
+
+/// A request turned away by its window
+#[derive(Debug)]
+struct Refused {
+    limit: u32,
+    /// The length of the window that refused it
+    window: Duration,
+    /// How long until that window resets
+    retry_after: Duration,
+    /// Why it was refused
+    code: RateLimitErrorCode,
+}
+
+/// The config registered for an endpoint
+#[derive(Clone, Debug)]
+enum EndpointConfig {
+    /// Enforced as is
+    Fixed(RateLimitConfig),
+    /// Applied on top of the limiter's config as it stands
+    Layered(ConfigOverrides),
+}
+
+impl EndpointConfig {
+    fn resolve(&self, base: &RateLimitConfig) -> RateLimitConfig {
+        match self {
+            EndpointConfig::Fixed(config) => config.clone(),
+            EndpointConfig::Layered(overrides) => overrides.apply(base),
+        }
+    }
+}
+
+/// The view a limiter counts preflights in, with the budget and namespace
+/// it was made for
+#[derive(Clone, Debug)]
+struct Preflights {
+    max_requests: u32,
+    namespace: Option<String>,
+    view: RateLimiter,
+}
+
+/// How a scoped view's config is made from the config it shares with the
+/// limiter it was made of: the fields the view changed are layered on top,
+/// and the rest follow the shared config as it is swapped
+#[derive(Debug)]
+struct Scope {
+    /// The scope of the view this one was made of, if it was made of one
+    parent: Option<Arc<Scope>>,
+    /// The parent's config when the view was made
+    base: RateLimitConfig,
+    /// The view's config when it was made
+    config: RateLimitConfig,
+}
+
+impl Scope {
+    /// The view's config, with the shared config now `shared`
+    fn resolve(&self, shared: &RateLimitConfig) -> RateLimitConfig {
+        match &self.parent {
+            Some(parent) => self.config.rebased(&self.base, &parent.resolve(shared)),
+            None => self.config.rebased(&self.base, shared),
+        }
+    }
+}
+
+/// Reconfigures a running limiter and every filter built from it, e.g. from
+/// an admin route during an incident, without handing out the limiter
+/// itself. Its endpoints and scoped views follow the new config in every
+/// field they don't set differently. Cheap to clone.
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100));
+/// let handle = limiter.handle();
+/// let api = warp::path("api").and(limiter.filter()).map(handler);
+/// // Later, from anywhere:
+/// handle.update_config(RateLimitConfig::max_per_minute(10));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimiterHandle {
+    config: Arc<StdRwLock<RateLimitConfig>>,
+}
+
+impl RateLimiterHandle {
+    /// Swaps in `config` for every request from now on. Requests already
+    /// being counted finish under the config they started with, and
+    /// current counters are kept.
+    pub fn update_config(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+
+    /// The configuration in force
+    pub fn config(&self) -> RateLimitConfig {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Metadata attached to keys, by key without namespace
+#[derive(Debug)]
+struct AttachedMetadata {
+    keys: HashMap<String, KeyMetadata>,
+    /// How many keys may have metadata at once
+    max_keys: usize,
+}
+
+impl AttachedMetadata {
+    /// Detaches the metadata of clients whose windows were just `removed`
+    /// from `state`, unless a window of theirs is left under another
+    /// namespace
+    fn forget(&mut self, state: &HashMap<String, Window>, removed: &[String]) {
+        if self.keys.is_empty() {
+            return;
+        }
+        let gone: HashSet<&str> =
+            removed.iter().flat_map(|key| clients_of(key)).filter(|client| self.keys.contains_key(*client)).collect();
+        if gone.is_empty() {
+            return;
+        }
+        let held: HashSet<&str> = state.keys().flat_map(|key| clients_of(key)).collect();
+        self.keys.retain(|client, _| !gone.contains(client.as_str()) || held.contains(client.as_str()));
+    }
+}
+
+/// The clients a stored key may belong to: the key itself and, for a key
+/// under a namespace, what follows each `:`
+fn clients_of(key: &str) -> impl Iterator<Item = &str> {
+    std::iter::once(key).chain(key.match_indices(':').map(move |(i, _)| &key[i + 1..]))
+}
+
+/// Drops the windows in `state` that `drop` matches, returning their keys
+fn drop_windows(state: &mut HashMap<String, Window>, drop: impl Fn(&Window) -> bool) -> Vec<String> {
+    let removed: Vec<String> = state.iter().filter(|(_, window)| drop(window)).map(|(key, _)| key.clone()).collect();
+    for key in &removed {
+        state.remove(key);
+    }
+    removed
+}
+
+/// The incident-time overrides set with [`RateLimiter::set_emergency`] and
+/// [`RateLimiter::set_maintenance`]
+#[derive(Debug, Default)]
+struct Emergency {
+    /// Enforces the emergency config, with counters of its own
+    limiter: Option<RateLimiter>,
+    /// When the maintenance window ends
+    maintenance: Option<DateTime<Utc>>,
+    /// Keys neither override applies to
+    allowlist: HashSet<String>,
+}
+
+impl RateLimiter {
+    /// Build a limiter that enforces `config`
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(StdRwLock::new(config)),
+            scope: None,
+            peers: None,
+            events: None,
+            usage: None,
+            rollups: None,
+            reputation: None,
+            endpoints: Arc::new(HashMap::new()),
+            swept: Arc::new(Mutex::new(Instant::now())),
+            emergency: Arc::new(StdRwLock::new(Emergency::default())),
+            pressure: Arc::new(PressureTracker::new()),
+            rollout: Arc::new(StdRwLock::new(None)),
+            runtime: default_runtime(),
+            metadata: Arc::new(StdRwLock::new(AttachedMetadata {
+                keys: HashMap::new(),
+                max_keys: MAX_METADATA_KEYS,
+            })),
+            store: None,
+            store_failure: StoreFailurePolicy::default(),
+            store_clock: Arc::new(StoreClock::new(CLOCK_SKEW_TOLERANCE)),
+            evicted: Arc::new(AtomicU64::new(0)),
+            quotas: None,
+            namespaces: Arc::new(StdRwLock::new(HashSet::new())),
+            proxies: None,
+            preflights: Arc::new(StdRwLock::new(None)),
+        }
+    }
+
+    /// A limiter sharing this one's counters but enforcing `config`, e.g.
+    /// with a different namespace. Peer counts are shared too, and so is
+    /// the config: when it is swapped (by `set_config` or a
+    /// [`RateLimiterHandle`]), the view follows in every field where
+    /// `config` matched this limiter's.
+    pub fn scoped(&self, config: RateLimitConfig) -> Self {
+        if let Some(namespace) = &config.namespace {
+            self.namespaces.write().unwrap_or_else(|e| e.into_inner()).insert(namespace.clone());
+        }
+        let scope = Scope {
+            parent: self.scope.clone(),
+            base: self.config(),
+            config,
+        };
+        Self {
+            state: self.state.clone(),
+            config: self.config.clone(),
+            scope: Some(Arc::new(scope)),
+            peers: self.peers.clone(),
+            events: self.events.clone(),
+            usage: self.usage.clone(),
+            rollups: self.rollups.clone(),
+            reputation: self.reputation.clone(),
+            endpoints: self.endpoints.clone(),
+            swept: self.swept.clone(),
+            emergency: self.emergency.clone(),
+            pressure: self.pressure.clone(),
+            rollout: Arc::new(StdRwLock::new(None)),
+            runtime: self.runtime.clone(),
+            metadata: self.metadata.clone(),
+            store: self.store.clone(),
+            store_failure: self.store_failure,
+            store_clock: self.store_clock.clone(),
+            evicted: self.evicted.clone(),
+            quotas: None,
+            namespaces: self.namespaces.clone(),
+            proxies: self.proxies.clone(),
+            preflights: Arc::new(StdRwLock::new(None)),
+        }
+    }
+
+    /// Give requests attached under `label` their own config, e.g. 10/min
+    /// for `"search"` and 600/min for `"status"`, while sharing this
+    /// limiter's store, peers, and event hook
+    pub fn with_endpoint(mut self, label: impl Into<String>, config: RateLimitConfig) -> Self {
+        Arc::make_mut(&mut self.endpoints).insert(label.into(), EndpointConfig::Fixed(config));
+        self
+    }
+
+    /// Give requests attached under `label` this limiter's config with
+    /// `overrides` applied. The endpoint follows later changes to the
+    /// limiter's config (e.g. from `set_config`) in every field it doesn't
+    /// override.
+    pub fn with_endpoint_overrides(mut self, label: impl Into<String>, overrides: ConfigOverrides) -> Self {
+        Arc::make_mut(&mut self.endpoints).insert(label.into(), EndpointConfig::Layered(overrides));
+        self
+    }
+
+    /// A view of this limiter for the endpoint `label`: it enforces the
+    /// endpoint's config (or this limiter's, for labels without one) and
+    /// keeps its keys apart from other endpoints'
+    pub fn endpoint(&self, label: &str) -> Self {
+        let base = self.config();
+        let config = self.endpoints.get(label).map_or_else(|| base.clone(), |endpoint| endpoint.resolve(&base));
+        let namespace = Self::child_namespace(&base, label);
+        self.scoped(config.with_namespace(namespace))
+    }
+
+    /// Every policy this limiter enforces, as `{"default": ..., "endpoints":
+    /// {"search": ..., ...}}` with each policy described by
+    /// [`RateLimitConfig::to_policy_json`]
+    pub fn policy_document(&self) -> serde_json::Value {
+        let base = self.config();
+        let endpoints: serde_json::Map<_, _> = self
+            .endpoints
+            .iter()
+            .map(|(label, endpoint)| (label.clone(), endpoint.resolve(&base).to_policy_json()))
+            .collect();
+        serde_json::json!({
+            "default": base.to_policy_json(),
+            "endpoints": endpoints,
+        })
+    }
+
+    /// Add counts reported by other instances to this limiter's own when
+    /// checking requests, approximating a limit shared across the cluster
+    pub fn with_peer_counts(mut self, peers: PeerCounts) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Record the units admitted for each key in `ledger`, for usage export
+    pub fn with_usage_ledger(mut self, ledger: UsageLedger) -> Self {
+        self.usage = Some(ledger);
+        self
+    }
+
+    /// Keep hourly and daily totals of the units admitted for each key in
+    /// `rollups`, to query later
+    pub fn with_usage_rollups(mut self, rollups: UsageRollups) -> Self {
+        self.rollups = Some(rollups);
+        self
+    }
+
+    /// Scale each key's limit by its standing in `reputation`, charging it
+    /// for every rejection. Report response statuses with
+    /// [`RateLimiter::record_status`] to charge client errors too.
+    pub fn with_reputation(mut self, reputation: Reputation) -> Self {
+        self.reputation = Some(reputation);
+        self
+    }
+
+    /// Sleep on `runtime` rather than tokio, e.g. to tarpit rejections under
+    /// async-std or smol. Without the `tokio` feature, a limiter needs this
+    /// for `tarpit` to have any effect.
+    pub fn with_runtime(mut self, runtime: impl Runtime) -> Self {
+        self.runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    /// Keep counts in `store` rather than in memory, e.g. a `RedisStore`
+    /// shared by every replica so they enforce one quota between them.
+    /// Stored counts use plain fixed windows, so this fails for a config
+    /// (the limiter's own or an endpoint's registered so far) using another
+    /// algorithm, carry-over, burst credits, borrowing, overage, or early
+    /// rejection, or for a limiter with peer counts. Aligned reset modes go
+    /// by the store's clock (see
+    /// [`with_clock_skew_tolerance`](RateLimiter::with_clock_skew_tolerance)). Requests under a config that
+    /// can't be stored, e.g. one swapped in later, are counted in memory.
+    /// While the store is unreachable, the
+    /// [`with_store_failure_policy`](RateLimiter::with_store_failure_policy)
+    /// decides requests.
+    pub fn with_store(mut self, store: impl RateLimitStore) -> Result<Self, RateLimitError> {
+        if self.peers.is_some() {
+            return Err(RateLimitError::Other("stored counts don't support peer counts".into()));
+        }
+        let base = self.config();
+        Self::storable(&base)?;
+        for (label, endpoint) in self.endpoints.iter() {
+            Self::storable(&endpoint.resolve(&base))
+                .map_err(|e| RateLimitError::Other(format!("endpoint {}: {}", label, e).into()))?;
+        }
+        self.store = Some(Arc::new(store));
+        Ok(self)
+    }
+
+    /// Fails for configs a store's plain fixed windows can't enforce
+    fn storable(config: &RateLimitConfig) -> Result<(), RateLimitError> {
+        let unsupported = if config.algorithm != RateLimitAlgorithm::FixedWindow {
+            "algorithms other than fixed-window"
+        } else if config.carry_over.is_some() {
+            "carry-over"
+        } else if config.burst_credits.is_some() {
+            "burst credits"
+        } else if config.borrowing.is_some() {
+            "borrowing"
+        } else if config.overage.is_some() {
+            "overage"
+        } else if config.early_rejection.is_some() {
+            "early rejection"
+        } else {
+            return Ok(());
+        };
+        Err(RateLimitError::Other(format!("stored counts don't support {}", unsupported).into()))
+    }
+
+    /// What to do with requests while the store fails or times out: count
+    /// them in memory (the default), admit them, or reject them with a `503`
+    pub fn with_store_failure_policy(mut self, policy: StoreFailurePolicy) -> Self {
+        self.store_failure = policy;
+        self
+    }
+
+    /// Under an aligned reset mode, windows in the store start and end by
+    /// the store's clock (its [`now`](RateLimitStore::now), read once a
+    /// minute), so replicas whose clocks disagree still reset keys together.
+    /// A store clock within `tolerance` (100ms by default) of this one's is
+    /// taken to agree with it.
+    pub fn with_clock_skew_tolerance(mut self, tolerance: Duration) -> Self {
+        self.store_clock = Arc::new(StoreClock::new(tolerance));
+        self
+    }
+
+    /// Match the config's allowlist and denylist against the client address
+    /// `proxies` find in the forwarding header, rather than the peer's
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.proxies = Some(Arc::new(proxies));
+        self
+    }
+
+    /// Whether [`screen`](Self::screen) reads forwarding headers, so
+    /// adapters can leave them unread otherwise
+    #[cfg(feature = "warp")]
+    pub(crate) fn trusts_proxies(&self) -> bool {
+        self.proxies.is_some()
+    }
+
+    /// Answers a request from `peer` whose client address is on the
+    /// config's allowlist (uncounted, with `key`'s status) or denylist (a
+    /// bare `banned` rejection), or `None` to count it as usual. The lists
+    /// match the peer's address, or the forwarded one under
+    /// [`with_trusted_proxies`](Self::with_trusted_proxies), so they work
+    /// whatever the key is. The adapters call this before counting; do the
+    /// same before `check_rate_limit` when calling the limiter directly.
+    pub async fn screen(
+        &self,
+        key: &str,
+        peer: Option<IpAddr>,
+        headers: &HeaderMap,
+    ) -> Option<Result<RateLimitInfo, RateLimitRejection>> {
+        let config = self.config_for(key);
+        if config.allowlist.is_empty() && config.denylist.is_empty() {
+            return None;
+        }
+        let Some(peer) = peer else {
+            tracing::debug!("no peer address to match the IP lists against; counting {} as usual", key);
+            return None;
+        };
+        let client = match &self.proxies {
+            Some(proxies) => proxies.client_ip(peer, headers),
+            None => peer,
+        };
+        if config.is_denied(client) {
+            return Some(Err(RateLimitRejection::new(config.window, config.max_requests)
+                .with_code(RateLimitErrorCode::Banned)
+                .with_status(config.deny_status)));
+        }
+        if config.is_allowed(client) {
+            return Some(Ok(self.peek(key).await));
+        }
+        None
+    }
+
+    /// Hold each key to the config `resolver` picks for it, e.g. by plan,
+    /// and the rest to this limiter's own. Resolved configs take the
+    /// limiter's namespace, so a key keeps its counter when its plan
+    /// changes, and their limits are what the headers report.
+    pub fn with_quota_resolver(mut self, resolver: impl QuotaResolver) -> Self {
+        self.quotas = Some(Quotas::new(resolver));
+        self
+    }
+
+    /// Spawns a task on the limiter's runtime that runs
+    /// [`purge_expired`](RateLimiter::purge_expired) every `interval`, so
+    /// keys that stopped sending requests, and reputation scores that
+    /// decayed away, don't stay in memory. The task stops once the limiter
+    /// and its clones are dropped. Call this after `with_runtime` and
+    /// `with_reputation`.
+    ///
+    /// Fails if the limiter has no runtime, or if its runtime can't spawn
+    /// from here: under tokio, call this from within the tokio runtime (e.g.
+    /// in `#[tokio::main]`), not while building the limiter beforehand.
+    pub fn with_cleanup(self, interval: Duration) -> Result<Self, RateLimitError> {
+        let Some(runtime) = self.runtime.clone() else {
+            return Err(RateLimitError::Other("no runtime to run rate limit cleanup on; call purge_expired instead".into()));
+        };
+        if !runtime.can_spawn() {
+            return Err(RateLimitError::Other(
+                "the limiter's runtime can't spawn the cleanup task from here; call with_cleanup from within it".into(),
+            ));
+        }
+        let state = Arc::downgrade(&self.state);
+        let metadata = Arc::downgrade(&self.metadata);
+        let reputation = self.reputation.clone();
+        let sleeper = runtime.clone();
+        runtime.spawn(Box::pin(async move {
+            loop {
+                sleeper.sleep(interval).await;
+                let (Some(state), Some(metadata)) = (state.upgrade(), metadata.upgrade()) else { break };
+                if let Some(reputation) = &reputation {
+                    reputation.purge();
+                }
+                purge_expired(&mut state.lock().unwrap_or_else(|e| e.into_inner()), &metadata, Instant::now());
+            }
+        }));
+        Ok(self)
+    }
+
+    /// Feeds the status of the response to a request from `key` into the
+    /// limiter's reputation, if it has one
+    pub fn record_status(&self, key: &str, status: StatusCode) {
+        if let Some(reputation) = &self.reputation {
+            reputation.record_status(key, status);
+        }
+    }
+
+    /// Report [`RateLimitEvent`]s to `callback`
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&RateLimitEvent) + Send + Sync + 'static,
+    {
+        self.events = Some(EventHook::new(callback));
+        self
+    }
+
+    /// A snapshot of the configuration this limiter currently enforces
+    pub fn config(&self) -> RateLimitConfig {
+        let shared = self.config.read().unwrap_or_else(|e| e.into_inner());
+        match &self.scope {
+            Some(scope) => scope.resolve(&shared),
+            None => shared.clone(),
+        }
+    }
+
+    /// Swaps in a new configuration for this limiter and all its clones,
+    /// which its endpoints and scoped views follow too. Current counters
+    /// are kept, so clients don't get a fresh budget just because the
+    /// policy was reloaded. On a view, this swaps the config it shares with
+    /// the limiter it was made of.
+    pub fn set_config(&self, config: RateLimitConfig) {
+        self.handle().update_config(config);
+    }
+
+    /// A handle for reconfiguring this limiter and its clones while they
+    /// serve requests
+    pub fn handle(&self) -> RateLimiterHandle {
+        RateLimiterHandle {
+            config: self.config.clone(),
+        }
+    }
+
+    /// Canaries `config` on `percent` of keys, chosen by a stable hash of
+    /// the key, while the rest stay under the limiter's own config. Keys
+    /// keep their counters as they move between arms (the candidate takes
+    /// the limiter's namespace). Admitted and rejected requests are counted
+    /// per arm from now on, see [`RateLimiter::rollout_stats`].
+    pub fn set_candidate(&self, mut config: RateLimitConfig, percent: u8) {
+        config.namespace = self.config().namespace;
+        *self.rollout.write().unwrap_or_else(|e| e.into_inner()) = Some(Rollout::new(config, percent));
+    }
+
+    /// Ends the rollout, returning every key to the limiter's own config
+    pub fn clear_candidate(&self) {
+        *self.rollout.write().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// How the control and candidate arms have fared since the candidate
+    /// was set, or `None` without one
+    pub fn rollout_stats(&self) -> Option<RolloutStats> {
+        self.rollout.read().unwrap_or_else(|e| e.into_inner()).as_ref().map(|rollout| rollout.stats())
+    }
+
+    /// The config `key` is held to: the resolver's, if it has one for the
+    /// key, else the candidate's, if a rollout selects it
+    fn config_for(&self, key: &str) -> RateLimitConfig {
+        if let Some(mut config) = self.quotas.as_ref().and_then(|quotas| quotas.resolve(key)) {
+            config.namespace = self.config().namespace;
+            return config;
+        }
+        match &*self.rollout.read().unwrap_or_else(|e| e.into_inner()) {
+            Some(rollout) if rollout.selects(key) => rollout.config.clone(),
+            _ => self.config(),
+        }
+    }
+
+    /// Pulls the emergency brake for this limiter, its clones, and every
+    /// endpoint and scoped view of it: until cleared with `None`, each key
+    /// outside the allowlist is also held to `config`, counted across all
+    /// routes. A config with `max_requests` of 0 locks everyone else out.
+    /// Each call starts the emergency counters afresh.
+    pub fn set_emergency(&self, config: Option<RateLimitConfig>) {
+        let mut emergency = self.emergency.write().unwrap_or_else(|e| e.into_inner());
+        emergency.limiter = config.map(RateLimiter::new);
+    }
+
+    /// The emergency config in force, if the brake is pulled
+    pub fn emergency(&self) -> Option<RateLimitConfig> {
+        let emergency = self.emergency.read().unwrap_or_else(|e| e.into_inner());
+        emergency.limiter.as_ref().map(RateLimiter::config)
+    }
+
+    /// Puts this limiter, its clones, and every endpoint and scoped view of
+    /// it into maintenance until `until`: each key outside the allowlist is
+    /// turned away with a `503 Service Unavailable` carrying the usual
+    /// headers, with Retry-After pointing at `until`. Lifts by itself once
+    /// `until` passes, or earlier with `None`.
+    pub fn set_maintenance(&self, until: Option<DateTime<Utc>>) {
+        let mut emergency = self.emergency.write().unwrap_or_else(|e| e.into_inner());
+        emergency.maintenance = until;
+    }
+
+    /// When the maintenance window in force ends, if there is one
+    pub fn maintenance(&self) -> Option<DateTime<Utc>> {
+        let emergency = self.emergency.read().unwrap_or_else(|e| e.into_inner());
+        emergency.maintenance.filter(|until| *until > wall_clock_now())
+    }
+
+    /// Keys the emergency config and maintenance mode never apply to, e.g.
+    /// health checks and operators' own tooling. Replaces any earlier
+    /// allowlist.
+    pub fn set_emergency_allowlist<I, K>(&self, keys: I)
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        let mut emergency = self.emergency.write().unwrap_or_else(|e| e.into_inner());
+        emergency.allowlist = keys.into_iter().map(Into::into).collect();
+    }
+
+    /// Attaches `metadata` to `key` for this limiter, its clones, and every
+    /// endpoint and scoped view of it, replacing any set before. It rides
+    /// along on the key's [`RateLimitInfo`], rejections,
+    /// [`RateLimitEvent`]s, [`KeyUsage`], and [`UsageSummary`](super::UsageSummary)s.
+    /// Empty metadata detaches it. Keep it small: it is copied into each of
+    /// those.
+    ///
+    /// The metadata is detached again when the key's windows are forgotten,
+    /// by [`purge_expired`](RateLimiter::purge_expired), the idle TTL, or
+    /// `max_tracked_keys`. Returns false, attaching nothing, if
+    /// [`with_max_metadata_keys`](RateLimiter::with_max_metadata_keys) keys
+    /// already have metadata.
+    pub fn set_metadata(&self, key: &str, metadata: KeyMetadata) -> bool {
+        let mut attached = self.metadata.write().unwrap_or_else(|e| e.into_inner());
+        if metadata.is_empty() {
+            attached.keys.remove(key);
+        } else if attached.keys.len() >= attached.max_keys && !attached.keys.contains_key(key) {
+            tracing::warn!("not attaching metadata to {}: {} keys already have metadata", key, attached.max_keys);
+            return false;
+        } else {
+            attached.keys.insert(key.to_string(), metadata);
+        }
+        true
+    }
+
+    /// Let up to `max` keys have metadata attached at once, rather than
+    /// 100,000. Shared with the limiter's clones and views.
+    pub fn with_max_metadata_keys(self, max: usize) -> Self {
+        self.metadata.write().unwrap_or_else(|e| e.into_inner()).max_keys = max;
+        self
+    }
+
+    /// The metadata attached to `key`, empty if there is none
+    pub fn metadata(&self, key: &str) -> KeyMetadata {
+        let attached = self.metadata.read().unwrap_or_else(|e| e.into_inner());
+        attached.keys.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Detaches the metadata of clients whose windows were just `removed`
+    fn forget_metadata(&self, state: &HashMap<String, Window>, removed: &[String]) {
+        if !removed.is_empty() {
+            self.metadata.write().unwrap_or_else(|e| e.into_inner()).forget(state, removed);
+        }
+    }
+
+    /// Counts a request against `key`, returning the updated status or a
+    /// rejection if the key has exhausted its window
+    pub async fn check_rate_limit(&self, key: &str) -> Result<RateLimitInfo, RateLimitRejection> {
+        self.check_rate_limit_with_cost(key, 1).await
+    }
+
+    /// Counts a request costing `cost` units against `key`. The request is
+    /// rejected if it would take the key past its limit.
+    pub async fn check_rate_limit_with_cost(&self, key: &str, cost: u32) -> Result<RateLimitInfo, RateLimitRejection> {
+        let checked = self.decide(key, cost).await;
+        self.pressure.record(checked.is_ok());
+        if let Some(rollout) = &*self.rollout.read().unwrap_or_else(|e| e.into_inner()) {
+            rollout.record(rollout.selects(key), checked.is_ok());
+        }
+        let metadata = self.metadata(key);
+        match checked {
+            Ok(info) => Ok(RateLimitInfo { metadata, ..info }),
+            Err(rejection) => {
+                if let Some(events) = &self.events {
+                    events.emit(&RateLimitEvent::Rejected {
+                        key: self.config_for(key).scoped_key(key).into_owned(),
+                        code: rejection.code,
+                        metadata: metadata.clone(),
+                    });
+                }
+                Err(rejection.with_metadata(metadata))
+            }
+        }
+    }
+
+    /// The share of requests this limiter (with its clones and every
+    /// endpoint and scoped view of it) rejected over the last ten seconds,
+    /// from 0 to 1, for autoscalers and load balancers to react to
+    pub fn pressure(&self) -> f32 {
+        self.pressure.rejection_rate()
+    }
+
+    /// How many keys have been forgotten to stay under the config's
+    /// `max_tracked_keys`, across this limiter and its views
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// How many rejected requests are being held in the tarpit right now
+    pub fn held_requests(&self) -> usize {
+        self.pressure.held()
+    }
+
+    /// Holds `rejection` for the tarpit delay of the config `key` is held
+    /// to, if it sets one, before the caller answers it. The HTTP adapters
+    /// call this for every rejection; connection admission doesn't. Only
+    /// rate limited rejections are held, and only while fewer than the
+    /// config's `max_tarpitted` are; the rest return at once.
+    pub async fn tarpit(&self, key: &str, rejection: &RateLimitRejection) {
+        if rejection.code != RateLimitErrorCode::RateLimited {
+            return;
+        }
+        let config = self.config_for(key);
+        let Some(delay) = config.tarpit else {
+            return;
+        };
+        let Some(_held) = self.pressure.try_hold(config.max_tarpitted) else {
+            return;
+        };
+        self.sleep(delay).await;
+    }
+
+    async fn decide(&self, key: &str, cost: u32) -> Result<RateLimitInfo, RateLimitRejection> {
+        let (maintenance, brake) = {
+            let emergency = self.emergency.read().unwrap_or_else(|e| e.into_inner());
+            if emergency.allowlist.contains(key) {
+                (None, None)
+            } else {
+                (emergency.maintenance, emergency.limiter.clone())
+            }
+        };
+        let now = wall_clock_now();
+        if let Some(until) = maintenance.filter(|until| *until > now) {
+            let config = self.config();
+            return Err(RateLimitRejection::new((until - now).to_std().unwrap_or_default(), config.max_requests)
+                .with_reset_time(until)
+                .with_retry_after_format(config.retry_after_format.clone())
+                .with_header_style(config.header_style)
+                .with_code(RateLimitErrorCode::Maintenance));
+        }
+        if let Some(brake) = brake {
+            // The brake's own limiter has no brake, so this recurses once
+            Box::pin(brake.check_rate_limit_with_cost(key, cost))
+                .await
+                .map_err(|rejection| rejection.with_code(RateLimitErrorCode::GlobalOverload))?;
+        }
+
+        let config = self.config_for(key);
+        let client = key;
+        let reputation = self.reputation.as_ref().map(|reputation| (reputation, key));
+        let scoped = config.scoped_key(key);
+        let key = scoped.as_ref();
+        let scale = reputation.map(|(reputation, client)| reputation.scale(client));
+        let counted = match self.store_for(&config) {
+            Some(store) => self.count_in_store(store, &config, key, cost, scale).await,
+            None => self.count(&config, key, cost, scale),
+        };
+        let admitted = match counted {
+            Ok(admitted) => admitted,
+            Err(rejected) => {
+                let rejection = RateLimitRejection::new(rejected.retry_after, rejected.limit)
+                    .with_window(rejected.window)
+                    .with_reset_mode(config.reset_mode)
+                    .with_retry_after_format(config.retry_after_format.clone())
+                    .with_header_style(config.header_style)
+                    .with_code(rejected.code);
+                // A client isn't to blame for the store being down
+                if rejected.code == RateLimitErrorCode::StoreUnavailable {
+                    return Err(rejection);
+                }
+                if let Some((reputation, client)) = reputation {
+                    reputation.record_rejection(client);
+                }
+                return Err(rejection);
+            }
+        };
+        let Counted {
+            window,
+            limit,
+            used,
+            overage,
+            soft_limit_reached,
+            newly_warned,
+        } = admitted;
+
+        if let Some(usage) = &self.usage {
+            usage.record_with_metadata(key, cost, self.metadata(client));
+        }
+        if let Some(rollups) = &self.rollups {
+            rollups.record_with_metadata(key, cost, self.metadata(client));
+        }
+
+        if newly_warned {
+            if let Some(events) = &self.events {
+                events.emit(&RateLimitEvent::SoftLimitReached {
+                    key: key.to_string(),
+                    used,
+                    limit,
+                    metadata: self.metadata(client),
+                });
+            }
+        }
+
+        let mut info = Self::create_info(&config, limit, used, &window);
+        if overage > 0 {
+            if let Some(events) = &self.events {
+                events.emit(&RateLimitEvent::Overage {
+                    key: key.to_string(),
+                    units: overage,
+                    period_start: info.window_start,
+                    period_end: info.window_end,
+                    metadata: self.metadata(client),
+                });
+            }
+        }
+        info.soft_limit_reached = soft_limit_reached;
+        info.credits = config.burst_credits.map(|_| window.credits);
+        Ok(info)
+    }
+
+    /// Counts a request costing `cost` against `key`'s window, reading and
+    /// updating the window under one lock. This is the synchronous heart of
+    /// the limiter, kept free of awaits so the loom models can check it.
+    /// `scale` shrinks the limit for keys with a poor reputation.
+    fn count(&self, config: &RateLimitConfig, key: &str, cost: u32, scale: Option<f64>) -> Result<Counted, Refused> {
+        let mut state = self.windows();
+        let now = Instant::now();
+        if let Some(ttl) = config.idle_ttl {
+            self.sweep_idle(&mut state, now, ttl);
+        }
+        self.make_room(&mut state, config, key);
+
+        let window = state.get(key).copied().unwrap_or_else(|| Window::open(config, now)).current(config, now);
+        let window = Window { touched: now, ..window };
+        let limit = match scale {
+            Some(scale) => (f64::from(window.limit(config)) * scale).ceil() as u32,
+            None => window.limit(config),
+        };
+
+        let remote = self.peers.as_ref().map_or(0, |peers| peers.total(key));
+        let current = window.count.saturating_add(remote);
+        let before = current.saturating_add(window.previous_share(config, now));
+        let used = before.saturating_add(cost);
+        // Whatever goes past the limit is paid for with burst credits, then
+        // borrowed from the next window, then out of the overage allowance
+        let past = used.saturating_sub(limit.max(before));
+        let spent = past.min(window.credits);
+        // A window still paying back what was borrowed can't borrow again
+        let borrowable = match config.borrowing {
+            Some(borrowing) if window.debt == 0 => borrowing.cap.saturating_sub(window.borrowed),
+            _ => 0,
+        };
+        let borrowed = (past - spent).min(borrowable);
+        let overage = past - spent - borrowed;
+        if window.overage.saturating_add(overage) > config.overage.unwrap_or(0) || Self::rejected_early(config, limit, used) {
+            // A key being turned away is still active, so it isn't forgotten
+            if config.idle_ttl.is_some() || config.max_tracked_keys.is_some() {
+                state.insert(key.to_string(), window);
+            }
+            return Err(Refused {
+                limit,
+                window: window.length,
+                retry_after: window.retry_after(config, limit, current, cost, now),
+                code: RateLimitErrorCode::RateLimited,
+            });
+        }
+
+        let mut window = Window {
+            count: window.count + cost,
+            credits: window.credits - spent,
+            last_seen: now,
+            overage: window.overage + overage,
+            borrowed: window.borrowed + borrowed,
+            ..window
+        };
+        let soft_limit_reached = config
+            .soft_limit
+            .is_some_and(|percent| u64::from(used) * 100 >= u64::from(limit) * u64::from(percent));
+        let newly_warned = soft_limit_reached && !window.warned;
+        window.warned |= soft_limit_reached;
+        state.insert(key.to_string(), window);
+        Ok(Counted {
+            window,
+            limit,
+            used,
+            overage,
+            soft_limit_reached,
+            newly_warned,
+        })
+    }
+
+    /// Counts a request costing `cost` against `key` in `store`. If the
+    /// store fails, the limiter's [`StoreFailurePolicy`] decides the request.
+    async fn count_in_store(
+        &self,
+        store: &dyn RateLimitStore,
+        config: &RateLimitConfig,
+        key: &str,
+        cost: u32,
+        scale: Option<f64>,
+    ) -> Result<Counted, Refused> {
+        let limit = match scale {
+            Some(scale) => (f64::from(config.max_requests) * scale).ceil() as u32,
+            None => config.max_requests,
+        };
+        let (left, length) = self.stored_span(store, config).await;
+        let stored = match store.increment_within(key, cost, limit, left).await {
+            Ok(Admission::Admitted(stored)) => stored,
+            Ok(Admission::Refused(stored)) => {
+                return Err(Refused {
+                    limit,
+                    window: length,
+                    retry_after: stored.ttl,
+                    code: RateLimitErrorCode::RateLimited,
+                })
+            }
+            Err(e) => return self.store_failed(e, config, key, cost, scale, limit),
+        };
+
+        let past_soft_limit = |used: u32| {
+            config
+                .soft_limit
+                .is_some_and(|percent| u64::from(used) * 100 >= u64::from(limit) * u64::from(percent))
+        };
+        let soft_limit_reached = past_soft_limit(stored.count);
+        Ok(Counted {
+            window: Window::stored(length, stored, Instant::now()),
+            limit,
+            used: stored.count,
+            overage: 0,
+            soft_limit_reached,
+            newly_warned: soft_limit_reached && !past_soft_limit(stored.count.saturating_sub(cost)),
+        })
+    }
+
+    /// Decides a request the store failed to count, per the failure policy
+    fn store_failed(
+        &self,
+        e: RateLimitError,
+        config: &RateLimitConfig,
+        key: &str,
+        cost: u32,
+        scale: Option<f64>,
+        limit: u32,
+    ) -> Result<Counted, Refused> {
+        match self.store_failure {
+            StoreFailurePolicy::CountLocally => {
+                tracing::warn!("rate limit store failed, counting in memory: {}", e);
+                self.count(config, key, cost, scale)
+            }
+            StoreFailurePolicy::Admit => {
+                tracing::warn!("rate limit store failed, admitting uncounted: {}", e);
+                Ok(Counted {
+                    window: Window::new(Instant::now(), config.window),
+                    limit,
+                    used: 0,
+                    overage: 0,
+                    soft_limit_reached: false,
+                    newly_warned: false,
+                })
+            }
+            StoreFailurePolicy::Reject => {
+                tracing::warn!("rate limit store failed, rejecting: {}", e);
+                Err(Refused {
+                    limit,
+                    window: config.window,
+                    retry_after: STORE_RETRY_AFTER,
+                    code: RateLimitErrorCode::StoreUnavailable,
+                })
+            }
+        }
+    }
+
+    /// The store counting requests under `config`, if it can
+    fn store_for(&self, config: &RateLimitConfig) -> Option<&dyn RateLimitStore> {
+        let store = self.store.as_deref()?;
+        let storable = match self.peers {
+            Some(_) => Err(RateLimitError::Other("stored counts don't support peer counts".into())),
+            None => Self::storable(config),
+        };
+        match storable {
+            Ok(()) => Some(store),
+            Err(e) => {
+                tracing::warn!("counting in memory: {}", e);
+                None
+            }
+        }
+    }
+
+    /// How long a window opened in `store` now would have left, and how
+    /// long such windows last. Aligned windows are placed by the store's
+    /// clock; each opens with only the time to its boundary left.
+    async fn stored_span(&self, store: &dyn RateLimitStore, config: &RateLimitConfig) -> (Duration, Duration) {
+        if config.reset_mode == ResetMode::Rolling {
+            return (config.window, config.window);
+        }
+        let now = self.store_clock.now(store).await;
+        match config.reset_mode.bounds(config.window, now) {
+            Some((start, end)) => (
+                (end - now).to_std().unwrap_or_default().max(Duration::from_millis(1)),
+                (end - start).to_std().unwrap_or(config.window),
+            ),
+            None => (config.window, config.window),
+        }
+    }
+
+    /// `key`'s window as the limiter's store holds it, or `None` to use the
+    /// in-memory one: when there is no store, or it can't be reached
+    async fn stored_window(&self, config: &RateLimitConfig, key: &str) -> Option<Window> {
+        let store = self.store_for(config)?;
+        let stored = store.get(key).await;
+        let (left, length) = self.stored_span(store, config).await;
+        let now = Instant::now();
+        match stored {
+            Ok(Some(stored)) => Some(Window::stored(length, stored, now)),
+            Ok(None) => Some(Window::stored(length, StoredCount { count: 0, ttl: left }, now)),
+            Err(e) => {
+                tracing::warn!("rate limit store failed, reading memory: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Handles a CORS preflight from `key` per the config's
+    /// [`PreflightPolicy`]. Exempt preflights are never rejected and report
+    /// the key's main budget without using it.
+    pub async fn check_preflight(&self, key: &str) -> Result<RateLimitInfo, RateLimitRejection> {
+        let config = self.config();
+        match config.preflight {
+            PreflightPolicy::Count => self.check_rate_limit(key).await,
+            PreflightPolicy::Exempt => Ok(self.peek(key).await),
+            PreflightPolicy::Budget(max_requests) => self.preflights(config, max_requests).check_rate_limit(key).await,
+        }
+    }
+
+    /// The view counting preflights against a budget of `max_requests`,
+    /// made once and again only when the budget or namespace changes
+    fn preflights(&self, config: RateLimitConfig, max_requests: u32) -> Self {
+        let made_for = |cached: &&Preflights| cached.max_requests == max_requests && cached.namespace == config.namespace;
+        if let Some(cached) = self.preflights.read().unwrap_or_else(|e| e.into_inner()).as_ref().filter(made_for) {
+            return cached.view.clone();
+        }
+        let mut cached = self.preflights.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = cached.as_ref().filter(made_for) {
+            return cached.view.clone();
+        }
+        let namespace = config.namespace.clone();
+        let preflights = RateLimitConfig {
+            max_requests,
+            preflight: PreflightPolicy::Count,
+            namespace: Some(Self::child_namespace(&config, "preflight")),
+            ..config
+        };
+        let view = self.scoped(preflights);
+        *cached = Some(Preflights {
+            max_requests,
+            namespace,
+            view: view.clone(),
+        });
+        view
+    }
+
+    /// `key`'s current status, without counting a request against it
+    pub async fn peek(&self, key: &str) -> RateLimitInfo {
+        let config = self.config_for(key);
+        let scoped = config.scoped_key(key);
+        let now = Instant::now();
+        let window = match self.stored_window(&config, &scoped).await {
+            Some(window) => window,
+            None => self
+                .windows()
+                .get(scoped.as_ref())
+                .copied()
+                .unwrap_or_else(|| Window::open(&config, now))
+                .current(&config, now),
+        };
+        let remote = self.peers.as_ref().map_or(0, |peers| peers.total(&scoped));
+        let limit = window.limit(&config);
+        let mut info = Self::create_info(&config, limit, window.used(&config, now).saturating_add(remote), &window);
+        info.credits = config.burst_credits.map(|_| window.credits);
+        info.metadata = self.metadata(key);
+        info
+    }
+
+    /// Gives back `amount` requests to `key` in its current window, e.g. when
+    /// a response turned out not to count against the client. With a store,
+    /// the store's count is decremented, so every replica sees the refund.
+    pub async fn refund(&self, key: &str, amount: u32) {
+        let key = self.config().scoped_key(key).into_owned();
+        let stored = match &self.store {
+            Some(store) => store
+                .decrement(&key, amount)
+                .await
+                .inspect_err(|e| tracing::warn!("rate limit store failed, refunding in memory: {}", e))
+                .is_ok(),
+            None => false,
+        };
+        if !stored {
+            let mut state = self.windows();
+            if let Some(window) = state.get_mut(&key) {
+                window.count = window.count.saturating_sub(amount);
+            }
+        }
+
+        if let Some(usage) = &self.usage {
+            usage.refund(&key, amount);
+        }
+        if let Some(rollups) = &self.rollups {
+            rollups.refund(&key, amount);
+        }
+    }
+
+    /// Erases everything this limiter holds about the identifier `key`, to
+    /// honor a deletion request: its windows (in memory and in the store)
+    /// under the limiter's namespace and those of its endpoints and views,
+    /// its emergency counters, reputation, usage totals, and rollups. Only
+    /// keys exactly `key` or `namespace:key` are erased. Peers hold their
+    /// own counts, so purge on every instance.
+    pub async fn purge_key(&self, key: &str) {
+        let keys = self.stored_keys(key);
+        if let Some(store) = &self.store {
+            for stored in &keys {
+                if let Err(e) = store.expire(stored, Duration::ZERO).await {
+                    tracing::warn!("failed to purge {} from the rate limit store: {}", stored, e);
+                }
+            }
+        }
+        self.purge_local(&|stored| keys.contains(stored));
+        let brake = self.emergency.read().unwrap_or_else(|e| e.into_inner()).limiter.clone();
+        if let Some(brake) = brake {
+            Box::pin(brake.purge_key(key)).await;
+        }
+    }
+
+    /// Every key `key`'s windows may be stored under: the key itself, and
+    /// the key under the namespace of this limiter, each registered
+    /// endpoint, the preflight budget, and every view made so far
+    fn stored_keys(&self, key: &str) -> HashSet<String> {
+        let base = self.config();
+        let mut namespaces = self.namespaces.read().unwrap_or_else(|e| e.into_inner()).clone();
+        namespaces.extend(base.namespace.clone());
+        namespaces.extend(self.config_for(key).namespace);
+        namespaces.extend(self.endpoints.keys().map(|label| Self::child_namespace(&base, label)));
+        namespaces.insert(Self::child_namespace(&base, "preflight"));
+        let mut keys: HashSet<String> = namespaces.iter().map(|namespace| format!("{}:{}", namespace, key)).collect();
+        keys.insert(key.to_string());
+        keys
+    }
+
+    /// The namespace a view of `base` labelled `label` keeps its keys under
+    fn child_namespace(base: &RateLimitConfig, label: &str) -> String {
+        match &base.namespace {
+            Some(namespace) => format!("{}:{}", namespace, label),
+            None => label.to_string(),
+        }
+    }
+
+    /// Erases everything held about every key `purge` matches, as
+    /// [`RateLimiter::purge_key`] does for one. Keys are passed as stored,
+    /// including any namespace. Counts in the limiter's store are kept, as
+    /// a store can't be searched.
+    pub async fn purge_keys_where<F: Fn(&str) -> bool>(&self, purge: F) {
+        self.purge_matching(&purge).await;
+    }
+
+    async fn purge_matching(&self, purge: &dyn Fn(&str) -> bool) {
+        self.purge_local(purge);
+        let brake = self.emergency.read().unwrap_or_else(|e| e.into_inner()).limiter.clone();
+        if let Some(brake) = brake {
+            Box::pin(brake.purge_matching(purge)).await;
+        }
+    }
+
+    /// Erases what this limiter holds in memory about keys `purge` matches
+    fn purge_local(&self, purge: &dyn Fn(&str) -> bool) {
+        self.windows().retain(|key, _| !purge(key));
+        self.metadata.write().unwrap_or_else(|e| e.into_inner()).keys.retain(|key, _| !purge(key));
+        if let Some(usage) = &self.usage {
+            usage.purge_where(purge);
+        }
+        if let Some(rollups) = &self.rollups {
+            rollups.purge_where(purge);
+        }
+        if let Some(reputation) = &self.reputation {
+            reputation.purge_where(purge);
+        }
+    }
+
+    /// Forgets every key whose window, and the one after it, has ended,
+    /// returning how many were dropped, along with their metadata, and
+    /// drops reputation scores that have decayed to zero. A returning key
+    /// starts over as a new one would, without any carry-over or burst
+    /// credits its old state would have earned.
+    /// [`with_cleanup`](RateLimiter::with_cleanup) does this on a timer.
+    pub fn purge_expired(&self) -> usize {
+        if let Some(reputation) = &self.reputation {
+            reputation.purge();
+        }
+        purge_expired(&mut self.windows(), &self.metadata, Instant::now())
+    }
+
+    /// Forgets `key`'s current window, restoring its full budget
+    pub async fn reset(&self, key: &str) {
+        let key = self.config().scoped_key(key).into_owned();
+        self.windows().remove(&key);
+        if let Some(store) = &self.store {
+            if let Err(e) = store.expire(&key, Duration::ZERO).await {
+                tracing::warn!("failed to reset {} in the rate limit store: {}", key, e);
+            }
+        }
+    }
+
+    /// Charges `amount` extra units to `key` after the fact, e.g. as a penalty
+    /// for the response its request produced. Unlike `check_rate_limit_with_cost`
+    /// this never rejects; it may push the key past its limit, which is then
+    /// enforced on its next request.
+    pub async fn charge(&self, key: &str, amount: u32) {
+        let config = self.config_for(key);
+        let client = key;
+        let key = config.scoped_key(key).into_owned();
+        let stored = match &self.store {
+            Some(store) => store
+                .increment(&key, amount, self.stored_span(store.as_ref(), &config).await.0)
+                .await
+                .inspect_err(|e| tracing::warn!("rate limit store failed, charging in memory: {}", e))
+                .is_ok(),
+            None => false,
+        };
+        if !stored {
+            let mut state = self.windows();
+            let now = Instant::now();
+            self.make_room(&mut state, &config, &key);
+            let entry = state.entry(key.clone()).or_insert_with(|| Window::open(&config, now));
+            *entry = entry.current(&config, now);
+            entry.count = entry.count.saturating_add(amount);
+            entry.touched = now;
+        }
+
+        if let Some(usage) = &self.usage {
+            usage.record_with_metadata(&key, amount, self.metadata(client));
+        }
+        if let Some(rollups) = &self.rollups {
+            rollups.record_with_metadata(&key, amount, self.metadata(client));
+        }
+    }
+
+    /// Adjusts `key`'s usage after the fact by `delta` units: positive to
+    /// charge for work that turned out bigger than it was checked at (a
+    /// huge report), negative to refund work that turned out cheaper (a
+    /// cache hit). Returns the key's status with the adjustment applied, for
+    /// the response's headers; later requests see it too.
+    pub async fn adjust(&self, key: &str, delta: i32) -> RateLimitInfo {
+        if delta > 0 {
+            self.charge(key, delta.unsigned_abs()).await;
+        } else if delta < 0 {
+            self.refund(key, delta.unsigned_abs()).await;
+        }
+        self.peek(key).await
+    }
+
+    /// This instance's count for every key with an open window, to publish
+    /// to peers. Keys include the config's namespace, if any.
+    pub async fn local_counts(&self) -> Vec<KeyCount> {
+        let state = self.windows();
+        let now = Instant::now();
+        let utc_now = Utc::now();
+
+        state
+            .iter()
+            .filter(|(_, w)| now.duration_since(w.start) <= w.length)
+            .map(|(key, w)| {
+                let left = w.length.saturating_sub(now.duration_since(w.start));
+                KeyCount {
+                    key: key.clone(),
+                    count: w.count,
+                    reset: (utc_now + ChronoDuration::from_std(left).unwrap_or_else(|_| ChronoDuration::zero()))
+                        .timestamp(),
+                }
+            })
+            .collect()
+    }
+
+    /// Every key with an open window and how much of it is used, in key
+    /// order. Keys include the config's namespace, if any, and `remaining`
+    /// is measured against the config each key is held to: its endpoint's,
+    /// its quota's, or its rollout arm's.
+    ///
+    /// The keys are copied out once, sorted, and their windows a batch at a
+    /// time, so checks only wait on the lock for one batch. Each item is a
+    /// snapshot of its key as of its batch; keys first seen after the
+    /// iterator was made are left out.
+    pub fn iter_usage(&self) -> UsageIter {
+        let mut keys: Vec<String> = self.windows().keys().cloned().collect();
+        keys.sort_unstable();
+        UsageIter {
+            limiter: self.clone(),
+            config: self.config(),
+            keys: keys.into_iter(),
+            batch: Vec::new().into_iter(),
+        }
+    }
+
+    /// The config the stored key `stored` is held to, and the client it
+    /// belongs to if it is one of this limiter's or its endpoints' keys
+    fn config_for_stored<'k>(&self, base: &RateLimitConfig, stored: &'k str) -> (RateLimitConfig, Option<&'k str>) {
+        let within = |namespace: &str| stored.strip_prefix(namespace).and_then(|key| key.strip_prefix(':'));
+        for (label, endpoint) in self.endpoints.iter() {
+            let namespace = Self::child_namespace(base, label);
+            if let Some(client) = within(&namespace) {
+                return (endpoint.resolve(base).with_namespace(namespace), Some(client));
+            }
+        }
+        match &base.namespace {
+            Some(namespace) => match within(namespace) {
+                Some(client) => (self.config_for(client), Some(client)),
+                None => (base.clone(), None),
+            },
+            None => (self.config_for(stored), Some(stored)),
+        }
+    }
+
+    /// Sleeps for `duration` on the limiter's runtime
+    pub(crate) async fn sleep(&self, duration: Duration) {
+        runtime::sleep(self.runtime.as_deref(), duration).await;
+    }
+
+    /// Every key's window. The lock is never held across an await, so
+    /// each check reads and updates its key's window in one step.
+    fn windows(&self) -> MutexGuard<'_, HashMap<String, Window>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Forgets keys past their idle TTL, at most once per `ttl`
+    fn sweep_idle(&self, state: &mut HashMap<String, Window>, now: Instant, ttl: Duration) {
+        let mut swept = self.swept.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(*swept) < ttl {
+            return;
+        }
+        *swept = now;
+        let removed = drop_windows(state, |window| window.is_idle(now));
+        self.forget_metadata(state, &removed);
+    }
+
+    /// Makes room for `key` under the config's `max_tracked_keys` by
+    /// forgetting the least recently seen keys. A tenth of the cap goes at
+    /// once, so the scan for them is paid for by many new keys.
+    fn make_room(&self, state: &mut HashMap<String, Window>, config: &RateLimitConfig, key: &str) {
+        let Some(max) = config.max_tracked_keys.map(|max| max.max(1)) else {
+            return;
+        };
+        if state.len() < max || state.contains_key(key) {
+            return;
+        }
+        let keep = max - 1 - max / 10;
+        let mut touched: Vec<Instant> = state.values().map(|window| window.touched).collect();
+        let (_, cutoff, _) = touched.select_nth_unstable(state.len() - keep - 1);
+        let cutoff = *cutoff;
+        let removed = drop_windows(state, |window| window.touched <= cutoff);
+        self.evicted.fetch_add(removed.len() as u64, Ordering::Relaxed);
+        self.forget_metadata(state, &removed);
+    }
+
+    /// With early rejection configured, whether to turn away a request that
+    /// would bring usage to `used`. The odds grow linearly from zero at the
+    /// threshold to nearly one at the limit.
+    fn rejected_early(config: &RateLimitConfig, limit: u32, used: u32) -> bool {
+        let Some(percent) = config.early_rejection else {
+            return false;
+        };
+        let threshold = u64::from(limit) * u64::from(percent) / 100;
+        let past = u64::from(used).saturating_sub(threshold);
+        // Requests past the limit are paid for with burst credits instead
+        if past == 0 || used > limit {
+            return false;
+        }
+        let odds = past as f64 / (u64::from(limit) - threshold + 1) as f64;
+        random_fraction() < odds
+    }
+
+    fn create_info(config: &RateLimitConfig, limit: u32, used: u32, window: &Window) -> RateLimitInfo {
+        // Aligned windows report their exact boundaries, which re-reading the
+        // system clock would be off by however long the request took
+        let midpoint = wall_clock_at(window.start + window.length / 2);
+        let bounds = match config.algorithm {
+            // A bucket "resets" once it has refilled completely
+            RateLimitAlgorithm::TokenBucket => {
+                let full = window.start + Window::refill_interval(config).checked_mul(window.count).unwrap_or(window.length);
+                Some((wall_clock_at(window.start), wall_clock_at(full)))
+            }
+            _ => config.reset_mode.bounds(config.window, midpoint),
+        };
+        let (window_start, window_end) = bounds.unwrap_or_else(|| {
+            let start = wall_clock_at(window.start);
+            (start, start + ChronoDuration::from_std(window.length).unwrap_or_else(|_| ChronoDuration::zero()))
+        });
+        let retry_after = match config.retry_after_format {
+            RetryAfterFormat::HttpDate => window_end.to_rfc2822(),
+            RetryAfterFormat::Seconds => seconds_until(window_end).to_string(),
+        };
+
+        RateLimitInfo {
+            retry_after,
+            limit,
+            remaining: limit.saturating_sub(used),
+            used,
+            window: window.length,
+            window_start,
+            window_end,
+            reset_timestamp: window_end.timestamp(),
+            retry_after_format: config.retry_after_format.clone(),
+            header_style: config.header_style,
+            soft_limit_reached: false,
+            credits: None,
+            reset_mode: config.reset_mode,
+            metadata: KeyMetadata::new(),
+        }
+    }
+}
+
+/// Drops the windows in `state` that have expired by `now`, and the
+/// metadata of the clients they leave without one, returning how many
+fn purge_expired(state: &mut HashMap<String, Window>, metadata: &StdRwLock<AttachedMetadata>, now: Instant) -> usize {
+    let removed = drop_windows(state, |window| window.is_expired(now));
+    if !removed.is_empty() {
+        metadata.write().unwrap_or_else(|e| e.into_inner()).forget(state, &removed);
+    }
+    removed.len()
+}
+
+/// A uniformly distributed value in `[0, 1)`, random enough for shedding
+/// load; std's hasher keys are seeded randomly per `RandomState`
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let bits = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A key's usage of its current window, as yielded by
+/// [`RateLimiter::iter_usage`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// The rate limit key, including its namespace
+    pub key: String,
+    /// Units used in the window, counting those reported by peers
+    pub used: u32,
+    /// Units left in the window
+    pub remaining: u32,
+    /// When the window started
+    pub window_start: DateTime<Utc>,
+    /// The metadata attached to the key. Only found for keys under the
+    /// iterating limiter's own namespace.
+    pub metadata: KeyMetadata,
+}
+
+/// The iterator returned by [`RateLimiter::iter_usage`]
+#[derive(Debug)]
+pub struct UsageIter {
+    limiter: RateLimiter,
+    /// The limiter's config when the iterator was made
+    config: RateLimitConfig,
+    /// The keys not yet read, in order
+    keys: std::vec::IntoIter<String>,
+    batch: std::vec::IntoIter<KeyUsage>,
+}
+
+impl UsageIter {
+    /// Copies out the windows of the next [`USAGE_BATCH`] keys still open,
+    /// or an empty batch once every key has been read
+    fn next_batch(&mut self) -> Vec<KeyUsage> {
+        let now = Instant::now();
+        let mut windows = Vec::with_capacity(USAGE_BATCH);
+        while windows.is_empty() && !self.keys.as_slice().is_empty() {
+            let state = self.limiter.windows();
+            windows.extend(self.keys.by_ref().take(USAGE_BATCH).filter_map(|key| {
+                let window = state.get(&key).copied()?;
+                (now.duration_since(window.start) <= window.length).then_some((key, window))
+            }));
+        }
+
+        windows
+            .into_iter()
+            .map(|(key, window)| {
+                let (config, client) = self.limiter.config_for_stored(&self.config, &key);
+                let remote = self.limiter.peers.as_ref().map_or(0, |peers| peers.total(&key));
+                let used = window.used(&config, now).saturating_add(remote);
+                KeyUsage {
+                    metadata: client.map(|client| self.limiter.metadata(client)).unwrap_or_default(),
+                    used,
+                    remaining: window.limit(&config).saturating_sub(used),
+                    window_start: wall_clock_at(window.start),
+                    key,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Iterator for UsageIter {
+    type Item = KeyUsage;
+
+    fn next(&mut self) -> Option<KeyUsage> {
+        if let Some(usage) = self.batch.next() {
+            return Some(usage);
+        }
+        self.batch = self.next_batch().into_iter();
+        self.batch.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        get_rate_limit_info, ArmStats, ForwardedHeader, HeaderStyle, IpCidr, MemoryStore, Rollup, TrustedProxies,
+    };
+    use http::StatusCode;
+    use std::net::IpAddr;
+
+    #[tokio::test]
+    async fn test_limiter_counts_per_key() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(2, 60));
+
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 1);
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 0);
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.limit, 2);
+
+        // Other keys are unaffected, and clones share the same counters
+        let clone = limiter.clone();
+        assert_eq!(clone.check_rate_limit("b").await.unwrap().remaining, 1);
+        assert!(clone.check_rate_limit("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refund_restores_budget() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+
+        limiter.check_rate_limit("a").await.unwrap();
+        assert!(limiter.check_rate_limit("a").await.is_err());
+
+        limiter.refund("a", 1).await;
+        assert!(limiter.check_rate_limit("a").await.is_ok());
+
+        // Refunding an unknown key is a no-op
+        limiter.refund("missing", 1).await;
+    }
+
+    #[tokio::test]
+    async fn test_weighted_cost() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60));
+
+        assert_eq!(limiter.check_rate_limit_with_cost("a", 7).await.unwrap().remaining, 3);
+        // A request that doesn't fit is rejected without consuming anything
+        assert!(limiter.check_rate_limit_with_cost("a", 4).await.is_err());
+        assert_eq!(limiter.check_rate_limit_with_cost("a", 3).await.unwrap().used, 10);
+    }
+
+    #[tokio::test]
+    async fn test_peer_counts_share_the_limit() {
+        let peers = PeerCounts::new("a");
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60)).with_peer_counts(peers.clone());
+
+        limiter.check_rate_limit("client").await.unwrap();
+        let counts = limiter.local_counts().await;
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].count, 1);
+
+        peers.merge(&crate::core::CountSnapshot {
+            node: "b".to_string(),
+            counts: vec![KeyCount { count: 3, ..counts[0].clone() }],
+        });
+        assert_eq!(limiter.check_rate_limit("client").await.unwrap().remaining, 0);
+        assert!(limiter.check_rate_limit("client").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_separate_tenants() {
+        let acme = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_namespace("acme"));
+        let globex = acme.scoped(RateLimitConfig::max_per_window(1, 60).with_namespace("globex"));
+
+        // Both tenants share one store without their keys colliding
+        assert!(acme.check_rate_limit("10.0.0.1").await.is_ok());
+        assert!(globex.check_rate_limit("10.0.0.1").await.is_ok());
+        assert!(acme.check_rate_limit("10.0.0.1").await.is_err());
+
+        let mut keys: Vec<_> = acme.local_counts().await.into_iter().map(|c| c.key).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["acme:10.0.0.1", "globex:10.0.0.1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_carry_over_rolls_unused_budget() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60).with_carry_over(50, 4));
+
+        limiter.check_rate_limit_with_cost("a", 4).await.unwrap();
+        tokio::time::advance(std::time::Duration::from_secs(61)).await;
+
+        // 6 unused, half of it carried, capped at 4
+        let info = limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!(info.limit, 13);
+        assert_eq!(info.remaining, 12);
+
+        // Keys without carry-over still get the plain allowance
+        let plain = RateLimiter::new(RateLimitConfig::max_per_window(10, 60));
+        plain.check_rate_limit("a").await.unwrap();
+        tokio::time::advance(std::time::Duration::from_secs(61)).await;
+        assert_eq!(plain.check_rate_limit("a").await.unwrap().limit, 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_keys_earn_burst_credits() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(2, 60).with_burst_credits(3));
+
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().credits, Some(0));
+        // Quiet for an hour: 59 idle windows' worth, capped at 3
+        tokio::time::advance(std::time::Duration::from_secs(60 * 60)).await;
+        for _ in 0..2 {
+            assert_eq!(limiter.check_rate_limit("a").await.unwrap().credits, Some(3));
+        }
+        let info = limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!((info.remaining, info.credits), (0, Some(2)));
+        limiter.check_rate_limit("a").await.unwrap();
+        limiter.check_rate_limit("a").await.unwrap();
+        assert!(limiter.check_rate_limit("a").await.is_err());
+
+        // A key kept busy every window earns nothing
+        tokio::time::advance(std::time::Duration::from_secs(61)).await;
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().credits, Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sliding_window_weighs_in_the_previous_window() {
+        let config = RateLimitConfig::max_per_window(10, 60).with_algorithm(RateLimitAlgorithm::SlidingWindow);
+        let limiter = RateLimiter::new(config);
+        limiter.check_rate_limit_with_cost("a", 10).await.unwrap();
+
+        // 15s into the next window, 45s of the previous one's 10 requests are in reach
+        tokio::time::advance(std::time::Duration::from_secs(75)).await;
+        let info = limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!((info.used, info.remaining), (8, 2));
+        limiter.check_rate_limit_with_cost("a", 2).await.unwrap();
+
+        // 3 of this window's plus 7 of the last: room opens once the share drops to 6
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.retry_after().as_secs_f64().round(), 9.0);
+        tokio::time::advance(std::time::Duration::from_secs(9)).await;
+        assert!(limiter.check_rate_limit("a").await.is_ok());
+
+        // A fixed window forgets the previous window entirely
+        let fixed = RateLimiter::new(RateLimitConfig::max_per_window(10, 60));
+        fixed.check_rate_limit_with_cost("a", 10).await.unwrap();
+        tokio::time::advance(std::time::Duration::from_secs(61)).await;
+        assert_eq!(fixed.check_rate_limit("a").await.unwrap().remaining, 9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replicas_share_a_store() {
+        let store = MemoryStore::new();
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let first = RateLimiter::new(config.clone()).with_store(store.clone()).unwrap();
+        let second = RateLimiter::new(config).with_store(store).unwrap();
+
+        assert_eq!(first.check_rate_limit("a").await.unwrap().remaining, 2);
+        tokio::time::advance(std::time::Duration::from_secs(20)).await;
+        assert_eq!(second.check_rate_limit_with_cost("a", 2).await.unwrap().remaining, 0);
+        let rejection = first.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.retry_after().as_secs_f64().round(), 40.0);
+        assert_eq!(second.peek("a").await.remaining, 0);
+
+        second.reset("a").await;
+        assert_eq!(first.peek("a").await.remaining, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refunds_reach_the_store() {
+        let store = MemoryStore::new();
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let first = RateLimiter::new(config.clone()).with_store(store.clone()).unwrap();
+        let second = RateLimiter::new(config).with_store(store).unwrap();
+
+        first.check_rate_limit_with_cost("a", 3).await.unwrap();
+        first.refund("a", 1).await;
+        assert_eq!(second.peek("a").await.remaining, 1);
+        assert_eq!(second.adjust("a", -2).await.remaining, 3);
+        assert_eq!(first.peek("a").await.remaining, 3);
+    }
+
+    /// A memory store whose clock is `ahead` of this process's
+    #[derive(Debug)]
+    struct SkewedStore {
+        store: MemoryStore,
+        ahead: ChronoDuration,
+    }
+
+    impl RateLimitStore for SkewedStore {
+        fn get<'a>(&'a self, key: &'a str) -> crate::core::BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+            self.store.get(key)
+        }
+
+        fn increment<'a>(
+            &'a self,
+            key: &'a str,
+            amount: u32,
+            window: Duration,
+        ) -> crate::core::BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+            self.store.increment(key, amount, window)
+        }
+
+        fn increment_within<'a>(
+            &'a self,
+            key: &'a str,
+            amount: u32,
+            limit: u32,
+            window: Duration,
+        ) -> crate::core::BoxFuture<'a, Result<Admission, RateLimitError>> {
+            self.store.increment_within(key, amount, limit, window)
+        }
+
+        fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> crate::core::BoxFuture<'a, Result<(), RateLimitError>> {
+            self.store.expire(key, ttl)
+        }
+
+        fn now(&self) -> crate::core::BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+            Box::pin(std::future::ready(Ok(wall_clock_now() + self.ahead)))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aligned_store_windows_follow_the_store_clock() {
+        let window = Duration::from_secs(60);
+        // The store's clock reads five seconds before a minute boundary,
+        // wherever this one is in its minute
+        let now = wall_clock_now();
+        let (_, end) = ResetMode::FixedInterval.bounds(window, now).unwrap();
+        let ahead = end - now + ChronoDuration::seconds(55);
+        let config = RateLimitConfig::max_per_window(2, 60).with_reset_mode(ResetMode::FixedInterval);
+        let limiter = RateLimiter::new(config)
+            .with_store(SkewedStore {
+                store: MemoryStore::new(),
+                ahead,
+            })
+            .unwrap();
+
+        limiter.check_rate_limit("a").await.unwrap();
+        limiter.check_rate_limit("a").await.unwrap();
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.retry_after().as_secs_f64().round(), 5.0);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 1);
+    }
+
+    /// A store whose server is down
+    #[derive(Debug)]
+    struct DownStore;
+
+    impl RateLimitStore for DownStore {
+        fn get<'a>(&'a self, _: &'a str) -> crate::core::BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+
+        fn increment<'a>(
+            &'a self,
+            _: &'a str,
+            _: u32,
+            _: Duration,
+        ) -> crate::core::BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+
+        fn increment_within<'a>(
+            &'a self,
+            _: &'a str,
+            _: u32,
+            _: u32,
+            _: Duration,
+        ) -> crate::core::BoxFuture<'a, Result<Admission, RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+
+        fn expire<'a>(&'a self, _: &'a str, _: Duration) -> crate::core::BoxFuture<'a, Result<(), RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stores_only_count_admitted_requests() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(3, 60)).with_store(MemoryStore::new()).unwrap();
+        assert_eq!(limiter.check_rate_limit_with_cost("a", 2).await.unwrap().remaining, 1);
+        assert!(limiter.check_rate_limit_with_cost("a", 2).await.is_err());
+        // The refused request used none of what was left
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 0);
+    }
+
+    #[test]
+    fn test_stores_reject_configs_they_cant_enforce() {
+        let sliding = RateLimitConfig::max_per_window(3, 60).with_algorithm(RateLimitAlgorithm::SlidingWindow);
+        let error = RateLimiter::new(sliding).with_store(MemoryStore::new()).unwrap_err();
+        assert!(error.to_string().contains("fixed-window"), "{}", error);
+
+        let overage = RateLimiter::new(RateLimitConfig::max_per_window(3, 60))
+            .with_endpoint("search", RateLimitConfig::max_per_window(3, 60).with_overage(1))
+            .with_store(MemoryStore::new())
+            .unwrap_err();
+        assert!(overage.to_string().contains("endpoint search"), "{}", overage);
+
+        let peers = RateLimiter::new(RateLimitConfig::max_per_window(3, 60)).with_peer_counts(PeerCounts::new("a"));
+        assert!(peers.with_store(MemoryStore::new()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_store_failure_policies() {
+        let config = RateLimitConfig::max_per_window(1, 60);
+        let local = RateLimiter::new(config.clone()).with_store(DownStore).unwrap();
+        assert!(local.check_rate_limit("a").await.is_ok());
+        assert_eq!(local.check_rate_limit("a").await.unwrap_err().code, RateLimitErrorCode::RateLimited);
+
+        let open = RateLimiter::new(config.clone())
+            .with_store(DownStore)
+            .unwrap()
+            .with_store_failure_policy(StoreFailurePolicy::Admit);
+        for _ in 0..3 {
+            assert!(open.check_rate_limit("a").await.is_ok());
+        }
+
+        let closed = RateLimiter::new(config)
+            .with_store(DownStore)
+            .unwrap()
+            .with_store_failure_policy(StoreFailurePolicy::Reject);
+        let rejection = closed.endpoint("search").check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.code, RateLimitErrorCode::StoreUnavailable);
+        assert_eq!(rejection.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_bursts_then_refills() {
+        let limiter = RateLimiter::new(RateLimitConfig::token_bucket(2.0, 4));
+        for remaining in (0..4).rev() {
+            assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, remaining);
+        }
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!((rejection.retry_after().as_secs_f64() * 10.0).round(), 5.0);
+
+        // One token every 500ms
+        tokio::time::advance(std::time::Duration::from_millis(500)).await;
+        let info = limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!((info.remaining, seconds_until(info.window_end)), (0, 2));
+        assert!(limiter.check_rate_limit("a").await.is_err());
+
+        // Idle keys never hold more than the burst
+        tokio::time::advance(std::time::Duration::from_secs(60)).await;
+        assert!(limiter.check_rate_limit_with_cost("a", 4).await.is_ok());
+        assert!(limiter.check_rate_limit("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preflight_policies() {
+        let exempt = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_preflight(PreflightPolicy::Exempt));
+        assert_eq!(exempt.check_preflight("a").await.unwrap().remaining, 1);
+        exempt.check_rate_limit("a").await.unwrap();
+        // Exempt preflights report the budget but are never rejected
+        assert_eq!(exempt.check_preflight("a").await.unwrap().remaining, 0);
+
+        let budget = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_preflight(PreflightPolicy::Budget(2)));
+        assert!(budget.check_preflight("a").await.is_ok());
+        assert!(budget.check_preflight("a").await.is_ok());
+        assert!(budget.check_preflight("a").await.is_err());
+        assert!(budget.check_rate_limit("a").await.is_ok());
+        // A raised budget applies to the same preflight counts
+        budget.set_config(RateLimitConfig::max_per_window(1, 60).with_preflight(PreflightPolicy::Budget(3)));
+        assert!(budget.check_preflight("a").await.is_ok());
+        assert!(budget.check_preflight("a").await.is_err());
+
+        // Preflights count by default
+        let count = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        assert!(count.check_preflight("a").await.is_ok());
+        assert!(count.check_rate_limit("a").await.is_err());
+        assert!(count.check_preflight("a").await.is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_tarpit_holds_only_up_to_the_cap() {
+        let delay = std::time::Duration::from_secs(5);
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_tarpit(delay).with_max_tarpitted(1));
+
+        let start = Instant::now();
+        assert!(limiter.check_rate_limit("a").await.is_ok());
+        // Checking never sleeps; the adapters hold the rejection
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+
+        let held = tokio::spawn({
+            let (limiter, rejection) = (limiter.clone(), rejection.clone());
+            async move { limiter.tarpit("a", &rejection).await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(limiter.held_requests(), 1);
+        // The tarpit is full, so the next rejection goes straight out
+        limiter.tarpit("a", &rejection).await;
+        assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+        held.await.unwrap();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(limiter.held_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tarpit_sleeps_on_the_given_runtime() {
+        #[derive(Debug, Default)]
+        struct Recorder(Arc<std::sync::Mutex<Vec<Duration>>>);
+
+        impl Runtime for Recorder {
+            fn spawn(&self, _task: futures_core::future::BoxFuture<'static, ()>) {}
+
+            fn sleep(&self, duration: Duration) -> futures_core::future::BoxFuture<'static, ()> {
+                self.0.lock().unwrap().push(duration);
+                Box::pin(async {})
+            }
+        }
+
+        let slept = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let delay = Duration::from_secs(5);
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_tarpit(delay))
+            .with_runtime(Recorder(slept.clone()));
+
+        assert!(limiter.check_rate_limit("a").await.is_ok());
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        limiter.tarpit("a", &rejection).await;
+        assert_eq!(*slept.lock().unwrap(), vec![delay]);
+    }
+
+    #[tokio::test]
+    async fn test_fixed_interval_windows_are_aligned() {
+        let config = RateLimitConfig::max_per_window(1, 60).with_reset_mode(ResetMode::FixedInterval);
+        let limiter = RateLimiter::new(config);
+
+        let a = limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!(a.window_start.timestamp() % 60, 0);
+        assert_eq!(a.reset_timestamp % 60, 0);
+        assert_eq!(a.reset_mode, ResetMode::FixedInterval);
+
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.reset_mode, ResetMode::FixedInterval);
+        let headers = get_rate_limit_info(&rejection).to_headers().unwrap();
+        assert_eq!(headers["x-ratelimit-reset-mode"], "fixed-interval");
+    }
+
+    #[tokio::test]
+    async fn test_early_rejection_smooths_the_cliff() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(100, 60).with_early_rejection(50));
+
+        let (mut accepted, mut early) = (0, 0);
+        for _ in 0..200 {
+            match limiter.check_rate_limit("a").await {
+                Ok(_) => accepted += 1,
+                Err(_) if accepted < 100 => early += 1,
+                Err(_) => {}
+            }
+            // Nothing is rejected before the threshold
+            assert!(early == 0 || accepted >= 50);
+        }
+        assert!(accepted <= 100);
+        assert!(early > 0, "no request was rejected before the limit");
+    }
+
+    #[tokio::test]
+    async fn test_soft_limit_warns_once_per_window() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60).with_soft_limit(80))
+            .on_event(move |event| seen.lock().unwrap().push(event.clone()));
+
+        for _ in 0..3 {
+            assert!(!limiter.check_rate_limit("a").await.unwrap().soft_limit_reached);
+        }
+        let info = limiter.check_rate_limit("a").await.unwrap();
+        assert!(info.soft_limit_reached);
+        assert!(info.to_headers().unwrap().contains_key("x-ratelimit-warning"));
+        assert!(limiter.check_rate_limit("a").await.unwrap().soft_limit_reached);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![RateLimitEvent::SoftLimitReached {
+                key: "a".to_string(),
+                used: 4,
+                limit: 5,
+                metadata: KeyMetadata::new(),
+            }]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_keys_are_forgotten() {
+        let config = RateLimitConfig::max_per_window(1, 30 * 86400).with_idle_ttl(Duration::from_secs(600));
+        let limiter = RateLimiter::new(config);
+        limiter.check_rate_limit("idle").await.unwrap();
+        limiter.check_rate_limit("busy").await.unwrap();
+
+        // Rejected requests keep a key alive too
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_secs(300)).await;
+            assert!(limiter.check_rate_limit("busy").await.is_err());
+        }
+
+        assert_eq!(limiter.local_counts().await.len(), 1);
+        assert!(limiter.check_rate_limit("idle").await.is_ok());
+        assert!(limiter.check_rate_limit("busy").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_least_recently_seen_keys_are_evicted() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_max_tracked_keys(10));
+        for i in 0..10 {
+            limiter.check_rate_limit(&format!("scanner-{}", i)).await.unwrap();
+            tokio::time::advance(Duration::from_millis(10)).await;
+        }
+        // Rejected requests count as being seen
+        assert!(limiter.check_rate_limit("scanner-0").await.is_err());
+
+        limiter.check_rate_limit("new").await.unwrap();
+        assert_eq!(limiter.evicted_keys(), 2);
+        assert_eq!(limiter.local_counts().await.len(), 9);
+        assert!(limiter.check_rate_limit("scanner-0").await.is_err());
+        // Evicted keys start over
+        assert!(limiter.check_rate_limit("scanner-1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_allowlisted_and_denylisted_clients() {
+        let config = RateLimitConfig::max_per_window(1, 60)
+            .allow("10.0.0.0/8".parse::<IpCidr>().unwrap())
+            .deny("203.0.113.0/24".parse::<IpCidr>().unwrap())
+            .deny("10.6.6.6".parse::<IpAddr>().unwrap())
+            .with_deny_status(StatusCode::NOT_FOUND);
+        let limiter = RateLimiter::new(config);
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        let headers = http::HeaderMap::new();
+
+        // The lists match the client's address, whatever the key
+        for _ in 0..3 {
+            let info = limiter.screen("user-1", ip("10.1.2.3"), &headers).await.unwrap().unwrap();
+            assert_eq!(info.used, 0);
+        }
+        // The allowlist wins over the denylist
+        assert!(limiter.screen("user-2", ip("10.6.6.6"), &headers).await.unwrap().is_ok());
+
+        let rejection = limiter.screen("user-1", ip("203.0.113.9"), &headers).await.unwrap().unwrap_err();
+        assert_eq!(rejection.code, RateLimitErrorCode::Banned);
+        assert_eq!(rejection.status(), StatusCode::NOT_FOUND);
+        // Banned clients aren't told when to come back, or what the limit is
+        let response = http::Response::<String>::from(&rejection);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!response.headers().contains_key(http::header::RETRY_AFTER));
+        assert!(!response.headers().keys().any(|name| name.as_str().contains("ratelimit")));
+
+        assert!(limiter.screen("user-1", ip("192.0.2.1"), &headers).await.is_none());
+        assert!(limiter.screen("user-1", None, &headers).await.is_none());
+
+        // Behind trusted proxies, the lists match the forwarded client
+        let proxied = limiter.clone().with_trusted_proxies(
+            TrustedProxies::parse(ForwardedHeader::XForwardedFor, ["192.0.2.0/24"]).unwrap(),
+        );
+        let mut forwarded = http::HeaderMap::new();
+        forwarded.insert("x-forwarded-for", "203.0.113.9".parse().unwrap());
+        assert!(proxied.screen("user-1", ip("192.0.2.1"), &forwarded).await.unwrap().is_err());
+        assert!(proxied.screen("user-1", ip("198.51.100.1"), &forwarded).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_quota_resolver_sets_per_key_limits() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(2, 60).with_namespace("api"))
+            .with_quota_resolver(|key: &str| key.starts_with("partner-").then(|| RateLimitConfig::max_per_window(5, 60)));
+
+        let info = limiter.check_rate_limit("partner-1").await.unwrap();
+        assert_eq!((info.limit, info.remaining), (5, 4));
+        assert_eq!(info.to_headers().unwrap().get("x-ratelimit-limit").unwrap(), "5");
+        for _ in 0..4 {
+            limiter.check_rate_limit("partner-1").await.unwrap();
+        }
+        assert_eq!(limiter.check_rate_limit("partner-1").await.unwrap_err().limit, 5);
+
+        let info = limiter.check_rate_limit("anonymous").await.unwrap();
+        assert_eq!((info.limit, info.remaining), (2, 1));
+        // Resolved configs count under the limiter's namespace
+        assert!(limiter.local_counts().await.iter().all(|count| count.key.starts_with("api:")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_purge_expired_drops_finished_windows() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        limiter.check_rate_limit("gone").await.unwrap();
+        tokio::time::advance(Duration::from_secs(90)).await;
+        limiter.check_rate_limit("recent").await.unwrap();
+
+        // One window past its end may still matter to the next
+        assert_eq!(limiter.purge_expired(), 0);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(limiter.purge_expired(), 1);
+        assert_eq!(limiter.windows().len(), 1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_cleanup_runs_in_the_background() {
+        let reputation = Reputation::new(Duration::from_secs(10));
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60))
+            .with_reputation(reputation.clone())
+            .with_cleanup(Duration::from_secs(30))
+            .unwrap();
+        limiter.check_rate_limit("a").await.unwrap();
+        assert!(limiter.check_rate_limit("a").await.is_err());
+        assert_eq!(reputation.score("a"), 1.0);
+        tokio::time::sleep(Duration::from_secs(125)).await;
+        assert!(limiter.windows().is_empty());
+        // The rejection's point decayed away and was dropped with the window
+        assert_eq!(reputation.purge(), 0);
+        assert_eq!(reputation.score("a"), 0.0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_cleanup_outside_of_a_runtime_is_an_error() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        assert!(limiter.with_cleanup(Duration::from_secs(30)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_emergency_overrides_every_route() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(100, 60));
+        let search = limiter.scoped(RateLimitConfig::max_per_window(100, 60).with_namespace("search"));
+        limiter.set_emergency_allowlist(["ops"]);
+        limiter.set_emergency(Some(RateLimitConfig::max_per_window(1, 60)));
+        assert_eq!(limiter.emergency().unwrap().max_requests, 1);
+
+        limiter.check_rate_limit("a").await.unwrap();
+        let rejection = search.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.code, RateLimitErrorCode::GlobalOverload);
+        for _ in 0..5 {
+            search.check_rate_limit("ops").await.unwrap();
+        }
+
+        limiter.set_emergency(None);
+        assert!(search.check_rate_limit("a").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_turns_everyone_away() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(100, 60));
+        limiter.set_emergency_allowlist(["ops"]);
+        let until = Utc::now() + ChronoDuration::seconds(120);
+        limiter.set_maintenance(Some(until));
+        assert_eq!(limiter.maintenance(), Some(until));
+
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(rejection.code, RateLimitErrorCode::Maintenance);
+        assert_eq!(rejection.reset_time, until);
+        limiter.check_rate_limit("ops").await.unwrap();
+
+        // A window that has already ended lets everyone through
+        limiter.set_maintenance(Some(Utc::now() - ChronoDuration::seconds(1)));
+        assert_eq!(limiter.maintenance(), None);
+        limiter.check_rate_limit("a").await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reputation_scales_the_limit() {
+        let reputation = Reputation::new(Duration::from_secs(3600));
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60)).with_reputation(reputation.clone());
+
+        limiter.record_status("bad", StatusCode::UNAUTHORIZED);
+        reputation.penalize("bad", 9.0);
+        assert_eq!(limiter.check_rate_limit("bad").await.unwrap().limit, 5);
+        assert_eq!(limiter.check_rate_limit("good").await.unwrap().limit, 10);
+
+        for _ in 0..4 {
+            limiter.check_rate_limit("bad").await.unwrap();
+        }
+        assert!(limiter.check_rate_limit("bad").await.is_err());
+        assert_eq!(reputation.score("bad").round(), 11.0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_pressure_tracks_rejections_across_views() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_tarpit(Duration::from_secs(5)));
+        let search = limiter.endpoint("search");
+        assert_eq!(limiter.pressure(), 0.0);
+
+        limiter.check_rate_limit("a").await.unwrap();
+        search.check_rate_limit("a").await.unwrap();
+        limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(search.pressure(), 1.0 / 3.0);
+
+        let held = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+                limiter.tarpit("a", &rejection).await;
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(limiter.held_requests(), 1);
+        held.await.unwrap();
+        assert_eq!(limiter.held_requests(), 0);
+        assert_eq!(limiter.pressure(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_purge_key_erases_every_trace() {
+        let usage = UsageLedger::new();
+        let rollups = UsageRollups::new();
+        let reputation = Reputation::new(Duration::from_secs(3600));
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60))
+            .with_usage_ledger(usage.clone())
+            .with_usage_rollups(rollups.clone())
+            .with_reputation(reputation.clone());
+        let search = limiter.endpoint("search");
+
+        for key in ["alice", "bob"] {
+            limiter.check_rate_limit(key).await.unwrap();
+            search.check_rate_limit(key).await.unwrap();
+            limiter.check_rate_limit(key).await.unwrap_err();
+        }
+        limiter.purge_key("alice").await;
+
+        let counted: Vec<_> = limiter.local_counts().await.into_iter().map(|count| count.key).collect();
+        assert!(counted.iter().all(|key| key.contains("bob")));
+        assert_eq!(reputation.score("alice"), 0.0);
+        assert!(reputation.score("bob") > 0.0);
+        let now = Utc::now();
+        let day = ChronoDuration::days(1);
+        assert!(rollups.query("alice", Rollup::Daily, now - day, now + day).is_empty());
+        assert_eq!(rollups.query("bob", Rollup::Daily, now - day, now + day).len(), 1);
+        assert!(usage.take(now, now).iter().all(|summary| summary.key.contains("bob")));
+        assert!(limiter.check_rate_limit("alice").await.is_ok());
+
+        limiter.purge_keys_where(|key| key.starts_with("search:")).await;
+        assert!(search.check_rate_limit("bob").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_purge_key_spares_keys_sharing_a_suffix() {
+        let store = MemoryStore::new();
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_namespace("api"))
+            .with_endpoint("search", RateLimitConfig::max_per_window(1, 60))
+            .with_store(store.clone())
+            .unwrap();
+        let search = limiter.endpoint("search");
+        for key in ["1", "::1", "alice", "org:alice"] {
+            limiter.check_rate_limit(key).await.unwrap();
+            search.check_rate_limit(key).await.unwrap();
+        }
+
+        limiter.purge_key("1").await;
+        limiter.purge_key("alice").await;
+        for key in ["1", "alice"] {
+            assert!(limiter.check_rate_limit(key).await.is_ok());
+            assert!(search.check_rate_limit(key).await.is_ok());
+        }
+        for key in ["::1", "org:alice"] {
+            assert!(limiter.check_rate_limit(key).await.is_err());
+            assert!(search.check_rate_limit(key).await.is_err());
+        }
+        assert!(store.get("api:search:org:alice").await.unwrap().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_borrowing_costs_the_next_window() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60).with_borrowing(5, 100));
+        for _ in 0..15 {
+            limiter.check_rate_limit("a").await.unwrap();
+        }
+        assert!(limiter.check_rate_limit("a").await.is_err());
+
+        // The next window pays back the 5 borrowed plus 5 interest, and
+        // can't borrow again while it does
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap_err().limit, 0);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().limit, 10);
+        assert_eq!(RateLimitConfig::max_per_window(10, 60).with_borrowing(5, 50).borrowing.unwrap().repayment(3), 5);
+    }
+
+    #[tokio::test]
+    async fn test_candidate_applies_to_its_share_of_keys() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(3, 60).with_namespace("api"));
+        assert_eq!(limiter.rollout_stats(), None);
+        limiter.set_candidate(RateLimitConfig::max_per_window(1, 60), 50);
+
+        let keys: Vec<_> = (0..40).map(|i| format!("key-{}", i)).collect();
+        let mut limits = Vec::new();
+        for key in &keys {
+            limits.push(limiter.check_rate_limit(key).await.unwrap().limit);
+            limiter.check_rate_limit(key).await.ok();
+        }
+        assert!(limits.contains(&1) && limits.contains(&3));
+        let stats = limiter.rollout_stats().unwrap();
+        let candidates = limits.iter().filter(|&&limit| limit == 1).count() as u64;
+        assert_eq!(stats.candidate, ArmStats { admitted: candidates, rejected: candidates });
+        assert_eq!(stats.control.rejected, 0);
+
+        // Keys keep their counts when the rollout ends
+        limiter.clear_candidate();
+        let info = limiter.check_rate_limit(&keys[limits.iter().position(|&limit| limit == 1).unwrap()]).await.unwrap();
+        assert_eq!((info.limit, info.remaining), (3, 1));
+    }
+
+    #[tokio::test]
+    async fn test_overage_is_admitted_and_reported() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(2, 60).with_overage(3))
+            .on_event(move |event| seen.lock().unwrap().push(event.clone()));
+
+        for _ in 0..2 {
+            limiter.check_rate_limit("a").await.unwrap();
+        }
+        assert!(events.lock().unwrap().is_empty());
+
+        let info = limiter.check_rate_limit_with_cost("a", 2).await.unwrap();
+        assert_eq!(info.remaining, 0);
+        assert!(limiter.check_rate_limit_with_cost("a", 2).await.is_err());
+        limiter.check_rate_limit("a").await.unwrap();
+        assert!(limiter.check_rate_limit("a").await.is_err());
+
+        let units: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                RateLimitEvent::Overage { key, units, period_start, period_end, .. } => {
+                    assert_eq!(key, "a");
+                    assert_eq!(*period_end - *period_start, ChronoDuration::seconds(60));
+                    Some(*units)
+                }
+                RateLimitEvent::Rejected { .. } => None,
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect();
+        assert_eq!(units, vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_layered_endpoints_follow_the_base() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60))
+            .with_endpoint_overrides("search", ConfigOverrides::new().with_max_requests(1));
+        let info = limiter.endpoint("search").check_rate_limit("a").await.unwrap();
+        assert_eq!((info.limit, info.window), (1, Duration::from_secs(60)));
+
+        limiter.set_config(RateLimitConfig::max_per_window(5, 3600).with_header_style(HeaderStyle::Draft));
+        let info = limiter.endpoint("search").check_rate_limit("b").await.unwrap();
+        assert_eq!((info.limit, info.window, info.header_style), (1, Duration::from_secs(3600), HeaderStyle::Draft));
+        assert_eq!(limiter.policy_document()["endpoints"]["search"]["window_seconds"], 3600);
+    }
+
+    #[tokio::test]
+    async fn test_adjust_charges_and_refunds() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60));
+        limiter.check_rate_limit("a").await.unwrap();
+
+        assert_eq!(limiter.adjust("a", 7).await.remaining, 2);
+        assert_eq!(limiter.adjust("a", -3).await.remaining, 5);
+        assert_eq!(limiter.adjust("a", 0).await.used, 5);
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 4);
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_share_a_store() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60))
+            .with_endpoint("search", RateLimitConfig::max_per_window(1, 60))
+            .with_endpoint("status", RateLimitConfig::max_per_window(3, 60));
+
+        assert!(limiter.endpoint("search").check_rate_limit("a").await.is_ok());
+        assert!(limiter.endpoint("search").check_rate_limit("a").await.is_err());
+        assert_eq!(limiter.endpoint("status").check_rate_limit("a").await.unwrap().remaining, 2);
+        // Unconfigured labels fall back to the limiter's own config
+        assert_eq!(limiter.endpoint("other").check_rate_limit("a").await.unwrap().remaining, 4);
+
+        assert_eq!(limiter.local_counts().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_rides_along() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60).with_namespace("api"))
+            .on_event(move |event| recorded.lock().unwrap().push(event.clone()));
+        let metadata = KeyMetadata::from([("plan".to_string(), "pro".to_string())]);
+        limiter.set_metadata("a", metadata.clone());
+
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().metadata, metadata);
+        assert!(limiter.check_rate_limit("b").await.unwrap().metadata.is_empty());
+        let rejection = limiter.check_rate_limit("a").await.unwrap_err();
+        assert_eq!(get_rate_limit_info(&rejection).metadata, metadata);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![RateLimitEvent::Rejected {
+                key: "api:a".to_string(),
+                code: RateLimitErrorCode::RateLimited,
+                metadata: metadata.clone(),
+            }]
+        );
+
+        let usage: Vec<_> = limiter.iter_usage().map(|usage| usage.metadata).collect();
+        assert_eq!(usage, vec![metadata, KeyMetadata::new()]);
+
+        limiter.set_metadata("a", KeyMetadata::new());
+        assert!(limiter.peek("a").await.metadata.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_metadata_is_forgotten_with_its_windows() {
+        let (usage, rollups) = (UsageLedger::new(), UsageRollups::new());
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60).with_namespace("api"))
+            .with_usage_ledger(usage.clone())
+            .with_usage_rollups(rollups.clone())
+            .with_max_metadata_keys(2);
+        let pro = KeyMetadata::from([("plan".to_string(), "pro".to_string())]);
+        assert!(limiter.set_metadata("a", pro.clone()));
+        assert!(limiter.set_metadata("b", pro.clone()));
+        assert!(!limiter.set_metadata("c", pro.clone()));
+        assert!(limiter.metadata("c").is_empty());
+
+        limiter.check_rate_limit("a").await.unwrap();
+        limiter.endpoint("search").check_rate_limit("b").await.unwrap();
+        assert_eq!(limiter.peek("a").await.metadata, pro);
+        let summaries = usage.take(wall_clock_now(), wall_clock_now());
+        assert!(summaries.iter().all(|summary| summary.metadata == pro), "{:?}", summaries);
+        let day = rollups.query("api:a", Rollup::Daily, wall_clock_now() - ChronoDuration::days(1), wall_clock_now());
+        assert_eq!(day[0].metadata, pro);
+
+        // b's window under the endpoint is still open when a's expires
+        tokio::time::advance(Duration::from_secs(90)).await;
+        limiter.endpoint("search").check_rate_limit("b").await.unwrap();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(limiter.purge_expired(), 1);
+        assert!(limiter.metadata("a").is_empty());
+        assert_eq!(limiter.metadata("b"), pro);
+        assert!(limiter.set_metadata("c", pro));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_iter_usage_pages_through_keys_in_order() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60));
+        for i in 0..USAGE_BATCH + 10 {
+            limiter.check_rate_limit(&format!("{:04}", i)).await.unwrap();
+        }
+        limiter.check_rate_limit_with_cost("0000", 3).await.unwrap();
+
+        let usage: Vec<_> = limiter.iter_usage().collect();
+        assert_eq!(usage.len(), USAGE_BATCH + 10);
+        assert!(usage.windows(2).all(|pair| pair[0].key < pair[1].key));
+        assert_eq!((usage[0].used, usage[0].remaining), (4, 1));
+        assert_eq!((usage[1].used, usage[1].remaining), (1, 4));
+
+        // Ended windows are left out
+        tokio::time::advance(std::time::Duration::from_secs(61)).await;
+        limiter.check_rate_limit("late").await.unwrap();
+        let keys: Vec<_> = limiter.iter_usage().map(|usage| usage.key).collect();
+        assert_eq!(keys, vec!["late"]);
+    }
+
+    #[tokio::test]
+    async fn test_iter_usage_reports_each_keys_own_limit() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60).with_namespace("api"))
+            .with_endpoint("search", RateLimitConfig::max_per_window(2, 60))
+            .with_quota_resolver(|key: &str| (key == "partner").then(|| RateLimitConfig::max_per_window(100, 60)));
+        limiter.check_rate_limit("anon").await.unwrap();
+        limiter.check_rate_limit("partner").await.unwrap();
+        limiter.endpoint("search").check_rate_limit("anon").await.unwrap();
+
+        let remaining: Vec<_> = limiter.iter_usage().map(|usage| (usage.key, usage.remaining)).collect();
+        assert_eq!(
+            remaining,
+            vec![
+                ("api:anon".to_string(), 4),
+                ("api:partner".to_string(), 99),
+                ("api:search:anon".to_string(), 1),
+            ]
+        );
+    }
+}
+
+#[cfg(all(test, rate_limit_loom))]
+mod loom_tests {
+    use super::*;
+
+    #[test]
+    fn test_last_request_admitted_once() {
+        loom::model(|| {
+            let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(1));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let limiter = limiter.clone();
+                    loom::thread::spawn(move || loom::future::block_on(limiter.check_rate_limit("a")).is_ok())
+                })
+                .collect();
+            let admitted = threads.into_iter().map(|t| t.join().unwrap()).filter(|&ok| ok).count();
+            assert_eq!(admitted, 1);
+        });
+    }
+}
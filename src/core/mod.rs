@@ -0,0 +1,96 @@
+//! Framework-agnostic rate limiting core: configuration, the limiter itself,
+//! and the info, header, and rejection types every adapter builds on. Nothing
+//! in this module depends on warp.
+
+#[cfg(feature = "signed-bypass")]
+mod bypass;
+mod blocking;
+mod challenge;
+mod cidr;
+mod clock;
+mod concurrency;
+#[cfg(feature = "tokio")]
+mod connection;
+mod config;
+mod error;
+mod event;
+mod extract;
+mod failover;
+mod global;
+#[cfg(feature = "hashed-keys")]
+mod hashed;
+mod identity;
+mod info;
+mod lease;
+mod limiter;
+#[cfg(feature = "openapi")]
+mod openapi;
+mod peer;
+mod penalty;
+mod pressure;
+#[cfg(feature = "tokio")]
+mod proxy;
+mod quota;
+mod rejection;
+mod replica;
+mod reputation;
+mod resolver;
+mod resource;
+mod rollout;
+mod rules;
+mod runtime;
+mod shard;
+mod store;
+mod sync;
+mod tenant;
+#[cfg(feature = "tokio")]
+mod throttle;
+mod usage;
+#[cfg(feature = "watch")]
+mod watch;
+
+#[cfg(feature = "signed-bypass")]
+pub use bypass::*;
+pub use blocking::*;
+pub use challenge::*;
+pub use cidr::*;
+pub use clock::*;
+pub use concurrency::*;
+#[cfg(feature = "tokio")]
+pub use connection::*;
+pub use config::*;
+pub use error::*;
+pub use event::*;
+pub use extract::*;
+pub use failover::*;
+pub use global::*;
+#[cfg(feature = "hashed-keys")]
+pub use hashed::*;
+pub use identity::*;
+pub use info::*;
+pub use lease::*;
+pub use limiter::*;
+#[cfg(feature = "openapi")]
+pub use openapi::*;
+pub use peer::*;
+pub use penalty::*;
+use pressure::PressureTracker;
+#[cfg(feature = "tokio")]
+pub use proxy::*;
+pub use quota::*;
+pub use rejection::*;
+pub use replica::*;
+pub use reputation::*;
+pub use resolver::*;
+pub use resource::*;
+pub use rollout::*;
+pub use rules::*;
+pub use runtime::*;
+pub use shard::*;
+pub use store::*;
+pub use tenant::*;
+#[cfg(feature = "tokio")]
+pub use throttle::*;
+pub use usage::*;
+#[cfg(feature = "watch")]
+pub use watch::*;
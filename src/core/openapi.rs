@@ -0,0 +1,141 @@
+//! OpenAPI schema types for rate limited responses, enabled by the `openapi`
+//! feature, so specs generated with utoipa document the limits for API
+//! consumers
+//!
+//! ```rust,no_run,ignore
+//! #[utoipa::path(get, path = "/search", responses((status = 200, body = [Hit]), TooManyRequests))]
+//! async fn search() { ... }
+//!
+//! #[derive(OpenApi)]
+//! #[openapi(paths(search), components(schemas(RateLimitExceededBody, RateLimitStatusBody)))]
+//! struct ApiDoc;
+//! ```
+
+use utoipa::openapi::header::{Header, HeaderBuilder};
+use utoipa::openapi::{KnownFormat, ObjectBuilder, SchemaFormat, SchemaType};
+use utoipa::{IntoResponses, ToSchema};
+
+use super::{HeaderStyle, RateLimitErrorCode};
+
+/// The body of a `429 Too Many Requests` response, as built by
+/// `RateLimitInfo::to_json_body`
+#[derive(Clone, Debug, ToSchema)]
+pub struct RateLimitExceededBody {
+    /// `"Rate limit exceeded"`, or `"Down for maintenance"`
+    #[schema(example = "Rate limit exceeded")]
+    pub error: String,
+    /// Why the request was rejected
+    pub code: RateLimitErrorCode,
+    /// Requests allowed per window
+    #[schema(example = 60)]
+    pub limit: u32,
+    /// Requests left in the window, always 0
+    #[schema(example = 0)]
+    pub remaining: u32,
+    /// Seconds until the client may retry
+    #[schema(example = 42)]
+    pub retry_after_seconds: i64,
+    /// Unix timestamp at which the window resets
+    #[schema(example = 1704067260)]
+    pub reset: i64,
+    /// How the limit's windows reset
+    #[schema(example = "rolling")]
+    pub reset_mode: String,
+}
+
+/// The body served by the rate limit status route, as built by
+/// `RateLimitInfo::to_status_body`
+#[derive(Clone, Debug, ToSchema)]
+pub struct RateLimitStatusBody {
+    /// Requests allowed per window
+    #[schema(example = 60)]
+    pub limit: u32,
+    /// Requests left in the window
+    #[schema(example = 18)]
+    pub remaining: u32,
+    /// Requests used in the window
+    #[schema(example = 42)]
+    pub used: u32,
+    /// Unix timestamp at which the window resets
+    #[schema(example = 1704067260)]
+    pub reset: i64,
+    /// Seconds until the window resets
+    #[schema(example = 37)]
+    pub reset_in_seconds: i64,
+}
+
+/// A `429 Too Many Requests` response with the default (`Legacy`) header
+/// set, for the `responses` of a `#[utoipa::path]`
+#[derive(Clone, Debug, IntoResponses)]
+#[response(
+    status = 429,
+    description = "Rate limit exceeded",
+    headers(
+        ("Retry-After" = String, description = "When the client may retry, as an HTTP date or in seconds"),
+        ("X-RateLimit-Limit" = u32, description = "Requests allowed per window"),
+        ("X-RateLimit-Remaining" = u32, description = "Requests left in the window"),
+        ("X-RateLimit-Reset" = i64, description = "Unix timestamp at which the window resets"),
+    )
+)]
+pub struct TooManyRequests(pub RateLimitExceededBody);
+
+/// The rate limit headers sent in `style`, for documenting responses built
+/// by hand. Every style sends `Retry-After`.
+pub fn openapi_headers(style: HeaderStyle) -> Vec<(&'static str, Header)> {
+    let integer = |description: &str, format: KnownFormat| {
+        HeaderBuilder::new()
+            .schema(ObjectBuilder::new().schema_type(SchemaType::Integer).format(Some(SchemaFormat::KnownFormat(format))))
+            .description(Some(description))
+            .build()
+    };
+    let retry_after = HeaderBuilder::new()
+        .schema(ObjectBuilder::new().schema_type(SchemaType::String))
+        .description(Some("When the client may retry, as an HTTP date or in seconds"))
+        .build();
+
+    let mut headers = vec![("Retry-After", retry_after)];
+    if style.sends_legacy() {
+        headers.push(("X-RateLimit-Limit", integer("Requests allowed per window", KnownFormat::Int32)));
+        headers.push(("X-RateLimit-Remaining", integer("Requests left in the window", KnownFormat::Int32)));
+        if style == HeaderStyle::GitHub {
+            headers.push(("X-RateLimit-Used", integer("Requests used in the window", KnownFormat::Int32)));
+        }
+        headers.push((
+            "X-RateLimit-Reset",
+            integer("Unix timestamp at which the window resets", KnownFormat::Int64),
+        ));
+    }
+    if style.sends_draft() {
+        headers.push(("RateLimit-Limit", integer("Requests allowed per window", KnownFormat::Int32)));
+        headers.push(("RateLimit-Remaining", integer("Requests left in the window", KnownFormat::Int32)));
+        headers.push(("RateLimit-Reset", integer("Seconds until the window resets", KnownFormat::Int64)));
+        let policy = HeaderBuilder::new()
+            .schema(ObjectBuilder::new().schema_type(SchemaType::String))
+            .description(Some("The policy as `limit;w=window`"))
+            .build();
+        headers.push(("RateLimit-Policy", policy));
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utoipa::openapi::RefOr;
+
+    #[test]
+    fn test_schemas_document_the_rate_limit() {
+        let responses = TooManyRequests::responses();
+        let RefOr::T(response) = &responses["429"] else {
+            panic!("expected an inline 429 response");
+        };
+        assert!(response.headers.contains_key("X-RateLimit-Reset"));
+        assert!(response.content.contains_key("application/json"));
+
+        let (name, _) = RateLimitExceededBody::schema();
+        assert_eq!(name, "RateLimitExceededBody");
+
+        let names: Vec<_> = openapi_headers(HeaderStyle::Draft).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Retry-After", "RateLimit-Limit", "RateLimit-Remaining", "RateLimit-Reset", "RateLimit-Policy"]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Eventually-consistent sharing of counts between instances that have no
+//! shared store. Each instance periodically publishes its local per-key
+//! counts; the others keep the latest count per key and node until that
+//! node's window ends, and add them to their own when checking a request.
+//!
+//! Per node, counts only grow within a window and merging takes the maximum,
+//! so this is a G-counter that resets each window: snapshots can arrive late,
+//! twice, or out of order without double counting. Limits are approximate,
+//! since requests made between two snapshots aren't seen by other instances.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// One instance's counts at a point in time, as exchanged between peers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountSnapshot {
+    /// The publishing instance's node ID
+    pub node: String,
+    /// Counts for every key with an open window on that instance
+    pub counts: Vec<KeyCount>,
+}
+
+/// A key's count in its current window on one instance
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyCount {
+    /// The rate limit key
+    pub key: String,
+    /// Units used in the window
+    pub count: u32,
+    /// Unix timestamp at which the window ends
+    pub reset: i64,
+}
+
+impl CountSnapshot {
+    /// The snapshot as JSON in pieces of at most `max_bytes` each, for
+    /// transports that cap message size. Each piece is a self-contained
+    /// snapshot from the same node holding some of the counts, so a lost
+    /// piece only delays its keys. A count too large for a piece of its own
+    /// is left out with a warning.
+    pub fn encode_split(&self, max_bytes: usize) -> Vec<Vec<u8>> {
+        let encode = |counts: Vec<KeyCount>| {
+            let piece = CountSnapshot {
+                node: self.node.clone(),
+                counts,
+            };
+            serde_json::to_vec(&piece).expect("count snapshots always encode")
+        };
+        // The snapshot with no counts, to which each count adds its own
+        // length and a comma after the first
+        let empty = encode(Vec::new()).len();
+        let mut pieces = Vec::new();
+        let mut piece = Vec::new();
+        let mut size = empty;
+        for count in &self.counts {
+            let len = serde_json::to_vec(count).expect("counts always encode").len();
+            if empty + len > max_bytes {
+                tracing::warn!("leaving {} out of count snapshots: too large for {} bytes", count.key, max_bytes);
+                continue;
+            }
+            if size + len + usize::from(!piece.is_empty()) > max_bytes {
+                pieces.push(encode(std::mem::take(&mut piece)));
+                size = empty;
+            }
+            size += len + usize::from(!piece.is_empty());
+            piece.push(count.clone());
+        }
+        if !piece.is_empty() {
+            pieces.push(encode(piece));
+        }
+        pieces
+    }
+}
+
+/// Per node, the count and window end last reported for one key
+type NodeCounts = HashMap<String, (u32, i64)>;
+
+/// Counts received from other instances. Attach it to a limiter with
+/// [`RateLimiter::with_peer_counts`](crate::RateLimiter::with_peer_counts),
+/// and feed it snapshots from whichever transport connects the instances.
+/// Cloning a `PeerCounts` is cheap and the clones share their state.
+#[derive(Clone, Debug)]
+pub struct PeerCounts {
+    node: String,
+    state: Arc<Mutex<HashMap<String, NodeCounts>>>,
+}
+
+impl PeerCounts {
+    /// Track peer counts on behalf of the instance identified by `node`,
+    /// which must be unique across the cluster
+    pub fn new(node: impl Into<String>) -> Self {
+        Self {
+            node: node.into(),
+            state: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// This instance's node ID
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Merges a snapshot from a peer. Snapshots published by this node are
+    /// ignored, so transports that echo messages back need no filtering.
+    pub fn merge(&self, snapshot: &CountSnapshot) {
+        if snapshot.node == self.node {
+            return;
+        }
+
+        let now = Utc::now().timestamp();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for KeyCount { key, count, reset } in &snapshot.counts {
+            if *reset <= now {
+                continue;
+            }
+            let slot = state
+                .entry(key.clone())
+                .or_default()
+                .entry(snapshot.node.clone())
+                .or_insert((0, *reset));
+            if *reset > slot.1 {
+                // A newer window replaces the old one outright
+                *slot = (*count, *reset);
+            } else if *reset == slot.1 {
+                slot.0 = slot.0.max(*count);
+            }
+        }
+
+        state.retain(|_, nodes| {
+            nodes.retain(|_, (_, reset)| *reset > now);
+            !nodes.is_empty()
+        });
+    }
+
+    /// Units used by `key` on all other instances in their current windows
+    pub fn total(&self, key: &str) -> u32 {
+        let now = Utc::now().timestamp();
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.get(key).map_or(0, |nodes| {
+            nodes
+                .values()
+                .filter(|(_, reset)| *reset > now)
+                .fold(0u32, |total, (count, _)| total.saturating_add(*count))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_is_idempotent_and_ignores_self() {
+        let peers = PeerCounts::new("a");
+        let reset = Utc::now().timestamp() + 60;
+        let snapshot = |node: &str, count| CountSnapshot {
+            node: node.to_string(),
+            counts: vec![KeyCount {
+                key: "client".to_string(),
+                count,
+                reset,
+            }],
+        };
+
+        peers.merge(&snapshot("b", 3));
+        peers.merge(&snapshot("b", 3));
+        peers.merge(&snapshot("c", 2));
+        assert_eq!(peers.total("client"), 5);
+
+        // Stale snapshots don't lower a node's count; our own are ignored
+        peers.merge(&snapshot("b", 1));
+        peers.merge(&snapshot("a", 10));
+        assert_eq!(peers.total("client"), 5);
+        assert_eq!(peers.total("other"), 0);
+    }
+
+    #[test]
+    fn test_snapshots_split_by_encoded_size() {
+        let reset = Utc::now().timestamp() + 60;
+        let snapshot = CountSnapshot {
+            node: "a".to_string(),
+            counts: (0..50)
+                .map(|i| KeyCount {
+                    key: format!("{}-{}", "k".repeat(i * 40), i),
+                    count: 1,
+                    reset,
+                })
+                .collect(),
+        };
+
+        let pieces = snapshot.encode_split(1_400);
+        assert!(pieces.len() > 1);
+        let mut counts = Vec::new();
+        for piece in &pieces {
+            assert!(piece.len() <= 1_400, "{} bytes", piece.len());
+            let piece: CountSnapshot = serde_json::from_slice(piece).unwrap();
+            assert_eq!(piece.node, "a");
+            counts.extend(piece.counts);
+        }
+        // Every key fits but the ones too long for a piece on their own
+        let fitting: Vec<_> = snapshot.counts.iter().filter(|count| count.key.len() < 1_340).cloned().collect();
+        assert_eq!(counts, fitting);
+        assert!(fitting.len() < snapshot.counts.len());
+    }
+}
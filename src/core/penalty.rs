@@ -0,0 +1,49 @@
+//! Response-status penalties, e.g. for brute-force protection on login routes
+
+use http::StatusCode;
+use std::collections::HashMap;
+
+/// Maps response statuses to the number of units a request costs once its
+/// response is known. Statuses without a penalty cost a single unit.
+///
+/// ```rust,no_run,ignore
+/// // Failed logins count ten times as much as successful ones
+/// let penalty = StatusPenalty::new()
+///     .penalize(StatusCode::UNAUTHORIZED, 10)
+///     .penalize(StatusCode::FORBIDDEN, 10);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusPenalty {
+    costs: HashMap<StatusCode, u32>,
+}
+
+impl StatusPenalty {
+    /// An empty set of penalties: every status costs one unit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charge `cost` units for requests answered with `status`
+    pub fn penalize(mut self, status: StatusCode, cost: u32) -> Self {
+        self.costs.insert(status, cost);
+        self
+    }
+
+    /// The total cost of a request answered with `status`
+    pub fn cost(&self, status: StatusCode) -> u32 {
+        self.costs.get(&status).copied().unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_costs() {
+        let penalty = StatusPenalty::new().penalize(StatusCode::UNAUTHORIZED, 10);
+
+        assert_eq!(penalty.cost(StatusCode::UNAUTHORIZED), 10);
+        assert_eq!(penalty.cost(StatusCode::OK), 1);
+    }
+}
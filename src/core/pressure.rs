@@ -0,0 +1,160 @@
+//! A load signal for autoscalers and load balancers: how much of the
+//! traffic a limiter has seen lately it turned away
+
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::sync::{AtomicUsize, Mutex, Ordering};
+
+/// How far back [`PressureTracker`] looks
+const PERIOD: Duration = Duration::from_secs(10);
+
+/// Decisions in one period, as (admitted, rejected)
+type Counts = (u64, u64);
+
+/// Admitted and rejected requests over a sliding period, and requests held
+/// in the tarpit right now
+#[derive(Debug)]
+pub(crate) struct PressureTracker {
+    buckets: Mutex<Buckets>,
+    held: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    started: Instant,
+    current: Counts,
+    previous: Counts,
+}
+
+impl Buckets {
+    /// Moves the current bucket along so it contains `now`
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed < PERIOD {
+            return;
+        }
+        self.previous = if elapsed < PERIOD * 2 { self.current } else { (0, 0) };
+        self.current = (0, 0);
+        let periods = (elapsed.as_nanos() / PERIOD.as_nanos()) as u32;
+        self.started += PERIOD * periods;
+    }
+}
+
+impl PressureTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                started: Instant::now(),
+                current: (0, 0),
+                previous: (0, 0),
+            }),
+            held: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, admitted: bool) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.advance(Instant::now());
+        if admitted {
+            buckets.current.0 += 1;
+        } else {
+            buckets.current.1 += 1;
+        }
+    }
+
+    /// The share of recent requests that were rejected, weighting the
+    /// previous period by how much of it is still inside the sliding period
+    pub(crate) fn rejection_rate(&self) -> f32 {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.advance(now);
+        let weight = 1.0 - now.duration_since(buckets.started).as_secs_f64() / PERIOD.as_secs_f64();
+        let admitted = buckets.current.0 as f64 + buckets.previous.0 as f64 * weight;
+        let rejected = buckets.current.1 as f64 + buckets.previous.1 as f64 * weight;
+        if admitted + rejected == 0.0 {
+            return 0.0;
+        }
+        (rejected / (admitted + rejected)) as f32
+    }
+
+    /// Counts a request as held until the returned guard is dropped, or
+    /// `None` if `max` requests are held already
+    pub(crate) fn try_hold(&self, max: usize) -> Option<HeldRequest<'_>> {
+        self.held
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| (held < max).then_some(held + 1))
+            .ok()?;
+        Some(HeldRequest(&self.held))
+    }
+
+    pub(crate) fn held(&self) -> usize {
+        self.held.load(Ordering::Relaxed)
+    }
+}
+
+/// A request held in the tarpit, released on drop even if the caller gives
+/// up on it
+pub(crate) struct HeldRequest<'a>(&'a AtomicUsize);
+
+impl Drop for HeldRequest<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rejection_rate_slides() {
+        let tracker = PressureTracker::new();
+        assert_eq!(tracker.rejection_rate(), 0.0);
+
+        tracker.record(true);
+        tracker.record(false);
+        assert_eq!(tracker.rejection_rate(), 0.5);
+
+        // Halfway through the next period, the old decisions count half
+        tokio::time::advance(PERIOD + PERIOD / 2).await;
+        tracker.record(true);
+        assert_eq!(tracker.rejection_rate(), 0.25);
+
+        tokio::time::advance(PERIOD * 3).await;
+        assert_eq!(tracker.rejection_rate(), 0.0);
+
+        let held = tracker.try_hold(1).unwrap();
+        assert_eq!(tracker.held(), 1);
+        assert!(tracker.try_hold(1).is_none());
+        drop(held);
+        assert_eq!(tracker.held(), 0);
+    }
+}
+
+#[cfg(all(test, rate_limit_loom))]
+mod loom_tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_concurrent_decisions_are_all_counted() {
+        loom::model(|| {
+            let tracker = Arc::new(PressureTracker::new());
+            let threads: Vec<_> = [true, false]
+                .into_iter()
+                .map(|admitted| {
+                    let tracker = tracker.clone();
+                    loom::thread::spawn(move || {
+                        let _held = tracker.try_hold(usize::MAX);
+                        tracker.record(admitted);
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(tracker.rejection_rate(), 0.5);
+            assert_eq!(tracker.held(), 0);
+        });
+    }
+}
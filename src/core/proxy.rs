@@ -0,0 +1,153 @@
+//! HAProxy PROXY protocol (v1 and v2) support, so deployments behind TCP load
+//! balancers key on the true client address rather than the balancer's
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+/// The client address reported by a PROXY protocol header.
+///
+/// Insert it into the request extensions from your accept loop, after
+/// [`read_proxy_header`], and key on it with `key::proxied_ip()`:
+///
+/// ```rust,no_run,ignore
+/// let (mut stream, _) = listener.accept().await?;
+/// let client = read_proxy_header(&mut stream).await?;
+/// let svc = warp::service(routes.clone());
+/// let svc = hyper::service::service_fn(move |mut req| {
+///     if let Some(addr) = client {
+///         req.extensions_mut().insert(ProxiedAddr(addr));
+///     }
+///     svc.call(req)
+/// });
+/// hyper::server::conn::Http::new().serve_connection(stream, svc).await?;
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxiedAddr(pub SocketAddr);
+
+impl fmt::Display for ProxiedAddr {
+    /// Renders the IP only, so a client's budget is shared across its ports
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.ip())
+    }
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`,
+/// consuming exactly the header's bytes so the rest of the stream can be
+/// handed to the HTTP server.
+///
+/// Returns the source address, or `None` for headers that carry no address
+/// (v1 `UNKNOWN`, v2 `LOCAL` health checks, non-IP families). A stream that
+/// doesn't start with a valid header is an `InvalidData` error: the protocol
+/// requires the header, and guessing would let clients spoof their address.
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long, so this never over-reads
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if prefix.starts_with(b"PROXY ") {
+        read_v1(stream, &prefix).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, prefix: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection, e.g. a health check
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("truncated PROXY v2 address block")),
+        // AF_UNSPEC and AF_UNIX carry no IP to key on
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_read_proxy_headers() {
+        let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        let addr = read_proxy_header(&mut v1).await.unwrap().unwrap();
+        assert_eq!(addr, "203.0.113.7:51234".parse().unwrap());
+        assert_eq!(ProxiedAddr(addr).to_string(), "203.0.113.7");
+        // Only the header is consumed
+        let mut rest = String::new();
+        v1.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut unknown).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12, 198, 51, 100, 9, 10, 0, 0, 1, 0x1f, 0x90, 0x01, 0xbb]);
+        v2.extend_from_slice(b"GET");
+        let mut v2: &[u8] = &v2;
+        let addr = read_proxy_header(&mut v2).await.unwrap().unwrap();
+        assert_eq!(addr, "198.51.100.9:8080".parse().unwrap());
+        assert_eq!(v2, b"GET");
+
+        let mut missing: &[u8] = b"GET / HTTP/1.1\r\n";
+        let err = read_proxy_header(&mut missing).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
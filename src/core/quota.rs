@@ -0,0 +1,268 @@
+//! Byte quotas per key per window, counted apart from request limits, e.g.
+//! 100 MB of uploads per day on a free tier, or 1 GB of exports
+
+use chrono::{DateTime, Utc};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Response, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::{seconds_until, wall_clock_at, RateLimitErrorCode};
+
+/// Which bytes a [`ByteQuota`] counts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaKind {
+    /// Request body bytes read from the client
+    Upload,
+    /// Response body bytes sent to the client
+    Download,
+}
+
+impl QuotaKind {
+    /// The word used in this kind's header names and messages
+    fn as_str(&self) -> &'static str {
+        match self {
+            QuotaKind::Upload => "upload",
+            QuotaKind::Download => "download",
+        }
+    }
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A key's usage of a byte quota in its current window
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaInfo {
+    /// Which bytes are counted
+    pub kind: QuotaKind,
+    /// Bytes allowed per window
+    pub limit: u64,
+    /// Bytes used in the current window
+    pub used: u64,
+    /// When the current window ends
+    pub reset: DateTime<Utc>,
+}
+
+impl QuotaInfo {
+    /// Bytes left in the current window
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+
+    /// The quota headers, e.g. `X-RateLimit-Upload-Limit`,
+    /// `X-RateLimit-Upload-Remaining`, and `X-RateLimit-Upload-Reset` as a
+    /// Unix timestamp
+    pub fn to_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let values = [
+            ("limit", self.limit.to_string()),
+            ("remaining", self.remaining().to_string()),
+            ("reset", self.reset.timestamp().to_string()),
+        ];
+        for (field, value) in values {
+            let name = format!("x-ratelimit-{}-{}", self.kind, field);
+            // Names and values built from numbers are always valid
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+}
+
+/// Rejection produced when a key has used up a byte quota
+#[derive(Clone, Debug)]
+pub struct QuotaRejection {
+    /// The key's usage, at or past the limit
+    pub info: QuotaInfo,
+}
+
+impl QuotaRejection {
+    /// The error code for an exhausted quota, `quota_exceeded`
+    pub fn code(&self) -> RateLimitErrorCode {
+        RateLimitErrorCode::QuotaExceeded
+    }
+}
+
+/// Builds a `429 Too Many Requests` response naming the exhausted quota,
+/// with the quota headers and `Retry-After` in seconds
+impl<B: From<String>> From<&QuotaRejection> for Response<B> {
+    fn from(rejection: &QuotaRejection) -> Self {
+        let info = &rejection.info;
+        let mut response = Response::new(B::from(format!(
+            "Rate limit exceeded: {} quota of {} bytes used up. Try again in {} seconds.",
+            info.kind,
+            info.limit,
+            seconds_until(info.reset)
+        )));
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        let headers = response.headers_mut();
+        headers.extend(info.to_headers());
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds_until(info.reset)));
+        response
+    }
+}
+
+/// Bytes each key has used in its window
+#[derive(Clone, Copy, Debug)]
+struct Usage {
+    start: Instant,
+    used: u64,
+}
+
+/// Every key's usage, and when keys past their window were last swept
+#[derive(Debug)]
+struct Usages {
+    keys: HashMap<String, Usage>,
+    swept: Instant,
+}
+
+impl Usages {
+    /// Forgets keys whose window has ended, returning how many
+    fn purge_expired(&mut self, now: Instant, window: Duration) -> usize {
+        let before = self.keys.len();
+        self.keys.retain(|_, usage| now.duration_since(usage.start) < window);
+        self.swept = now;
+        before - self.keys.len()
+    }
+}
+
+/// Caps the bytes each key may transfer per window. Cloning a `ByteQuota` is
+/// cheap and the clones share their counters.
+#[derive(Clone, Debug)]
+pub struct ByteQuota {
+    kind: QuotaKind,
+    max_bytes: u64,
+    window: Duration,
+    state: Arc<Mutex<Usages>>,
+}
+
+impl ByteQuota {
+    /// Allow each key `max_bytes` of request bodies per `window`
+    pub fn upload(max_bytes: u64, window: Duration) -> Self {
+        Self::new(QuotaKind::Upload, max_bytes, window)
+    }
+
+    /// Allow each key `max_bytes` of response bodies per `window`
+    pub fn download(max_bytes: u64, window: Duration) -> Self {
+        Self::new(QuotaKind::Download, max_bytes, window)
+    }
+
+    fn new(kind: QuotaKind, max_bytes: u64, window: Duration) -> Self {
+        Self {
+            kind,
+            max_bytes,
+            window,
+            state: Arc::new(Mutex::new(Usages {
+                keys: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Which bytes this quota counts
+    pub fn kind(&self) -> QuotaKind {
+        self.kind
+    }
+
+    /// `key`'s usage, rejecting if it has already used up its quota
+    pub fn check(&self, key: &str) -> Result<QuotaInfo, QuotaRejection> {
+        self.consume(key, 0)
+    }
+
+    /// Adds `bytes` to `key`'s usage. The bytes are counted even when they
+    /// push the key past its quota, since they have already been
+    /// transferred; the rejection tells the caller to stop.
+    pub fn consume(&self, key: &str, bytes: u64) -> Result<QuotaInfo, QuotaRejection> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // Keys past their window are swept at most once per window, so the
+        // scan is paid for by every request in it
+        if now.duration_since(state.swept) >= self.window {
+            state.purge_expired(now, self.window);
+        }
+        let usage = state.keys.entry(key.to_string()).or_insert(Usage { start: now, used: 0 });
+        if now.duration_since(usage.start) >= self.window {
+            *usage = Usage { start: now, used: 0 };
+        }
+        usage.used = usage.used.saturating_add(bytes);
+
+        let info = QuotaInfo {
+            kind: self.kind,
+            limit: self.max_bytes,
+            used: usage.used,
+            reset: wall_clock_at(usage.start + self.window),
+        };
+        if usage.used > self.max_bytes || (bytes == 0 && usage.used >= self.max_bytes) {
+            return Err(QuotaRejection { info });
+        }
+        Ok(info)
+    }
+
+    /// Forgets keys whose window has ended, returning how many. Keys are
+    /// also swept as requests come in, at most once per window; call this
+    /// from a periodic task to free memory when traffic stops.
+    pub fn purge_expired(&self) -> usize {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.purge_expired(Instant::now(), self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_quota() {
+        let quota = ByteQuota::upload(100, Duration::from_secs(60));
+
+        assert_eq!(quota.consume("a", 60).unwrap().remaining(), 40);
+        let rejection = quota.consume("a", 60).unwrap_err();
+        assert_eq!(rejection.info.used, 120);
+        assert!(quota.check("a").is_err());
+        assert!(quota.check("b").is_ok());
+
+        let response = Response::<String>::from(&rejection);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-upload-limit"], "100");
+        assert_eq!(response.headers()["x-ratelimit-upload-remaining"], "0");
+        assert!(response.body().contains("upload quota of 100 bytes"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_keys_are_swept_once_per_window() {
+        let window = Duration::from_secs(60);
+        let quota = ByteQuota::download(100, window);
+        let keys = || quota.state.lock().unwrap().keys.len();
+        quota.consume("x", 10).unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        quota.consume("a", 120).unwrap_err();
+        tokio::time::advance(Duration::from_secs(20)).await;
+        quota.consume("b", 10).unwrap();
+
+        // A window after the last sweep, keys past their window go
+        tokio::time::advance(Duration::from_secs(30)).await;
+        quota.consume("b", 10).unwrap();
+        assert_eq!(keys(), 2);
+
+        // Between sweeps, a key starts over once its window ends
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(quota.consume("a", 10).unwrap().used, 10);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        quota.consume("c", 10).unwrap();
+        assert_eq!(keys(), 3);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        quota.consume("c", 10).unwrap();
+        assert_eq!(keys(), 2);
+        tokio::time::advance(window).await;
+        assert_eq!(quota.purge_expired(), 2);
+    }
+}
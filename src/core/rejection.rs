@@ -0,0 +1,444 @@
+//! The rejection produced when a client exceeds its limit
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::{
+    add_rate_limit_headers, seconds_until, wall_clock_now, HeaderStyle, KeyMetadata, RateLimitInfo, ResetMode, RetryAfterFormat,
+};
+
+/// Why a request was turned away, as a stable code client SDKs can branch
+/// on instead of parsing messages
+///
+/// Serialized as `"rate_limited"`, `"quota_exceeded"`, `"banned"`,
+/// `"global_overload"`, `"maintenance"`, or `"store_unavailable"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[non_exhaustive]
+pub enum RateLimitErrorCode {
+    /// The key used up its budget for the window
+    #[default]
+    RateLimited,
+    /// The key used up a byte or usage quota
+    QuotaExceeded,
+    /// The key is blocked outright
+    Banned,
+    /// The service as a whole is at capacity, whatever the key's own usage
+    GlobalOverload,
+    /// The service is down for planned maintenance, answered with a 503
+    Maintenance,
+    /// The limiter's store couldn't be reached and its failure policy turns
+    /// requests away, answered with a 503
+    StoreUnavailable,
+}
+
+impl RateLimitErrorCode {
+    /// The code as serialized, e.g. `"rate_limited"`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitErrorCode::RateLimited => "rate_limited",
+            RateLimitErrorCode::QuotaExceeded => "quota_exceeded",
+            RateLimitErrorCode::Banned => "banned",
+            RateLimitErrorCode::GlobalOverload => "global_overload",
+            RateLimitErrorCode::Maintenance => "maintenance",
+            RateLimitErrorCode::StoreUnavailable => "store_unavailable",
+        }
+    }
+
+    /// The status a rejection with this code is answered with:
+    /// `503 Service Unavailable` for maintenance or an unreachable store,
+    /// `429 Too Many Requests` otherwise
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RateLimitErrorCode::Maintenance | RateLimitErrorCode::StoreUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl std::fmt::Display for RateLimitErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Custom rejection type for rate limiting
+///
+/// `reset_time` is the single source of truth for when the client may
+/// retry: it is computed once when the rejection is built, and Retry-After,
+/// the reset header, and [`RateLimitRejection::retry_after`] are all derived
+/// from it, so they never disagree.
+///
+/// Fields may be added in minor releases; build one outside the limiter with
+/// [`RateLimitRejection::new`] and the `with_*` setters.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RateLimitRejection {
+    /// Whole seconds until the rate limit resets, as of when the rejection
+    /// was built; kept in step with `reset_time` by the constructors
+    #[deprecated(note = "use `retry_after()`, which follows `reset_time`")]
+    pub retry_after: Duration,
+    /// Maximum requests allowed in the window
+    pub limit: u32,
+    /// Length of the rate limiting window
+    pub window: Duration,
+    /// When the rate limit resets
+    pub reset_time: DateTime<Utc>,
+    /// Format to use for Retry-After header
+    pub retry_after_format: RetryAfterFormat,
+    /// Which gateway's rate limit headers to emit
+    pub header_style: HeaderStyle,
+    /// How the limit's windows reset
+    pub reset_mode: ResetMode,
+    /// Why the request was rejected
+    pub code: RateLimitErrorCode,
+    /// The metadata attached to the rejected key
+    pub metadata: KeyMetadata,
+    /// The status to answer with in place of the code's own
+    pub status: Option<StatusCode>,
+}
+
+/// Constructors for building a rejection outside of the rate limiting filter
+impl RateLimitRejection {
+    /// Build a `RateLimitRejection` that resets `retry_after` from now. The
+    /// window defaults to `retry_after`; use `with_window` to override it.
+    pub fn new(retry_after: Duration, limit: u32) -> Self {
+        let reset_time = wall_clock_now() + ChronoDuration::from_std(retry_after).unwrap_or_else(|_| ChronoDuration::zero());
+        #[allow(deprecated)]
+        Self {
+            retry_after: seconds_left(reset_time),
+            limit,
+            window: retry_after,
+            reset_time,
+            retry_after_format: RetryAfterFormat::default(),
+            header_style: HeaderStyle::default(),
+            reset_mode: ResetMode::default(),
+            code: RateLimitErrorCode::default(),
+            metadata: KeyMetadata::new(),
+            status: None,
+        }
+    }
+
+    /// Set the format used for the Retry-After header
+    pub fn with_retry_after_format(mut self, retry_after_format: RetryAfterFormat) -> Self {
+        self.retry_after_format = retry_after_format;
+        self
+    }
+
+    /// Set which gateway's rate limit headers to emit
+    pub fn with_header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.header_style = header_style;
+        self
+    }
+
+    /// Set the error code reported for this rejection
+    pub fn with_code(mut self, code: RateLimitErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Set how the limit's windows reset
+    pub fn with_reset_mode(mut self, reset_mode: ResetMode) -> Self {
+        self.reset_mode = reset_mode;
+        self
+    }
+
+    /// Set the metadata attached to the rejected key
+    pub fn with_metadata(mut self, metadata: KeyMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Answer with `status` rather than the code's own (see
+    /// [`RateLimitErrorCode::status`])
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// The status this rejection is answered with
+    pub fn status(&self) -> StatusCode {
+        self.status.unwrap_or_else(|| self.code.status())
+    }
+
+    /// Set the length of the rate limiting window
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set the instant at which the rate limit resets
+    pub fn with_reset_time(mut self, reset_time: DateTime<Utc>) -> Self {
+        self.reset_time = reset_time;
+        #[allow(deprecated)]
+        {
+            self.retry_after = seconds_left(reset_time);
+        }
+        self
+    }
+
+    /// Time left until the rate limit resets
+    pub fn retry_after(&self) -> Duration {
+        (self.reset_time - wall_clock_now()).to_std().unwrap_or(Duration::ZERO)
+    }
+
+    /// The standard JSON body (see [`RateLimitInfo::to_json_body`]) with
+    /// this rejection's error code
+    pub fn to_json_body(&self) -> serde_json::Value {
+        if self.code == RateLimitErrorCode::Banned {
+            return serde_json::json!({ "error": "Access denied", "code": self.code });
+        }
+        let mut body = get_rate_limit_info(self).to_json_body();
+        body["code"] = serde_json::json!(self.code);
+        if self.code == RateLimitErrorCode::Maintenance {
+            body["error"] = serde_json::json!("Down for maintenance");
+        } else if self.code == RateLimitErrorCode::StoreUnavailable {
+            body["error"] = serde_json::json!("Service unavailable");
+        }
+        body
+    }
+
+    /// Builds the complete response with the [`to_json_body`](Self::to_json_body)
+    /// body in place of the plain-text one `Response::from` gives
+    pub fn to_json_response<B: From<String>>(&self) -> Response<B> {
+        if self.code == RateLimitErrorCode::Banned {
+            return bare_response(self.status(), self.to_json_body().to_string(), JSON);
+        }
+        let info = get_rate_limit_info(self);
+        rejection_response(self.status(), &info, self.to_json_body().to_string(), JSON)
+    }
+}
+
+/// The whole seconds left until `reset_time`, as Retry-After reports them
+fn seconds_left(reset_time: DateTime<Utc>) -> Duration {
+    Duration::from_secs(seconds_until(reset_time) as u64)
+}
+
+const PLAIN_TEXT: &str = "text/plain; charset=utf-8";
+const JSON: &str = "application/json";
+
+/// The canonical `429 Too Many Requests` for a client whose status is
+/// `info`: `Retry-After`, the rate limit headers, and a plain-text body.
+/// For handlers that track the limit themselves rather than holding a
+/// [`RateLimitRejection`].
+pub fn too_many_requests<B: From<String>>(info: &RateLimitInfo) -> Response<B> {
+    let body = format!("Rate limit exceeded. Try again after {}.", info.retry_after);
+    rejection_response(StatusCode::TOO_MANY_REQUESTS, info, body, PLAIN_TEXT)
+}
+
+/// [`too_many_requests`] with the [`RateLimitInfo::to_json_body`] body
+pub fn too_many_requests_json<B: From<String>>(info: &RateLimitInfo) -> Response<B> {
+    rejection_response(StatusCode::TOO_MANY_REQUESTS, info, info.to_json_body().to_string(), JSON)
+}
+
+/// A response turning a client away with `body`, carrying `info`'s headers
+fn rejection_response<B: From<String>>(
+    status: StatusCode,
+    info: &RateLimitInfo,
+    body: String,
+    content_type: &'static str,
+) -> Response<B> {
+    let mut response = bare_response(status, body, content_type);
+    // Values derived from the limiter are always valid header values
+    let _ = add_rate_limit_headers(response.headers_mut(), info);
+    response
+}
+
+/// A response turning a client away with `body` and no rate limit headers
+fn bare_response<B: From<String>>(status: StatusCode, body: String, content_type: &'static str) -> Response<B> {
+    let mut response = Response::new(B::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Gets rate limit information from a rejection
+pub fn get_rate_limit_info(rejection: &RateLimitRejection) -> RateLimitInfo {
+    let retry_after = match rejection.retry_after_format {
+        RetryAfterFormat::HttpDate => rejection.reset_time.to_rfc2822(),
+        RetryAfterFormat::Seconds => seconds_until(rejection.reset_time).to_string(),
+    };
+
+    RateLimitInfo {
+        retry_after,
+        limit: rejection.limit,
+        remaining: 0,
+        used: rejection.limit,
+        window: rejection.window,
+        window_start: rejection.reset_time - ChronoDuration::from_std(rejection.window).unwrap_or_else(|_| ChronoDuration::zero()),
+        window_end: rejection.reset_time,
+        reset_timestamp: rejection.reset_time.timestamp(),
+        retry_after_format: rejection.retry_after_format.clone(),
+        header_style: rejection.header_style,
+        soft_limit_reached: false,
+        credits: None,
+        reset_mode: rejection.reset_mode,
+        metadata: rejection.metadata.clone(),
+    }
+}
+
+/// Builds a complete response from a rejection (`429 Too Many Requests`, or
+/// `503 Service Unavailable` for maintenance), including the rate limit
+/// headers and a plain-text body that honors the rejection's
+/// `RetryAfterFormat`. Banned clients get the deny status and nothing else,
+/// since there is no limit for them to wait out.
+impl<B: From<String>> From<&RateLimitRejection> for Response<B> {
+    fn from(rejection: &RateLimitRejection) -> Self {
+        if rejection.code == RateLimitErrorCode::Banned {
+            return bare_response(rejection.status(), "Access denied.".to_string(), PLAIN_TEXT);
+        }
+        let info = get_rate_limit_info(rejection);
+        let body = match rejection.code {
+            RateLimitErrorCode::Maintenance => format!("Down for maintenance. Try again after {}.", info.retry_after),
+            RateLimitErrorCode::StoreUnavailable => format!("Service unavailable. Try again after {}.", info.retry_after),
+            _ => format!("Rate limit exceeded. Try again after {}.", info.retry_after),
+        };
+        rejection_response(rejection.status(), &info, body, PLAIN_TEXT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header;
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_rate_limit_info_extraction() {
+        let reset = Utc::now() + ChronoDuration::seconds(60);
+        let rejection = RateLimitRejection {
+            retry_after: Duration::from_secs(60),
+            limit: 100,
+            window: Duration::from_secs(60),
+            reset_time: reset,
+            retry_after_format: RetryAfterFormat::Seconds,
+            header_style: HeaderStyle::Legacy,
+            reset_mode: ResetMode::Rolling,
+            code: RateLimitErrorCode::RateLimited,
+            metadata: KeyMetadata::new(),
+            status: None,
+        };
+
+        let info = get_rate_limit_info(&rejection);
+
+        assert_eq!(info.limit, 100);
+        assert_eq!(info.remaining, 0);
+        assert_eq!(info.used, 100);
+        assert_eq!(info.window, Duration::from_secs(60));
+        assert_eq!(info.window_end, reset);
+        assert_eq!(info.window_start, reset - ChronoDuration::seconds(60));
+        assert_eq!(info.reset_timestamp, reset.timestamp());
+        assert_eq!(info.retry_after, "60");
+
+        // The header follows the reset time, not the field frozen at construction
+        let later = RateLimitRejection {
+            reset_time: reset - ChronoDuration::seconds(45),
+            ..rejection.clone()
+        };
+        assert_eq!(get_rate_limit_info(&later).retry_after, "15");
+        
+        // Test with HttpDate format
+        let rejection_http = RateLimitRejection {
+            retry_after: Duration::from_secs(60),
+            limit: 100,
+            window: Duration::from_secs(60),
+            reset_time: reset,
+            retry_after_format: RetryAfterFormat::HttpDate,
+            header_style: HeaderStyle::Legacy,
+            reset_mode: ResetMode::Rolling,
+            code: RateLimitErrorCode::RateLimited,
+            metadata: KeyMetadata::new(),
+            status: None,
+        };
+
+        let info_http = get_rate_limit_info(&rejection_http);
+        assert!(!info_http.retry_after.is_empty()); // RFC2822 date format
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_rejection_into_response() {
+        let rejection = RateLimitRejection {
+            retry_after: Duration::from_secs(30),
+            limit: 10,
+            window: Duration::from_secs(30),
+            reset_time: Utc::now() + ChronoDuration::seconds(30),
+            retry_after_format: RetryAfterFormat::Seconds,
+            header_style: HeaderStyle::Legacy,
+            reset_mode: ResetMode::Rolling,
+            code: RateLimitErrorCode::RateLimited,
+            metadata: KeyMetadata::new(),
+            status: None,
+        };
+
+        let response: Response<String> = (&rejection).into();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+        assert_eq!(response.headers().get("X-RateLimit-Limit").unwrap(), "10");
+        assert_eq!(response.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+
+        let maintenance = rejection.with_code(RateLimitErrorCode::Maintenance);
+        let response: Response<String> = (&maintenance).into();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+        assert!(response.body().starts_with("Down for maintenance"));
+        assert_eq!(maintenance.to_json_body()["code"], "maintenance");
+
+        let response: Response<String> = maintenance.to_json_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body: serde_json::Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["code"], "maintenance");
+    }
+
+    #[test]
+    fn test_too_many_requests_from_info() {
+        let rejection = RateLimitRejection::new(Duration::from_secs(30), 10);
+        let info = get_rate_limit_info(&rejection.with_retry_after_format(RetryAfterFormat::Seconds));
+
+        let response: Response<String> = too_many_requests(&info);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(response.body(), "Rate limit exceeded. Try again after 30.");
+
+        let response: Response<String> = too_many_requests_json(&info);
+        assert_eq!(response.headers()["x-ratelimit-limit"], "10");
+        let body: serde_json::Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!((body["code"].as_str(), body["retry_after_seconds"].as_i64()), (Some("rate_limited"), Some(30)));
+    }
+
+    #[test]
+    fn test_rejection_constructors() {
+        let now = Utc::now();
+        let reset = now + ChronoDuration::seconds(10);
+        let rejection = RateLimitRejection::new(Duration::from_secs(30), 5)
+            .with_retry_after_format(RetryAfterFormat::Seconds)
+            .with_reset_time(reset);
+
+        let copy = rejection.clone();
+        #[allow(deprecated)]
+        let retry_after = copy.retry_after;
+        assert_eq!(retry_after, Duration::from_secs(10));
+        assert!(copy.retry_after() <= Duration::from_secs(10));
+        assert!(copy.retry_after() > Duration::from_secs(9));
+        assert_eq!(copy.limit, 5);
+        assert_eq!(copy.reset_time, reset);
+        assert_eq!(copy.retry_after_format, RetryAfterFormat::Seconds);
+
+        // Every emitted value follows the reset time, not the original duration
+        let headers = get_rate_limit_info(&copy).to_headers().unwrap();
+        assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "10");
+        assert_eq!(headers.get("x-ratelimit-reset").unwrap(), &reset.timestamp().to_string());
+
+        // Without an explicit reset time, the rejection resets retry_after from now
+        let fresh = RateLimitRejection::new(Duration::from_secs(10), 5);
+        assert!(fresh.reset_time > now);
+        assert_eq!(fresh.retry_after_format, RetryAfterFormat::HttpDate);
+    }
+}
@@ -0,0 +1,160 @@
+//! Reading counts from a store's replicas, so status checks scale with the
+//! replicas rather than loading the primary
+//!
+//! ```rust,no_run,ignore
+//! let store = ReplicatedStore::new(RedisStore::open("redis://redis-primary:6379")?)
+//!     .with_replica(RedisStore::open("redis://redis-replica-1:6379")?)
+//!     .with_replica(RedisStore::open("redis://redis-replica-2:6379")?);
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_store(store)?;
+//! ```
+
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+use super::sync::{AtomicUsize, Ordering};
+use super::{Admission, BoxFuture, RateLimitError, RateLimitStore, StoredCount};
+
+/// Counts requests in a primary store and reads counts from its replicas,
+/// taking turns between them. Checks always count on the primary, so
+/// enforcement stays exact; only reads that don't count anything, such as
+/// [`RateLimiter::peek`](crate::RateLimiter::peek), go to a replica and may
+/// trail the primary by the replication lag. A replica that fails is read
+/// around, from the primary.
+#[derive(Debug)]
+pub struct ReplicatedStore<P> {
+    primary: P,
+    replicas: Vec<Box<dyn RateLimitStore>>,
+    next: AtomicUsize,
+}
+
+impl<P: RateLimitStore> ReplicatedStore<P> {
+    /// Count and read in `primary`, until replicas are added
+    pub fn new(primary: P) -> Self {
+        Self {
+            primary,
+            replicas: Vec::new(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Read counts from `replica` too, e.g. a `RedisStore` on a read replica
+    /// of the primary's server
+    pub fn with_replica(mut self, replica: impl RateLimitStore) -> Self {
+        self.replicas.push(Box::new(replica));
+        self
+    }
+}
+
+impl<P: RateLimitStore> RateLimitStore for ReplicatedStore<P> {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            if self.replicas.is_empty() {
+                return self.primary.get(key).await;
+            }
+            let replica = &self.replicas[self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len()];
+            match replica.get(key).await {
+                Ok(stored) => Ok(stored),
+                Err(e) => {
+                    tracing::warn!("rate limit store replica failed, reading the primary: {}", e);
+                    self.primary.get(key).await
+                }
+            }
+        })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+        self.primary.increment(key, amount, window)
+    }
+
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+        self.primary.increment_within(key, amount, limit, window)
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+        self.primary.expire(key, ttl)
+    }
+
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        self.primary.decrement(key, amount)
+    }
+
+    fn now(&self) -> BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+        self.primary.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{MemoryStore, RateLimitConfig, RateLimiter};
+
+    /// A store that is always down
+    #[derive(Debug)]
+    struct Down;
+
+    impl RateLimitStore for Down {
+        fn get<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+
+        fn increment<'a>(&'a self, _: &'a str, _: u32, _: Duration) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+
+        fn increment_within<'a>(
+            &'a self,
+            _: &'a str,
+            _: u32,
+            _: u32,
+            _: Duration,
+        ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+
+        fn expire<'a>(&'a self, _: &'a str, _: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+            Box::pin(std::future::ready(Err(RateLimitError::Other("down".into()))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checks_count_on_the_primary_and_peeks_read_replicas() {
+        let primary = MemoryStore::new();
+        let replica = MemoryStore::new();
+        let store = ReplicatedStore::new(primary.clone()).with_replica(replica.clone());
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(3, 60)).with_store(store).unwrap();
+
+        limiter.check_rate_limit("a").await.unwrap();
+        limiter.check_rate_limit("a").await.unwrap();
+        assert_eq!(primary.get("a").await.unwrap().unwrap().count, 2);
+        // The replica hasn't caught up yet, and peeks see it as it is
+        assert_eq!(limiter.peek("a").await.remaining, 3);
+        replica.increment("a", 2, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(limiter.peek("a").await.remaining, 1);
+
+        limiter.check_rate_limit("a").await.unwrap();
+        assert!(limiter.check_rate_limit("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_a_failed_replica_is_read_around() {
+        let primary = MemoryStore::new();
+        let store = ReplicatedStore::new(primary.clone()).with_replica(Down);
+        primary.increment("a", 2, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap().unwrap().count, 2);
+    }
+}
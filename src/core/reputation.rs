@@ -0,0 +1,203 @@
+//! Per-key reputation scores that scale a key's limit down as it misbehaves
+
+use http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::runtime::Instant;
+
+/// Scores decayed below this count as zero
+const NEGLIGIBLE_POINTS: f64 = 0.01;
+
+/// A key's accumulated penalty points, as of `at`
+#[derive(Clone, Copy, Debug)]
+struct Score {
+    points: f64,
+    at: Instant,
+}
+
+/// Penalty points per key, fed by rejections, client error responses, and
+/// whatever the app reports with [`Reputation::penalize`], and decaying with
+/// a configurable half-life. Attached with `RateLimiter::with_reputation`,
+/// it scales each key's limit by [`Reputation::scale`], so chronically
+/// abusive keys tighten automatically while clean keys keep their full
+/// limit.
+///
+/// ```rust,no_run,ignore
+/// // A key with 10 points gets half its limit, and never less than a tenth
+/// let reputation = Reputation::new(Duration::from_secs(3600)).with_tolerance(10.0).with_floor(0.1);
+/// let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_reputation(reputation.clone());
+///
+/// // Failed signature checks are worth more than an ordinary 4xx
+/// reputation.penalize(&key, 5.0);
+/// ```
+///
+/// Cloning a `Reputation` is cheap and the clones share their scores.
+#[derive(Clone, Debug)]
+pub struct Reputation {
+    scores: Arc<Mutex<HashMap<String, Score>>>,
+    half_life: Duration,
+    rejection_penalty: f64,
+    error_penalty: f64,
+    tolerance: f64,
+    floor: f64,
+}
+
+impl Reputation {
+    /// Scores that halve every `half_life`. A rejection or client error
+    /// costs one point, a key with 10 points gets half its limit, and no key
+    /// drops below a tenth of it.
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            scores: Arc::new(Mutex::new(HashMap::new())),
+            half_life,
+            rejection_penalty: 1.0,
+            error_penalty: 1.0,
+            tolerance: 10.0,
+            floor: 0.1,
+        }
+    }
+
+    /// Points charged when the limiter rejects the key
+    pub fn with_rejection_penalty(mut self, points: f64) -> Self {
+        self.rejection_penalty = points;
+        self
+    }
+
+    /// Points charged when a request from the key is answered with a
+    /// client error (`4xx`)
+    pub fn with_error_penalty(mut self, points: f64) -> Self {
+        self.error_penalty = points;
+        self
+    }
+
+    /// The score at which a key's limit is halved
+    pub fn with_tolerance(mut self, points: f64) -> Self {
+        self.tolerance = points.max(f64::MIN_POSITIVE);
+        self
+    }
+
+    /// The smallest share of its limit a key can be scaled down to, from 0
+    /// (a bad enough key is shut out) to 1 (scores are tracked but never
+    /// applied)
+    pub fn with_floor(mut self, floor: f64) -> Self {
+        self.floor = floor.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Adds `points` to `key`'s score, e.g. for an app-level abuse signal
+    pub fn penalize(&self, key: &str, points: f64) {
+        if points <= 0.0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut scores = self.scores.lock().unwrap_or_else(|e| e.into_inner());
+        let score = scores.entry(key.to_string()).or_insert(Score { points: 0.0, at: now });
+        *score = Score {
+            points: self.decayed(*score, now) + points,
+            at: now,
+        };
+    }
+
+    /// Charges the rejection penalty to `key`
+    pub fn record_rejection(&self, key: &str) {
+        self.penalize(key, self.rejection_penalty);
+    }
+
+    /// Charges the error penalty to `key` if `status` is a client error.
+    /// `429`s are left to [`Reputation::record_rejection`], so they aren't
+    /// counted twice.
+    pub fn record_status(&self, key: &str, status: StatusCode) {
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            self.penalize(key, self.error_penalty);
+        }
+    }
+
+    /// Clears `key`'s score
+    pub fn forgive(&self, key: &str) {
+        self.scores.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+    }
+
+    /// Clears the score of every key `purge` matches
+    pub fn purge_where<F: Fn(&str) -> bool>(&self, purge: F) {
+        self.scores.lock().unwrap_or_else(|e| e.into_inner()).retain(|key, _| !purge(key));
+    }
+
+    /// `key`'s current score, after decay
+    pub fn score(&self, key: &str) -> f64 {
+        let scores = self.scores.lock().unwrap_or_else(|e| e.into_inner());
+        scores.get(key).map_or(0.0, |score| self.decayed(*score, Instant::now()))
+    }
+
+    /// The share of its limit `key` is held to: 1 for a clean key, falling
+    /// towards the floor as its score grows
+    pub fn scale(&self, key: &str) -> f64 {
+        (1.0 / (1.0 + self.score(key) / self.tolerance)).max(self.floor)
+    }
+
+    /// Drops keys whose score has decayed to zero, returning how many.
+    /// `RateLimiter::purge_expired` and `with_cleanup` do this for the
+    /// limiter's reputation.
+    pub fn purge(&self) -> usize {
+        let now = Instant::now();
+        let mut scores = self.scores.lock().unwrap_or_else(|e| e.into_inner());
+        let before = scores.len();
+        scores.retain(|_, score| self.decayed(*score, now) > 0.0);
+        before - scores.len()
+    }
+
+    /// `score` decayed to `now`, reaching zero once it falls below
+    /// [`NEGLIGIBLE_POINTS`]
+    fn decayed(&self, score: Score, now: Instant) -> f64 {
+        let half_lives = now.duration_since(score.at).as_secs_f64() / self.half_life.as_secs_f64().max(f64::MIN_POSITIVE);
+        let points = score.points * 0.5f64.powf(half_lives);
+        if points < NEGLIGIBLE_POINTS {
+            0.0
+        } else {
+            points
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scores_scale_and_decay() {
+        let reputation = Reputation::new(Duration::from_secs(60)).with_floor(0.25);
+        assert_eq!(reputation.scale("a"), 1.0);
+
+        for _ in 0..10 {
+            reputation.record_rejection("a");
+        }
+        reputation.record_status("a", StatusCode::TOO_MANY_REQUESTS);
+        reputation.record_status("a", StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(reputation.score("a"), 10.0);
+        assert_eq!(reputation.scale("a"), 0.5);
+
+        reputation.penalize("a", 90.0);
+        assert_eq!(reputation.scale("a"), 0.25);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(reputation.score("a"), 50.0);
+
+        reputation.forgive("a");
+        assert_eq!(reputation.scale("a"), 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_decayed_scores_reach_zero_and_are_purged() {
+        let reputation = Reputation::new(Duration::from_secs(60));
+        reputation.penalize("a", 1.0);
+        reputation.penalize("b", 100.0);
+
+        // Seven half-lives take a point below a hundredth
+        tokio::time::advance(Duration::from_secs(7 * 60)).await;
+        assert_eq!(reputation.score("a"), 0.0);
+        assert!(reputation.score("b") > 0.0);
+        assert_eq!(reputation.purge(), 1);
+        assert_eq!(reputation.scores.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,60 @@
+//! Per-key configs, so one limiter can hold different callers to different
+//! ceilings, e.g. partners to 1000 requests a minute and everyone else to 60
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::RateLimitConfig;
+
+/// Picks the config a key is held to. Keys it returns `None` for get the
+/// limiter's own config. Runs on every request, so keep lookups in memory
+/// (e.g. a cache of plans refreshed in the background).
+///
+/// Closures taking the key implement it:
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(60)).with_quota_resolver(move |key: &str| {
+///     partners.contains(key).then(|| RateLimitConfig::max_per_minute(1000))
+/// });
+/// ```
+pub trait QuotaResolver: Send + Sync + 'static {
+    /// The config for `key`, or `None` for the limiter's own
+    fn resolve(&self, key: &str) -> Option<RateLimitConfig>;
+}
+
+impl<F> QuotaResolver for F
+where
+    F: Fn(&str) -> Option<RateLimitConfig> + Send + Sync + 'static,
+{
+    fn resolve(&self, key: &str) -> Option<RateLimitConfig> {
+        self(key)
+    }
+}
+
+/// Looks keys up in a fixed table
+impl QuotaResolver for HashMap<String, RateLimitConfig> {
+    fn resolve(&self, key: &str) -> Option<RateLimitConfig> {
+        self.get(key).cloned()
+    }
+}
+
+/// A resolver shared by a limiter's clones
+#[derive(Clone)]
+pub(crate) struct Quotas(Arc<dyn QuotaResolver>);
+
+impl Quotas {
+    pub(crate) fn new(resolver: impl QuotaResolver) -> Self {
+        Self(Arc::new(resolver))
+    }
+
+    pub(crate) fn resolve(&self, key: &str) -> Option<RateLimitConfig> {
+        self.0.resolve(key)
+    }
+}
+
+impl fmt::Debug for Quotas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Quotas(..)")
+    }
+}
@@ -0,0 +1,98 @@
+//! Keys naming the resource a request targets, built from its path, so hot
+//! resources can be limited individually rather than only per client
+
+/// Builds a key from parameters captured out of the request path, e.g. the
+/// route `/orgs/{org_id}/repos/{repo}` with the template `org:{org_id}`
+/// keys `/orgs/acme/repos/site` as `org:acme`. Every request for one org
+/// then shares a budget, whichever client sends it.
+///
+/// Route segments are literals, `{name}` captures (one segment each), or a
+/// trailing `**` for anything below. Placeholders in the template that the
+/// route doesn't capture are left as they are.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceKey {
+    route: Vec<String>,
+    template: String,
+}
+
+impl ResourceKey {
+    /// Key requests matching `route` by `template`
+    pub fn new(route: &str, template: impl Into<String>) -> Self {
+        Self {
+            route: segments(route).map(str::to_string).collect(),
+            template: template.into(),
+        }
+    }
+
+    /// The key for a request to `path`, or `None` if `path` doesn't match
+    /// the route
+    pub fn key_for(&self, path: &str) -> Option<String> {
+        let mut captures = Vec::new();
+        let mut path = segments(path);
+        for segment in &self.route {
+            if segment == "**" {
+                return Some(self.render(&captures));
+            }
+            let value = path.next()?;
+            match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => captures.push((name, value)),
+                None if segment == value => {}
+                None => return None,
+            }
+        }
+        path.next().is_none().then(|| self.render(&captures))
+    }
+
+    /// Fills the template's placeholders in one pass, so a captured value
+    /// that itself looks like a placeholder is copied as it is
+    fn render(&self, captures: &[(&str, &str)]) -> String {
+        let mut key = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+                break;
+            };
+            key.push_str(&rest[..open]);
+            let name = &rest[open + 1..close];
+            match captures.iter().find(|(captured, _)| *captured == name) {
+                Some((_, value)) => key.push_str(value),
+                None => key.push_str(&rest[open..=close]),
+            }
+            rest = &rest[close + 1..];
+        }
+        key.push_str(rest);
+        key
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_key_from_path() {
+        let key = ResourceKey::new("/orgs/{org_id}/repos/{repo}", "repo:{org_id}/{repo}");
+        assert_eq!(key.key_for("/orgs/acme/repos/site").as_deref(), Some("repo:acme/site"));
+        assert_eq!(key.key_for("/orgs/acme/repos/site/").as_deref(), Some("repo:acme/site"));
+        assert_eq!(key.key_for("/orgs/acme/repos"), None);
+        assert_eq!(key.key_for("/orgs/acme/repos/site/issues"), None);
+        assert_eq!(key.key_for("/users/acme/repos/site"), None);
+
+        let key = ResourceKey::new("/orgs/{org_id}/**", "org:{org_id}:{missing}");
+        assert_eq!(key.key_for("/orgs/acme/repos/site").as_deref(), Some("org:acme:{missing}"));
+    }
+
+    #[test]
+    fn test_captured_braces_are_not_expanded() {
+        let key = ResourceKey::new("/orgs/{org_id}/repos/{repo}", "repo:{org_id}/{repo}");
+        assert_eq!(key.key_for("/orgs/{repo}/repos/site").as_deref(), Some("repo:{repo}/site"));
+        assert_eq!(key.key_for("/orgs/acme/repos/{org_id}").as_deref(), Some("repo:acme/{org_id}"));
+
+        let key = ResourceKey::new("/orgs/{org_id}", "org:{org_id}:{unclosed");
+        assert_eq!(key.key_for("/orgs/acme").as_deref(), Some("org:acme:{unclosed"));
+    }
+}
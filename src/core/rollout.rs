@@ -0,0 +1,127 @@
+//! Canary rollouts of a candidate config to a stable share of keys, with
+//! separate counts for each arm so the candidate can be judged before it
+//! applies to everyone
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::RateLimitConfig;
+
+/// Admitted and rejected requests for one arm of a rollout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArmStats {
+    /// Requests let through
+    pub admitted: u64,
+    /// Requests turned away
+    pub rejected: u64,
+}
+
+impl ArmStats {
+    /// The share of the arm's requests that were rejected, from 0 to 1
+    pub fn rejection_rate(&self) -> f64 {
+        match self.admitted + self.rejected {
+            0 => 0.0,
+            total => self.rejected as f64 / total as f64,
+        }
+    }
+}
+
+/// How each arm of a rollout has fared since the candidate was set, from
+/// `RateLimiter::rollout_stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RolloutStats {
+    /// Keys still under the limiter's own config
+    pub control: ArmStats,
+    /// Keys under the candidate config
+    pub candidate: ArmStats,
+}
+
+/// A candidate config applied to `percent` of keys
+#[derive(Debug)]
+pub(crate) struct Rollout {
+    pub(crate) config: RateLimitConfig,
+    percent: u8,
+    /// Admitted and rejected counts for control, then candidate
+    counts: [[AtomicU64; 2]; 2],
+}
+
+impl Rollout {
+    pub(crate) fn new(config: RateLimitConfig, percent: u8) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            percent: percent.min(100),
+            counts: Default::default(),
+        })
+    }
+
+    /// Whether `key` falls in the candidate arm. The assignment hashes the
+    /// key with a fixed function, so it is stable across requests,
+    /// processes, replicas, and Rust versions, and raising `percent` only
+    /// adds keys.
+    pub(crate) fn selects(&self, key: &str) -> bool {
+        bucket(key) < u64::from(self.percent)
+    }
+
+    pub(crate) fn record(&self, candidate: bool, admitted: bool) {
+        self.counts[usize::from(candidate)][usize::from(!admitted)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> RolloutStats {
+        let arm = |counts: &[AtomicU64; 2]| ArmStats {
+            admitted: counts[0].load(Ordering::Relaxed),
+            rejected: counts[1].load(Ordering::Relaxed),
+        };
+        RolloutStats {
+            control: arm(&self.counts[0]),
+            candidate: arm(&self.counts[1]),
+        }
+    }
+}
+
+/// FNV-1a's 64-bit offset basis and prime
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// `key`'s bucket from 0 to 99, by its 64-bit FNV-1a hash
+fn bucket(key: &str) -> u64 {
+    fnv1a(key) % 100
+}
+
+/// `key`'s 64-bit FNV-1a hash, which unlike std's hashers stays the same
+/// across builds and platforms
+pub(crate) fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(FNV_OFFSET, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assignment_is_stable_and_proportional() {
+        let rollout = Rollout::new(RateLimitConfig::default(), 25);
+        let selected = (0..10_000).filter(|i| rollout.selects(&format!("key-{}", i))).count();
+        assert!((2_000..3_000).contains(&selected), "selected {}", selected);
+        assert_eq!(rollout.selects("key-1"), rollout.selects("key-1"));
+
+        // Widening the rollout keeps every key already in it
+        let wider = Rollout::new(RateLimitConfig::default(), 50);
+        assert!((0..1_000).map(|i| format!("key-{}", i)).all(|key| !rollout.selects(&key) || wider.selects(&key)));
+
+        rollout.record(true, false);
+        rollout.record(false, true);
+        let stats = rollout.stats();
+        assert_eq!(stats.candidate.rejected, 1);
+        assert_eq!(stats.control.admitted, 1);
+        assert_eq!(stats.candidate.rejection_rate(), 1.0);
+    }
+
+    #[test]
+    fn test_buckets_never_change() {
+        // Changing these moves keys between arms mid-rollout on upgrade
+        assert_eq!(bucket(""), 37);
+        assert_eq!(bucket("a"), 96);
+        assert_eq!(bucket("key-1"), 22);
+        assert_eq!(bucket("alice"), 83);
+    }
+}
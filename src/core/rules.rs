@@ -0,0 +1,188 @@
+//! Ordered rules selecting a rate limit policy per request, so a whole route
+//! tree can be limited from one place
+
+use http::{HeaderMap, HeaderName, Method};
+use std::sync::Arc;
+
+use super::{RateLimitConfig, RateLimiter};
+
+type Predicate = Arc<dyn Fn(&Method, &str, &HeaderMap) -> bool + Send + Sync>;
+
+/// Conditions a request must meet for a rule to apply. Every condition set
+/// must hold; `Match::any()` with nothing added matches every request.
+#[derive(Clone, Default)]
+pub struct Match {
+    method: Option<Method>,
+    path: Option<String>,
+    headers: Vec<HeaderName>,
+    predicate: Option<Predicate>,
+}
+
+impl Match {
+    /// Match every request
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    /// Match requests whose path matches `glob`, where `*` stands for any
+    /// run of characters within one segment and `**` for anything, e.g.
+    /// `/api/*/search` or `/static/**`
+    pub fn path(glob: impl Into<String>) -> Self {
+        Self::any().and_path(glob)
+    }
+
+    /// Also require the path to match `glob`
+    pub fn and_path(mut self, glob: impl Into<String>) -> Self {
+        self.path = Some(glob.into());
+        self
+    }
+
+    /// Also require the request method to be `method`
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// Also require the header `name` to be present
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.headers.push(name);
+        self
+    }
+
+    /// Also require `predicate` to return true
+    pub fn when<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Method, &str, &HeaderMap) -> bool + Send + Sync + 'static,
+    {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Whether a request with the given method, path, and headers matches
+    pub fn matches(&self, method: &Method, path: &str, headers: &HeaderMap) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && self.path.as_deref().is_none_or(|glob| glob_matches(glob.as_bytes(), path.as_bytes()))
+            && self.headers.iter().all(|name| headers.contains_key(name))
+            && self.predicate.as_ref().is_none_or(|p| p(method, path, headers))
+    }
+}
+
+impl std::fmt::Debug for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Match")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("headers", &self.headers)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+fn glob_matches(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+/// What a matching rule does with the request
+#[derive(Clone, Debug)]
+enum Policy {
+    /// Limit under the endpoint config registered for this label
+    Limit(String),
+    Exempt,
+}
+
+/// An ordered set of rules, each selecting a config or an exemption. The
+/// first matching rule wins; requests no rule matches are limited under the
+/// limiter's own config.
+///
+/// ```rust,no_run,ignore
+/// let rules = RateLimitRules::new(RateLimiter::new(RateLimitConfig::max_per_minute(120)))
+///     .exempt(Match::path("/health"))
+///     .rule(Match::path("/search/**").method(Method::GET), RateLimitConfig::max_per_minute(10))
+///     .rule(Match::any().header(HeaderName::from_static("x-api-key")), RateLimitConfig::max_per_minute(600));
+/// let api = with_rate_limit_rules(rules, key::remote_ip()).and(routes);
+/// ```
+///
+/// Each rule's config is registered as an endpoint on the limiter, so all
+/// rules share one store, and a key's counts under one rule don't affect
+/// another.
+#[derive(Clone, Debug)]
+pub struct RateLimitRules {
+    limiter: RateLimiter,
+    rules: Vec<(Match, Policy)>,
+}
+
+impl RateLimitRules {
+    /// Start an empty rule set on `limiter`
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self {
+            limiter,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Limit requests matching `matcher` under `config`
+    pub fn rule(mut self, matcher: Match, config: RateLimitConfig) -> Self {
+        let label = format!("rule{}", self.rules.len());
+        self.limiter = self.limiter.with_endpoint(label.clone(), config);
+        self.rules.push((matcher, Policy::Limit(label)));
+        self
+    }
+
+    /// Let requests matching `matcher` through without counting them
+    pub fn exempt(mut self, matcher: Match) -> Self {
+        self.rules.push((matcher, Policy::Exempt));
+        self
+    }
+
+    /// The limiter to count a request against, or `None` if it is exempt
+    pub fn resolve(&self, method: &Method, path: &str, headers: &HeaderMap) -> Option<RateLimiter> {
+        match self.rules.iter().find(|(matcher, _)| matcher.matches(method, path, headers)) {
+            Some((_, Policy::Limit(label))) => Some(self.limiter.endpoint(label)),
+            Some((_, Policy::Exempt)) => None,
+            None => Some(self.limiter.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matching() {
+        assert!(glob_matches(b"/api/*/search", b"/api/v1/search"));
+        assert!(!glob_matches(b"/api/*/search", b"/api/v1/x/search"));
+        assert!(glob_matches(b"/static/**", b"/static/css/site.css"));
+        assert!(glob_matches(b"/health", b"/health"));
+        assert!(!glob_matches(b"/health", b"/healthz"));
+    }
+
+    #[tokio::test]
+    async fn test_first_matching_rule_wins() {
+        let rules = RateLimitRules::new(RateLimiter::new(RateLimitConfig::max_per_window(5, 60)))
+            .exempt(Match::path("/health"))
+            .rule(Match::path("/search/**").method(Method::GET), RateLimitConfig::max_per_window(1, 60))
+            .rule(
+                Match::any().when(|_, path, _| path.ends_with(".csv")),
+                RateLimitConfig::max_per_window(2, 60),
+            );
+        let headers = HeaderMap::new();
+
+        assert!(rules.resolve(&Method::GET, "/health", &headers).is_none());
+
+        let search = rules.resolve(&Method::GET, "/search/books", &headers).unwrap();
+        assert_eq!(search.config().max_requests, 1);
+        let export = rules.resolve(&Method::POST, "/search/export.csv", &headers).unwrap();
+        assert_eq!(export.config().max_requests, 2);
+        let other = rules.resolve(&Method::GET, "/", &headers).unwrap();
+        assert_eq!(other.config().max_requests, 5);
+    }
+}
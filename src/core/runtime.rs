@@ -0,0 +1,120 @@
+//! The async runtime the core spawns background tasks and sleeps on. Tokio
+//! is built in behind the default `tokio` feature; other executors plug in
+//! by implementing [`Runtime`], so async-std or smol users of hyper
+//! compatible stacks can use the core limiter too. The locks the counting
+//! core holds are plain `std` ones, never held across an await, so they
+//! work on any executor.
+//!
+//! ```rust,no_run,ignore
+//! #[derive(Debug)]
+//! struct Smol;
+//!
+//! impl Runtime for Smol {
+//!     fn spawn(&self, task: BoxFuture<'static, ()>) {
+//!         smol::spawn(task).detach();
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+//!         Box::pin(async move {
+//!             smol::Timer::after(duration).await;
+//!         })
+//!     }
+//! }
+//!
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_runtime(Smol);
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use futures_core::future::BoxFuture;
+
+/// The monotonic clock windows run on: tokio's when it is available, so
+/// tests can pause and advance it, and std's otherwise
+#[cfg(any(feature = "tokio", test))]
+pub(crate) use tokio::time::Instant;
+#[cfg(not(any(feature = "tokio", test)))]
+pub(crate) use std::time::Instant;
+
+/// Spawns background tasks and sleeps for the limiter
+pub trait Runtime: fmt::Debug + Send + Sync + 'static {
+    /// Runs `task` to completion in the background
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// A future that completes after `duration`
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Whether [`spawn`](Runtime::spawn) can run a task when called from
+    /// here, e.g. `false` for tokio outside of a tokio runtime
+    fn can_spawn(&self) -> bool {
+        true
+    }
+}
+
+/// The tokio runtime. Tasks are spawned onto whichever tokio runtime is
+/// current when [`Runtime::spawn`] is called.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio")]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::spawn(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn can_spawn(&self) -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+}
+
+/// The runtime used when none is given: tokio when the feature is enabled
+#[cfg(feature = "tokio")]
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    Some(Arc::new(TokioRuntime))
+}
+
+/// The runtime used when none is given: tokio when the feature is enabled
+#[cfg(not(feature = "tokio"))]
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    None
+}
+
+/// Sleeps for `duration` on `runtime`. Without one there is nothing to sleep
+/// on, so the delay is skipped with a warning.
+pub(crate) async fn sleep(runtime: Option<&dyn Runtime>, duration: Duration) {
+    match runtime {
+        Some(runtime) => runtime.sleep(duration).await,
+        None => tracing::warn!("no runtime to sleep on; skipping a {:?} delay", duration),
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_runtime_sleeps_and_spawns() {
+        let runtime = TokioRuntime;
+        let start = Instant::now();
+        runtime.sleep(Duration::from_secs(5)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        runtime.spawn(Box::pin(async move {
+            tx.send(()).unwrap();
+        }));
+        rx.await.unwrap();
+        assert!(runtime.can_spawn());
+    }
+
+    #[test]
+    fn test_tokio_runtime_cannot_spawn_outside_of_tokio() {
+        assert!(!TokioRuntime.can_spawn());
+    }
+}
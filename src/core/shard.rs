@@ -0,0 +1,313 @@
+//! Spreading counts over several stores by consistent hashing, for keyspaces
+//! or request rates one Redis can't hold
+//!
+//! ```rust,no_run,ignore
+//! let store = ShardedStore::new()
+//!     .with_shard("redis-a", RedisStore::open("redis://redis-a:6379")?)
+//!     .with_shard("redis-b", RedisStore::open("redis://redis-b:6379")?)
+//!     .with_shard("redis-c", RedisStore::open("redis://redis-c:6379")?)
+//!     .with_hot_key("tenant:acme", 3);
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_store(store)?;
+//! ```
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::rollout::fnv1a;
+use super::sync::{AtomicUsize, Ordering};
+use super::{Admission, BoxFuture, RateLimitError, RateLimitStore, StoredCount};
+
+/// Points each shard gets on the hash ring, so keys spread evenly
+const POINTS_PER_SHARD: usize = 160;
+
+/// Where `key` falls on the ring: its FNV-1a hash, mixed (by MurmurHash3's
+/// finalizer) so that similar keys, whose FNV-1a hashes differ mostly in
+/// the low bits, still land far apart
+fn position(key: &str) -> u64 {
+    let mut hash = fnv1a(key);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Counts each key in one of several stores, picked by hashing the key onto
+/// a ring of the shards' names. Adding or removing a shard only moves the
+/// keys on its arcs of the ring; the rest keep their shard and their counts.
+///
+/// A hot key can be spread over several shards with
+/// [`with_hot_key`](ShardedStore::with_hot_key): each holds an equal share
+/// of the key's limit and its requests go to the shards in turn, moving on
+/// to the next when one's share is used up. The total stays exact, but a
+/// request costing more than one share is refused, and the count reported
+/// for a request is estimated from the shard that counted it.
+#[derive(Debug, Default)]
+pub struct ShardedStore {
+    shards: Vec<(String, Box<dyn RateLimitStore>)>,
+    /// Ring points by hash, each with the index of its shard
+    ring: Vec<(u64, usize)>,
+    /// Keys spread over several shards, with how many
+    hot: HashMap<String, usize>,
+    next: AtomicUsize,
+}
+
+impl ShardedStore {
+    /// A store with no shards yet, which fails every call until one is added
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `store` as a shard named `name`. Keys are placed by the names, so
+    /// keep each shard's name the same across restarts and replicas.
+    pub fn with_shard(mut self, name: impl Into<String>, store: impl RateLimitStore) -> Self {
+        let name = name.into();
+        let index = self.shards.len();
+        self.ring.extend((0..POINTS_PER_SHARD).map(|point| (position(&format!("{}#{}", name, point)), index)));
+        self.ring.sort_unstable();
+        self.shards.push((name, Box::new(store)));
+        self
+    }
+
+    /// Spread `key` over `shards` shards (up to as many as there are), e.g.
+    /// a tenant whose traffic would overload any one of them. Keys are
+    /// matched as the limiter stores them, including any namespace.
+    pub fn with_hot_key(mut self, key: impl Into<String>, shards: usize) -> Self {
+        self.hot.insert(key.into(), shards.max(1));
+        self
+    }
+
+    /// The shards holding `key`: the first on the ring at or after its
+    /// hash, then for a hot key the next distinct ones around the ring
+    fn shards_for(&self, key: &str) -> Vec<usize> {
+        let wanted = self.hot.get(key).copied().unwrap_or(1).min(self.shards.len());
+        let start = self.ring.partition_point(|(point, _)| *point < position(key));
+        let mut shards = Vec::with_capacity(wanted);
+        for (_, shard) in self.ring.iter().cycle().skip(start).take(self.ring.len()) {
+            if shards.len() == wanted {
+                break;
+            }
+            if !shards.contains(shard) {
+                shards.push(*shard);
+            }
+        }
+        shards
+    }
+
+    fn store(&self, shard: usize) -> &dyn RateLimitStore {
+        self.shards[shard].1.as_ref()
+    }
+
+    /// Where to start taking turns over a hot key's shards
+    fn turn(&self, shards: usize) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % shards
+    }
+}
+
+/// `limit` split evenly over `shards`, with the remainder going to the first
+/// ones: the share of the shard at `index`
+fn share(limit: u32, shards: usize, index: usize) -> u32 {
+    let shards = shards as u32;
+    limit / shards + u32::from((index as u32) < limit % shards)
+}
+
+/// The whole key's count, estimated from one shard's count of its `share`
+fn estimate(stored: StoredCount, share: u32, limit: u32) -> StoredCount {
+    let count = u64::from(stored.count) * u64::from(limit) / u64::from(share.max(1));
+    StoredCount {
+        count: count.min(u64::from(u32::MAX)) as u32,
+        ..stored
+    }
+}
+
+fn no_shards() -> RateLimitError {
+    RateLimitError::Other("sharded store has no shards".into())
+}
+
+impl RateLimitStore for ShardedStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            let mut total: Option<StoredCount> = None;
+            for shard in self.shards_for(key) {
+                if let Some(stored) = self.store(shard).get(key).await? {
+                    let total = total.get_or_insert(StoredCount {
+                        count: 0,
+                        ttl: Duration::ZERO,
+                    });
+                    total.count = total.count.saturating_add(stored.count);
+                    total.ttl = total.ttl.max(stored.ttl);
+                }
+            }
+            Ok(total)
+        })
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+        Box::pin(async move {
+            let shards = self.shards_for(key);
+            let Some(&shard) = shards.get(self.turn(shards.len().max(1))) else {
+                return Err(no_shards());
+            };
+            let stored = self.store(shard).increment(key, amount, window).await?;
+            Ok(StoredCount {
+                count: stored.count.saturating_mul(shards.len() as u32),
+                ..stored
+            })
+        })
+    }
+
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+        Box::pin(async move {
+            let shards = self.shards_for(key);
+            if let [shard] = shards[..] {
+                return self.store(shard).increment_within(key, amount, limit, window).await;
+            }
+            let first = self.turn(shards.len().max(1));
+            let mut refused = Err(no_shards());
+            for index in (0..shards.len()).map(|turn| (first + turn) % shards.len()) {
+                let share = share(limit, shards.len(), index);
+                match self.store(shards[index]).increment_within(key, amount, share, window).await? {
+                    Admission::Admitted(stored) => return Ok(Admission::Admitted(estimate(stored, share, limit))),
+                    Admission::Refused(stored) => refused = Ok(Admission::Refused(estimate(stored, share, limit))),
+                }
+            }
+            refused
+        })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+        Box::pin(async move {
+            let shards = self.shards_for(key);
+            if shards.is_empty() {
+                return Err(no_shards());
+            }
+            for shard in shards {
+                self.store(shard).expire(key, ttl).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Takes `amount` off a hot key's shards in turn, each down to zero, and
+    /// returns the key's total as [`get`](RateLimitStore::get) would
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        Box::pin(async move {
+            let shards = self.shards_for(key);
+            if let [shard] = shards[..] {
+                return self.store(shard).decrement(key, amount).await;
+            }
+            if shards.is_empty() {
+                return Err(no_shards());
+            }
+            let mut left = amount;
+            let mut total: Option<StoredCount> = None;
+            for shard in shards {
+                let store = self.store(shard);
+                let stored = match store.get(key).await? {
+                    Some(stored) if left > 0 && stored.count > 0 => {
+                        let taken = left.min(stored.count);
+                        left -= taken;
+                        store.decrement(key, taken).await?
+                    }
+                    stored => stored,
+                };
+                if let Some(stored) = stored {
+                    let total = total.get_or_insert(StoredCount {
+                        count: 0,
+                        ttl: Duration::ZERO,
+                    });
+                    total.count = total.count.saturating_add(stored.count);
+                    total.ttl = total.ttl.max(stored.ttl);
+                }
+            }
+            Ok(total)
+        })
+    }
+
+    /// The first shard's clock, so every key's windows go by the same one
+    fn now(&self) -> BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+        match self.shards.first() {
+            Some((_, store)) => store.now(),
+            None => Box::pin(std::future::ready(Err(no_shards()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{MemoryStore, RateLimitConfig, RateLimiter};
+
+    fn names(store: &ShardedStore, key: &str) -> Vec<String> {
+        store.shards_for(key).into_iter().map(|shard| store.shards[shard].0.clone()).collect()
+    }
+
+    #[test]
+    fn test_adding_a_shard_only_moves_keys_to_it() {
+        let three = || {
+            ShardedStore::new()
+                .with_shard("a", MemoryStore::new())
+                .with_shard("b", MemoryStore::new())
+                .with_shard("c", MemoryStore::new())
+        };
+        let (before, four) = (three(), three().with_shard("d", MemoryStore::new()));
+        let keys: Vec<String> = (0..3_000).map(|i| format!("client-{}", i)).collect();
+        for name in ["a", "b", "c"] {
+            let held = keys.iter().filter(|key| names(&before, key) == [name]).count();
+            assert!((700..1_300).contains(&held), "{} holds {}", name, held);
+        }
+
+        let mut moved = 0;
+        for key in &keys {
+            let (before, after) = (names(&before, key), names(&four, key));
+            if before != after {
+                assert_eq!(after, ["d"]);
+                moved += 1;
+            }
+        }
+        assert!((450..1_050).contains(&moved), "{} moved", moved);
+    }
+
+    #[tokio::test]
+    async fn test_hot_keys_split_their_limit_over_shards() {
+        let (a, b) = (MemoryStore::new(), MemoryStore::new());
+        let store = ShardedStore::new().with_shard("a", a.clone()).with_shard("b", b.clone()).with_hot_key("hot", 2);
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(9, 60)).with_store(store).unwrap();
+
+        for _ in 0..9 {
+            limiter.check_rate_limit("hot").await.unwrap();
+        }
+        assert!(limiter.check_rate_limit("hot").await.is_err());
+        let counts = (a.get("hot").await.unwrap().unwrap().count, b.get("hot").await.unwrap().unwrap().count);
+        assert!(counts == (5, 4) || counts == (4, 5), "{:?}", counts);
+        assert_eq!(limiter.peek("hot").await.remaining, 0);
+
+        // Other keys stay on one shard
+        limiter.check_rate_limit("cold").await.unwrap();
+        let held = [a.get("cold").await.unwrap(), b.get("cold").await.unwrap()];
+        assert_eq!(held.iter().flatten().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_shards_is_an_error() {
+        let store = ShardedStore::new();
+        assert!(store.increment_within("a", 1, 5, Duration::from_secs(60)).await.is_err());
+        assert!(store.increment("a", 1, Duration::from_secs(60)).await.is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! Pluggable storage for the limiter's counters. In memory, each replica
+//! behind a load balancer enforces its own quota; a shared
+//! [`RateLimitStore`] (Redis, with the `redis` feature) lets every replica
+//! count against the same one.
+//!
+//! ```rust,no_run,ignore
+//! let store = RedisStore::open("redis://redis:6379")?.with_prefix("api:");
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100))
+//!     .with_store(store)?
+//!     .with_store_failure_policy(StoreFailurePolicy::Reject);
+//! ```
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::runtime::Instant;
+use super::sync::{Mutex, MutexGuard};
+use super::{wall_clock_now, BoxFuture, RateLimitError};
+
+/// A key's count as a store holds it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoredCount {
+    /// Units counted in the key's current window
+    pub count: u32,
+    /// Time until the window expires and the count goes back to zero
+    pub ttl: Duration,
+}
+
+/// What a store made of a request in
+/// [`RateLimitStore::increment_within`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// The request fit under the limit and was counted; holds the new count
+    Admitted(StoredCount),
+    /// The request would have gone past the limit and was not counted;
+    /// holds the count as it stands
+    Refused(StoredCount),
+}
+
+/// What a limiter does with a request when its store fails or times out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreFailurePolicy {
+    /// Count it in memory, so each replica still enforces the limit alone
+    #[default]
+    CountLocally,
+    /// Let it through uncounted (fail open)
+    Admit,
+    /// Turn it away with a `503` and the `store_unavailable` code (fail closed)
+    Reject,
+}
+
+/// Holds the limiter's per-key counts outside the process. Stores count in
+/// fixed windows: the first increment of a key starts its window, and the
+/// count expires with it.
+pub trait RateLimitStore: fmt::Debug + Send + Sync + 'static {
+    /// `key`'s count, or `None` if it has no open window
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>>;
+
+    /// Adds `amount` to `key`'s count, opening a window of length `window`
+    /// if it has none, and returns the new count. The increment and the
+    /// expiry must be applied atomically, so concurrent replicas can't leave
+    /// a count that never expires. Stores that talk to a server should
+    /// bound how long this waits and fail rather than hang.
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>>;
+
+    /// [`increment`](RateLimitStore::increment), but only if the new count
+    /// would stay within `limit`; otherwise leaves the count as it is. The
+    /// check and the increment must be applied atomically, so requests
+    /// turned away don't use up budget and concurrent replicas can't both
+    /// take the last unit.
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>>;
+
+    /// Expires `key`'s count after `ttl`; a zero `ttl` forgets it now
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>>;
+
+    /// Takes `amount` off `key`'s count, stopping at zero and leaving its
+    /// expiry as it is, and returns the new count, or `None` if it has no
+    /// open window. Limiters use it to refund requests. The default fails,
+    /// for stores that can't take counts back.
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        let _ = (key, amount);
+        Box::pin(std::future::ready(Err(RateLimitError::Other(
+            "this rate limit store can't decrement counts".into(),
+        ))))
+    }
+
+    /// The store's current time, so replicas whose own clocks disagree can
+    /// still agree on where aligned windows start and end. Stores on a
+    /// server should report the server's clock; the default is this
+    /// process's.
+    fn now(&self) -> BoxFuture<'_, Result<DateTime<Utc>, RateLimitError>> {
+        Box::pin(std::future::ready(Ok(wall_clock_now())))
+    }
+}
+
+/// How often a [`MemoryStore`] sweeps out the expired counts of keys that
+/// went quiet; a key that's counted again expires on its own
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A store in process memory, for tests and for sharing counts between
+/// limiters in one process. Cloning a `MemoryStore` is cheap and the clones
+/// share their counts.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    keys: HashMap<String, (u32, Instant)>,
+    /// When expired keys were last swept out
+    swept: Option<Instant>,
+}
+
+impl Counts {
+    /// `key`'s count and expiry, forgetting them if they've expired
+    fn live(&mut self, key: &str, now: Instant) -> Option<(u32, Instant)> {
+        let &(count, expires) = self.keys.get(key)?;
+        if expires > now {
+            return Some((count, expires));
+        }
+        self.keys.remove(key);
+        None
+    }
+
+    /// Forgets every expired key, at most once per [`SWEEP_INTERVAL`]
+    fn sweep(&mut self, now: Instant) {
+        if self.swept.is_some_and(|swept| now.duration_since(swept) < SWEEP_INTERVAL) {
+            return;
+        }
+        self.swept = Some(now);
+        self.keys.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+impl MemoryStore {
+    /// An empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets every expired count now rather than at the next periodic
+    /// sweep, returning how many went
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut counts = self.counts();
+        let before = counts.keys.len();
+        counts.keys.retain(|_, (_, expires)| *expires > now);
+        counts.swept = Some(now);
+        before - counts.keys.len()
+    }
+
+    fn counts(&self) -> MutexGuard<'_, Counts> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        let now = Instant::now();
+        let stored = self.counts().live(key, now).map(|(count, expires)| StoredCount {
+            count,
+            ttl: expires.duration_since(now),
+        });
+        Box::pin(std::future::ready(Ok(stored)))
+    }
+
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<StoredCount, RateLimitError>> {
+        let now = Instant::now();
+        let mut counts = self.counts();
+        counts.sweep(now);
+        let (count, expires) = counts.live(key, now).unwrap_or((0, now + window));
+        let count = count.saturating_add(amount);
+        counts.keys.insert(key.to_string(), (count, expires));
+        let stored = StoredCount {
+            count,
+            ttl: expires.duration_since(now),
+        };
+        Box::pin(std::future::ready(Ok(stored)))
+    }
+
+    fn increment_within<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+        limit: u32,
+        window: Duration,
+    ) -> BoxFuture<'a, Result<Admission, RateLimitError>> {
+        let now = Instant::now();
+        let mut counts = self.counts();
+        counts.sweep(now);
+        let (count, expires) = counts.live(key, now).unwrap_or((0, now + window));
+        let admission = if count.saturating_add(amount) > limit {
+            Admission::Refused(StoredCount {
+                count,
+                ttl: expires.duration_since(now),
+            })
+        } else {
+            counts.keys.insert(key.to_string(), (count + amount, expires));
+            Admission::Admitted(StoredCount {
+                count: count + amount,
+                ttl: expires.duration_since(now),
+            })
+        };
+        Box::pin(std::future::ready(Ok(admission)))
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<(), RateLimitError>> {
+        let mut counts = self.counts();
+        if ttl.is_zero() {
+            counts.keys.remove(key);
+        } else if let Some((_, expires)) = counts.keys.get_mut(key) {
+            *expires = Instant::now() + ttl;
+        }
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn decrement<'a>(
+        &'a self,
+        key: &'a str,
+        amount: u32,
+    ) -> BoxFuture<'a, Result<Option<StoredCount>, RateLimitError>> {
+        let now = Instant::now();
+        let mut counts = self.counts();
+        let stored = counts.live(key, now).map(|(count, expires)| {
+            let count = count.saturating_sub(amount);
+            counts.keys.insert(key.to_string(), (count, expires));
+            StoredCount {
+                count,
+                ttl: expires.duration_since(now),
+            }
+        });
+        Box::pin(std::future::ready(Ok(stored)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_store_counts_expire_with_the_window() {
+        let store = MemoryStore::new();
+        let window = Duration::from_secs(60);
+        assert_eq!(store.increment("a", 2, window).await.unwrap().count, 2);
+        tokio::time::advance(Duration::from_secs(20)).await;
+        let stored = store.increment("a", 1, window).await.unwrap();
+        assert_eq!(stored, StoredCount { count: 3, ttl: Duration::from_secs(40) });
+
+        tokio::time::advance(Duration::from_secs(40)).await;
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.increment("a", 1, window).await.unwrap().count, 1);
+        store.expire("a", Duration::ZERO).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_store_only_counts_what_fits() {
+        let store = MemoryStore::new();
+        let window = Duration::from_secs(60);
+        let admitted = store.increment_within("a", 2, 3, window).await.unwrap();
+        assert_eq!(admitted, Admission::Admitted(StoredCount { count: 2, ttl: window }));
+        let refused = store.increment_within("a", 2, 3, window).await.unwrap();
+        assert_eq!(refused, Admission::Refused(StoredCount { count: 2, ttl: window }));
+        assert!(matches!(store.increment_within("a", 1, 3, window).await.unwrap(), Admission::Admitted(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_store_sweeps_out_expired_keys() {
+        let store = MemoryStore::new();
+        store.increment("a", 1, Duration::from_secs(10)).await.unwrap();
+        store.increment("b", 1, Duration::from_secs(10)).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        // Within the sweep interval only the key counted again goes
+        store.increment("a", 1, Duration::from_secs(120)).await.unwrap();
+        assert_eq!(store.counts().keys.len(), 2);
+
+        tokio::time::advance(SWEEP_INTERVAL).await;
+        store.increment("c", 1, Duration::from_secs(10)).await.unwrap();
+        let mut keys: Vec<_> = store.counts().keys.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "c"]);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(store.purge_expired(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_store_decrements_down_to_zero() {
+        let store = MemoryStore::new();
+        let window = Duration::from_secs(60);
+        assert_eq!(store.decrement("a", 1).await.unwrap(), None);
+        store.increment("a", 3, window).await.unwrap();
+        tokio::time::advance(Duration::from_secs(20)).await;
+        let stored = store.decrement("a", 2).await.unwrap();
+        assert_eq!(stored, Some(StoredCount { count: 1, ttl: Duration::from_secs(40) }));
+        assert_eq!(store.decrement("a", 5).await.unwrap().unwrap().count, 0);
+    }
+}
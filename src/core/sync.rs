@@ -0,0 +1,19 @@
+//! The synchronization primitives the counting core is built on: std's
+//! normally, loom's when built with `--cfg rate_limit_loom`, so the loom
+//! models can explore every interleaving of concurrent checks. The cfg isn't
+//! plain `loom` because tokio reads that one itself.
+//!
+//! ```text
+//! RUSTFLAGS="--cfg rate_limit_loom" cargo test --release --lib loom_tests
+//! ```
+
+#[cfg(rate_limit_loom)]
+pub(crate) use loom::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, MutexGuard,
+};
+#[cfg(not(rate_limit_loom))]
+pub(crate) use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, MutexGuard,
+};
@@ -0,0 +1,93 @@
+//! Per-tenant limiter instances, so one tenant's traffic never contends on
+//! another's counters
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::{RateLimitConfig, RateLimiter};
+
+/// Resolves each tenant to its own [`RateLimiter`], created lazily from
+/// `config_for(tenant)` the first time the tenant is seen.
+///
+/// At most `max_tenants` get a dedicated limiter. Tenants seen after that
+/// share one overflow limiter, still under their own config and with keys
+/// namespaced by tenant, so the number of instances stays bounded without
+/// evicting anyone's counters. Cloning a `TenantLimiters` is cheap and the
+/// clones share their limiters.
+#[derive(Clone)]
+pub struct TenantLimiters {
+    config_for: Arc<dyn Fn(&str) -> RateLimitConfig + Send + Sync>,
+    max_tenants: usize,
+    limiters: Arc<Mutex<HashMap<String, RateLimiter>>>,
+    overflow: RateLimiter,
+}
+
+impl TenantLimiters {
+    /// Build a resolver giving up to `max_tenants` tenants a dedicated
+    /// limiter configured by `config_for`
+    pub fn new<F>(max_tenants: usize, config_for: F) -> Self
+    where
+        F: Fn(&str) -> RateLimitConfig + Send + Sync + 'static,
+    {
+        Self {
+            config_for: Arc::new(config_for),
+            max_tenants,
+            limiters: Arc::new(Mutex::new(HashMap::new())),
+            overflow: RateLimiter::new(RateLimitConfig::default()),
+        }
+    }
+
+    /// The limiter for `tenant`, creating it if needed
+    pub fn limiter(&self, tenant: &str) -> RateLimiter {
+        let mut limiters = self.limiters.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(limiter) = limiters.get(tenant) {
+            return limiter.clone();
+        }
+
+        let config = (self.config_for)(tenant);
+        if limiters.len() < self.max_tenants {
+            let limiter = RateLimiter::new(config);
+            limiters.insert(tenant.to_string(), limiter.clone());
+            return limiter;
+        }
+
+        self.overflow.scoped(config.with_namespace(format!("overflow:{}", tenant)))
+    }
+
+    /// Number of tenants holding a dedicated limiter
+    pub fn dedicated_tenants(&self) -> usize {
+        self.limiters.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+impl std::fmt::Debug for TenantLimiters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TenantLimiters")
+            .field("max_tenants", &self.max_tenants)
+            .field("dedicated_tenants", &self.dedicated_tenants())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tenants_get_isolated_limiters() {
+        let tenants = TenantLimiters::new(1, |tenant| {
+            RateLimitConfig::max_per_window(if tenant == "acme" { 1 } else { 2 }, 60)
+        });
+
+        let acme = tenants.limiter("acme");
+        assert!(acme.check_rate_limit("k").await.is_ok());
+        assert!(tenants.limiter("acme").check_rate_limit("k").await.is_err());
+
+        // Past the bound, tenants share the overflow limiter under their own config
+        let globex = tenants.limiter("globex");
+        assert_eq!(tenants.dedicated_tenants(), 1);
+        assert_eq!(globex.check_rate_limit("k").await.unwrap().remaining, 1);
+        assert_eq!(tenants.limiter("initech").check_rate_limit("k").await.unwrap().remaining, 1);
+        assert_eq!(tenants.limiter("globex").check_rate_limit("k").await.unwrap().remaining, 0);
+    }
+}
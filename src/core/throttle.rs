@@ -0,0 +1,120 @@
+//! Per-connection message throttling for long-lived streams such as
+//! WebSockets
+
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Wraps `stream` so it yields at most `max_messages` items per `per`.
+/// Messages beyond the budget are not dropped; reading pauses until the next
+/// window, which pushes back on the sender.
+///
+/// Pair it with a rate limited upgrade route to cover both ends of a realtime
+/// endpoint:
+///
+/// ```rust,no_run,ignore
+/// let chat = warp::path("chat")
+///     .and(with_rate_limit(RateLimitConfig::max_per_minute(10)))
+///     .and(warp::ws())
+///     .map(|_info: RateLimitInfo, ws: warp::ws::Ws| {
+///         ws.on_upgrade(|socket| async move {
+///             let (tx, rx) = socket.split();
+///             let rx = throttle_messages(rx, 5, Duration::from_secs(1));
+///             // ...
+///         })
+///     });
+/// ```
+pub fn throttle_messages<S: Stream + Unpin>(stream: S, max_messages: u32, per: Duration) -> MessageThrottle<S> {
+    MessageThrottle {
+        inner: stream,
+        max_messages,
+        per,
+        window_start: Instant::now(),
+        count: 0,
+        sleep: None,
+    }
+}
+
+/// The stream returned by [`throttle_messages`]
+#[derive(Debug)]
+pub struct MessageThrottle<S> {
+    inner: S,
+    max_messages: u32,
+    per: Duration,
+    window_start: Instant,
+    count: u32,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> MessageThrottle<S> {
+    /// Unwraps the throttle, returning the underlying stream
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Stream + Unpin> Stream for MessageThrottle<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(sleep) = this.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.sleep = None;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(this.window_start) >= this.per {
+            this.window_start = now;
+            this.count = 0;
+        }
+
+        if this.count >= this.max_messages {
+            // Out of budget: wait for the window to roll over before reading
+            let mut sleep = Box::pin(sleep_until(this.window_start + this.per));
+            let _ = sleep.as_mut().poll(cx);
+            this.sleep = Some(sleep);
+            return Poll::Pending;
+        }
+
+        let item = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(_)) = item {
+            this.count += 1;
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_delays_excess_messages() {
+        let start = Instant::now();
+        let mut throttled = throttle_messages(futures_util::stream::iter(1..=5), 2, Duration::from_secs(1));
+
+        assert_eq!(throttled.next().await, Some(1));
+        assert_eq!(throttled.next().await, Some(2));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The third message waits for the next window
+        assert_eq!(throttled.next().await, Some(3));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let rest: Vec<_> = throttled.collect().await;
+        assert_eq!(rest, vec![4, 5]);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+}
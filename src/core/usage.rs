@@ -0,0 +1,541 @@
+//! Per-key usage totals exported at the end of each billing period, so
+//! billing and analytics pipelines can consume API usage without scraping
+//! metrics, and hourly and daily rollups kept in memory for answering
+//! questions like "how much did this customer use last Tuesday"
+//!
+//! ```rust,no_run,ignore
+//! let ledger = UsageLedger::new();
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_usage_ledger(ledger.clone());
+//! let file = std::fs::File::options().append(true).create(true).open("usage.jsonl")?;
+//! spawn_usage_export(ledger, Duration::from_secs(3600), JsonLinesSink::new(file));
+//! ```
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "tokio")]
+use super::TokioRuntime;
+use super::{wall_clock_now, KeyMetadata, ResetMode, Runtime};
+
+/// Units a key used in one export period
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageSummary {
+    /// The key, including its namespace
+    pub key: String,
+    /// Units admitted for the key during the period
+    pub units: u64,
+    /// When the period started
+    pub period_start: DateTime<Utc>,
+    /// When the period ended
+    pub period_end: DateTime<Utc>,
+    /// The metadata attached to the key when it last recorded usage
+    #[serde(default, skip_serializing_if = "KeyMetadata::is_empty")]
+    pub metadata: KeyMetadata,
+}
+
+/// Units admitted per key since the last export. Cloning a `UsageLedger` is
+/// cheap and the clones share their totals.
+#[derive(Clone, Debug, Default)]
+pub struct UsageLedger {
+    totals: Arc<Mutex<HashMap<String, Total>>>,
+}
+
+/// A key's units in the current period, with its latest metadata
+#[derive(Debug, Default)]
+struct Total {
+    units: u64,
+    metadata: KeyMetadata,
+}
+
+impl UsageLedger {
+    /// An empty ledger
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `units` to `key`'s total
+    pub fn record(&self, key: &str, units: u32) {
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        let total = totals.entry(key.to_string()).or_default();
+        total.units = total.units.saturating_add(u64::from(units));
+    }
+
+    /// Adds `units` to `key`'s total, reporting `metadata` with it in place
+    /// of any recorded before
+    pub fn record_with_metadata(&self, key: &str, units: u32, metadata: KeyMetadata) {
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        let total = totals.entry(key.to_string()).or_default();
+        total.units = total.units.saturating_add(u64::from(units));
+        total.metadata = metadata;
+    }
+
+    /// Takes back `units` of `key`'s total, e.g. for a refunded request
+    pub fn refund(&self, key: &str, units: u32) {
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(total) = totals.get_mut(key) {
+            total.units = total.units.saturating_sub(u64::from(units));
+        }
+    }
+
+    /// Drops the totals of every key `purge` matches, e.g. to honor a
+    /// deletion request
+    pub fn purge_where<F: Fn(&str) -> bool>(&self, purge: F) {
+        self.totals.lock().unwrap_or_else(|e| e.into_inner()).retain(|key, _| !purge(key));
+    }
+
+    /// Empties the ledger, returning each key's total for the period from
+    /// `period_start` to `period_end`, sorted by key
+    pub fn take(&self, period_start: DateTime<Utc>, period_end: DateTime<Utc>) -> Vec<UsageSummary> {
+        let totals = std::mem::take(&mut *self.totals.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut summaries: Vec<_> = totals
+            .into_iter()
+            .map(|(key, total)| UsageSummary {
+                key,
+                units: total.units,
+                period_start,
+                period_end,
+                metadata: total.metadata,
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.key.cmp(&b.key));
+        summaries
+    }
+}
+
+/// The granularity of a usage rollup
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rollup {
+    /// Totals per UTC hour
+    Hourly,
+    /// Totals per UTC day
+    Daily,
+}
+
+impl Rollup {
+    fn length(&self) -> Duration {
+        match self {
+            Rollup::Hourly => Duration::from_secs(3600),
+            Rollup::Daily => Duration::from_secs(86400),
+        }
+    }
+}
+
+/// Per-key totals for every hour and day, kept for a retention period.
+/// Cloning a `UsageRollups` is cheap and the clones share their totals.
+///
+/// ```rust,no_run,ignore
+/// let rollups = UsageRollups::new();
+/// let limiter = RateLimiter::new(config).with_usage_rollups(rollups.clone());
+/// // Later: what did customer-42 use last Tuesday?
+/// let tuesday = rollups.query("customer-42", Rollup::Daily, tuesday_start, tuesday_start + Duration::days(1));
+/// ```
+#[derive(Clone, Debug)]
+pub struct UsageRollups {
+    state: Arc<Mutex<RollupState>>,
+}
+
+#[derive(Debug)]
+struct RollupState {
+    /// Totals by rollup, key, and bucket start as a Unix timestamp
+    totals: HashMap<Rollup, HashMap<String, BTreeMap<i64, u64>>>,
+    retention: HashMap<Rollup, Duration>,
+    /// The bucket each rollup was last pruned at
+    pruned: HashMap<Rollup, i64>,
+    /// The metadata each key last recorded usage with, while it has buckets
+    metadata: HashMap<String, KeyMetadata>,
+}
+
+impl RollupState {
+    /// Drops the metadata of keys left without buckets
+    fn forget_metadata(&mut self) {
+        let RollupState { totals, metadata, .. } = self;
+        metadata.retain(|key, _| totals.values().any(|keys| keys.contains_key(key)));
+    }
+}
+
+impl Default for UsageRollups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsageRollups {
+    /// Empty rollups, keeping hourly totals for 7 days and daily totals for
+    /// 90 days
+    pub fn new() -> Self {
+        let retention = HashMap::from([
+            (Rollup::Hourly, Duration::from_secs(7 * 86400)),
+            (Rollup::Daily, Duration::from_secs(90 * 86400)),
+        ]);
+        Self {
+            state: Arc::new(Mutex::new(RollupState {
+                totals: HashMap::new(),
+                retention,
+                pruned: HashMap::new(),
+                metadata: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Keep `rollup` totals for `retention`
+    pub fn with_retention(self, rollup: Rollup, retention: Duration) -> Self {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).retention.insert(rollup, retention);
+        self
+    }
+
+    /// Adds `units` to `key`'s current hour and day
+    pub fn record(&self, key: &str, units: u32) {
+        self.add(key, units, |total, units| total.saturating_add(units));
+    }
+
+    /// Adds `units` to `key`'s current hour and day, reporting `metadata`
+    /// with its totals in place of any recorded before
+    pub fn record_with_metadata(&self, key: &str, units: u32, metadata: KeyMetadata) {
+        self.add(key, units, |total, units| total.saturating_add(units));
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if metadata.is_empty() {
+            state.metadata.remove(key);
+        } else {
+            state.metadata.insert(key.to_string(), metadata);
+        }
+    }
+
+    /// Takes back `units` from `key`'s current hour and day, e.g. for a
+    /// refunded request
+    pub fn refund(&self, key: &str, units: u32) {
+        self.add(key, units, |total, units| total.saturating_sub(units));
+    }
+
+    fn add(&self, key: &str, units: u32, apply: fn(u64, u64) -> u64) {
+        let now = wall_clock_now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let RollupState { totals, retention, pruned, .. } = &mut *state;
+        let mut purged = false;
+        for rollup in [Rollup::Hourly, Rollup::Daily] {
+            let Some((start, _)) = ResetMode::FixedInterval.bounds(rollup.length(), now) else {
+                continue;
+            };
+            let keys = totals.entry(rollup).or_default();
+            if pruned.insert(rollup, start.timestamp()) != Some(start.timestamp()) {
+                // The first record in a new bucket drops those past retention
+                if let Some(before) = oldest(now, retention[&rollup]) {
+                    purge(keys, before);
+                    purged = true;
+                }
+            }
+            let total = keys.entry(key.to_string()).or_default().entry(start.timestamp()).or_default();
+            *total = apply(*total, u64::from(units));
+        }
+        if purged {
+            state.forget_metadata();
+        }
+    }
+
+    /// Drops every bucket past its rollup's retention. Recording usage does
+    /// this as each new hour starts; [`spawn_rollup_purge`] does it on a
+    /// timer, for when traffic stops.
+    pub fn purge_expired(&self) {
+        let now = wall_clock_now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let RollupState { totals, retention, .. } = &mut *state;
+        for (rollup, keys) in totals.iter_mut() {
+            if let Some(before) = oldest(now, retention[rollup]) {
+                purge(keys, before);
+            }
+        }
+        state.forget_metadata();
+    }
+
+    /// Drops every bucket that started before `before`, whatever the
+    /// retention, e.g. to honor a deletion request
+    pub fn purge_before(&self, before: DateTime<Utc>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for keys in state.totals.values_mut() {
+            purge(keys, before);
+        }
+        state.forget_metadata();
+    }
+
+    /// Drops every bucket of every key `purge` matches, e.g. to honor a
+    /// deletion request
+    pub fn purge_where<F: Fn(&str) -> bool>(&self, purge: F) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for keys in state.totals.values_mut() {
+            keys.retain(|key, _| !purge(key));
+        }
+        state.forget_metadata();
+    }
+
+    /// `key`'s `rollup` totals for buckets starting from `from` up to `to`,
+    /// oldest first. Buckets without usage are left out.
+    pub fn query(&self, key: &str, rollup: Rollup, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<UsageSummary> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(buckets) = state.totals.get(&rollup).and_then(|keys| keys.get(key)) else {
+            return Vec::new();
+        };
+        let length = chrono::Duration::from_std(rollup.length()).unwrap_or_else(|_| chrono::Duration::zero());
+        buckets
+            .range(from.timestamp()..to.timestamp())
+            .filter_map(|(start, units)| {
+                let period_start = DateTime::from_timestamp(*start, 0)?;
+                Some(UsageSummary {
+                    key: key.to_string(),
+                    units: *units,
+                    period_start,
+                    period_end: period_start + length,
+                    metadata: state.metadata.get(key).cloned().unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The start of the oldest bucket kept under `retention`, or `None` to keep
+/// every bucket when `retention` reaches back past the earliest date
+fn oldest(now: DateTime<Utc>, retention: Duration) -> Option<DateTime<Utc>> {
+    now.checked_sub_signed(chrono::Duration::from_std(retention).ok()?)
+}
+
+/// Drops buckets that started before `before`, and keys left without any
+fn purge(keys: &mut HashMap<String, BTreeMap<i64, u64>>, before: DateTime<Utc>) {
+    keys.retain(|_, buckets| {
+        buckets.retain(|bucket, _| *bucket >= before.timestamp());
+        !buckets.is_empty()
+    });
+}
+
+/// Spawns a task that drops rollup buckets past their retention every
+/// `interval`. Runs until the returned handle is aborted.
+#[cfg(feature = "tokio")]
+pub fn spawn_rollup_purge(rollups: UsageRollups, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(purge_rollups(rollups, interval, Arc::new(TokioRuntime)))
+}
+
+/// [`spawn_rollup_purge`] on any [`Runtime`]. Runs for as long as the
+/// runtime does.
+pub fn spawn_rollup_purge_on(runtime: Arc<dyn Runtime>, rollups: UsageRollups, interval: Duration) {
+    runtime.spawn(Box::pin(purge_rollups(rollups, interval, runtime.clone())));
+}
+
+async fn purge_rollups(rollups: UsageRollups, interval: Duration, runtime: Arc<dyn Runtime>) {
+    loop {
+        rollups.purge_expired();
+        runtime.sleep(interval).await;
+    }
+}
+
+/// Where exported usage goes. The export task calls it on the runtime, so
+/// hand slow work off to a channel or blocking task.
+pub trait UsageSink: Send + Sync + 'static {
+    /// Writes one period's summaries. On error, they are retried with the
+    /// next period's.
+    fn export(&self, summaries: &[UsageSummary]) -> io::Result<()>;
+}
+
+/// Writes each summary as a line of JSON
+#[derive(Debug)]
+pub struct JsonLinesSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> JsonLinesSink<W> {
+    /// Write summaries to `writer`, e.g. a file opened for appending
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> UsageSink for JsonLinesSink<W> {
+    fn export(&self, summaries: &[UsageSummary]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        for summary in summaries {
+            serde_json::to_writer(&mut *writer, summary)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+}
+
+/// Writes summaries as CSV rows of `key,units,period_start,period_end`, with
+/// a header row before the first export
+#[derive(Debug)]
+pub struct CsvSink<W> {
+    writer: Mutex<(W, bool)>,
+}
+
+impl<W: Write + Send + 'static> CsvSink<W> {
+    /// Write summaries to `writer`, starting with a header row
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new((writer, false)),
+        }
+    }
+
+    /// Write summaries to `writer` without a header row, e.g. when appending
+    /// to an existing file
+    pub fn without_header(writer: W) -> Self {
+        Self {
+            writer: Mutex::new((writer, true)),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> UsageSink for CsvSink<W> {
+    fn export(&self, summaries: &[UsageSummary]) -> io::Result<()> {
+        let mut guard = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let (writer, header_written) = &mut *guard;
+        if !*header_written {
+            writer.write_all(b"key,units,period_start,period_end\n")?;
+            *header_written = true;
+        }
+        for summary in summaries {
+            writeln!(
+                writer,
+                "{},{},{},{}",
+                csv_field(&summary.key),
+                summary.units,
+                summary.period_start.to_rfc3339(),
+                summary.period_end.to_rfc3339()
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+/// `field` quoted if it contains a delimiter, quote, or line break
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Spawns a task that empties `ledger` into `sink` at the end of every
+/// `period`. Periods are aligned to the clock, so hourly exports happen on
+/// the hour; the first one covers the partial period since the task started.
+/// Summaries the sink fails to write are logged and retried with the next
+/// period's. Runs until the returned handle is aborted.
+#[cfg(feature = "tokio")]
+pub fn spawn_usage_export<S: UsageSink>(ledger: UsageLedger, period: Duration, sink: S) -> JoinHandle<()> {
+    tokio::spawn(export_usage(ledger, period, sink, Arc::new(TokioRuntime)))
+}
+
+/// [`spawn_usage_export`] on any [`Runtime`]. Runs for as long as the
+/// runtime does.
+pub fn spawn_usage_export_on<S: UsageSink>(runtime: Arc<dyn Runtime>, ledger: UsageLedger, period: Duration, sink: S) {
+    runtime.spawn(Box::pin(export_usage(ledger, period, sink, runtime.clone())));
+}
+
+async fn export_usage<S: UsageSink>(ledger: UsageLedger, period: Duration, sink: S, runtime: Arc<dyn Runtime>) {
+    let mut period_start = wall_clock_now();
+    let mut pending = Vec::new();
+    loop {
+        let now = wall_clock_now();
+        // Measured from the last boundary, in case the clock lags the timer
+        let period_end = ResetMode::FixedInterval
+            .bounds(period, now.max(period_start))
+            .map_or(now + chrono::Duration::seconds(1), |(_, end)| end);
+        runtime.sleep((period_end - now).to_std().unwrap_or_default()).await;
+
+        pending.extend(ledger.take(period_start, period_end));
+        period_start = period_end;
+        if pending.is_empty() {
+            continue;
+        }
+        match sink.export(&pending) {
+            Ok(()) => pending.clear(),
+            Err(e) => tracing::warn!("failed to export usage for {} keys: {}", pending.len(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledger_exports_to_sinks() {
+        let ledger = UsageLedger::new();
+        ledger.record("b", 2);
+        ledger.record("a,1", 1);
+        ledger.record("b", 3);
+
+        let end = Utc::now();
+        let start = end - chrono::Duration::hours(1);
+        let summaries = ledger.take(start, end);
+        assert_eq!(summaries.iter().map(|s| s.units).collect::<Vec<_>>(), vec![1, 5]);
+        assert!(ledger.take(start, end).is_empty());
+
+        let sink = JsonLinesSink::new(Vec::new());
+        sink.export(&summaries).unwrap();
+        let json = String::from_utf8(sink.writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<UsageSummary> = json.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines, summaries);
+
+        let sink = CsvSink::new(Vec::new());
+        sink.export(&summaries).unwrap();
+        let csv = String::from_utf8(sink.writer.into_inner().unwrap().0).unwrap();
+        let rows: Vec<_> = csv.lines().collect();
+        assert_eq!(rows[0], "key,units,period_start,period_end");
+        assert!(rows[1].starts_with("\"a,1\",1,"));
+        assert!(rows[2].starts_with("b,5,"));
+    }
+
+    #[test]
+    fn test_rollups_by_hour_and_day() {
+        let rollups = UsageRollups::new();
+        rollups.record("customer", 3);
+        rollups.record("customer", 4);
+        rollups.refund("customer", 2);
+        rollups.record("other", 1);
+
+        let now = Utc::now();
+        let day = rollups.query("customer", Rollup::Daily, now - chrono::Duration::days(1), now + chrono::Duration::days(1));
+        assert_eq!(day.len(), 1);
+        assert_eq!(day[0].units, 5);
+        assert_eq!(day[0].period_end - day[0].period_start, chrono::Duration::days(1));
+        let hour = rollups.query("customer", Rollup::Hourly, now - chrono::Duration::hours(1), now + chrono::Duration::hours(1));
+        assert_eq!(hour[0].units, 5);
+
+        assert!(rollups.query("customer", Rollup::Daily, now - chrono::Duration::days(9), now - chrono::Duration::days(2)).is_empty());
+        assert!(rollups.query("nobody", Rollup::Hourly, now - chrono::Duration::days(1), now).is_empty());
+    }
+
+    #[test]
+    fn test_rollup_purging() {
+        let rollups = UsageRollups::new().with_retention(Rollup::Hourly, Duration::ZERO);
+        let metadata = KeyMetadata::from([("plan".to_string(), "pro".to_string())]);
+        rollups.record_with_metadata("customer", 1, metadata.clone());
+        let now = Utc::now();
+        let day = now - chrono::Duration::days(1)..now + chrono::Duration::days(1);
+
+        // Only this hour's bucket is past a zero retention
+        rollups.purge_expired();
+        assert!(rollups.query("customer", Rollup::Hourly, day.start, day.end).is_empty());
+        assert_eq!(rollups.query("customer", Rollup::Daily, day.start, day.end)[0].metadata, metadata);
+
+        // The metadata goes with the key's last bucket
+        rollups.purge_before(now + chrono::Duration::days(1));
+        assert!(rollups.query("customer", Rollup::Daily, day.start, day.end).is_empty());
+        assert!(rollups.state.lock().unwrap().metadata.is_empty());
+    }
+
+    #[test]
+    fn test_unbounded_retention_keeps_everything() {
+        let rollups = UsageRollups::new().with_retention(Rollup::Hourly, Duration::MAX);
+        rollups.record("customer", 1);
+        rollups.purge_expired();
+        let now = Utc::now();
+        let hourly = rollups.query("customer", Rollup::Hourly, now - chrono::Duration::days(1), now + chrono::Duration::days(1));
+        assert_eq!(hourly.len(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Reloads a limiter's policy file when it changes on disk, enabled by the
+//! `watch` feature
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+
+use super::{RateLimitConfig, RateLimitError, RateLimiter};
+
+/// Watches a JSON policy file (see [`RateLimitConfig::from_json`]) and swaps
+/// each new version into `limiter` without dropping its counters.
+///
+/// The file's directory is watched rather than the file itself, so
+/// replacements by rename, such as the symlink swap Kubernetes performs when
+/// a mounted ConfigMap changes, are picked up. A file that fails to parse is
+/// logged and ignored, leaving the previous policy in force. Watching stops
+/// when the returned [`ConfigWatcher`] is dropped.
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiter::new(RateLimitConfig::from_file("/etc/ratelimit/policy.json")?);
+/// let _watcher = watch_config("/etc/ratelimit/policy.json", limiter.clone())?;
+/// ```
+pub fn watch_config(path: impl AsRef<Path>, limiter: RateLimiter) -> Result<ConfigWatcher, RateLimitError> {
+    let path: PathBuf = path.as_ref().to_path_buf();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let file = path.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.map_or(true, |e| e.kind.is_access()) {
+            return;
+        }
+        match RateLimitConfig::from_file(&file) {
+            Ok(config) if config != limiter.config() => limiter.set_config(config),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("keeping previous rate limit policy, {}: {}", file.display(), e),
+        }
+    })
+    .map_err(|e| RateLimitError::Other(Box::new(e)))?;
+
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| RateLimitError::Other(Box::new(e)))?;
+
+    Ok(ConfigWatcher { _watcher: watcher })
+}
+
+/// Keeps a [`watch_config`] watch alive; dropping it stops reloading
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reload_keeps_counters() {
+        let dir = std::env::temp_dir().join(format!("warp-rate-limit-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.json");
+        std::fs::write(&path, r#"{"max_requests": 2, "window_secs": 60}"#).unwrap();
+
+        let limiter = RateLimiter::new(RateLimitConfig::from_file(&path).unwrap());
+        let _watcher = watch_config(&path, limiter.clone()).unwrap();
+        limiter.check_rate_limit("a").await.unwrap();
+
+        std::fs::write(&path, r#"{"max_requests": 5, "window_secs": 60}"#).unwrap();
+        for _ in 0..100 {
+            if limiter.config().max_requests == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(limiter.config().max_requests, 5);
+
+        // The request made under the old policy still counts
+        assert_eq!(limiter.check_rate_limit("a").await.unwrap().remaining, 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+//! The axum adapter: an extractor and a middleware function built on the same
+//! core limiter, so apps running both warp and axum share one set of
+//! policies.
+//!
+//! Both key on the client IP from `ConnectInfo<SocketAddr>`, so the app must
+//! be served with `into_make_service_with_connect_info::<SocketAddr>()`:
+//!
+//! ```rust,no_run,ignore
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100));
+//!
+//! let app = Router::new()
+//!     .route("/", get(handler))
+//!     .route_layer(middleware::from_fn_with_state(limiter.clone(), rate_limit))
+//!     .with_state(limiter);
+//! ```
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRef, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use crate::core::{add_rate_limit_headers, is_preflight, rate_limit_info, RateLimitInfo, RateLimitRejection, RateLimiter};
+
+/// Extracts the caller's rate limit status, counting the request against
+/// the `RateLimiter` in the router state. Rejects with a complete 429 when
+/// the caller is over its limit.
+#[derive(Clone, Debug)]
+pub struct RateLimited(pub RateLimitInfo);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RateLimited
+where
+    RateLimiter: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = RateLimitRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let limiter = RateLimiter::from_ref(state);
+        check(&limiter, parts).await.map(RateLimited)
+    }
+}
+
+impl IntoResponse for RateLimitRejection {
+    fn into_response(self) -> Response {
+        Response::<String>::from(&self).into_response()
+    }
+}
+
+/// Extracts the status the [`rate_limit`] middleware computed for this
+/// request, without counting it again. `None` when no middleware ran.
+#[derive(Clone, Debug)]
+pub struct RateLimitStatus(pub Option<RateLimitInfo>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RateLimitStatus {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(RateLimitStatus(rate_limit_info(&parts.extensions).cloned()))
+    }
+}
+
+/// Middleware for `axum::middleware::from_fn_with_state` that limits every
+/// request and adds the rate limit headers to successful responses. Handlers
+/// behind it can read the status with [`RateLimitStatus`].
+pub async fn rate_limit<B>(State(limiter): State<RateLimiter>, req: Request<B>, next: Next<B>) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let info = match check(&limiter, &parts).await {
+        Ok(info) => info,
+        Err(rejection) => return rejection.into_response(),
+    };
+    parts.extensions.insert(info.clone());
+
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    if let Err(e) = add_rate_limit_headers(response.headers_mut(), &info) {
+        tracing::warn!("{}", e);
+    }
+    response
+}
+
+/// Counts the request against its client, or per the preflight policy for
+/// CORS preflights
+async fn check(limiter: &RateLimiter, parts: &Parts) -> Result<RateLimitInfo, RateLimitRejection> {
+    let key = client_key(parts);
+    let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let checked = match limiter.screen(&key, peer, &parts.headers).await {
+        Some(screened) => screened,
+        None if is_preflight(&parts.method, &parts.headers) => limiter.check_preflight(&key).await,
+        None => limiter.check_rate_limit(&key).await,
+    };
+    if let Err(rejection) = &checked {
+        limiter.tarpit(&key, rejection).await;
+    }
+    checked
+}
+
+fn client_key(parts: &Parts) -> String {
+    parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::RateLimitConfig;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    fn request_from(addr: &str) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        req.extensions_mut().insert(ConnectInfo(addr.parse::<SocketAddr>().unwrap()));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_extractor() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let app = Router::new()
+            .route("/", get(|RateLimited(info): RateLimited| async move { info.remaining.to_string() }))
+            .with_state(limiter);
+
+        let resp = app.clone().oneshot(request_from("10.0.0.1:1")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = app.oneshot(request_from("10.0.0.1:1")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let app = Router::new()
+            .route("/", get(|RateLimitStatus(info): RateLimitStatus| async move { info.unwrap().used.to_string() }))
+            .route_layer(middleware::from_fn_with_state(limiter, rate_limit));
+
+        let resp = app.clone().oneshot(request_from("10.0.0.1:1")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+
+        let resp = app.oneshot(request_from("10.0.0.1:1")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
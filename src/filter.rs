@@ -0,0 +1,1892 @@
+//! The warp adapter: filters and reply helpers built on the core limiter
+
+use warp::filters::BoxedFilter;
+use warp::{reject, Filter, Rejection, Reply};
+
+use crate::core::{
+    add_rate_limit_headers, is_preflight, request_fingerprint, ByteQuota, ChallengeRejection, Challenges,
+    ConcurrencyLimitRejection, ConcurrencyLimiter, ConnectionPermit, ContentLengthCost, GlobalLimiter, Priority, QuotaInfo,
+    QuotaRejection, RateLimitConfig, RateLimitInfo, RateLimitRejection, RateLimitRules, RateLimiter, RateLimiterHandle,
+    SlowRequestGuard, SlowRequestPermit, StatusPenalty, TenantLimiters, CHALLENGE_RESPONSE_HEADER,
+};
+
+impl reject::Reject for RateLimitRejection {}
+impl reject::Reject for ConcurrencyLimitRejection {}
+impl reject::Reject for ChallengeRejection {}
+impl reject::Reject for QuotaRejection {}
+
+/// Replies with the complete `429` (or `503` for maintenance), so recovery
+/// handlers can return the rejection itself
+impl Reply for RateLimitRejection {
+    fn into_response(self) -> warp::reply::Response {
+        warp::reply::Response::from(&self)
+    }
+}
+
+/// Creates a rate limiting filter with the given configuration, keyed on the
+/// remote IP address
+pub fn with_rate_limit(
+    config: RateLimitConfig,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+    with_rate_limit_by(config, key::remote_ip())
+}
+
+/// Creates a rate limiting filter keyed on whatever `key` extracts. Use one of
+/// the sources in [`key`], or any filter extracting a `String`, e.g.
+/// `warp::any().map(|| ...)` for a custom callback.
+pub fn with_rate_limit_by<K>(
+    config: RateLimitConfig,
+    key: K,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    RateLimiter::new(config).filter_by(key)
+}
+
+/// [`with_rate_limit`], along with a handle for swapping the filter's config
+/// while it serves requests, e.g. to tighten limits during an incident:
+///
+/// ```rust,no_run,ignore
+/// let (handle, limit) = with_rate_limit_handle(RateLimitConfig::max_per_minute(100));
+/// let api = warp::path("api").and(limit).map(handler);
+/// // Later, from anywhere:
+/// handle.update_config(RateLimitConfig::max_per_minute(10));
+/// ```
+pub fn with_rate_limit_handle(
+    config: RateLimitConfig,
+) -> (RateLimiterHandle, impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone) {
+    with_rate_limit_by_handle(config, key::remote_ip())
+}
+
+/// [`with_rate_limit_by`], along with a handle for swapping the filter's
+/// config while it serves requests
+pub fn with_rate_limit_by_handle<K>(
+    config: RateLimitConfig,
+    key: K,
+) -> (RateLimiterHandle, impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone)
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    let limiter = RateLimiter::new(config);
+    (limiter.handle(), limiter.filter_by(key))
+}
+
+/// Creates a rate limiting filter keyed on the remote IP that charges every
+/// request `cost` units, e.g. 5 for a search endpoint that shares a budget
+/// with cheaper routes
+pub fn with_rate_limit_cost(
+    config: RateLimitConfig,
+    cost: u32,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+    RateLimiter::new(config).counting(key::remote_ip().map(move |key: String| (key, Some(cost))))
+}
+
+/// Creates a rate limiting filter keyed on whatever `key` extracts and
+/// charging each request whatever `cost` extracts, so the cost can depend on
+/// the request:
+///
+/// ```rust,no_run,ignore
+/// let cost = warp::query::<SearchParams>().map(|params: SearchParams| if params.fuzzy { 5 } else { 1 });
+/// let search = warp::path("search").and(with_rate_limit_cost_by(config, key::remote_ip(), cost));
+/// ```
+pub fn with_rate_limit_cost_by<K, C>(
+    config: RateLimitConfig,
+    key: K,
+    cost: C,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+    C: Filter<Extract = (u32,), Error = Rejection> + Clone + Send + Sync,
+{
+    RateLimiter::new(config).filter_with_cost(key, cost)
+}
+
+/// Rate limiting filters sharing a limiter's counters. Each call to
+/// `with_rate_limit` builds a limiter of its own, so two routes given the
+/// same config are limited separately; filters made from one `RateLimiter`
+/// (or its clones) count against the same budget:
+///
+/// ```rust,no_run,ignore
+/// let public = RateLimiter::new(RateLimitConfig::max_per_minute(100));
+/// let a = warp::path("public").and(public.filter()).map(handler);
+/// let b = warp::path("also_public").and(public.filter()).map(handler);
+/// ```
+impl RateLimiter {
+    /// A filter counting each request against this limiter, keyed on the
+    /// remote IP
+    pub fn filter(&self) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+        self.filter_by(key::remote_ip())
+    }
+
+    /// A filter counting each request against this limiter, keyed on
+    /// whatever `key` extracts
+    pub fn filter_by<K>(&self, key: K) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+    where
+        K: Filter<Extract = (String,), Error = Rejection> + Clone,
+    {
+        self.counting(key.map(|key: String| (key, None)))
+    }
+
+    /// A filter charging each request whatever `cost` extracts against this
+    /// limiter, keyed on whatever `key` extracts. The cost replaces the
+    /// config's `Content-Length` cost.
+    pub fn filter_with_cost<K, C>(
+        &self,
+        key: K,
+        cost: C,
+    ) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+    where
+        K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+        C: Filter<Extract = (u32,), Error = Rejection> + Clone + Send + Sync,
+    {
+        self.counting(key.and(cost).map(|key: String, cost: u32| (key, Some(cost))))
+    }
+
+    /// Counts each request against the key `keyed` extracts, at the cost
+    /// alongside it if there is one
+    fn counting<K>(&self, keyed: K) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+    where
+        K: Filter<Extract = ((String, Option<u32>),), Error = Rejection> + Clone,
+    {
+        let rate_limiter = self.clone();
+        keyed
+            .and(request_shape())
+            .and(peer(self.trusts_proxies()))
+            .map(move |(key, cost): (String, Option<u32>), content_length: Option<u64>, preflight: bool, peer: Peer| {
+                (rate_limiter.clone(), key, cost, content_length, preflight, peer)
+            })
+            .and_then(
+                |(rate_limiter, key, cost, content_length, preflight, peer): (
+                    RateLimiter,
+                    String,
+                    Option<u32>,
+                    Option<u64>,
+                    bool,
+                    Peer,
+                )| async move { enforce(&rate_limiter, &key, cost, content_length, preflight, &peer).await },
+            )
+    }
+}
+
+/// The request's `Content-Length`, and whether it is a CORS preflight
+fn request_shape() -> impl Filter<Extract = (Option<u64>, bool), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::method())
+        .and(warp::header::optional::<String>("access-control-request-method"))
+        .map(|content_length: Option<u64>, method: warp::http::Method, requested: Option<String>| {
+            (content_length, method == warp::http::Method::OPTIONS && requested.is_some())
+        })
+        .untuple_one()
+}
+
+/// The peer's address and the request headers, for matching the client
+/// against the config's IP lists (see [`RateLimiter::screen`])
+#[derive(Clone, Debug)]
+struct Peer {
+    addr: Option<std::net::IpAddr>,
+    headers: warp::http::HeaderMap,
+}
+
+/// The peer, with the request headers only if `headers` asks for them
+/// (e.g. for a limiter that [trusts proxies](RateLimiter::with_trusted_proxies)),
+/// so other requests don't pay for copying them
+fn peer(headers: bool) -> BoxedFilter<(Peer,)> {
+    let addr = warp::addr::remote().map(|addr: Option<std::net::SocketAddr>| addr.map(|addr| addr.ip()));
+    if headers {
+        addr.and(warp::header::headers_cloned())
+            .map(|addr, headers| Peer { addr, headers })
+            .boxed()
+    } else {
+        addr.map(|addr| Peer {
+            addr,
+            headers: warp::http::HeaderMap::new(),
+        })
+        .boxed()
+    }
+}
+
+/// Counts a request against `key` unless the client is on the config's IP
+/// lists, charging `cost` if given, or by `Content-Length` if the config
+/// asks for it, or per the preflight policy for CORS preflights
+async fn check(
+    rate_limiter: &RateLimiter,
+    key: &str,
+    cost: Option<u32>,
+    content_length: Option<u64>,
+    preflight: bool,
+    peer: &Peer,
+) -> Result<RateLimitInfo, RateLimitRejection> {
+    if let Some(screened) = rate_limiter.screen(key, peer.addr, &peer.headers).await {
+        return screened;
+    }
+    admit(rate_limiter, key, cost, content_length, preflight).await
+}
+
+/// [`check`], holding a rate limited request for the config's tarpit delay
+/// before rejecting it
+async fn enforce(
+    rate_limiter: &RateLimiter,
+    key: &str,
+    cost: Option<u32>,
+    content_length: Option<u64>,
+    preflight: bool,
+    peer: &Peer,
+) -> Result<RateLimitInfo, Rejection> {
+    match check(rate_limiter, key, cost, content_length, preflight, peer).await {
+        Ok(info) => Ok(info),
+        Err(rejection) => {
+            rate_limiter.tarpit(key, &rejection).await;
+            Err(reject::custom(rejection))
+        }
+    }
+}
+
+/// [`check`] for a client already screened against the IP lists
+async fn admit(
+    rate_limiter: &RateLimiter,
+    key: &str,
+    cost: Option<u32>,
+    content_length: Option<u64>,
+    preflight: bool,
+) -> Result<RateLimitInfo, RateLimitRejection> {
+    if preflight {
+        return rate_limiter.check_preflight(key).await;
+    }
+    let cost = cost.unwrap_or_else(|| request_cost(rate_limiter, content_length));
+    rate_limiter.check_rate_limit_with_cost(key, cost).await
+}
+
+/// What a request costs up front: its `Content-Length` cost if the config
+/// asks for one, otherwise one unit
+fn request_cost(rate_limiter: &RateLimiter, content_length: Option<u64>) -> u32 {
+    rate_limiter
+        .config()
+        .content_length_cost
+        .map_or(1, |c| c.cost(content_length))
+}
+
+/// A route answering `GET` with the calling client's own limit, remaining,
+/// and reset as JSON (see [`RateLimitInfo::to_status_body`]). Checking does
+/// not count against the client's budget.
+///
+/// ```rust,no_run,ignore
+/// let status = warp::path("rate-limit").and(rate_limit_status_route(limiter.clone(), key::remote_ip()));
+/// ```
+pub fn rate_limit_status_route<K>(
+    limiter: RateLimiter,
+    key: K,
+) -> impl Filter<Extract = (warp::reply::Json,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    warp::get().and(key).then(move |key: String| {
+        let limiter = limiter.clone();
+        async move { warp::reply::json(&limiter.peek(&key).await.to_status_body()) }
+    })
+}
+
+/// A route answering `GET` with a machine-readable description of the
+/// limiter's policies (see [`RateLimiter::policy_document`]): limits,
+/// windows, reset modes, and header names, so client SDKs can configure
+/// their backoff without reading your docs
+///
+/// ```rust,no_run,ignore
+/// let policies = warp::path!(".well-known" / "rate-limits").and(rate_limit_policy_route(limiter.clone()));
+/// ```
+pub fn rate_limit_policy_route(
+    limiter: RateLimiter,
+) -> impl Filter<Extract = (warp::reply::Json,), Error = Rejection> + Clone {
+    warp::get().map(move || warp::reply::json(&limiter.policy_document()))
+}
+
+/// A route answering `GET` with the limiter's load signal as Prometheus
+/// gauges, for autoscalers and load balancers to scrape:
+///
+/// ```text
+/// rate_limit_pressure 0.25
+/// rate_limit_held_requests 3
+/// ```
+///
+/// See [`RateLimiter::pressure`] and [`RateLimiter::held_requests`].
+pub fn rate_limit_pressure_route(
+    limiter: RateLimiter,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::get().map(move || {
+        let gauges = format!(
+            "# TYPE rate_limit_pressure gauge\nrate_limit_pressure {}\n\
+             # TYPE rate_limit_held_requests gauge\nrate_limit_held_requests {}\n",
+            limiter.pressure(),
+            limiter.held_requests()
+        );
+        warp::reply::with_header(gauges, "content-type", "text/plain; version=0.0.4").into_response()
+    })
+}
+
+/// Creates a rate limiting filter for the endpoint `label` of a limiter
+/// configured with `RateLimiter::with_endpoint`, keyed on whatever `key`
+/// extracts
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiter::new(RateLimitConfig::default())
+///     .with_endpoint("search", RateLimitConfig::max_per_minute(10))
+///     .with_endpoint("status", RateLimitConfig::max_per_minute(600));
+/// let search = warp::path("search").and(with_endpoint_rate_limit(limiter.clone(), "search", key::remote_ip()));
+/// let status = warp::path("status").and(with_endpoint_rate_limit(limiter, "status", key::remote_ip()));
+/// ```
+pub fn with_endpoint_rate_limit<K>(
+    limiter: RateLimiter,
+    label: &'static str,
+    key: K,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    let rate_limiter = limiter.endpoint(label);
+    key.and(request_shape())
+        .and(peer(rate_limiter.trusts_proxies()))
+        .and_then(move |key: String, content_length: Option<u64>, preflight: bool, peer: Peer| {
+            let rate_limiter = rate_limiter.clone();
+            async move { enforce(&rate_limiter, &key, None, content_length, preflight, &peer).await }
+        })
+}
+
+/// Creates one rate limiting filter for a whole route tree, applying the
+/// first of `rules` that matches each request. Exempt requests extract
+/// `None` and are not counted.
+pub fn with_rate_limit_rules<K>(
+    rules: RateLimitRules,
+    key: K,
+) -> impl Filter<Extract = (Option<RateLimitInfo>,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    // Rules match on headers, so read them whatever the limiters trust
+    warp::method()
+        .and(warp::path::full())
+        .and(peer(true))
+        .and(key)
+        .and_then(
+            move |method: warp::http::Method, path: warp::path::FullPath, peer: Peer, key: String| {
+                let rate_limiter = rules.resolve(&method, path.as_str(), &peer.headers);
+                async move {
+                    let Some(rate_limiter) = rate_limiter else {
+                        return Ok(None);
+                    };
+                    let content_length = peer
+                        .headers
+                        .get(warp::http::header::CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok());
+                    let preflight = is_preflight(&method, &peer.headers);
+                    enforce(&rate_limiter, &key, None, content_length, preflight, &peer).await.map(Some)
+                }
+            },
+        )
+}
+
+/// Creates a rate limiting filter that lets callers presenting a valid
+/// signed token in the `x-ratelimit-bypass` header skip the limit (the
+/// filter extracts `None`), or limits them by caller name under the
+/// bypass's elevated config. Tokens are checked against the request's
+/// method and path. Everyone else is limited by `limiter` as usual.
+///
+/// ```rust,no_run,ignore
+/// let bypass = SignedBypass::new(std::env::var("BYPASS_SECRET")?);
+/// let api = with_signed_bypass(limiter, bypass, key::remote_ip()).and(routes);
+/// // In the batch job: .header("x-ratelimit-bypass", bypass.sign("nightly-export", "GET", "/export"))
+/// ```
+#[cfg(feature = "signed-bypass")]
+pub fn with_signed_bypass<K>(
+    limiter: RateLimiter,
+    bypass: crate::core::SignedBypass,
+    key: K,
+) -> impl Filter<Extract = (Option<RateLimitInfo>,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    key.and(request_shape())
+        .and(warp::header::optional::<String>(crate::core::BYPASS_HEADER))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(peer(limiter.trusts_proxies()))
+        .and_then(
+            move |key: String,
+                  content_length: Option<u64>,
+                  preflight: bool,
+                  token: Option<String>,
+                  method: warp::http::Method,
+                  path: warp::path::FullPath,
+                  peer: Peer| {
+                let caller = token.and_then(|token| bypass.verify(&token, method.as_str(), path.as_str()));
+                let elevated = bypass.elevated_limit().cloned();
+                let limiter = limiter.clone();
+                async move {
+                    let checked = match (caller, elevated) {
+                        (Some(_), None) => return Ok(None),
+                        (Some(caller), Some(config)) => {
+                            let namespace = match &limiter.config().namespace {
+                                Some(namespace) => format!("{}:bypass", namespace),
+                                None => "bypass".to_string(),
+                            };
+                            let elevated = limiter.scoped(config.with_namespace(namespace));
+                            enforce(&elevated, &caller, None, content_length, preflight, &peer).await
+                        }
+                        (None, _) => enforce(&limiter, &key, None, content_length, preflight, &peer).await,
+                    };
+                    checked.map(Some)
+                }
+            },
+        )
+}
+
+/// Creates a rate limiting filter that routes each request to its tenant's
+/// own limiter, keyed within it on whatever `key` extracts
+///
+/// ```rust,no_run,ignore
+/// let tenants = TenantLimiters::new(1_000, |tenant| plans.config_for(tenant));
+/// let route = warp::path("api")
+///     .and(with_tenant_rate_limit(tenants, key::header("x-tenant"), key::remote_ip()))
+///     .map(|info: RateLimitInfo| ...);
+/// ```
+pub fn with_tenant_rate_limit<T, K>(
+    tenants: TenantLimiters,
+    tenant: T,
+    key: K,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    T: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    tenant
+        .and(key)
+        .and(request_shape())
+        // Tenant limiters trust no proxies
+        .and(peer(false))
+        .and_then(move |tenant: String, key: String, content_length: Option<u64>, preflight: bool, peer: Peer| {
+            let rate_limiter = tenants.limiter(&tenant);
+            async move { enforce(&rate_limiter, &key, None, content_length, preflight, &peer).await }
+        })
+}
+
+/// Creates a rate limiting filter enforcing both a per-key limit and the
+/// global ceiling of `limiter`. `priority` decides which requests may draw
+/// on the global reserve, e.g. from the caller's plan:
+///
+/// ```rust,no_run,ignore
+/// let priority = warp::header::optional::<String>("x-plan")
+///     .map(|plan: Option<String>| if plan.as_deref() == Some("paid") { Priority::High } else { Priority::Normal });
+/// let route = warp::path("api")
+///     .and(with_global_rate_limit(limiter, key::remote_ip(), priority))
+///     .map(|info: RateLimitInfo| ...);
+/// ```
+pub fn with_global_rate_limit<K, P>(
+    limiter: GlobalLimiter,
+    key: K,
+    priority: P,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+    P: Filter<Extract = (Priority,), Error = Rejection> + Clone + Send + Sync,
+{
+    key.and(priority).and_then(move |key: String, priority: Priority| {
+        let limiter = limiter.clone();
+        async move { limiter.check_rate_limit(&key, priority).await.map_err(reject::custom) }
+    })
+}
+
+/// Creates a rate limiting filter that rejects keys over their limit with a
+/// [`ChallengeRejection`] from `challenges` instead of a plain
+/// `RateLimitRejection`. A challenged client that sends a correct answer in
+/// the `x-ratelimit-challenge-response` header gets its full budget back.
+///
+/// Turn the rejection into a reply with `Response::from(&challenge_rejection)`
+/// in your rejection handler.
+pub fn with_challenge_rate_limit<K>(
+    limiter: RateLimiter,
+    challenges: Challenges,
+    key: K,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    key.and(warp::header::optional::<String>(CHALLENGE_RESPONSE_HEADER))
+        .and(peer(limiter.trusts_proxies()))
+        .and_then(move |key: String, answer: Option<String>, peer: Peer| {
+            let limiter = limiter.clone();
+            let challenges = challenges.clone();
+            async move {
+                if let Some(screened) = limiter.screen(&key, peer.addr, &peer.headers).await {
+                    return screened.map_err(reject::custom);
+                }
+                if answer.is_some_and(|answer| challenges.verify(&key, &answer)) {
+                    limiter.reset(&key).await;
+                }
+                match limiter.check_rate_limit(&key).await {
+                    Ok(info) => Ok(info),
+                    Err(rejection) => {
+                        limiter.tarpit(&key, &rejection).await;
+                        Err(reject::custom(challenges.challenge(&key, rejection)))
+                    }
+                }
+            }
+        })
+}
+
+/// A priority for [`with_global_rate_limit`] read from the request header
+/// `name` (`low`, `normal`, or `high`), defaulting to normal when it is
+/// missing or unrecognized. Only trust a header your own proxy sets.
+pub fn priority_header(name: &'static str) -> impl Filter<Extract = (Priority,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(name)
+        .map(|value: Option<String>| value.and_then(|v| v.parse().ok()).unwrap_or_default())
+}
+
+/// Rate limits `filter` and charges each request an extra cost computed from
+/// the reply it produced. A request is checked once `filter` has matched,
+/// so requests for other routes are never charged, at one unit (or its
+/// `Content-Length` cost, if the config has one); `cost` returns the
+/// request's total cost, and anything above that is charged once the reply
+/// is known and enforced on the key's next request. Preflights and clients
+/// on the IP lists are handled as by the other filters. Replies carry the
+/// rate limit headers.
+///
+/// Since matching runs `filter`, its handler has already run when a limited
+/// request is rejected; only its reply is discarded. Only replies are seen
+/// by `cost`, so recover rejections inside `filter` if they should be
+/// charged too.
+pub fn with_response_cost<F, R, K, C>(
+    filter: F,
+    limiter: RateLimiter,
+    key: K,
+    cost: C,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync + 'static,
+    C: Fn(&warp::reply::Response) -> u32 + Clone + Send + Sync + 'static,
+{
+    key.and(request_shape())
+        .and(peer(limiter.trusts_proxies()))
+        .and(filter)
+        .and_then(move |key: String, content_length: Option<u64>, preflight: bool, peer: Peer, reply: R| {
+            let limiter = limiter.clone();
+            let cost = cost.clone();
+            let response = reply.into_response();
+            async move {
+                // Allowlisted clients and preflights aren't charged for their replies
+                let upfront = request_cost(&limiter, content_length);
+                let (checked, counted) = match limiter.screen(&key, peer.addr, &peer.headers).await {
+                    Some(screened) => (screened, false),
+                    None => (admit(&limiter, &key, Some(upfront), content_length, preflight).await, !preflight),
+                };
+                let info = match checked {
+                    Ok(info) => info,
+                    Err(rejection) => {
+                        limiter.tarpit(&key, &rejection).await;
+                        return Err(reject::custom(rejection));
+                    }
+                };
+                let cost = cost(&response);
+                limiter.record_status(&key, response.status());
+                if counted && cost > upfront {
+                    limiter.charge(&key, cost - upfront).await;
+                }
+                Ok::<_, Rejection>(with_rate_limit_headers(response, &info))
+            }
+        })
+}
+
+/// Rate limits `filter` and charges each request by the status of the reply
+/// it produced, per `penalty`
+///
+/// ```rust,no_run,ignore
+/// let login = with_status_penalty(
+///     warp::path("login").and(warp::post()).and_then(handle_login).recover(handle_auth_rejection),
+///     RateLimiter::new(RateLimitConfig::max_per_minute(30)),
+///     key::remote_ip(),
+///     StatusPenalty::new().penalize(StatusCode::UNAUTHORIZED, 10),
+/// );
+/// ```
+pub fn with_status_penalty<F, R, K>(
+    filter: F,
+    limiter: RateLimiter,
+    key: K,
+    penalty: StatusPenalty,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    with_response_cost(filter, limiter, key, move |response: &warp::reply::Response| {
+        penalty.cost(response.status())
+    })
+}
+
+/// Rate limits `filter` and charges each request by the `Content-Length` of
+/// the reply it produced, so clients hammering the largest files served by
+/// `warp::fs` exhaust their budget proportionally
+///
+/// ```rust,no_run,ignore
+/// let assets = with_response_size_cost(
+///     warp::path("assets").and(warp::fs::dir("./assets")),
+///     RateLimiter::new(RateLimitConfig::max_per_minute(10_000)),
+///     key::remote_ip(),
+///     ContentLengthCost { bytes_per_unit: 100 * 1024, min_cost: 1 },
+/// );
+/// ```
+pub fn with_response_size_cost<F, R, K>(
+    filter: F,
+    limiter: RateLimiter,
+    key: K,
+    size_cost: ContentLengthCost,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    with_response_cost(filter, limiter, key, move |response: &warp::reply::Response| {
+        let content_length = response
+            .headers()
+            .get(warp::http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        size_cost.cost(content_length)
+    })
+}
+
+/// Limits how many connections each key may hold open at once, for
+/// streaming routes such as SSE or long polling. The extracted permit holds
+/// the slot until it is dropped, so move it into the response stream:
+///
+/// ```rust,no_run,ignore
+/// let events = warp::path("events")
+///     .and(with_concurrency_limit(limiter, key::remote_ip()))
+///     .map(|permit: ConnectionPermit| {
+///         let stream = event_stream().map(move |event| {
+///             let _held = &permit;
+///             event
+///         });
+///         warp::sse::reply(stream)
+///     });
+/// ```
+pub fn with_concurrency_limit<K>(
+    limiter: ConcurrencyLimiter,
+    key: K,
+) -> impl Filter<Extract = (ConnectionPermit,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    key.and_then(move |key: String| {
+        let result = limiter.try_acquire(&key).map_err(reject::custom);
+        async move { result }
+    })
+}
+
+/// Rejects requests from keys that already have their maximum of slow
+/// requests running, per `guard`. Keep the permit until the reply is built,
+/// e.g. by taking it as a handler argument, so the request's full duration
+/// is tracked.
+pub fn with_slow_request_limit<K>(
+    guard: SlowRequestGuard,
+    key: K,
+) -> impl Filter<Extract = (SlowRequestPermit,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    key.and_then(move |key: String| {
+        let result = guard.try_start(&key).map_err(reject::custom);
+        async move { result }
+    })
+}
+
+/// Caps identical requests in flight: requests from the same `key` with the
+/// same method, path, and query share a slot in `limiter`, so
+/// `ConcurrencyLimiter::new(1)` rejects a duplicate while the first is still
+/// running. Keep the permit until the reply is built, e.g. by taking it as a
+/// handler argument.
+pub fn with_duplicate_limit<K>(
+    limiter: ConcurrencyLimiter,
+    key: K,
+) -> impl Filter<Extract = (ConnectionPermit,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    key.and(request_target()).and_then(
+        move |key: String, method: warp::http::Method, target: String| {
+            let result = limiter
+                .try_acquire(&request_fingerprint(&key, &method, &target, None))
+                .map_err(reject::custom);
+            async move { result }
+        },
+    )
+}
+
+/// Like [`with_duplicate_limit`], but also tells requests apart by a hash of
+/// their body. The body is buffered and extracted alongside the permit, so
+/// put a `warp::body::content_length_limit` in front.
+pub fn with_duplicate_body_limit<K>(
+    limiter: ConcurrencyLimiter,
+    key: K,
+) -> impl Filter<Extract = (ConnectionPermit, warp::hyper::body::Bytes), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    key.and(request_target())
+        .and(warp::body::bytes())
+        .and_then(
+            move |key: String, method: warp::http::Method, target: String, body: warp::hyper::body::Bytes| {
+                let result = limiter
+                    .try_acquire(&request_fingerprint(&key, &method, &target, Some(&body)))
+                    .map(|permit| (permit, body))
+                    .map_err(reject::custom);
+                async move { result }
+            },
+        )
+        .untuple_one()
+}
+
+/// The request's method, and its path with the query string if any
+fn request_target() -> impl Filter<Extract = (warp::http::Method, String), Error = std::convert::Infallible> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(|method, path: warp::path::FullPath, query: String| {
+            let target = if query.is_empty() {
+                path.as_str().to_string()
+            } else {
+                format!("{}?{}", path.as_str(), query)
+            };
+            (method, target)
+        })
+        .untuple_one()
+}
+
+/// Reads the request body against `quota`, counting the bytes actually
+/// received rather than trusting `Content-Length`. Keys that have used up
+/// their quota are rejected with a [`QuotaRejection`] before the body is
+/// read, and reading stops as soon as a body pushes a key past it. Put a
+/// `warp::body::content_length_limit` in front to bound each body too.
+pub fn with_upload_quota<K>(
+    quota: ByteQuota,
+    key: K,
+) -> impl Filter<Extract = (QuotaInfo, warp::hyper::body::Bytes), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    key.and_then(move |key: String| {
+        let result = quota.check(&key).map(|info| (quota.clone(), key, info)).map_err(reject::custom);
+        async move { result }
+    })
+    .and(warp::body::stream())
+    .and_then(|(quota, key, info): (ByteQuota, String, QuotaInfo), body| read_counted(quota, key, info, body))
+    .untuple_one()
+}
+
+/// Caps the response bytes each key may download per window with `quota`,
+/// e.g. on export or media routes. Keys that have used up their quota are
+/// rejected with a [`QuotaRejection`] before `filter` runs. Bytes are
+/// counted as the response body is streamed to the client, and a response
+/// that pushes its key past the quota is cut off there. Replies carry the
+/// `X-RateLimit-Download-*` headers as of the start of the request.
+pub fn with_download_quota<F, R, K>(
+    filter: F,
+    quota: ByteQuota,
+    key: K,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    key.and_then(move |key: String| {
+        let result = quota.check(&key).map(|info| (quota.clone(), key, info)).map_err(reject::custom);
+        async move { result }
+    })
+    .and(filter)
+    .map(|(quota, key, info): (ByteQuota, String, QuotaInfo), reply: R| {
+        let (mut parts, body) = reply.into_response().into_parts();
+        parts.headers.extend(info.to_headers());
+        let body = warp::hyper::Body::wrap_stream(CountedBody { body, quota, key });
+        warp::reply::Response::from_parts(parts, body)
+    })
+}
+
+/// A response body that charges each chunk to `key`'s quota as it is sent,
+/// ending in an error once the key is past its quota
+struct CountedBody {
+    body: warp::hyper::Body,
+    quota: ByteQuota,
+    key: String,
+}
+
+impl futures_core::Stream for CountedBody {
+    type Item = Result<warp::hyper::body::Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        use warp::hyper::body::HttpBody;
+
+        let chunk = match std::task::ready!(std::pin::Pin::new(&mut self.body).poll_data(cx)) {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => return std::task::Poll::Ready(Some(Err(e.into()))),
+            None => return std::task::Poll::Ready(None),
+        };
+        let result = match self.quota.consume(&self.key, chunk.len() as u64) {
+            Ok(_) => Ok(chunk),
+            Err(rejection) => Err(format!("{} quota of {} bytes used up", rejection.info.kind, rejection.info.limit).into()),
+        };
+        std::task::Poll::Ready(Some(result))
+    }
+}
+
+/// Buffers `body`, charging each chunk to `key`'s quota as it arrives
+async fn read_counted<S, B>(
+    quota: ByteQuota,
+    key: String,
+    mut info: QuotaInfo,
+    body: S,
+) -> Result<(QuotaInfo, warp::hyper::body::Bytes), Rejection>
+where
+    S: futures_core::Stream<Item = Result<B, warp::Error>>,
+    B: warp::hyper::body::Buf,
+{
+    let mut body = Box::pin(body);
+    let mut read = Vec::new();
+    while let Some(chunk) = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        let chunk = chunk.map_err(|_| reject::custom(BodyReadRejection))?;
+        info = quota.consume(&key, chunk.remaining() as u64).map_err(reject::custom)?;
+        read.extend_from_slice(chunk.chunk());
+    }
+    Ok((info, read.into()))
+}
+
+/// Rejection for a request whose body could not be read, e.g. because the
+/// client disconnected mid-upload
+#[derive(Debug)]
+pub struct BodyReadRejection;
+
+impl reject::Reject for BodyReadRejection {}
+
+/// Rate limits a whole route tree without handlers taking a
+/// `RateLimitInfo`, keyed on the remote IP. Replies carry the rate limit
+/// headers, and limited requests are answered with a complete 429 rather
+/// than a rejection, so no `recover` is needed either. Requests the wrapped
+/// filter doesn't match fall through uncounted.
+///
+/// ```rust,no_run,ignore
+/// let api = routes.with(warp::wrap_fn(wrap(RateLimitConfig::max_per_minute(100))));
+/// // or, without `with`:
+/// let api = wrap(RateLimitConfig::max_per_minute(100))(routes.boxed());
+/// ```
+///
+/// Every filter the returned function wraps shares its limiter. It takes
+/// one type of filter, so box routes (`.boxed()`) to wrap several.
+pub fn wrap<F, R>(config: RateLimitConfig) -> impl Fn(F) -> BoxedFilter<(warp::reply::Response,)> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    wrap_by(config, key::remote_ip())
+}
+
+/// [`wrap`], keyed on whatever `key` extracts
+pub fn wrap_by<F, R, K>(config: RateLimitConfig, key: K) -> impl Fn(F) -> BoxedFilter<(warp::reply::Response,)> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+    K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    let limiter = RateLimiter::new(config);
+    move |filter: F| {
+        with_response_cost(filter, limiter.clone(), key.clone(), |_| 1)
+            .recover(answer_rate_limited)
+            .map(Reply::into_response)
+            .boxed()
+    }
+}
+
+/// Answers a rate limited request with a 429 reply, passing other
+/// rejections on
+pub(crate) async fn answer_rate_limited(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    match rejection.find::<RateLimitRejection>() {
+        Some(rate_limited) => Ok(warp::reply::Response::from(rate_limited)),
+        None => Err(rejection),
+    }
+}
+
+/// Attaches a rate limit to a route where it is declared
+///
+/// ```rust,no_run,ignore
+/// let search = warp::path("search").map(handler).rate_limited(rate_limit!("100/1m"));
+/// ```
+pub trait RateLimitedExt<R>: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + Sized + 'static
+where
+    R: Reply,
+{
+    /// Rate limits this route under `config`, keyed on the remote IP.
+    /// Only requests the route matches are counted. Replies carry the rate
+    /// limit headers, and limited requests are answered with a 429 rather
+    /// than a rejection that could escape an `.or` chain.
+    fn rate_limited(
+        self,
+        config: RateLimitConfig,
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+        with_response_cost(self, RateLimiter::new(config), key::remote_ip(), |_| 1)
+            .recover(answer_rate_limited)
+            .map(Reply::into_response)
+    }
+}
+
+impl<F, R> RateLimitedExt<R> for F
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+}
+
+/// Sources for the rate limit key. Each yields `"unknown"` when its source
+/// is missing, so requests without a key share one budget.
+pub mod key {
+    use std::net::SocketAddr;
+    use warp::http::{HeaderMap, Method, Request};
+    use warp::path::FullPath;
+    use warp::{Filter, Rejection};
+
+    /// The IP of the connection's remote address
+    pub fn remote_ip() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::addr::remote()
+            .map(|addr: Option<SocketAddr>| or_unknown(addr.map(|a| a.ip().to_string())))
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    /// The client IP behind `proxies`: from the forwarding headers when
+    /// the remote address is a trusted proxy, otherwise the remote IP
+    pub fn client_ip(proxies: crate::core::TrustedProxies) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::header::headers_cloned()
+            .and(warp::addr::remote())
+            .map(move |headers: HeaderMap, addr: Option<SocketAddr>| {
+                or_unknown(addr.map(|addr| proxies.client_ip(addr.ip(), &headers).to_string()))
+            })
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    /// The value of the request header `name`
+    pub fn header(name: &'static str) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::header::optional::<String>(name).map(or_unknown)
+    }
+
+    /// A value stashed in the request extensions, e.g. by a hyper service or
+    /// connection acceptor in front of warp
+    pub fn extension<T>() -> impl Filter<Extract = (String,), Error = Rejection> + Clone
+    where
+        T: ToString + Clone + Send + Sync + 'static,
+    {
+        warp::ext::optional::<T>()
+            .map(|value: Option<T>| or_unknown(value.map(|v| v.to_string())))
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    /// The [`ClientIdentity`](crate::ClientIdentity) stashed by an mTLS
+    /// acceptor, falling back to the remote IP for connections without a
+    /// client certificate
+    pub fn client_identity() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        extension_or_remote_ip::<crate::core::ClientIdentity>()
+    }
+
+    /// The client IP from the [`ProxiedAddr`](crate::ProxiedAddr) stashed by
+    /// an accept loop reading PROXY protocol headers, falling back to the
+    /// remote IP
+    pub fn proxied_ip() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        extension_or_remote_ip::<crate::core::ProxiedAddr>()
+    }
+
+    /// Prefixes the key `key` extracts with the tenant ID `tenant` extracts,
+    /// as `tenant:key`. This is the per-request counterpart of
+    /// `RateLimitConfig::with_namespace`, for apps that resolve the tenant
+    /// from the request.
+    pub fn tenant<T, K>(tenant: T, key: K) -> impl Filter<Extract = (String,), Error = Rejection> + Clone
+    where
+        T: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+        K: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+    {
+        tenant.and(key).map(|tenant: String, key: String| format!("{}:{}", tenant, key))
+    }
+
+    /// A key derived by `fingerprint` from the request headers and remote
+    /// address, falling back to the remote IP when it returns `None`. Use it
+    /// to group IP-rotating clients, e.g. by a TLS fingerprint your proxy
+    /// forwards, or by [`header_fingerprint`](crate::header_fingerprint):
+    ///
+    /// ```rust,no_run,ignore
+    /// let key = key::fingerprint(|headers, _| {
+    ///     match headers.get("x-ja4").and_then(|v| v.to_str().ok()) {
+    ///         Some(ja4) => Some(format!("ja4:{}", ja4)),
+    ///         None => Some(header_fingerprint(headers, &["user-agent", "accept-language"])),
+    ///     }
+    /// });
+    /// ```
+    pub fn fingerprint<F>(fingerprint: F) -> impl Filter<Extract = (String,), Error = Rejection> + Clone
+    where
+        F: Fn(&warp::http::HeaderMap, Option<SocketAddr>) -> Option<String> + Clone + Send + Sync + 'static,
+    {
+        warp::header::headers_cloned()
+            .and(warp::addr::remote())
+            .map(move |headers: warp::http::HeaderMap, addr: Option<SocketAddr>| {
+                or_unknown(fingerprint(&headers, addr).or_else(|| addr.map(|a| a.ip().to_string())))
+            })
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    /// The resource the request targets, as keyed by `resource` from the
+    /// request path, e.g. `org:acme` for `/orgs/acme/repos/site` under
+    /// `ResourceKey::new("/orgs/{org_id}/**", "org:{org_id}")`. Combine it
+    /// with a client key through [`tenant`] to limit each client per
+    /// resource.
+    pub fn resource(resource: crate::core::ResourceKey) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::path::full()
+            .map(move |path: warp::path::FullPath| or_unknown(resource.key_for(path.as_str())))
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    /// The key `extractor` derives from the request head, with the remote
+    /// address in the extensions as a `SocketAddr`. The request body is not
+    /// available to it.
+    ///
+    /// ```rust,no_run,ignore
+    /// let limited = with_rate_limit_by(config, key::extractor(ApiKey::default().or_else(ForwardedFor)));
+    /// ```
+    pub fn extractor<E: crate::core::KeyExtractor>(
+        extractor: E,
+    ) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        let extractor = std::sync::Arc::new(extractor);
+        warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().map(Some).or(warp::any().map(|| None)).unify())
+            .and(warp::header::headers_cloned())
+            .and(warp::addr::remote())
+            .and_then(
+                move |method: Method, path: FullPath, query: Option<String>, headers: HeaderMap, addr: Option<SocketAddr>| {
+                    let extractor = extractor.clone();
+                    async move {
+                        let mut request = Request::new(());
+                        *request.method_mut() = method;
+                        *request.headers_mut() = headers;
+                        let uri = match query {
+                            Some(query) => format!("{}?{}", path.as_str(), query),
+                            None => path.as_str().to_string(),
+                        };
+                        if let Ok(uri) = uri.parse() {
+                            *request.uri_mut() = uri;
+                        }
+                        if let Some(addr) = addr {
+                            request.extensions_mut().insert(addr);
+                        }
+                        let (parts, ()) = request.into_parts();
+                        Ok::<_, Rejection>(or_unknown(extractor.extract(&parts).await))
+                    }
+                },
+            )
+    }
+
+    fn extension_or_remote_ip<T>() -> impl Filter<Extract = (String,), Error = Rejection> + Clone
+    where
+        T: ToString + Clone + Send + Sync + 'static,
+    {
+        warp::ext::optional::<T>()
+            .and(warp::addr::remote())
+            .map(|value: Option<T>, addr: Option<SocketAddr>| {
+                or_unknown(
+                    value
+                        .map(|v| v.to_string())
+                        .or_else(|| addr.map(|a| a.ip().to_string())),
+                )
+            })
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    fn or_unknown(key: Option<String>) -> String {
+        key.unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+/// Converts any reply into a response carrying the rate limit headers. If
+/// the headers cannot be built, the reply is returned unchanged and a
+/// warning is logged.
+pub fn with_rate_limit_headers(reply: impl Reply, info: &RateLimitInfo) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    if let Err(e) = add_rate_limit_headers(response.headers_mut(), info) {
+        tracing::warn!("{}", e);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        get_rate_limit_info, BearerToken, Challenge, ClientIdentity, ConfigOverrides, ForwardedHeader, KeyExtractor,
+        PreflightPolicy, ProxiedAddr, RetryAfterFormat, TrustedProxies,
+    };
+    use chrono::Duration as ChronoDuration;
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::task::JoinSet;
+    use warp::{
+        test::request,
+        http::{header, StatusCode},
+    };
+
+    // Helper function to create a test rate limiter with rejection handling
+    async fn create_test_route(
+        config: RateLimitConfig,
+    ) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        with_rate_limit(config)
+            .map(|info: RateLimitInfo| info.remaining.to_string())
+            .recover(|rejection: Rejection| async move {
+                if let Some(rate_limit) = rejection.find::<RateLimitRejection>() {
+                    let info = get_rate_limit_info(rate_limit);
+                    let mut resp = warp::reply::with_status(
+                        "Rate limit exceeded",
+                        StatusCode::TOO_MANY_REQUESTS,
+                    ).into_response();
+                    add_rate_limit_headers(resp.headers_mut(), &info).unwrap();
+                    Ok(resp)
+                } else {
+                    Ok(warp::reply::with_status(
+                        "Internal error", 
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ).into_response())
+                }
+            })
+    }
+
+    #[tokio::test]
+    async fn test_comprehensive_rate_limit_rejection() {
+        let config = RateLimitConfig {
+            max_requests: 1,
+            window: Duration::from_secs(5),
+            retry_after_format: RetryAfterFormat::Seconds,
+            ..Default::default()
+        };
+
+        let route = create_test_route(config.clone()).await;
+
+        // First request succeeds
+        let resp1 = request()
+            .remote_addr("127.0.0.1:1234".parse().unwrap())
+            .reply(&route)
+            .await;
+        assert_eq!(resp1.status(), 200);
+        assert_eq!(resp1.body(), "0"); // Last remaining request
+
+        // Second request gets rejected with proper headers
+        let resp2 = request()
+            .remote_addr("127.0.0.1:1234".parse().unwrap())
+            .reply(&route)
+            .await;
+        
+        assert_eq!(resp2.status(), 429);
+        
+        // Verify rate limit headers exist and have correct format
+        let headers = resp2.headers();
+        assert!(headers.contains_key(header::RETRY_AFTER));
+        assert!(headers.contains_key("X-RateLimit-Limit"));
+        assert!(headers.contains_key("X-RateLimit-Remaining"));
+        assert!(headers.contains_key("X-RateLimit-Reset"));
+        
+        // Verify header values
+        assert_eq!(headers.get("X-RateLimit-Limit").unwrap(), "1");
+        assert_eq!(headers.get("X-RateLimit-Remaining").unwrap(), "0");
+        
+        // Verify Retry-After is a number of seconds
+        let retry_after = headers.get(header::RETRY_AFTER).unwrap().to_str().unwrap();
+        assert!(retry_after.parse::<u64>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_formats() {
+        // Test HttpDate format
+        let http_date_config = RateLimitConfig {
+            max_requests: 1,
+            window: Duration::from_secs(15),
+            retry_after_format: RetryAfterFormat::HttpDate,
+            ..Default::default()
+        };
+
+        let http_date_route = create_test_route(http_date_config).await;
+
+        // Trigger rate limit with HttpDate format
+        let _ = request()
+            .remote_addr("127.0.0.1:1234".parse().unwrap())
+            .reply(&http_date_route)
+            .await;
+        
+        let resp_http = request()
+            .remote_addr("127.0.0.1:1234".parse().unwrap())
+            .reply(&http_date_route)
+            .await;
+
+        // Verify HttpDate format
+        let retry_after_http = resp_http.headers().get(header::RETRY_AFTER).unwrap().to_str().unwrap();
+        assert!(!retry_after_http.is_empty()); // RFC2822 date contains GMT
+        
+        // Test Seconds format
+        let seconds_config = RateLimitConfig {
+            max_requests: 1,
+            window: Duration::from_secs(5),
+            retry_after_format: RetryAfterFormat::Seconds,
+            ..Default::default()
+        };
+
+        let seconds_route = create_test_route(seconds_config).await;
+
+        // Trigger rate limit with Seconds format
+        let _ = request()
+            .remote_addr("127.0.0.2:1234".parse().unwrap())
+            .reply(&seconds_route)
+            .await;
+        
+        let resp_sec = request()
+            .remote_addr("127.0.0.2:1234".parse().unwrap())
+            .reply(&seconds_route)
+            .await;
+
+        // Verify Seconds format
+        let retry_after_sec = resp_sec.headers().get(header::RETRY_AFTER).unwrap().to_str().unwrap();
+        assert!(retry_after_sec.parse::<u64>().is_ok());
+        assert!(retry_after_sec.parse::<u64>().unwrap() <= 5);
+    }
+
+    #[tokio::test]
+    async fn test_info_reports_used_and_window() {
+        let route = with_rate_limit(RateLimitConfig::max_per_window(3, 20))
+            .map(|info: RateLimitInfo| format!("{}/{}/{}", info.used, info.remaining, info.window.as_secs()));
+
+        for expected in ["1/2/20", "2/1/20"] {
+            let resp = request()
+                .remote_addr("127.0.0.1:1234".parse().unwrap())
+                .reply(&route)
+                .await;
+            assert_eq!(resp.body(), expected);
+        }
+
+        let route = with_rate_limit(RateLimitConfig::max_per_window(3, 20))
+            .map(|info: RateLimitInfo| {
+                assert_eq!(info.window_end - info.window_start, ChronoDuration::seconds(20));
+                assert_eq!(info.reset_timestamp, info.window_end.timestamp());
+                "ok"
+            });
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.body(), "ok");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        let config = RateLimitConfig {
+            max_requests: 5,
+            window: Duration::from_secs(1),
+            retry_after_format: RetryAfterFormat::Seconds,
+            ..Default::default()
+        };
+
+        let route = create_test_route(config.clone()).await;
+        let mut set = JoinSet::new();
+
+        // Launch 10 concurrent requests
+        for _ in 0..10 {
+            let route = route.clone();
+            set.spawn(async move {
+                request()
+                    .remote_addr("127.0.0.1:1234".parse().unwrap())
+                    .reply(&route)
+                    .await
+            });
+        }
+
+        let mut success_count = 0;
+        let mut rate_limited_count = 0;
+
+        while let Some(Ok(resp)) = set.join_next().await {
+            match resp.status() {
+                StatusCode::OK => success_count += 1,
+                StatusCode::TOO_MANY_REQUESTS => rate_limited_count += 1,
+                _ => panic!("Unexpected response status"),
+            }
+        }
+
+        assert_eq!(success_count, 5, "Expected exactly 5 successful requests");
+        assert_eq!(rate_limited_count, 5, "Expected exactly 5 rate-limited requests");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_filters_tarpit_rejections() {
+        let delay = Duration::from_secs(5);
+        let route = with_rate_limit(RateLimitConfig::max_per_window(1, 60).with_tarpit(delay));
+
+        let start = tokio::time::Instant::now();
+        assert!(request().filter(&route).await.is_ok());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(request().filter(&route).await.is_err());
+        assert!(start.elapsed() >= delay);
+
+        let limited = RateLimitConfig::max_per_window(1, 60).with_tarpit(delay);
+        let limiter = RateLimiter::new(RateLimitConfig::default()).with_endpoint("search", limited.clone());
+        let endpoint = with_endpoint_rate_limit(limiter, "search", key::remote_ip());
+        let tenants = TenantLimiters::new(10, move |_| limited.clone());
+        let tenant = with_tenant_rate_limit(tenants, key::header("x-tenant"), key::remote_ip());
+        let start = tokio::time::Instant::now();
+        assert!(request().filter(&endpoint).await.is_ok());
+        assert!(request().filter(&tenant).await.is_ok());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(request().filter(&endpoint).await.is_err());
+        assert!(request().filter(&tenant).await.is_err());
+        assert!(start.elapsed() >= delay * 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_route() {
+        let route = warp::path("search")
+            .map(|| "results")
+            .rate_limited(crate::rate_limit!("1/1m"))
+            .or(warp::path("other").map(|| "other"));
+
+        // Other routes don't spend the search budget
+        assert_eq!(request().path("/other").reply(&route).await.status(), StatusCode::OK);
+        let resp = request().path("/search").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-ratelimit-limit"], "1");
+        assert_eq!(request().path("/search").reply(&route).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(request().path("/unrouted").reply(&route).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_wrapped_routes() {
+        let wrap = wrap(RateLimitConfig::max_per_window(1, 60));
+        let search = warp::path("search").map(|| "results").boxed().with(warp::wrap_fn(wrap.clone()));
+        let status = wrap(warp::path("status").map(|| "ok").boxed());
+
+        let resp = request().path("/search").reply(&search).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-ratelimit-remaining"], "0");
+        let resp = request().path("/search").reply(&search).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("retry-after"));
+        // Both routes share the wrap's limiter
+        assert_eq!(request().path("/status").reply(&status).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_wrapped_routes_count_each_request_once() {
+        let wrap = wrap(RateLimitConfig::max_per_window(2, 60));
+        let route = wrap(warp::path("a").map(|| "a").boxed()).or(wrap(warp::path("b").map(|| "b").boxed()));
+
+        for remaining in ["1", "0"] {
+            let resp = request().path("/b").reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()["x-ratelimit-remaining"], remaining);
+        }
+        assert_eq!(request().path("/b").reply(&route).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(request().path("/unrouted").reply(&route).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_routes_share_a_limiter() {
+        let shared = RateLimiter::new(RateLimitConfig::max_per_window(2, 60));
+        let public = warp::path("public").and(shared.filter());
+        let also_public = warp::path("also_public").and(shared.filter());
+        let isolated = warp::path("isolated").and(with_rate_limit(RateLimitConfig::max_per_window(2, 60)));
+
+        assert_eq!(request().path("/public").filter(&public).await.unwrap().remaining, 1);
+        assert_eq!(request().path("/also_public").filter(&also_public).await.unwrap().remaining, 0);
+        assert!(request().path("/public").filter(&public).await.is_err());
+        assert_eq!(request().path("/isolated").filter(&isolated).await.unwrap().remaining, 1);
+
+        let by_header = shared.filter_by(key::header("x-api-key"));
+        assert!(request().header("x-api-key", "a").filter(&by_header).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_replies() {
+        let route = with_rate_limit(RateLimitConfig::max_per_window(1, 60))
+            .map(|_: RateLimitInfo| "ok")
+            .recover(|rejection: Rejection| async move {
+                match rejection.find::<RateLimitRejection>() {
+                    Some(rate_limited) => Ok(rate_limited.clone().into_response()),
+                    None => Err(rejection),
+                }
+            });
+
+        assert_eq!(request().reply(&route).await.status(), StatusCode::OK);
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(resp.headers()["x-ratelimit-remaining"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_limit() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let route = with_duplicate_limit(limiter.clone(), key::header("x-api-key"));
+
+        let held = request().path("/orders?page=1").header("x-api-key", "a").filter(&route).await.unwrap();
+        // The same request again is a duplicate; a different page is not
+        assert!(request().path("/orders?page=1").header("x-api-key", "a").filter(&route).await.is_err());
+        assert!(request().path("/orders?page=2").header("x-api-key", "a").filter(&route).await.is_ok());
+        drop(held);
+        assert!(request().path("/orders?page=1").header("x-api-key", "a").filter(&route).await.is_ok());
+
+        let route = with_duplicate_body_limit(limiter, key::header("x-api-key"));
+        let (_held, body) = request().method("POST").body("one").header("x-api-key", "a").filter(&route).await.unwrap();
+        assert_eq!(body, "one");
+        assert!(request().method("POST").body("one").header("x-api-key", "a").filter(&route).await.is_err());
+        assert!(request().method("POST").body("two").header("x-api-key", "a").filter(&route).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_upload_quota_counts_body_bytes() {
+        let route = with_upload_quota(ByteQuota::upload(10, Duration::from_secs(60)), key::header("x-api-key"));
+
+        let (info, body) = request().method("POST").header("x-api-key", "a").body("123456").filter(&route).await.unwrap();
+        assert_eq!((info.used, body.as_ref()), (6, &b"123456"[..]));
+        // The second body pushes the key past its quota, and every byte read counts
+        let rejection = request().method("POST").header("x-api-key", "a").body("123456").filter(&route).await.unwrap_err();
+        assert_eq!(rejection.find::<QuotaRejection>().unwrap().info.used, 12);
+        assert!(request().method("POST").header("x-api-key", "a").filter(&route).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_quota_counts_streamed_bytes() {
+        let quota = ByteQuota::download(10, Duration::from_secs(60));
+        let route = with_download_quota(warp::path::end().map(|| "123456"), quota.clone(), key::header("x-api-key"));
+
+        let resp = request().header("x-api-key", "a").reply(&route).await;
+        assert_eq!(resp.body(), "123456");
+        assert_eq!(resp.headers()["x-ratelimit-download-remaining"], "10");
+        assert_eq!(quota.check("a").unwrap().used, 6);
+
+        // The second response is cut off once it passes the quota
+        let resp = request().header("x-api-key", "a").filter(&route).await.unwrap();
+        assert!(warp::hyper::body::to_bytes(resp.into_body()).await.is_err());
+        let rejection = request().header("x-api-key", "a").filter(&route).await.unwrap_err();
+        assert!(rejection.find::<QuotaRejection>().is_some());
+    }
+
+    #[cfg(feature = "signed-bypass")]
+    #[tokio::test]
+    async fn test_signed_bypass() {
+        let bypass = crate::core::SignedBypass::new("s3cret");
+        let token = bypass.sign("batch", "GET", "/");
+        let route = with_signed_bypass(RateLimiter::new(RateLimitConfig::max_per_window(1, 60)), bypass.clone(), key::remote_ip());
+
+        assert!(request().filter(&route).await.unwrap().is_some());
+        assert!(request().filter(&route).await.is_err());
+        assert!(request().header("x-ratelimit-bypass", &token).filter(&route).await.unwrap().is_none());
+        assert!(request().header("x-ratelimit-bypass", "batch:0:00").filter(&route).await.is_err());
+        // A token signed for one route doesn't open another
+        assert!(request().path("/admin").header("x-ratelimit-bypass", &token).filter(&route).await.is_err());
+
+        let elevated = bypass.with_elevated_limit(RateLimitConfig::max_per_window(100, 60));
+        let route = with_signed_bypass(RateLimiter::new(RateLimitConfig::max_per_window(1, 60)), elevated, key::remote_ip());
+        let info = request().header("x-ratelimit-bypass", &token).filter(&route).await.unwrap().unwrap();
+        assert_eq!(info.limit, 100);
+    }
+
+    #[tokio::test]
+    async fn test_status_route_does_not_count() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(3, 60));
+        limiter.check_rate_limit("127.0.0.1").await.unwrap();
+        let route = rate_limit_status_route(limiter.clone(), key::remote_ip());
+
+        for _ in 0..5 {
+            let resp = request().remote_addr("127.0.0.1:1234".parse().unwrap()).reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+            assert_eq!(body["limit"], 3);
+            assert_eq!(body["remaining"], 2);
+        }
+        assert_eq!(limiter.check_rate_limit("127.0.0.1").await.unwrap().remaining, 1);
+    }
+
+    #[tokio::test]
+    async fn test_policy_route_describes_every_endpoint() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(100, 60))
+            .with_endpoint("search", RateLimitConfig::max_per_window(10, 60).with_header_style(crate::core::HeaderStyle::Draft));
+        let route = rate_limit_policy_route(limiter);
+
+        let resp = request().path("/.well-known/rate-limits").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["default"]["limit"], 100);
+        assert_eq!(body["default"]["retry_after_format"], "http-date");
+        assert_eq!(body["endpoints"]["search"]["window_seconds"], 60);
+        assert_eq!(body["endpoints"]["search"]["headers"][0], "RateLimit-Limit");
+    }
+
+    #[tokio::test]
+    async fn test_pressure_route_exposes_gauges() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        limiter.check_rate_limit("a").await.unwrap();
+        limiter.check_rate_limit("a").await.unwrap_err();
+
+        let resp = request().reply(&rate_limit_pressure_route(limiter)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains("\nrate_limit_pressure 0.5\n"));
+        assert!(body.contains("\nrate_limit_held_requests 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_challenge_restores_budget() {
+        let challenges = Challenges::new(|_| Challenge::Redirect("/captcha".to_string()), |_, answer| answer == "ok");
+        let route = with_challenge_rate_limit(
+            RateLimiter::new(RateLimitConfig::max_per_window(1, 60)),
+            challenges,
+            key::header("x-api-key"),
+        );
+
+        assert!(request().header("x-api-key", "bot").filter(&route).await.is_ok());
+        let rejection = request().header("x-api-key", "bot").filter(&route).await.unwrap_err();
+        let challenge = rejection.find::<ChallengeRejection>().unwrap();
+        assert_eq!(challenge.challenge, Challenge::Redirect("/captcha".to_string()));
+
+        let answered = request()
+            .header("x-api-key", "bot")
+            .header(CHALLENGE_RESPONSE_HEADER, "ok")
+            .filter(&route)
+            .await;
+        assert!(answered.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ip_lists_match_the_peer_whatever_the_key() {
+        let config = RateLimitConfig::max_per_window(1, 60)
+            .allow("10.0.0.0/8".parse::<crate::core::IpCidr>().unwrap())
+            .deny("203.0.113.0/24".parse::<crate::core::IpCidr>().unwrap());
+        let route = with_rate_limit_by(config, key::header("x-api-key"));
+        let from = |ip: &str| {
+            let addr = format!("{}:1234", ip).parse().unwrap();
+            request().header("x-api-key", "alpha").remote_addr(addr)
+        };
+
+        for _ in 0..3 {
+            assert!(from("10.1.2.3").filter(&route).await.is_ok());
+        }
+        let rejection = from("203.0.113.9").filter(&route).await.unwrap_err();
+        let banned = rejection.find::<RateLimitRejection>().unwrap();
+        assert_eq!(banned.code, crate::core::RateLimitErrorCode::Banned);
+        let response = warp::reply::Response::from(banned);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers().get("retry-after").is_none());
+        assert!(response.headers().get("x-ratelimit-limit").is_none());
+
+        assert!(from("192.0.2.1").filter(&route).await.is_ok());
+        assert!(from("192.0.2.1").filter(&route).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_sources() {
+        let route = with_rate_limit_by(
+            RateLimitConfig::max_per_window(1, 60).with_preflight(PreflightPolicy::Exempt),
+            key::header("x-api-key"),
+        );
+
+        // Exempt preflights from the same client don't touch its budget
+        let preflight = request()
+            .method("OPTIONS")
+            .header("x-api-key", "alpha")
+            .header("access-control-request-method", "POST");
+        assert!(preflight.filter(&route).await.is_ok());
+
+        // The same IP gets separate budgets per header value
+        assert!(request().header("x-api-key", "alpha").filter(&route).await.is_ok());
+        assert!(request().header("x-api-key", "beta").filter(&route).await.is_ok());
+        assert!(request().header("x-api-key", "alpha").filter(&route).await.is_err());
+
+        #[derive(Clone)]
+        struct Tenant(&'static str);
+        impl std::fmt::Display for Tenant {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.0)
+            }
+        }
+
+        let route = with_rate_limit_by(RateLimitConfig::max_per_window(1, 60), key::extension::<Tenant>());
+        assert!(request().extension(Tenant("acme")).filter(&route).await.is_ok());
+        assert!(request().extension(Tenant("acme")).filter(&route).await.is_err());
+        assert!(request().extension(Tenant("globex")).filter(&route).await.is_ok());
+
+        // Extractors see the whole request head, query included
+        let by_user = |parts: &warp::http::request::Parts| {
+            let user = parts.uri.query().and_then(|q| q.strip_prefix("user=")).map(str::to_string);
+            async move { user }
+        };
+        let extractor = by_user.or_else(BearerToken);
+        let route = with_rate_limit_by(RateLimitConfig::max_per_window(1, 60), key::extractor(extractor));
+        assert!(request().path("/?user=ada").filter(&route).await.is_ok());
+        assert!(request().path("/?user=bob").filter(&route).await.is_ok());
+        assert!(request().path("/?user=ada").filter(&route).await.is_err());
+        assert!(request().header("authorization", "Bearer t1").filter(&route).await.is_ok());
+        assert!(request().header("authorization", "Bearer t1").filter(&route).await.is_err());
+
+        // Behind a trusted proxy, clients are told apart by the header it sets
+        let proxies = TrustedProxies::parse(ForwardedHeader::XForwardedFor, ["127.0.0.0/8"]).unwrap();
+        let route = with_rate_limit_by(RateLimitConfig::max_per_window(1, 60), key::client_ip(proxies));
+        let via_proxy = |client: &str| {
+            request()
+                .remote_addr("127.0.0.1:1234".parse().unwrap())
+                .header("x-forwarded-for", client)
+        };
+        assert!(via_proxy("203.0.113.7").filter(&route).await.is_ok());
+        assert!(via_proxy("203.0.113.8").filter(&route).await.is_ok());
+        assert!(via_proxy("203.0.113.7").filter(&route).await.is_err());
+
+        // Clients sharing one NAT IP are limited by certificate identity
+        let route = with_rate_limit_by(RateLimitConfig::max_per_window(1, 60), key::client_identity());
+        let billing = ClientIdentity::new("spiffe://example.org/billing");
+        let search = ClientIdentity::new("spiffe://example.org/search");
+        assert!(request().extension(billing.clone()).filter(&route).await.is_ok());
+        assert!(request().extension(search).filter(&route).await.is_ok());
+        assert!(request().extension(billing).filter(&route).await.is_err());
+
+        // Clients rotating IPs share a budget when their fingerprint matches
+        let route = with_rate_limit_by(
+            RateLimitConfig::max_per_window(1, 60),
+            key::fingerprint(|headers, _| headers.get("x-ja4").map(|v| format!("ja4:{}", v.to_str().unwrap_or("")))),
+        );
+        let from = |ip: &str| request().remote_addr(format!("{}:1234", ip).parse().unwrap()).header("x-ja4", "t13d1516h2");
+        assert!(from("198.51.100.1").filter(&route).await.is_ok());
+        assert!(from("198.51.100.2").filter(&route).await.is_err());
+
+        let route = with_rate_limit_by(RateLimitConfig::max_per_window(1, 60), key::proxied_ip());
+        let client = ProxiedAddr("203.0.113.7:51234".parse().unwrap());
+        assert!(request().extension(client).filter(&route).await.is_ok());
+        assert!(request().extension(client).filter(&route).await.is_err());
+
+        let route = with_rate_limit_by(
+            RateLimitConfig::max_per_window(1, 60),
+            key::tenant(key::header("x-tenant"), key::header("x-api-key")),
+        );
+        assert!(request().header("x-tenant", "acme").header("x-api-key", "k").filter(&route).await.is_ok());
+        assert!(request().header("x-tenant", "globex").header("x-api-key", "k").filter(&route).await.is_ok());
+        assert!(request().header("x-tenant", "acme").header("x-api-key", "k").filter(&route).await.is_err());
+
+        // Hot resources are limited on their own, whoever asks for them
+        let route = with_rate_limit_by(
+            RateLimitConfig::max_per_window(1, 60),
+            key::resource(crate::core::ResourceKey::new("/orgs/{org_id}/**", "org:{org_id}")),
+        );
+        assert!(request().path("/orgs/acme/repos/site").filter(&route).await.is_ok());
+        assert!(request().path("/orgs/globex/repos/site").filter(&route).await.is_ok());
+        assert!(request().path("/orgs/acme/members").filter(&route).await.is_err());
+
+        let tenants = TenantLimiters::new(10, |_| RateLimitConfig::max_per_window(1, 60));
+        let route = with_tenant_rate_limit(tenants.clone(), key::header("x-tenant"), key::header("x-api-key"));
+        assert!(request().header("x-tenant", "acme").header("x-api-key", "k").filter(&route).await.is_ok());
+        assert!(request().header("x-tenant", "globex").header("x-api-key", "k").filter(&route).await.is_ok());
+        assert!(request().header("x-tenant", "acme").header("x-api-key", "k").filter(&route).await.is_err());
+        assert_eq!(tenants.dedicated_tenants(), 2);
+
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(5, 60))
+            .with_endpoint("search", RateLimitConfig::max_per_window(1, 60));
+        let search = with_endpoint_rate_limit(limiter.clone(), "search", key::header("x-api-key"));
+        let status = with_endpoint_rate_limit(limiter, "status", key::header("x-api-key"));
+        assert!(request().header("x-api-key", "k").filter(&search).await.is_ok());
+        assert!(request().header("x-api-key", "k").filter(&search).await.is_err());
+        assert!(request().header("x-api-key", "k").filter(&status).await.is_ok());
+
+        let rules = RateLimitRules::new(RateLimiter::new(RateLimitConfig::max_per_window(1, 60)))
+            .exempt(crate::core::Match::path("/health"));
+        let route = with_rate_limit_rules(rules, key::header("x-api-key"));
+        assert!(request().path("/health").filter(&route).await.unwrap().is_none());
+        assert!(request().path("/health").filter(&route).await.unwrap().is_none());
+        assert!(request().path("/").filter(&route).await.unwrap().is_some());
+        assert!(request().path("/").filter(&route).await.is_err());
+
+        let global = GlobalLimiter::new(RateLimitConfig::max_per_window(5, 60), 2).with_reserve(0.5);
+        let priority = key::header("x-plan")
+            .map(|plan: String| if plan == "paid" { Priority::High } else { Priority::Normal });
+        let route = with_global_rate_limit(global, key::header("x-api-key"), priority);
+        assert!(request().header("x-api-key", "a").filter(&route).await.is_ok());
+        assert!(request().header("x-api-key", "b").filter(&route).await.is_err());
+        assert!(request().header("x-api-key", "b").header("x-plan", "paid").filter(&route).await.is_ok());
+
+        let global = GlobalLimiter::new(RateLimitConfig::max_per_window(5, 60), 2).with_shedding(0.5);
+        let route = with_global_rate_limit(global, key::header("x-api-key"), priority_header("x-priority"));
+        assert!(request().header("x-api-key", "a").filter(&route).await.is_ok());
+        assert!(request().header("x-api-key", "b").header("x-priority", "low").filter(&route).await.is_err());
+        assert!(request().header("x-api-key", "b").filter(&route).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_content_length_cost() {
+        let config = RateLimitConfig::max_per_window(10, 60).with_content_length_cost(100, 1);
+        let route = with_rate_limit(config).map(|info: RateLimitInfo| info.used.to_string());
+
+        // No body costs the minimum, a 450 byte body costs 5 units
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.body(), "1");
+        let resp = request().body(vec![0u8; 450]).reply(&route).await;
+        assert_eq!(resp.body(), "6");
+
+        // 500 bytes would take the client past its budget
+        assert!(request().body(vec![0u8; 500]).filter(&route).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_reconfigures_running_filters() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60));
+        let handle = limiter.handle();
+        let route = limiter.filter();
+        drop(limiter);
+
+        assert_eq!(request().filter(&route).await.unwrap().remaining, 9);
+        handle.update_config(RateLimitConfig::max_per_window(2, 3600));
+        assert_eq!(handle.config().max_requests, 2);
+
+        // The request already counted still counts against the tighter limit
+        let info = request().filter(&route).await.unwrap();
+        assert_eq!((info.limit, info.remaining, info.window.as_secs()), (2, 0, 3600));
+        assert!(request().filter(&route).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_reconfigures_endpoints_and_builder_filters() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60))
+            .with_endpoint_overrides("search", ConfigOverrides::new().with_window(Duration::from_secs(120)));
+        let handle = limiter.handle();
+        let search = limiter.endpoint("search");
+        let bypass = limiter.scoped(limiter.config().with_namespace("bypass"));
+        let route = with_endpoint_rate_limit(limiter, "search", key::remote_ip());
+
+        assert_eq!(request().filter(&route).await.unwrap().remaining, 9);
+        handle.update_config(RateLimitConfig::max_per_window(2, 3600));
+
+        // Views follow the swap, keeping what they set themselves
+        let info = request().filter(&route).await.unwrap();
+        assert_eq!((info.limit, info.remaining, info.window.as_secs()), (2, 0, 120));
+        assert_eq!(search.config().max_requests, 2);
+        assert_eq!(search.config().namespace.as_deref(), Some("search"));
+        assert_eq!((bypass.config().max_requests, bypass.config().namespace.as_deref()), (2, Some("bypass")));
+        assert_eq!(bypass.endpoint("inner").config().max_requests, 2);
+
+        let (handle, limit) = with_rate_limit_handle(RateLimitConfig::max_per_window(10, 60));
+        assert_eq!(request().filter(&limit).await.unwrap().remaining, 9);
+        handle.update_config(RateLimitConfig::max_per_window(1, 60));
+        assert!(request().filter(&limit).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_weighted_costs() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(10, 60));
+        let cost = warp::header::optional::<u32>("x-cost").map(|cost: Option<u32>| cost.unwrap_or(5));
+        let search = warp::path("search").and(limiter.filter_with_cost(key::remote_ip(), cost));
+        let health = warp::path("health").and(limiter.filter());
+        let route = search.or(health).unify().map(|info: RateLimitInfo| info.used.to_string());
+
+        assert_eq!(request().path("/search").reply(&route).await.body(), "5");
+        assert_eq!(request().path("/health").reply(&route).await.body(), "6");
+        // Another search would take the client to 11 units
+        assert!(request().path("/search").filter(&route).await.is_err());
+        assert_eq!(request().path("/health").reply(&route).await.body(), "7");
+
+        let cost = warp::header::optional::<u32>("x-cost").map(|cost: Option<u32>| cost.unwrap_or(1));
+        let route = with_rate_limit_cost_by(RateLimitConfig::max_per_window(10, 60), key::remote_ip(), cost)
+            .map(|info: RateLimitInfo| info.used.to_string());
+        assert_eq!(request().header("x-cost", "4").reply(&route).await.body(), "4");
+        assert_eq!(request().reply(&route).await.body(), "5");
+
+        let route = with_rate_limit_cost(RateLimitConfig::max_per_window(10, 60), 3);
+        assert_eq!(request().filter(&route).await.unwrap().remaining, 7);
+    }
+
+    #[tokio::test]
+    async fn test_status_penalty() {
+        let login = warp::header::<String>("x-password").map(|password: String| {
+            let status = if password == "hunter2" { StatusCode::OK } else { StatusCode::UNAUTHORIZED };
+            warp::reply::with_status("", status)
+        });
+        let route = with_status_penalty(
+            login,
+            RateLimiter::new(RateLimitConfig::max_per_window(10, 60)),
+            key::remote_ip(),
+            StatusPenalty::new().penalize(StatusCode::UNAUTHORIZED, 5),
+        );
+
+        let resp = request().header("x-password", "hunter2").reply(&route).await;
+        assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "9");
+
+        // Two failures cost five units each, exhausting the budget
+        for _ in 0..2 {
+            let resp = request().header("x-password", "guess").reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(request().header("x-password", "hunter2").filter(&route).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_response_cost_charges_only_matching_routes() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(2, 60));
+        let charge = |path: &'static str| {
+            with_response_cost(warp::path(path).map(|| "ok"), limiter.clone(), key::remote_ip(), |_| 1)
+        };
+        let route = charge("a").or(charge("b"));
+
+        // Requests for /b fall through the /a route without being charged there
+        for remaining in ["1", "0"] {
+            let resp = request().path("/b").reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()["x-ratelimit-remaining"], remaining);
+        }
+        let rejection = request().path("/c").filter(&route).await.unwrap_err();
+        assert!(rejection.is_not_found());
+    }
+
+    #[tokio::test]
+    async fn test_response_size_cost() {
+        let size = std::fs::metadata("Cargo.toml").unwrap().len();
+        let route = with_response_size_cost(
+            warp::fs::file("Cargo.toml"),
+            RateLimiter::new(RateLimitConfig::max_per_window(1000, 60)),
+            key::remote_ip(),
+            ContentLengthCost { bytes_per_unit: 100, min_cost: 1 },
+        );
+
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "999");
+
+        // The next request sees the file's size charged against the budget
+        let resp = request().reply(&route).await;
+        let expected = 1000 - 1 - size.div_ceil(100) as u32;
+        assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), expected.to_string().as_str());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let route = with_concurrency_limit(limiter.clone(), key::remote_ip());
+
+        let permit = request().filter(&route).await.unwrap();
+        let rejection = request().filter(&route).await.unwrap_err();
+        assert_eq!(rejection.find::<ConcurrencyLimitRejection>().unwrap().limit, 1);
+
+        drop(permit);
+        assert!(request().filter(&route).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_with_rate_limit_headers() {
+        let route = with_rate_limit(RateLimitConfig::max_per_minute(10))
+            .map(|info: RateLimitInfo| with_rate_limit_headers("hello", &info));
+
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "hello");
+        assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "9");
+    }
+}
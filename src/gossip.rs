@@ -0,0 +1,155 @@
+//! Zero-infrastructure count sharing: instances gossip their local counts to
+//! each other over UDP and merge what they receive into their
+//! [`PeerCounts`]. Limits become approximately global without any shared
+//! store, at the cost of one datagram per peer per interval.
+//!
+//! ```rust,no_run,ignore
+//! let peers = PeerCounts::new("node-a");
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_peer_counts(peers.clone());
+//! let socket = UdpSocket::bind("0.0.0.0:7946").await?;
+//! spawn_gossip(socket, vec!["10.0.0.2:7946".parse()?], limiter.clone(), peers, Duration::from_secs(1));
+//! ```
+//!
+//! Datagrams are neither authenticated nor encrypted, so bind the socket to
+//! a private network only.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::core::{CountSnapshot, PeerCounts, RateLimiter};
+
+/// Most bytes [`spawn_gossip`] puts in a datagram, so each fits a 1500-byte
+/// Ethernet MTU without fragmenting
+pub const DEFAULT_MAX_DATAGRAM: usize = 1_400;
+
+/// The most a UDP datagram over IPv4 can carry
+const UDP_MAX_PAYLOAD: usize = 65_507;
+
+/// Spawns a task that sends `limiter`'s local counts to every address in
+/// `peer_addrs` each `interval`, and merges snapshots received on `socket`
+/// into `peers`. Large snapshots are split across datagrams of at most
+/// [`DEFAULT_MAX_DATAGRAM`] bytes, each a self-contained `CountSnapshot`, so
+/// a lost datagram only delays some keys. Runs until the returned handle is
+/// aborted.
+pub fn spawn_gossip(
+    socket: UdpSocket,
+    peer_addrs: Vec<SocketAddr>,
+    limiter: RateLimiter,
+    peers: PeerCounts,
+    interval: Duration,
+) -> JoinHandle<()> {
+    spawn_gossip_with_max_datagram(socket, peer_addrs, limiter, peers, interval, DEFAULT_MAX_DATAGRAM)
+}
+
+/// [`spawn_gossip`] with datagrams of at most `max_datagram` bytes, e.g.
+/// larger on a network with jumbo frames. Capped at the UDP limit of 65,507.
+pub fn spawn_gossip_with_max_datagram(
+    socket: UdpSocket,
+    peer_addrs: Vec<SocketAddr>,
+    limiter: RateLimiter,
+    peers: PeerCounts,
+    interval: Duration,
+    max_datagram: usize,
+) -> JoinHandle<()> {
+    let max_datagram = max_datagram.min(UDP_MAX_PAYLOAD);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut buf = vec![0u8; 65_536];
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let snapshot = CountSnapshot {
+                        node: peers.node().to_string(),
+                        counts: limiter.local_counts().await,
+                    };
+                    for datagram in snapshot.encode_split(max_datagram) {
+                        for addr in &peer_addrs {
+                            if let Err(e) = socket.send_to(&datagram, addr).await {
+                                tracing::warn!("failed to gossip counts to {}: {}", addr, e);
+                            }
+                        }
+                    }
+                }
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => match serde_json::from_slice::<CountSnapshot>(&buf[..len]) {
+                        Ok(snapshot) => peers.merge(&snapshot),
+                        Err(e) => tracing::warn!("ignoring malformed count snapshot from {}: {}", from, e),
+                    },
+                    Err(e) => tracing::warn!("failed to receive gossip: {}", e),
+                },
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::RateLimitConfig;
+
+    #[tokio::test]
+    async fn test_gossip_shares_counts() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (addr_a, addr_b) = (socket_a.local_addr().unwrap(), socket_b.local_addr().unwrap());
+
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let peers_a = PeerCounts::new("a");
+        let peers_b = PeerCounts::new("b");
+        let limiter_a = RateLimiter::new(config.clone()).with_peer_counts(peers_a.clone());
+        let limiter_b = RateLimiter::new(config).with_peer_counts(peers_b.clone());
+
+        limiter_a.check_rate_limit("client").await.unwrap();
+        limiter_a.check_rate_limit("client").await.unwrap();
+
+        let interval = Duration::from_millis(10);
+        let a = spawn_gossip(socket_a, vec![addr_b], limiter_a, peers_a, interval);
+        let b = spawn_gossip(socket_b, vec![addr_a], limiter_b.clone(), peers_b.clone(), interval);
+
+        for _ in 0..100 {
+            if peers_b.total("client") == 2 {
+                break;
+            }
+            tokio::time::sleep(interval).await;
+        }
+        assert_eq!(limiter_b.check_rate_limit("client").await.unwrap().remaining, 0);
+        assert!(limiter_b.check_rate_limit("client").await.is_err());
+
+        a.abort();
+        b.abort();
+    }
+
+    #[tokio::test]
+    async fn test_gossip_splits_long_keys_across_datagrams() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr_b = socket_b.local_addr().unwrap();
+
+        // 70 keys of 1,000 bytes is more than one datagram can carry
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let peers_a = PeerCounts::new("a");
+        let peers_b = PeerCounts::new("b");
+        let limiter_a = RateLimiter::new(config.clone()).with_peer_counts(peers_a.clone());
+        let keys: Vec<String> = (0..70).map(|i| format!("{:04}{}", i, "k".repeat(996))).collect();
+        for key in &keys {
+            limiter_a.check_rate_limit(key).await.unwrap();
+        }
+
+        let interval = Duration::from_millis(10);
+        let a = spawn_gossip(socket_a, vec![addr_b], limiter_a, peers_a, interval);
+        let b = spawn_gossip(socket_b, vec![], RateLimiter::new(config), peers_b.clone(), interval);
+
+        for _ in 0..200 {
+            if keys.iter().all(|key| peers_b.total(key) == 1) {
+                break;
+            }
+            tokio::time::sleep(interval).await;
+        }
+        assert!(keys.iter().all(|key| peers_b.total(key) == 1));
+
+        a.abort();
+        b.abort();
+    }
+}
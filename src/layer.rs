@@ -0,0 +1,316 @@
+//! A tower `Layer`, for teams already composing tower (or tower-http)
+//! middleware around their services.
+//!
+//! Like tower-http's `classify` module, the layer hands every response to a
+//! classifier that decides whether the request keeps counting against the
+//! client or is refunded:
+//!
+//! ```rust,no_run,ignore
+//! let service = ServiceBuilder::new()
+//!     .layer(RateLimitLayer::new(limiter).classify(RefundServerErrors))
+//!     .service(my_service);
+//! ```
+
+use http::request::Parts;
+use http::{Request, Response};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::core::{add_rate_limit_headers, is_preflight, KeyExtractor, RateLimiter};
+
+/// What a classifier decided about a response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Classification {
+    /// The request counts against the client's budget
+    Count,
+    /// The request is given back to the client's budget
+    Refund,
+}
+
+/// Decides whether a response counts against the client. Implemented for
+/// any `Fn(&Response<B>) -> Classification`.
+pub trait ClassifyResponse<B> {
+    /// Classify a response produced by the inner service
+    fn classify(&self, response: &Response<B>) -> Classification;
+}
+
+impl<B, F> ClassifyResponse<B> for F
+where
+    F: Fn(&Response<B>) -> Classification,
+{
+    fn classify(&self, response: &Response<B>) -> Classification {
+        self(response)
+    }
+}
+
+/// Every response counts. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CountAll;
+
+impl<B> ClassifyResponse<B> for CountAll {
+    fn classify(&self, _response: &Response<B>) -> Classification {
+        Classification::Count
+    }
+}
+
+/// Server errors (5xx) are refunded, matching tower-http's
+/// `ServerErrorsAsFailures`: the client shouldn't pay for our failures.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RefundServerErrors;
+
+impl<B> ClassifyResponse<B> for RefundServerErrors {
+    fn classify(&self, response: &Response<B>) -> Classification {
+        if response.status().is_server_error() {
+            Classification::Refund
+        } else {
+            Classification::Count
+        }
+    }
+}
+
+type KeySource = Arc<dyn KeyExtractor>;
+
+/// Applies a [`RateLimiter`] to the wrapped service. By default, requests are
+/// keyed on the IP of a `SocketAddr` stored in the request extensions by the
+/// connection acceptor; use `key_fn` or `key_extractor` to key on anything
+/// else. Requests let through carry their `RateLimitInfo` in the request
+/// extensions.
+#[derive(Clone)]
+pub struct RateLimitLayer<C = CountAll> {
+    limiter: RateLimiter,
+    classifier: C,
+    keys: KeySource,
+}
+
+impl RateLimitLayer {
+    /// Build a layer enforcing `limiter`, counting every response
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self {
+            limiter,
+            classifier: CountAll,
+            keys: Arc::new(|parts: &Parts| std::future::ready(Some(peer_ip_key(parts)))),
+        }
+    }
+}
+
+impl<C> RateLimitLayer<C> {
+    /// Use `classifier` to decide which responses are refunded
+    pub fn classify<C2>(self, classifier: C2) -> RateLimitLayer<C2> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            classifier,
+            keys: self.keys,
+        }
+    }
+
+    /// Derive the rate limit key from the request head
+    pub fn key_fn<F>(mut self, key_fn: F) -> Self
+    where
+        F: Fn(&Parts) -> String + Send + Sync + 'static,
+    {
+        self.keys = Arc::new(move |parts: &Parts| std::future::ready(Some(key_fn(parts))));
+        self
+    }
+
+    /// Derive the rate limit key with `extractor`, e.g. [`ForwardedFor`](crate::ForwardedFor)
+    /// or [`ApiKey`](crate::ApiKey). Requests it finds no key in are keyed
+    /// as `"unknown"`.
+    pub fn key_extractor<E: KeyExtractor>(mut self, extractor: E) -> Self {
+        self.keys = Arc::new(extractor);
+        self
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for RateLimitLayer<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitLayer")
+            .field("limiter", &self.limiter)
+            .field("classifier", &self.classifier)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, C: Clone> Layer<S> for RateLimitLayer<C> {
+    type Service = RateLimit<S, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            classifier: self.classifier.clone(),
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+/// The service produced by [`RateLimitLayer`]
+#[derive(Clone)]
+pub struct RateLimit<S, C = CountAll> {
+    inner: S,
+    limiter: RateLimiter,
+    classifier: C,
+    keys: KeySource,
+}
+
+impl<S, C, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimit<S, C>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    C: ClassifyResponse<ResBody> + Clone + Send + 'static,
+    ReqBody: Send + 'static,
+    ResBody: From<String> + Send,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (parts, body) = req.into_parts();
+        let preflight = is_preflight(&parts.method, &parts.headers);
+
+        // Take the service that was driven to readiness and leave a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let classifier = self.classifier.clone();
+        let keys = self.keys.clone();
+
+        Box::pin(async move {
+            let key = keys.extract(&parts).await.unwrap_or_else(|| "unknown".to_string());
+            let peer = parts.extensions.get::<SocketAddr>().map(|addr| addr.ip());
+            let checked = match limiter.screen(&key, peer, &parts.headers).await {
+                Some(screened) => screened,
+                None if preflight => limiter.check_preflight(&key).await,
+                None => limiter.check_rate_limit(&key).await,
+            };
+            let mut req = Request::from_parts(parts, body);
+            let mut info = match checked {
+                Ok(info) => info,
+                Err(rejection) => {
+                    limiter.tarpit(&key, &rejection).await;
+                    return Ok(Response::from(&rejection));
+                }
+            };
+
+            req.extensions_mut().insert(info.clone());
+            let mut response = inner.call(req).await?;
+            limiter.record_status(&key, response.status());
+            if classifier.classify(&response) == Classification::Refund {
+                limiter.refund(&key, 1).await;
+                info.used = info.used.saturating_sub(1);
+                info.remaining = (info.remaining + 1).min(info.limit);
+            }
+            if let Err(e) = add_rate_limit_headers(response.headers_mut(), &info) {
+                tracing::warn!("{}", e);
+            }
+            Ok(response)
+        })
+    }
+}
+
+fn peer_ip_key(parts: &Parts) -> String {
+    parts
+        .extensions
+        .get::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::RateLimitConfig;
+    use http::StatusCode;
+    use std::convert::Infallible;
+
+    #[derive(Clone)]
+    struct Status(StatusCode);
+
+    impl Service<Request<String>> for Status {
+        type Response = Response<String>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Response<String>, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<String>) -> Self::Future {
+            let mut response = Response::new(String::new());
+            *response.status_mut() = self.0;
+            std::future::ready(Ok(response))
+        }
+    }
+
+    fn request_from(addr: &str) -> Request<String> {
+        let mut req = Request::new(String::new());
+        req.extensions_mut().insert(addr.parse::<SocketAddr>().unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn test_layer_refunds_classified_responses() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let layer = RateLimitLayer::new(limiter).classify(RefundServerErrors);
+
+        // Server errors are refunded, so the single-request budget survives them
+        let mut failing = layer.layer(Status(StatusCode::INTERNAL_SERVER_ERROR));
+        for _ in 0..3 {
+            let resp = failing.call(request_from("10.0.0.1:1")).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "1");
+        }
+
+        let mut ok = layer.layer(Status(StatusCode::OK));
+        assert_eq!(ok.call(request_from("10.0.0.1:1")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            ok.call(request_from("10.0.0.1:1")).await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_layer_custom_key_fn() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let layer = RateLimitLayer::new(limiter).key_fn(|parts: &Parts| {
+            parts.headers.get("x-api-key").and_then(|v| v.to_str().ok()).unwrap_or("anonymous").to_string()
+        });
+        let mut svc = layer.layer(Status(StatusCode::OK));
+
+        let mut req = Request::new(String::new());
+        req.headers_mut().insert("x-api-key", "alpha".parse().unwrap());
+        assert_eq!(svc.call(req).await.unwrap().status(), StatusCode::OK);
+
+        // A different key has its own budget
+        let mut req = Request::new(String::new());
+        req.headers_mut().insert("x-api-key", "beta".parse().unwrap());
+        assert_eq!(svc.call(req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_layer_key_extractor() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let layer = RateLimitLayer::new(limiter).key_extractor(crate::core::ForwardedFor);
+        let mut svc = layer.layer(Status(StatusCode::OK));
+
+        // Clients behind the same proxy get their own budgets
+        for client in ["203.0.113.7", "203.0.113.8"] {
+            let mut req = request_from("10.0.0.1:1");
+            req.headers_mut().insert("x-forwarded-for", client.parse().unwrap());
+            assert_eq!(svc.call(req).await.unwrap().status(), StatusCode::OK);
+        }
+        let mut req = request_from("10.0.0.1:1");
+        req.headers_mut().insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        assert_eq!(svc.call(req).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,205 @@
+//! Shares counts between instances over NATS, enabled by the `nats` feature.
+//! For clusters that have NATS but no shared store: every instance publishes
+//! its local counts to one subject and merges what the others publish into
+//! its [`PeerCounts`].
+//!
+//! ```rust,no_run,ignore
+//! let peers = PeerCounts::new(hostname);
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_peer_counts(peers.clone());
+//! let client = async_nats::connect("nats://nats:4222").await?;
+//! sync_over_nats(client, "ratelimit.counts", limiter.clone(), peers, Duration::from_secs(1)).await?;
+//! ```
+
+use futures_core::Stream;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::core::{CountSnapshot, PeerCounts, RateLimitError, RateLimiter};
+
+/// Subscribes to `subject`, then spawns a task that publishes `limiter`'s
+/// local counts every `interval` and merges peers' snapshots into `peers` as
+/// they arrive. Snapshots larger than the server's `max_payload` go out as
+/// several messages, each a snapshot of some of the counts. Malformed
+/// messages are logged and skipped. The task runs until
+/// the subscription ends or the returned handle is aborted.
+pub async fn sync_over_nats(
+    client: async_nats::Client,
+    subject: impl Into<String>,
+    limiter: RateLimiter,
+    peers: PeerCounts,
+    interval: Duration,
+) -> Result<JoinHandle<()>, RateLimitError> {
+    let subject = subject.into();
+    let mut subscriber = client
+        .subscribe(subject.clone())
+        .await
+        .map_err(|e| RateLimitError::Other(Box::new(e)))?;
+
+    Ok(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let snapshot = CountSnapshot {
+                        node: peers.node().to_string(),
+                        counts: limiter.local_counts().await,
+                    };
+                    // One message per piece, each within what the server accepts
+                    for payload in snapshot.encode_split(client.max_payload()) {
+                        if let Err(e) = client.publish(subject.clone(), payload.into()).await {
+                            tracing::warn!("failed to publish count snapshot: {}", e);
+                        }
+                    }
+                }
+                message = std::future::poll_fn(|cx| std::pin::Pin::new(&mut subscriber).poll_next(cx)) => {
+                    let Some(message) = message else { break };
+                    match serde_json::from_slice::<CountSnapshot>(&message.payload) {
+                        Ok(snapshot) => peers.merge(&snapshot),
+                        Err(e) => tracing::warn!("ignoring malformed count snapshot: {}", e),
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::RateLimitConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    /// Subscriptions on the fake server: subject, sid and the subscriber's outbox
+    type Subscriptions = Arc<Mutex<Vec<(String, String, UnboundedSender<Vec<u8>>)>>>;
+
+    /// Just enough of a NATS server to relay messages between clients,
+    /// advertising `max_payload`. Returns its URL and the size of the largest
+    /// message published to it.
+    async fn fake_server(max_payload: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let largest = Arc::new(AtomicUsize::new(0));
+        let subscriptions = Subscriptions::default();
+
+        let served = largest.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (reader, mut writer) = stream.into_split();
+                let (outbox, mut sent) = unbounded_channel::<Vec<u8>>();
+                outbox
+                    .send(format!("INFO {{\"server_id\":\"fake\",\"max_payload\":{max_payload},\"proto\":1}}\r\n").into_bytes())
+                    .unwrap();
+                tokio::spawn(async move {
+                    while let Some(bytes) = sent.recv().await {
+                        if writer.write_all(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                let (subscriptions, largest) = (subscriptions.clone(), served.clone());
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(reader);
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let words: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+                        line.clear();
+                        match words.first().map(String::as_str) {
+                            Some("PING") => drop(outbox.send(b"PONG\r\n".to_vec())),
+                            Some("SUB") => subscriptions.lock().unwrap().push((
+                                words[1].clone(),
+                                words[words.len() - 1].clone(),
+                                outbox.clone(),
+                            )),
+                            Some("PUB") => {
+                                let len: usize = words[words.len() - 1].parse().unwrap();
+                                let mut payload = vec![0; len + 2];
+                                reader.read_exact(&mut payload).await.unwrap();
+                                payload.truncate(len);
+                                largest.fetch_max(len, Ordering::SeqCst);
+                                for (subject, sid, outbox) in subscriptions.lock().unwrap().iter() {
+                                    if *subject == words[1] {
+                                        let mut message = format!("MSG {subject} {sid} {len}\r\n").into_bytes();
+                                        message.extend_from_slice(&payload);
+                                        message.extend_from_slice(b"\r\n");
+                                        let _ = outbox.send(message);
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+        (url, largest)
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_over_max_payload_are_chunked() {
+        let (url, largest) = fake_server(1_024).await;
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let peers_a = PeerCounts::new("a");
+        let peers_b = PeerCounts::new("b");
+        let limiter_a = RateLimiter::new(config.clone()).with_peer_counts(peers_a.clone());
+        let limiter_b = RateLimiter::new(config).with_peer_counts(peers_b.clone());
+
+        // Far more counts than fit in one message
+        let keys: Vec<String> = (0..200).map(|i| format!("client-{i}")).collect();
+        for key in &keys {
+            limiter_a.check_rate_limit(key).await.unwrap();
+        }
+
+        let client_a = async_nats::connect(&url).await.unwrap();
+        let client_b = async_nats::connect(&url).await.unwrap();
+        assert_eq!(client_a.max_payload(), 1_024);
+        let interval = Duration::from_millis(10);
+        let a = sync_over_nats(client_a, "counts", limiter_a, peers_a, interval).await.unwrap();
+        let b = sync_over_nats(client_b, "counts", limiter_b, peers_b.clone(), interval).await.unwrap();
+
+        for _ in 0..200 {
+            if keys.iter().all(|key| peers_b.total(key) == 1) {
+                break;
+            }
+            tokio::time::sleep(interval).await;
+        }
+        assert!(keys.iter().all(|key| peers_b.total(key) == 1));
+        assert!(largest.load(Ordering::SeqCst) <= 1_024);
+
+        a.abort();
+        b.abort();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_messages_are_skipped() {
+        let (url, _) = fake_server(1_024).await;
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let peers_a = PeerCounts::new("a");
+        let peers_b = PeerCounts::new("b");
+        let limiter_a = RateLimiter::new(config.clone()).with_peer_counts(peers_a.clone());
+        let limiter_b = RateLimiter::new(config).with_peer_counts(peers_b.clone());
+        limiter_a.check_rate_limit("client").await.unwrap();
+
+        let client_b = async_nats::connect(&url).await.unwrap();
+        let interval = Duration::from_millis(10);
+        let b = sync_over_nats(client_b.clone(), "counts", limiter_b, peers_b.clone(), interval).await.unwrap();
+        client_b.publish("counts", "not a snapshot".into()).await.unwrap();
+
+        let client_a = async_nats::connect(&url).await.unwrap();
+        let a = sync_over_nats(client_a, "counts", limiter_a, peers_a, interval).await.unwrap();
+        for _ in 0..200 {
+            if peers_b.total("client") == 1 {
+                break;
+            }
+            tokio::time::sleep(interval).await;
+        }
+        assert_eq!(peers_b.total("client"), 1);
+
+        a.abort();
+        b.abort();
+    }
+}
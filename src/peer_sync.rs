@@ -0,0 +1,127 @@
+//! Count sharing over plain HTTP(S), enabled by the `peer-sync` feature. Each
+//! instance serves its local counts on a warp route and pulls its peers'
+//! counts into its [`PeerCounts`], so a small deployment can share limits
+//! with nothing but the HTTPS it already has.
+//!
+//! ```rust,no_run,ignore
+//! let peers = PeerCounts::new("node-a");
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100)).with_peer_counts(peers.clone());
+//!
+//! let sync = warp::path!("internal" / "ratelimit").and(counts_route(limiter.clone(), peers.clone()));
+//! spawn_peer_pull(vec!["https://node-b.internal/internal/ratelimit".into()], peers, Duration::from_secs(1));
+//! ```
+//!
+//! The route serves each key's full count in its current window rather than
+//! a delta since the last pull. Merging counts is idempotent, so a pull that
+//! is missed or repeated can't skew the totals. Keep the route off the public
+//! internet, e.g. behind an internal path filter or mTLS.
+
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Uri};
+use hyper_rustls::HttpsConnector;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use warp::{Filter, Rejection};
+
+use crate::core::{CountSnapshot, PeerCounts, RateLimiter};
+
+/// Largest snapshot accepted from a peer
+const MAX_SNAPSHOT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Serves `limiter`'s local counts as a JSON `CountSnapshot` on GET
+pub fn counts_route(
+    limiter: RateLimiter,
+    peers: PeerCounts,
+) -> impl Filter<Extract = (warp::reply::Json,), Error = Rejection> + Clone {
+    warp::get().and_then(move || {
+        let limiter = limiter.clone();
+        let node = peers.node().to_string();
+        async move {
+            let snapshot = CountSnapshot {
+                node,
+                counts: limiter.local_counts().await,
+            };
+            Ok::<_, Rejection>(warp::reply::json(&snapshot))
+        }
+    })
+}
+
+/// Spawns a task that fetches every URL in `peer_urls` each `interval` and
+/// merges the snapshots into `peers`. Both `https` and `http` URLs are
+/// accepted; TLS uses the platform's root certificates. Failed pulls are
+/// logged and retried on the next tick. Runs until the returned handle is
+/// aborted.
+pub fn spawn_peer_pull(peer_urls: Vec<String>, peers: PeerCounts, interval: Duration) -> JoinHandle<()> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<HttpsConnector<HttpConnector>> = Client::builder().build(connector);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for url in &peer_urls {
+                match pull(&client, url).await {
+                    Ok(snapshot) => peers.merge(&snapshot),
+                    Err(e) => tracing::warn!("failed to pull counts from {}: {}", url, e),
+                }
+            }
+        }
+    })
+}
+
+async fn pull(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &str,
+) -> Result<CountSnapshot, Box<dyn std::error::Error + Send + Sync>> {
+    let uri: Uri = url.parse()?;
+    let response = client.get(uri).await?;
+    if !response.status().is_success() {
+        return Err(format!("peer answered {}", response.status()).into());
+    }
+
+    let mut body: Body = response.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > MAX_SNAPSHOT_BYTES {
+            return Err("snapshot too large".into());
+        }
+    }
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::RateLimitConfig;
+
+    #[tokio::test]
+    async fn test_pull_merges_peer_counts() {
+        let config = RateLimitConfig::max_per_window(3, 60);
+        let peers_a = PeerCounts::new("a");
+        let limiter_a = RateLimiter::new(config.clone()).with_peer_counts(peers_a.clone());
+        limiter_a.check_rate_limit("client").await.unwrap();
+        limiter_a.check_rate_limit("client").await.unwrap();
+
+        let (addr, server) = warp::serve(counts_route(limiter_a, peers_a)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let peers_b = PeerCounts::new("b");
+        let limiter_b = RateLimiter::new(config).with_peer_counts(peers_b.clone());
+        let pull = spawn_peer_pull(vec![format!("http://{}/", addr)], peers_b.clone(), Duration::from_millis(10));
+
+        for _ in 0..100 {
+            if peers_b.total("client") == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(limiter_b.check_rate_limit("client").await.unwrap().remaining, 0);
+        pull.abort();
+    }
+}
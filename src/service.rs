@@ -0,0 +1,129 @@
+//! A plain hyper `Service` adapter, for stacks that drive hyper directly
+//! (or mix warp with other hyper services) instead of composing filters.
+//!
+//! The key is the peer address handed over when the connection is accepted:
+//!
+//! ```rust,no_run,ignore
+//! let limiter = RateLimiter::new(RateLimitConfig::max_per_minute(100));
+//!
+//! let make_svc = make_service_fn(move |conn: &AddrStream| {
+//!     let limiter = limiter.clone();
+//!     let addr = conn.remote_addr();
+//!     async move {
+//!         Ok::<_, Infallible>(RateLimitService::new(service_fn(handle), limiter, addr))
+//!     }
+//! });
+//!
+//! Server::bind(&addr).serve(make_svc).await?;
+//! ```
+
+use hyper::service::Service;
+use hyper::{Request, Response};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::core::{add_rate_limit_headers, is_preflight, RateLimiter};
+
+/// Wraps a hyper service so every request on the connection is counted
+/// against the peer's IP. Limited requests are answered with a complete 429
+/// response without reaching the inner service; the rest carry their
+/// `RateLimitInfo` in the request extensions (see [`rate_limit_info`]).
+///
+/// [`rate_limit_info`]: crate::core::rate_limit_info
+#[derive(Clone, Debug)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: RateLimiter,
+    peer: IpAddr,
+    key: String,
+}
+
+impl<S> RateLimitService<S> {
+    /// Wrap `inner`, keying requests on the IP of `peer`
+    pub fn new(inner: S, limiter: RateLimiter, peer: SocketAddr) -> Self {
+        Self {
+            inner,
+            limiter,
+            peer: peer.ip(),
+            key: peer.ip().to_string(),
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: From<String>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // Take the service that was driven to readiness and leave a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let key = self.key.clone();
+        let peer = self.peer;
+        let preflight = is_preflight(req.method(), req.headers());
+
+        Box::pin(async move {
+            let checked = match limiter.screen(&key, Some(peer), req.headers()).await {
+                Some(screened) => screened,
+                None if preflight => limiter.check_preflight(&key).await,
+                None => limiter.check_rate_limit(&key).await,
+            };
+            match checked {
+                Ok(info) => {
+                    req.extensions_mut().insert(info.clone());
+                    let mut response = inner.call(req).await?;
+                    if let Err(e) = add_rate_limit_headers(response.headers_mut(), &info) {
+                        tracing::warn!("{}", e);
+                    }
+                    Ok(response)
+                }
+                Err(rejection) => {
+                    limiter.tarpit(&key, &rejection).await;
+                    Ok(Response::from(&rejection))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{rate_limit_info, RateLimitConfig};
+    use hyper::service::service_fn;
+    use hyper::{Body, StatusCode};
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn test_service_limits_peer() {
+        let limiter = RateLimiter::new(RateLimitConfig::max_per_window(1, 60));
+        let inner = service_fn(|req: Request<Body>| async move {
+            let remaining = rate_limit_info(req.extensions()).unwrap().remaining;
+            Ok::<_, Infallible>(Response::new(Body::from(remaining.to_string())))
+        });
+        let mut svc = RateLimitService::new(inner, limiter, "10.0.0.1:5000".parse().unwrap());
+
+        let ok = svc.call(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(ok.status(), StatusCode::OK);
+        assert_eq!(ok.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+        assert_eq!(hyper::body::to_bytes(ok.into_body()).await.unwrap(), "0");
+
+        let limited = svc.call(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers().get("X-RateLimit-Limit").unwrap(), "1");
+    }
+}
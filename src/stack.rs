@@ -0,0 +1,144 @@
+//! Applies the rate limiter together with warp's `trace`, CORS, and
+//! compression wraps in a defined order.
+//!
+//! Where the limiter sits relative to those wraps matters, and getting it
+//! wrong fails silently. From the outside in, `RateLimitStack` applies:
+//!
+//! 1. **trace**: spans cover every request, including rate limited ones.
+//! 2. **CORS**: preflights are answered before they reach the limiter, so they
+//!    don't burn quota, and 429 responses carry the CORS headers browsers need
+//!    to read them. This only works because the limiter answers with a 429
+//!    reply instead of a rejection, which the CORS wrap would pass through
+//!    untouched.
+//! 3. **rate limit**: every route inside the stack is counted. Routes `or`-ed
+//!    on outside the stack are not, so apply the stack to the whole tree.
+//! 4. **compression**: only replies from routes that were let through are
+//!    compressed.
+//!
+//! ```rust,no_run,ignore
+//! let api = RateLimitStack::new(RateLimiter::new(RateLimitConfig::max_per_minute(100)))
+//!     .cors(warp::cors().allow_any_origin())
+//!     .trace("api")
+//!     .apply(routes);
+//! ```
+
+use warp::filters::BoxedFilter;
+use warp::{Filter, Rejection, Reply};
+
+use crate::core::RateLimiter;
+use crate::filter::{answer_rate_limited, key, with_response_cost};
+
+/// Builds a route tree wrapped with the limiter and warp's standard wraps
+#[derive(Debug)]
+pub struct RateLimitStack {
+    limiter: RateLimiter,
+    cors: Option<warp::cors::Builder>,
+    trace: Option<&'static str>,
+    #[cfg(feature = "compression")]
+    gzip: bool,
+}
+
+impl RateLimitStack {
+    /// Start a stack around `limiter`, keyed on the remote IP
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self {
+            limiter,
+            cors: None,
+            trace: None,
+            #[cfg(feature = "compression")]
+            gzip: false,
+        }
+    }
+
+    /// Apply `cors` outside the limiter
+    pub fn cors(mut self, cors: warp::cors::Builder) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Trace every request in a span with the given name, outermost
+    pub fn trace(mut self, name: &'static str) -> Self {
+        self.trace = Some(name);
+        self
+    }
+
+    /// Gzip replies from routes that were let through
+    #[cfg(feature = "compression")]
+    pub fn gzip(mut self) -> Self {
+        self.gzip = true;
+        self
+    }
+
+    /// Wrap `filter`, producing a boxed filter that never rejects with a
+    /// `RateLimitRejection`: limited requests are answered with a complete
+    /// 429 response
+    pub fn apply<F, R>(self, filter: F) -> BoxedFilter<(warp::reply::Response,)>
+    where
+        F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+    {
+        #[cfg(feature = "compression")]
+        let filter = {
+            let filter = filter.map(Reply::into_response).boxed();
+            if self.gzip {
+                filter.with(warp::compression::gzip()).map(Reply::into_response).boxed()
+            } else {
+                filter
+            }
+        };
+
+        let limited = with_response_cost(filter, self.limiter, key::remote_ip(), |_: &warp::reply::Response| 1)
+            .recover(answer_rate_limited)
+            .map(Reply::into_response)
+            .boxed();
+
+        let cors_wrapped = match self.cors {
+            Some(cors) => limited.with(cors).map(Reply::into_response).boxed(),
+            None => limited,
+        };
+
+        match self.trace {
+            Some(name) => cors_wrapped
+                .with(warp::trace::named(name))
+                .map(Reply::into_response)
+                .boxed(),
+            None => cors_wrapped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::RateLimitConfig;
+    use warp::http::StatusCode;
+    use warp::test::request;
+
+    #[tokio::test]
+    async fn test_stack_ordering() {
+        let route = RateLimitStack::new(RateLimiter::new(RateLimitConfig::max_per_window(1, 60)))
+            .cors(warp::cors().allow_any_origin().allow_methods(vec!["GET"]))
+            .trace("test")
+            .apply(warp::path::end().map(|| "hello"));
+
+        // Preflights are answered by CORS without counting against the limit
+        for _ in 0..3 {
+            let resp = request()
+                .method("OPTIONS")
+                .header("origin", "https://example.com")
+                .header("access-control-request-method", "GET")
+                .reply(&route)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let resp = request().header("origin", "https://example.com").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+
+        // The 429 is a reply, so it still carries the CORS headers
+        let resp = request().header("origin", "https://example.com").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("access-control-allow-origin"));
+    }
+}
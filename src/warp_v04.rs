@@ -0,0 +1,163 @@
+//! The warp 0.4 adapter, enabled by the `warp04` feature. It mirrors the warp
+//! 0.3 filters at the crate root, but never relies on the remote address
+//! filter, which only yields a value when warp's own server accepted the
+//! connection. Key on a header, on a request extension stashed by your
+//! connection acceptor, or on any filter extracting a `String`.
+//!
+//! ```rust,no_run,ignore
+//! use warp_rate_limit::warp_v04::{key, with_rate_limit_by};
+//!
+//! let route = warp::path!("hello")
+//!     .and(with_rate_limit_by(RateLimitConfig::default(), key::header("x-forwarded-for")))
+//!     .map(|info: RateLimitInfo| ...);
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+use warp04::http::{HeaderName, HeaderValue};
+use warp04::{reject, Filter, Rejection, Reply};
+
+use crate::core::{RateLimitConfig, RateLimitInfo, RateLimitRejection, RateLimiter};
+
+impl reject::Reject for RateLimitRejection {}
+
+/// Creates a rate limiting filter keyed on whatever `key` extracts
+pub fn with_rate_limit_by<K>(
+    config: RateLimitConfig,
+    key: K,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+where
+    K: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    let rate_limiter = RateLimiter::new(config);
+
+    key.and(warp04::header::optional::<u64>("content-length"))
+        .and(warp04::method())
+        .and(warp04::header::optional::<String>("access-control-request-method"))
+        .and(warp04::ext::optional::<SocketAddr>())
+        .and(warp04::header::headers_cloned())
+        .map(
+            move |key: String,
+                  content_length: Option<u64>,
+                  method: warp04::http::Method,
+                  requested: Option<String>,
+                  peer: Option<SocketAddr>,
+                  headers: warp04::http::HeaderMap| {
+                let preflight = method == warp04::http::Method::OPTIONS && requested.is_some();
+                let peer = Peer {
+                    addr: peer.map(|peer| peer.ip()),
+                    headers: forwarding_headers(&headers),
+                };
+                (rate_limiter.clone(), key, content_length, preflight, peer)
+            },
+        )
+        .and_then(|(rate_limiter, key, content_length, preflight, peer): Checked| async move {
+            let screened = rate_limiter.screen(&key, peer.addr, &peer.headers).await;
+            let checked = if let Some(screened) = screened {
+                screened
+            } else if preflight {
+                rate_limiter.check_preflight(&key).await
+            } else {
+                let cost = rate_limiter
+                    .config()
+                    .content_length_cost
+                    .map_or(1, |c| c.cost(content_length));
+                rate_limiter.check_rate_limit_with_cost(&key, cost).await
+            };
+            if let Err(rejection) = &checked {
+                rate_limiter.tarpit(&key, rejection).await;
+            }
+            checked.map_err(reject::custom)
+        })
+}
+
+/// What a request is checked with
+type Checked = (RateLimiter, String, Option<u64>, bool, Peer);
+
+/// The peer's address, from a `SocketAddr` your connection acceptor stashed
+/// in the request extensions, and the headers that can forward it, for
+/// matching the client against the config's IP lists
+struct Peer {
+    addr: Option<IpAddr>,
+    headers: http::HeaderMap,
+}
+
+/// The forwarding headers of `headers`, converted to the http 0.2 types the
+/// core uses
+fn forwarding_headers(headers: &warp04::http::HeaderMap) -> http::HeaderMap {
+    let mut forwarding = http::HeaderMap::new();
+    for name in [http::header::FORWARDED, http::HeaderName::from_static("x-forwarded-for")] {
+        for value in headers.get_all(name.as_str()) {
+            if let Ok(value) = http::HeaderValue::from_bytes(value.as_bytes()) {
+                forwarding.append(name.clone(), value);
+            }
+        }
+    }
+    forwarding
+}
+
+/// Converts any reply into a response carrying the rate limit headers. If
+/// the headers cannot be built, the reply is returned unchanged and a
+/// warning is logged.
+pub fn with_rate_limit_headers(reply: impl Reply, info: &RateLimitInfo) -> warp04::reply::Response {
+    let mut response = reply.into_response();
+    match info.header_pairs(&info.retry_after_format) {
+        Ok(pairs) => {
+            // warp 0.4 is on http 1.x, so the header types are converted by name and bytes
+            for (name, value) in pairs {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.as_str().as_bytes()),
+                    HeaderValue::from_bytes(value.as_bytes()),
+                ) {
+                    response.headers_mut().insert(name, value);
+                }
+            }
+        }
+        Err(e) => tracing::warn!("{}", e),
+    }
+    response
+}
+
+/// Sources for the rate limit key. Each yields `"unknown"` when its source
+/// is missing, so requests without a key share one budget.
+pub mod key {
+    use warp04::{Filter, Rejection};
+
+    /// The value of the request header `name`
+    pub fn header(name: &'static str) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp04::header::optional::<String>(name).map(or_unknown)
+    }
+
+    /// A value stashed in the request extensions, e.g. by a connection
+    /// acceptor or hyper service in front of warp
+    pub fn extension<T>() -> impl Filter<Extract = (String,), Error = Rejection> + Clone
+    where
+        T: ToString + Clone + Send + Sync + 'static,
+    {
+        warp04::ext::optional::<T>()
+            .map(|value: Option<T>| or_unknown(value.map(|v| v.to_string())))
+            .and_then(|key: String| async move { Ok::<_, Rejection>(key) })
+    }
+
+    fn or_unknown(key: Option<String>) -> String {
+        key.unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp04::test::request;
+
+    #[tokio::test]
+    async fn test_header_key_and_headers() {
+        let route = with_rate_limit_by(RateLimitConfig::max_per_window(1, 60), key::header("x-api-key"))
+            .map(|info: RateLimitInfo| with_rate_limit_headers("hello", &info));
+
+        let resp = request().header("x-api-key", "alpha").reply(&route).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-ratelimit-remaining").unwrap(), "0");
+
+        assert!(request().header("x-api-key", "alpha").filter(&route).await.is_err());
+        assert!(request().header("x-api-key", "beta").filter(&route).await.is_ok());
+    }
+}